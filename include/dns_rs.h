@@ -0,0 +1,117 @@
+/*
+ * C interface to the dns-rs message codec. Build the shared library with
+ * `cargo rustc --lib --crate-type cdylib --features ffi` and link against it.
+ *
+ * Messages returned by dns_rs_parse are owned by the caller and must be released with
+ * dns_rs_message_free, which frees every name and rdata buffer reachable from them. Messages
+ * built by the caller and passed to dns_rs_encode stay owned by the caller.
+ */
+#ifndef DNS_RS_H
+#define DNS_RS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define DNS_RS_OK 0
+#define DNS_RS_ERR_NULL (-1)
+#define DNS_RS_ERR_PARSE (-2)
+#define DNS_RS_ERR_BUFFER_TOO_SMALL (-3)
+#define DNS_RS_ERR_INVALID (-4)
+
+#define DNS_RS_SECTION_QUESTION 0
+#define DNS_RS_SECTION_ANSWER 1
+#define DNS_RS_SECTION_AUTHORITY 2
+#define DNS_RS_SECTION_ADDITIONAL 3
+
+typedef struct DnsRsHeader {
+    uint16_t id;
+    bool qr;
+    uint8_t opcode;
+    bool aa;
+    bool tc;
+    bool rd;
+    bool ra;
+    uint8_t z;
+    uint8_t rcode;
+} DnsRsHeader;
+
+typedef struct DnsRsQuestion {
+    /*
+     * NUL terminated presentation form: "www.example.com.", "." for the root. Dots and
+     * backslashes inside labels are escaped with a backslash ("a\.b.example."), and bytes
+     * outside printable ASCII, NUL included, are written as \DDD in decimal ("a\000b.").
+     * Names passed to dns_rs_encode may leave out the trailing dot.
+     */
+    char *name;
+    uint16_t qtype;
+    /* As on the wire: the top bit is the mDNS unicast-response bit. */
+    uint16_t qclass;
+} DnsRsQuestion;
+
+typedef struct DnsRsRecord {
+    /* NUL terminated presentation form, as in DnsRsQuestion. */
+    char *name;
+    uint16_t rtype;
+    /* As on the wire: the top bit is the mDNS cache-flush bit. */
+    uint16_t rclass;
+    uint32_t ttl;
+    uint8_t *rdata;
+    size_t rdlength;
+} DnsRsRecord;
+
+typedef struct DnsRsMessage {
+    DnsRsHeader header;
+    DnsRsQuestion *questions;
+    size_t question_count;
+    DnsRsRecord *answers;
+    size_t answer_count;
+    DnsRsRecord *authorities;
+    size_t authority_count;
+    /* The OPT pseudo-record, if there is one, is among these as a record of type 41. */
+    DnsRsRecord *additionals;
+    size_t additional_count;
+} DnsRsMessage;
+
+/* Parses len bytes at buf and stores a newly allocated message in *out. */
+int dns_rs_parse(const uint8_t *buf, size_t len, DnsRsMessage **out);
+
+/*
+ * Serializes msg into buf. On success *written holds the number of bytes written. If cap is too
+ * small nothing is written, *written holds the required size and DNS_RS_ERR_BUFFER_TOO_SMALL is
+ * returned.
+ */
+int dns_rs_encode(const DnsRsMessage *msg, uint8_t *buf, size_t cap, size_t *written);
+
+/*
+ * Number of entries in section of msg, one of the DNS_RS_SECTION_* constants. 0 if msg is NULL
+ * or section isn't known.
+ */
+size_t dns_rs_section_count(const DnsRsMessage *msg, int section);
+
+/* The i-th question of msg, or NULL if there is none. The question belongs to msg. */
+const DnsRsQuestion *dns_rs_question(const DnsRsMessage *msg, size_t i);
+
+/* The i-th record of section of msg, or NULL if there is none. The record belongs to msg. */
+const DnsRsRecord *dns_rs_record(const DnsRsMessage *msg, int section, size_t i);
+
+/*
+ * Writes record in presentation form, as a line of a zone file, NUL terminated into buf.
+ * *written holds the length without the NUL. If cap can't hold the line and the NUL, nothing is
+ * written, *written holds the length and DNS_RS_ERR_BUFFER_TOO_SMALL is returned.
+ * DNS_RS_ERR_INVALID means the rdata doesn't fit the record type.
+ */
+int dns_rs_record_format(const DnsRsRecord *record, char *buf, size_t cap, size_t *written);
+
+/* Frees a message returned by dns_rs_parse. Passing NULL is a no-op. */
+void dns_rs_message_free(DnsRsMessage *msg);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* DNS_RS_H */
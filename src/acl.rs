@@ -0,0 +1,68 @@
+//! Which clients may do what, by address. An `Acl` is a list of subnets, each written
+//! `ADDR/PREFIX` or, for a single address, just `ADDR`.
+use std::{net::IpAddr, str::FromStr};
+
+use crate::text::TextError;
+
+/// The addresses sharing their leading `prefix` bits with `addr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Subnet {
+    /// Whether `ip` is in the subnet. IPv4 addresses mapped into IPv6, as a dual stack socket
+    /// reports IPv4 clients, count as the IPv4 address.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        return match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        };
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = TextError;
+
+    fn from_str(s: &str) -> Result<Subnet, TextError> {
+        let bad = TextError::BadField("subnet");
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| bad)?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| bad)?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(bad);
+        }
+        return Ok(Subnet { addr, prefix });
+    }
+}
+
+/// A list of subnets. An address is allowed if any of them contains it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    subnets: Vec<Subnet>,
+}
+
+impl Acl {
+    pub fn new(subnets: Vec<Subnet>) -> Acl {
+        return Acl { subnets };
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        return self.subnets.iter().any(|s| s.contains(ip));
+    }
+}
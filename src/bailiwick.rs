@@ -0,0 +1,167 @@
+//! Bailiwick checks for upstream answers, a basic defence against cache poisoning: records an
+//! answer carries about names it has no business speaking for are dropped before the answer is
+//! cached or passed on.
+//!
+//! The answer section may only hold records for the name asked about and the names its CNAME
+//! chain leads to. The authority section may only hold records at or below a zone enclosing one
+//! of those names, going by the SOA and NS records in the section. The additional section may
+//! only hold records at or below those zones, or below a name in the chain. The root only counts
+//! as such a zone for its own SOA record in a negative answer, e.g. NXDOMAIN for a name under a
+//! TLD that doesn't exist, which needs it to be cached.
+use crate::message::{rcode, Message, QType};
+use crate::name::Name;
+use crate::rdata::RData;
+
+/// Drops the records of the single-question answer `m` that are out of bailiwick. Returns how
+/// many were dropped. Answers to no question, or to several, are left alone.
+pub fn scrub(m: &mut Message) -> usize {
+    let [q] = m.questions.as_slice() else {
+        return 0;
+    };
+    let chain = cname_chain(m, &q.name);
+    let zones: Vec<Name> = m
+        .authorities
+        .iter()
+        .filter(|r| matches!(r.rdata, RData::SOA(_) | RData::NS(_)))
+        .map(|r| r.name.clone())
+        .filter(|zone| chain.iter().any(|name| name.is_subdomain_of(zone)))
+        // the root would vouch for anything.
+        .filter(|zone| !zone.is_root() || q.name.is_root())
+        .collect();
+    let before = m.answers.len() + m.authorities.len() + m.additionals.len();
+    m.answers.retain(|r| chain.contains(&r.name));
+    let negative = m.header.rcode == rcode::NXDOMAIN
+        || (m.header.rcode == rcode::NOERROR
+            && !m
+                .answers
+                .iter()
+                .any(|r| r.tipe == q.tipe || q.tipe == QType::ANY));
+    m.authorities.retain(|r| {
+        let root_soa = negative && r.name.is_root() && matches!(r.rdata, RData::SOA(_));
+        root_soa || zones.iter().any(|zone| r.name.is_subdomain_of(zone))
+    });
+    m.additionals.retain(|r| {
+        zones
+            .iter()
+            .chain(&chain)
+            .any(|zone| r.name.is_subdomain_of(zone))
+    });
+    return before - (m.answers.len() + m.authorities.len() + m.additionals.len());
+}
+
+/// `name`, followed by the names the CNAME records in the answer section of `m` lead it to, in
+/// order. A loop ends the chain.
+fn cname_chain(m: &Message, name: &Name) -> Vec<Name> {
+    let mut chain = vec![name.clone()];
+    loop {
+        let last = chain.last().unwrap();
+        let next = m.answers.iter().find_map(|r| match &r.rdata {
+            RData::CNAME(target) if r.name == *last && !chain.contains(target) => {
+                Some(target.clone())
+            }
+            _ => None,
+        });
+        match next {
+            Some(target) => chain.push(target),
+            None => return chain,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Header, Question, Record, ResourceClass};
+
+    fn answer(qname: &str, tipe: QType, rcode: u8, records: [&[&str]; 3]) -> Message {
+        let mut m = Message::new(Header::new(1));
+        m.header.qr = true;
+        m.header.rcode = rcode;
+        m.questions.push(Question {
+            name: qname.parse().unwrap(),
+            tipe,
+            class: ResourceClass::IN,
+            unicast_response: false,
+        });
+        let parse =
+            |lines: &[&str]| -> Vec<Record> { lines.iter().map(|l| l.parse().unwrap()).collect() };
+        m.answers = parse(records[0]);
+        m.authorities = parse(records[1]);
+        m.additionals = parse(records[2]);
+        return m;
+    }
+
+    const ROOT_SOA: &str =
+        ". 86400 IN SOA a.root-servers.net. nstld.verisign-grs.com. 1 1800 900 604800 86400";
+
+    #[test]
+    fn keeps_root_soa_of_nxdomain() {
+        let mut m = answer(
+            "printer.lan.",
+            QType::A,
+            rcode::NXDOMAIN,
+            [&[], &[ROOT_SOA], &[]],
+        );
+        assert_eq!(scrub(&mut m), 0);
+        assert_eq!(m.authorities.len(), 1);
+    }
+
+    #[test]
+    fn keeps_root_soa_of_nodata() {
+        let mut m = answer("lan.", QType::AAAA, rcode::NOERROR, [&[], &[ROOT_SOA], &[]]);
+        assert_eq!(scrub(&mut m), 0);
+    }
+
+    #[test]
+    fn drops_root_soa_of_positive_answer() {
+        let mut m = answer(
+            "example.com.",
+            QType::A,
+            rcode::NOERROR,
+            [&["example.com. 300 IN A 192.0.2.1"], &[ROOT_SOA], &[]],
+        );
+        assert_eq!(scrub(&mut m), 1);
+        assert!(m.authorities.is_empty());
+    }
+
+    #[test]
+    fn drops_root_delegation_and_its_glue() {
+        let mut m = answer(
+            "printer.lan.",
+            QType::A,
+            rcode::NXDOMAIN,
+            [
+                &[],
+                &[ROOT_SOA, ". 300 IN NS ns.evil.example."],
+                &["ns.evil.example. 300 IN A 192.0.2.66"],
+            ],
+        );
+        assert_eq!(scrub(&mut m), 2);
+        assert_eq!(m.authorities.len(), 1);
+        assert!(m.additionals.is_empty());
+    }
+
+    #[test]
+    fn drops_records_outside_the_chain() {
+        let mut m = answer(
+            "www.example.com.",
+            QType::A,
+            rcode::NOERROR,
+            [
+                &[
+                    "www.example.com. 300 IN CNAME web.example.net.",
+                    "web.example.net. 300 IN A 192.0.2.1",
+                    "bank.example. 300 IN A 192.0.2.66",
+                ],
+                &["example.net. 300 IN NS ns.example.net."],
+                &[
+                    "ns.example.net. 300 IN A 192.0.2.53",
+                    "ns.bank.example. 300 IN A 192.0.2.66",
+                ],
+            ],
+        );
+        assert_eq!(scrub(&mut m), 2);
+        assert_eq!(m.answers.len(), 2);
+        assert_eq!(m.additionals.len(), 1);
+    }
+}
@@ -0,0 +1,536 @@
+//! Answers from upstream resolvers, kept until their records expire so repeated questions are
+//! answered without asking upstream again. Negative answers, that a name doesn't exist or has no
+//! records of the type asked for, are kept too, for as long as the SOA record in their authority
+//! section allows (RFC 2308).
+//!
+//! An answer is only served to a question asked the same way as the one it answered, see
+//! `Asked`. Answers tailored to the client's subnet (RFC 7871) aren't cached at all.
+//!
+//! Entries count how often they are served, so the server can refresh popular ones shortly
+//! before they expire instead of letting their clients run into a miss.
+//!
+//! The whole cache can be written out with `Cache::save` and read back with `Cache::load`, e.g.
+//! across a restart. The file holds a magic line, then one record per entry: the wall clock times
+//! it was inserted and expires, in milliseconds since the Unix epoch, and the length of the
+//! answer followed by the answer itself in wire form, all integers big endian. The rcode and AD
+//! bit are in its header, along with the CD bit it was asked with, and an OPT record stands for
+//! the DO bit it was asked with.
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Write},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::message::{
+    rcode, Edns, EdnsOption, Header, Message, QType, Question, Record, ResourceClass,
+};
+use crate::name::Name;
+use crate::rdata::RData;
+
+/// Share of an entry's lifetime, at its end, in which a hit may trigger a prefetch.
+const PREFETCH_WINDOW: u32 = 10;
+
+/// Longest a negative answer is kept, whatever its SOA says. RFC 2308 suggests one to three
+/// hours.
+const MAX_NEGATIVE_TTL: u32 = 3 * 60 * 60;
+
+/// First line of a saved cache, with the format version.
+const MAGIC: &[u8] = b"dns-rs cache 2\n";
+
+/// owner name, type and class of a question, and how it was asked
+type Key = (Name, u16, u16, Asked);
+
+/// How a question was asked, as far as that changes the answer: with DNSSEC OK (RFC 3225), so
+/// the answer carries signatures, and with checking disabled (RFC 4035), so it may not have been
+/// validated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Asked {
+    pub dnssec_ok: bool,
+    pub checking_disabled: bool,
+}
+
+impl Asked {
+    /// How the query `m` asks its questions.
+    pub fn of(m: &Message) -> Asked {
+        return Asked {
+            dnssec_ok: m.edns.as_ref().is_some_and(|edns| edns.dnssec_ok()),
+            checking_disabled: m.header.z & Header::CHECKING_DISABLED != 0,
+        };
+    }
+}
+
+/// A cached answer to one question.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub rcode: u8,
+    /// whether the upstream set AD, vouching it validated the answer
+    pub authentic: bool,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+    /// when the answer came in
+    pub inserted: Instant,
+    /// when its record with the smallest TTL runs out
+    pub expires: Instant,
+    /// times it has been served
+    pub hits: u32,
+}
+
+impl Entry {
+    /// Whole seconds since the answer came in, as of `now`: how much the TTLs of its records have
+    /// run down.
+    pub fn age(&self, now: Instant) -> u32 {
+        let age = now.saturating_duration_since(self.inserted).as_secs();
+        return age.min(u32::MAX as u64) as u32;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Cache {
+    entries: HashMap<Key, Entry>,
+    /// most entries kept at once
+    capacity: usize,
+    /// bounds the TTLs of cached records are clamped to
+    min_ttl: u32,
+    max_ttl: u32,
+}
+
+impl Cache {
+    /// A cache holding at most `capacity` answers. With capacity 0 nothing is ever cached.
+    pub fn new(capacity: usize) -> Cache {
+        return Cache {
+            entries: HashMap::new(),
+            capacity,
+            min_ttl: 0,
+            max_ttl: u32::MAX,
+        };
+    }
+
+    /// Clamps the TTLs of records to between `min` and `max` seconds as they are cached, which
+    /// also decides how long they are kept.
+    pub fn with_ttl_limits(mut self, min: u32, max: u32) -> Cache {
+        self.min_ttl = min;
+        self.max_ttl = max;
+        return self;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    /// The answer to `q` asked as `asked`, if one is cached and hasn't expired at `now`. Counts
+    /// as a hit.
+    pub fn get(&mut self, q: &Question, asked: Asked, now: Instant) -> Option<&Entry> {
+        let entry = self
+            .entries
+            .get_mut(&key(q, asked))
+            .filter(|entry| entry.expires > now)?;
+        entry.hits = entry.hits.saturating_add(1);
+        return Some(entry);
+    }
+
+    /// Whether the answer to `q` is worth refreshing ahead of its expiry: it has been served at
+    /// least `min_hits` times and is into the last tenth of its lifetime at `now`. Never with
+    /// `min_hits` 0.
+    pub fn prefetch_due(&self, q: &Question, asked: Asked, min_hits: u32, now: Instant) -> bool {
+        let Some(entry) = self.entries.get(&key(q, asked)) else {
+            return false;
+        };
+        let lifetime = entry.expires.saturating_duration_since(entry.inserted);
+        let remaining = entry.expires.saturating_duration_since(now);
+        return min_hits > 0
+            && entry.hits >= min_hits
+            && !remaining.is_zero()
+            && remaining <= lifetime / PREFETCH_WINDOW;
+    }
+
+    /// Caches `answer` as the answer to its question asked as `asked`, if it is worth keeping: a
+    /// complete answer to a single question, the same for every client subnet, with a TTL above
+    /// zero once clamped to the TTL limits. See `ttl` for which answers have one.
+    pub fn insert(&mut self, answer: &Message, asked: Asked, now: Instant) {
+        if self.capacity == 0 || answer.header.tc || subnet_scoped(answer) {
+            return;
+        }
+        let [q] = answer.questions.as_slice() else {
+            return;
+        };
+        let mut answer = answer.clone();
+        clamp_ttls(&mut answer, self.min_ttl, self.max_ttl);
+        let min_ttl = match ttl(&answer).map(|ttl| ttl.max(self.min_ttl).min(self.max_ttl)) {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return,
+        };
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key(q, asked)) {
+            self.make_room(now);
+        }
+        let entry = Entry {
+            rcode: answer.header.rcode,
+            authentic: answer.header.z & Header::AUTHENTIC_DATA != 0,
+            answers: answer.answers,
+            authorities: answer.authorities,
+            additionals: answer.additionals,
+            inserted: now,
+            expires: now + Duration::from_secs(min_ttl as u64),
+            hits: 0,
+        };
+        self.entries.insert(key(q, asked), entry);
+    }
+
+    /// Writes every entry still live at `now` to `w`, in the format described at the top of this
+    /// module. `wall` is the wall clock time at `now`.
+    pub fn save(&self, w: &mut impl Write, now: Instant, wall: SystemTime) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        for ((name, tipe, class, asked), entry) in self.entries.iter() {
+            if entry.expires <= now {
+                continue;
+            }
+            let mut m = Message::new(Header::new(0));
+            m.header.qr = true;
+            m.header.rcode = entry.rcode;
+            if entry.authentic {
+                m.header.z = Header::AUTHENTIC_DATA;
+            }
+            if asked.checking_disabled {
+                m.header.z |= Header::CHECKING_DISABLED;
+            }
+            if asked.dnssec_ok {
+                let mut edns = Edns::new(512);
+                edns.set_dnssec_ok(true);
+                m.edns = Some(edns);
+            }
+            m.questions.push(Question {
+                name: name.clone(),
+                tipe: QType::from_value(*tipe),
+                class: ResourceClass::from_value(*class),
+                unicast_response: false,
+            });
+            m.answers = entry.answers.clone();
+            m.authorities = entry.authorities.clone();
+            m.additionals = entry.additionals.clone();
+            let bites = m.to_bytes();
+            // too long to be a DNS message, so `load` would refuse the file.
+            if bites.len() > u16::MAX as usize {
+                continue;
+            }
+            w.write_all(&unix_millis(entry.inserted, now, wall).to_be_bytes())?;
+            w.write_all(&unix_millis(entry.expires, now, wall).to_be_bytes())?;
+            w.write_all(&(bites.len() as u32).to_be_bytes())?;
+            w.write_all(&bites)?;
+        }
+        return Ok(());
+    }
+
+    /// Reads entries written by `save` from `r`, dropping those expired by `now` and any beyond
+    /// the capacity. `wall` is the wall clock time at `now`. Returns how many were loaded. A
+    /// file cut off inside an entry, or with an entry over 65535 bytes, is an error; the entries
+    /// before it stay loaded.
+    pub fn load(&mut self, r: &mut impl Read, now: Instant, wall: SystemTime) -> io::Result<usize> {
+        let mut magic = [0; MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a saved cache"));
+        }
+        let mut loaded = 0;
+        loop {
+            let mut times = [0; 20];
+            match read_full(r, &mut times)? {
+                0 => return Ok(loaded),
+                20 => {}
+                _ => return Err(ErrorKind::UnexpectedEof.into()),
+            }
+            let inserted = u64::from_be_bytes(times[..8].try_into().unwrap());
+            let expires = u64::from_be_bytes(times[8..16].try_into().unwrap());
+            let len = u32::from_be_bytes(times[16..].try_into().unwrap());
+            // checked before allocating, as the file may not be one `save` wrote.
+            if len > u16::MAX as u32 {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("saved answer of {} bytes", len),
+                ));
+            }
+            let mut bites = vec![0; len as usize];
+            r.read_exact(&mut bites)?;
+            let m = Message::parse(&bites)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            let (Some(inserted), Some(expires)) = (
+                from_unix_millis(inserted, now, wall),
+                from_unix_millis(expires, now, wall),
+            ) else {
+                continue;
+            };
+            let [q] = m.questions.as_slice() else {
+                continue;
+            };
+            if expires <= now || self.entries.len() >= self.capacity {
+                continue;
+            }
+            let entry = Entry {
+                rcode: m.header.rcode,
+                authentic: m.header.z & Header::AUTHENTIC_DATA != 0,
+                answers: m.answers.clone(),
+                authorities: m.authorities.clone(),
+                additionals: m.additionals.clone(),
+                inserted,
+                expires,
+                hits: 0,
+            };
+            self.entries.insert(key(q, Asked::of(&m)), entry);
+            loaded += 1;
+        }
+    }
+
+    /// Drops every entry expired at `now`.
+    pub fn purge(&mut self, now: Instant) {
+        self.entries.retain(|_, entry| entry.expires > now);
+    }
+
+    /// Frees a slot for a new entry: drops what has expired, or failing that the entry that
+    /// would expire first.
+    fn make_room(&mut self, now: Instant) {
+        self.purge(now);
+        if self.entries.len() < self.capacity {
+            return;
+        }
+        let soonest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.expires)
+            .map(|(key, _)| key.clone());
+        if let Some(soonest) = soonest {
+            self.entries.remove(&soonest);
+        }
+    }
+}
+
+/// How long `answer` may be cached, if at all. A positive answer lasts as long as the record with
+/// the smallest TTL. NXDOMAIN and NODATA answers last as long as the SOA record in the authority
+/// section, or its minimum field if that is smaller (RFC 2308 section 5); without an SOA record
+/// they aren't cached. Other rcodes, and referrals, aren't cached either.
+fn ttl(answer: &Message) -> Option<u32> {
+    let positive = answer.header.rcode == rcode::NOERROR && !answer.answers.is_empty();
+    if positive {
+        return answer
+            .answers
+            .iter()
+            .chain(&answer.authorities)
+            .chain(&answer.additionals)
+            .map(|r| r.ttl)
+            .min();
+    }
+    if answer.header.rcode != rcode::NOERROR && answer.header.rcode != rcode::NXDOMAIN {
+        return None;
+    }
+    return answer.authorities.iter().find_map(|r| match &r.rdata {
+        RData::SOA(soa) => Some(r.ttl.min(soa.minimum).min(MAX_NEGATIVE_TTL)),
+        _ => None,
+    });
+}
+
+/// Clamps the TTL of every record in `m` to between `min` and `max` seconds.
+pub fn clamp_ttls(m: &mut Message, min: u32, max: u32) {
+    for r in m
+        .answers
+        .iter_mut()
+        .chain(&mut m.authorities)
+        .chain(&mut m.additionals)
+    {
+        r.ttl = r.ttl.max(min).min(max);
+    }
+}
+
+fn key(q: &Question, asked: Asked) -> Key {
+    return (q.name.clone(), q.tipe.value(), q.class.value(), asked);
+}
+
+/// Whether `answer` says it only holds for part of the address space (RFC 7871 section 7.3).
+fn subnet_scoped(answer: &Message) -> bool {
+    return answer
+        .edns
+        .iter()
+        .flat_map(|edns| &edns.options)
+        .any(|o| matches!(o, EdnsOption::ClientSubnet { scope_prefix, .. } if *scope_prefix > 0));
+}
+
+/// `at` as milliseconds since the Unix epoch, going by `wall` being the wall clock time at `now`.
+fn unix_millis(at: Instant, now: Instant, wall: SystemTime) -> u64 {
+    let wall_at = if at >= now {
+        wall + (at - now)
+    } else {
+        wall - (now - at)
+    };
+    let since_epoch = wall_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    return since_epoch.as_millis() as u64;
+}
+
+/// The inverse of `unix_millis`. None if the result can't be represented as an `Instant`.
+fn from_unix_millis(millis: u64, now: Instant, wall: SystemTime) -> Option<Instant> {
+    let wall_at = UNIX_EPOCH + Duration::from_millis(millis);
+    return match wall_at.duration_since(wall) {
+        Ok(ahead) => now.checked_add(ahead),
+        Err(e) => now.checked_sub(e.duration()),
+    };
+}
+
+/// Reads from `r` until `buf` is full or the stream ends. Returns how much was read.
+fn read_full(r: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match r.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(read);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn question(name: &str) -> Question {
+        return Question {
+            name: name.parse().unwrap(),
+            tipe: QType::A,
+            class: ResourceClass::IN,
+            unicast_response: false,
+        };
+    }
+
+    fn answer(name: &str) -> Message {
+        let mut m = Message::new(Header::new(1));
+        m.header.qr = true;
+        m.questions.push(question(name));
+        let record = format!("{} 300 IN A 192.0.2.1", name);
+        m.answers.push(record.parse().unwrap());
+        return m;
+    }
+
+    const PLAIN: Asked = Asked {
+        dnssec_ok: false,
+        checking_disabled: false,
+    };
+
+    #[test]
+    fn answers_are_kept_apart_by_do_and_cd() {
+        let now = Instant::now();
+        let mut cache = Cache::new(10);
+        let unvalidated = Asked {
+            checking_disabled: true,
+            ..PLAIN
+        };
+        cache.insert(&answer("example.com."), unvalidated, now);
+        let q = question("example.com.");
+        assert!(cache.get(&q, unvalidated, now).is_some());
+        assert!(cache.get(&q, PLAIN, now).is_none());
+        let validator = Asked {
+            dnssec_ok: true,
+            ..PLAIN
+        };
+        assert!(cache.get(&q, validator, now).is_none());
+    }
+
+    #[test]
+    fn subnet_scoped_answers_are_not_cached() {
+        let now = Instant::now();
+        let mut cache = Cache::new(10);
+        let mut scoped = answer("example.com.");
+        let mut edns = Edns::new(1232);
+        edns.options.push(EdnsOption::ClientSubnet {
+            source_prefix: 24,
+            scope_prefix: 24,
+            address: "192.0.2.0".parse().unwrap(),
+        });
+        scoped.edns = Some(edns.clone());
+        cache.insert(&scoped, PLAIN, now);
+        assert!(cache.is_empty());
+        // scope 0 says the answer is the same for everyone.
+        if let EdnsOption::ClientSubnet { scope_prefix, .. } = &mut edns.options[0] {
+            *scope_prefix = 0;
+        }
+        scoped.edns = Some(edns);
+        cache.insert(&scoped, PLAIN, now);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn entries_expire_with_their_smallest_ttl() {
+        let now = Instant::now();
+        let mut cache = Cache::new(10);
+        cache.insert(&answer("example.com."), PLAIN, now);
+        let q = question("example.com.");
+        let later = now + Duration::from_secs(299);
+        assert_eq!(cache.get(&q, PLAIN, later).unwrap().age(later), 299);
+        assert!(cache
+            .get(&q, PLAIN, now + Duration::from_secs(300))
+            .is_none());
+    }
+
+    #[test]
+    fn nxdomain_with_a_root_soa_is_cached() {
+        let now = Instant::now();
+        let mut cache = Cache::new(10);
+        let mut m = Message::new(Header::new(1));
+        m.header.qr = true;
+        m.header.rcode = rcode::NXDOMAIN;
+        m.questions.push(question("printer.lan."));
+        let soa =
+            ". 86400 IN SOA a.root-servers.net. nstld.verisign-grs.com. 1 1800 900 604800 3600";
+        m.authorities.push(soa.parse().unwrap());
+        cache.insert(&m, PLAIN, now);
+        let entry = cache.get(&question("printer.lan."), PLAIN, now).unwrap();
+        assert_eq!(entry.rcode, rcode::NXDOMAIN);
+        assert_eq!(entry.expires - entry.inserted, Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn save_and_load_keep_how_answers_were_asked() {
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let mut cache = Cache::new(10);
+        let validator = Asked {
+            dnssec_ok: true,
+            checking_disabled: true,
+        };
+        cache.insert(&answer("example.com."), validator, now);
+        let mut saved = Vec::new();
+        cache.save(&mut saved, now, wall).unwrap();
+        let mut loaded = Cache::new(10);
+        assert_eq!(loaded.load(&mut &saved[..], now, wall).unwrap(), 1);
+        let q = question("example.com.");
+        assert!(loaded.get(&q, PLAIN, now).is_none());
+        assert!(loaded.get(&q, validator, now).is_some());
+    }
+
+    #[test]
+    fn broken_files_fail_to_load() {
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let mut cache = Cache::new(10);
+        cache.insert(&answer("example.com."), PLAIN, now);
+        let mut saved = Vec::new();
+        cache.save(&mut saved, now, wall).unwrap();
+        let entry_at = MAGIC.len();
+
+        // cut off in the times, and in the answer.
+        for len in [entry_at + 10, saved.len() - 1] {
+            let err = Cache::new(10)
+                .load(&mut &saved[..len], now, wall)
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        }
+
+        // a length no DNS message can have.
+        let mut oversized = saved.clone();
+        oversized[entry_at + 16..entry_at + 20].copy_from_slice(&u32::MAX.to_be_bytes());
+        let err = Cache::new(10)
+            .load(&mut &oversized[..], now, wall)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
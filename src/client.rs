@@ -0,0 +1,184 @@
+//! The stub resolver's side of an exchange: send a query to a server and wait for its response,
+//! over UDP, and again over TCP when the UDP response comes back truncated (RFC 7766). Blocking,
+//! for scripts and tools rather than the server.
+use bytes::BytesMut;
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::message::{Message, MessageError};
+use crate::stream::{self, FrameDecoder};
+
+/// Why a query got no usable response.
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("malformed response: {0}")]
+    Parse(#[from] MessageError),
+    #[error("query is too long for TCP")]
+    TooLong,
+}
+
+/// Sends `query` to `server` over UDP, retrying over TCP if the response is truncated.
+/// `timeout` applies to each transport.
+pub fn query(
+    server: SocketAddr,
+    query: &Message,
+    timeout: Duration,
+) -> Result<Message, ClientError> {
+    let response = query_udp(server, query, timeout)?;
+    if !response.header.tc {
+        return Ok(response);
+    }
+    return query_tcp(server, query, timeout);
+}
+
+/// Sends `query` to `server` over UDP and waits up to `timeout` for the response. Datagrams that
+/// aren't a response to it, e.g. a late response to an earlier query, are skipped.
+pub fn query_udp(
+    server: SocketAddr,
+    query: &Message,
+    timeout: Duration,
+) -> Result<Message, ClientError> {
+    let local = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local)?;
+    // a connected socket only hears from the server.
+    socket.connect(server)?;
+    socket.send(&query.to_bytes())?;
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0; u16::MAX as usize];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::from(ErrorKind::TimedOut).into());
+        }
+        socket.set_read_timeout(Some(left))?;
+        let len = socket.recv(&mut buf)?;
+        if let Ok(response) = Message::parse(&buf[..len]) {
+            if answers(query, &response) {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+/// Sends `query` to `server` over a new TCP connection and waits for the response. `timeout`
+/// applies to the connect and to each read and write.
+pub fn query_tcp(
+    server: SocketAddr,
+    query: &Message,
+    timeout: Duration,
+) -> Result<Message, ClientError> {
+    let mut framed = BytesMut::new();
+    stream::write_frame(query, &mut framed).map_err(|_| ClientError::TooLong)?;
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(&framed)?;
+    let mut frames = FrameDecoder::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(frame) = frames.next_frame() {
+            return Ok(Message::parse(&frame)?);
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(n) => frames.push(&chunk[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Whether `response` is the response to `query`: same id, same questions.
+fn answers(query: &Message, response: &Message) -> bool {
+    return response.header.qr
+        && response.header.id == query.header.id
+        && response.questions.len() == query.questions.len()
+        && response
+            .questions
+            .iter()
+            .zip(&query.questions)
+            .all(|(a, q)| a.name == q.name && a.tipe == q.tipe && a.class == q.class);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Header, QType, Question, ResourceClass};
+    use std::{net::TcpListener, thread};
+
+    fn question() -> Message {
+        let mut m = Message::new(Header::new(0x4242));
+        m.header.rd = true;
+        m.questions.push(Question {
+            name: "example.com".parse().unwrap(),
+            tipe: QType::A,
+            class: ResourceClass::IN,
+            unicast_response: false,
+        });
+        return m;
+    }
+
+    #[test]
+    fn truncated_udp_response_is_retried_over_tcp() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(server).unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, from) = udp.recv_from(&mut buf).unwrap();
+            let query = Message::parse(&buf[..len]).unwrap();
+            // a stray datagram first, which must be skipped.
+            let mut stray = query.clone();
+            stray.header.qr = true;
+            stray.header.id ^= 1;
+            udp.send_to(&stray.to_bytes(), from).unwrap();
+            let mut truncated = query;
+            truncated.header.qr = true;
+            truncated.header.tc = true;
+            udp.send_to(&truncated.to_bytes(), from).unwrap();
+        });
+        thread::spawn(move || {
+            let (mut stream, _) = tcp.accept().unwrap();
+            let mut frames = FrameDecoder::new();
+            let mut chunk = [0; 512];
+            let frame = loop {
+                if let Some(frame) = frames.next_frame() {
+                    break frame;
+                }
+                let n = stream.read(&mut chunk).unwrap();
+                frames.push(&chunk[..n]);
+            };
+            let mut response = Message::parse(&frame).unwrap();
+            response.header.qr = true;
+            response
+                .answers
+                .push("example.com. 60 IN A 192.0.2.1".parse().unwrap());
+            let bites = response.to_bytes();
+            stream
+                .write_all(&(bites.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&bites).unwrap();
+        });
+        let response = query(server, &question(), Duration::from_secs(2)).unwrap();
+        assert_eq!(response.header.id, 0x4242);
+        assert!(!response.header.tc);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn silent_server_times_out() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = udp.local_addr().unwrap();
+        let err = query_udp(server, &question(), Duration::from_millis(50)).unwrap_err();
+        assert!(matches!(err, ClientError::Io(_)));
+    }
+}
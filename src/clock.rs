@@ -0,0 +1,81 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Source of "now" for everything in the server that expires: cached records and pending
+/// upstream transactions.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The real monotonic clock. This is what the server uses unless told otherwise.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        return Instant::now();
+    }
+}
+
+/// A clock that only moves when it is told to, so TTL expiry and retry timeouts can be exercised
+/// without sleeping. Clones share the same time, so a test can keep one handle and give the other
+/// to the server.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += by;
+    }
+
+    pub fn set(&self, to: Instant) {
+        *self.now.lock().unwrap() = to;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        return *self.now.lock().unwrap();
+    }
+}
+
+/// The point in time by which a client query must be answered. Every stage that works on the
+/// query (upstream attempts, retries, fallbacks) takes its time out of the same budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    pub fn after(clock: &dyn Clock, budget: Duration) -> Self {
+        Deadline(clock.now() + budget)
+    }
+
+    pub fn at(&self) -> Instant {
+        self.0
+    }
+
+    /// Time left in the budget, zero once it is spent.
+    pub fn remaining(&self, clock: &dyn Clock) -> Duration {
+        self.0.saturating_duration_since(clock.now())
+    }
+
+    pub fn expired(&self, clock: &dyn Clock) -> bool {
+        clock.now() >= self.0
+    }
+}
@@ -0,0 +1,477 @@
+//! DNS over HTTPS (RFC 8484), client side, without any I/O: `Request` turns a query into the
+//! HTTP request that carries it, and `parse_response` or `ResponseDecoder` get the response
+//! back out. Sending the request, over TLS, is up to the caller: `fetch` in a browser, any HTTP
+//! client elsewhere, or `Request::to_http1` written to a TLS stream.
+use alloc::{string::String, vec::Vec};
+use bytes::{Buf, BufMut, BytesMut};
+use core::str;
+
+use crate::message::{Message, MessageError};
+
+/// The media type of DNS messages in requests and responses.
+pub const MEDIA_TYPE: &str = "application/dns-message";
+
+/// Longest status line and headers `ResponseDecoder` buffers before giving up on a response.
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Why an HTTP response holds no DNS response.
+#[derive(Debug, thiserror::Error)]
+pub enum DohError {
+    #[error("malformed HTTP response: {0}")]
+    Http(&'static str),
+    #[error("HTTP status {0}")]
+    Status(u16),
+    #[error("response of type {0:?} instead of application/dns-message")]
+    ContentType(String),
+    #[error("malformed DNS response: {0}")]
+    Parse(#[from] MessageError),
+}
+
+/// How the query goes to the server (RFC 8484 section 4.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// in the `dns` parameter of the URL, which HTTP caches can key on
+    Get,
+    /// as the request body
+    Post,
+}
+
+/// The HTTP request for one query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    /// path and query string, e.g. `/dns-query?dns=AAABAAAB...`
+    pub path: String,
+    /// the query for POST, empty for GET
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// The request for `query` to the endpoint at `path`, e.g. `/dns-query`. The query is sent
+    /// with id 0, as RFC 8484 section 4.1 asks so that identical queries are cached alike; match
+    /// the response to the request it came back on instead.
+    pub fn new(query: &Message, path: &str, method: Method) -> Request {
+        let mut query = query.clone();
+        query.header.id = 0;
+        let bites = query.to_bytes();
+        return match method {
+            Method::Get => {
+                let mut path = String::from(path);
+                path.push(if path.contains('?') { '&' } else { '?' });
+                path.push_str("dns=");
+                base64url(&bites, &mut path);
+                Request {
+                    method,
+                    path,
+                    body: Vec::new(),
+                }
+            }
+            Method::Post => Request {
+                method,
+                path: String::from(path),
+                body: bites.to_vec(),
+            },
+        };
+    }
+
+    pub fn method_name(&self) -> &'static str {
+        return match self.method {
+            Method::Get => "GET",
+            Method::Post => "POST",
+        };
+    }
+
+    /// The request as HTTP/1.1 to send to `host`, headers and body included.
+    pub fn to_http1(&self, host: &str) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.path.len() + self.body.len() + 128);
+        for part in [
+            self.method_name(),
+            " ",
+            self.path.as_str(),
+            " HTTP/1.1\r\nHost: ",
+            host,
+        ] {
+            buf.put_slice(part.as_bytes());
+        }
+        buf.put_slice(b"\r\nAccept: ");
+        buf.put_slice(MEDIA_TYPE.as_bytes());
+        if self.method == Method::Post {
+            buf.put_slice(b"\r\nContent-Type: ");
+            buf.put_slice(MEDIA_TYPE.as_bytes());
+            buf.put_slice(alloc::format!("\r\nContent-Length: {}", self.body.len()).as_bytes());
+        }
+        buf.put_slice(b"\r\n\r\n");
+        buf.put_slice(&self.body);
+        return buf;
+    }
+}
+
+/// The DNS response in an HTTP response, for callers whose HTTP client has already read the
+/// status, the Content-Type header (if any) and the body.
+pub fn parse_response(
+    status: u16,
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<Message, DohError> {
+    if !(200..300).contains(&status) {
+        return Err(DohError::Status(status));
+    }
+    let content_type = content_type.unwrap_or("");
+    // parameters, if any, don't matter.
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    if !media_type.eq_ignore_ascii_case(MEDIA_TYPE) {
+        return Err(DohError::ContentType(String::from(content_type)));
+    }
+    return Ok(Message::parse(body)?);
+}
+
+/// Collects what comes back on an HTTP/1.1 connection and splits it into responses. Bodies must
+/// have a Content-Length or be chunked; interim 1xx responses are skipped.
+#[derive(Debug, Default)]
+pub struct ResponseDecoder {
+    buf: BytesMut,
+}
+
+impl ResponseDecoder {
+    pub fn new() -> ResponseDecoder {
+        return ResponseDecoder::default();
+    }
+
+    /// Appends data read from the connection.
+    pub fn push(&mut self, bites: &[u8]) {
+        self.buf.extend_from_slice(bites);
+    }
+
+    /// Takes the next complete response off the front and gets the DNS response out of it. None
+    /// until all of it has arrived. A status or content type that isn't a DNS response, or a
+    /// malformed message, only costs this response; after `DohError::Http` the connection is
+    /// beyond repair.
+    pub fn next_response(&mut self) -> Result<Option<Message>, DohError> {
+        loop {
+            let Some(head_len) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+                if self.buf.len() > MAX_HEAD_LEN {
+                    return Err(DohError::Http("headers too long"));
+                }
+                return Ok(None);
+            };
+            let head = Head::parse(&self.buf[..head_len])?;
+            let rest = &self.buf[head_len + 4..];
+            if (100..200).contains(&head.status) {
+                self.buf.advance(head_len + 4);
+                continue;
+            }
+            let (body, body_len) = if head.chunked {
+                match dechunk(rest)? {
+                    Some(body) => body,
+                    None => return Ok(None),
+                }
+            } else {
+                let len = head
+                    .content_length
+                    .ok_or(DohError::Http("no Content-Length"))?;
+                if rest.len() < len {
+                    return Ok(None);
+                }
+                (rest[..len].to_vec(), len)
+            };
+            self.buf.advance(head_len + 4 + body_len);
+            let content_type = head.content_type.as_deref();
+            return parse_response(head.status, content_type, &body).map(Some);
+        }
+    }
+
+    /// Bytes received that aren't part of a complete response yet.
+    pub fn buffered(&self) -> usize {
+        return self.buf.len();
+    }
+}
+
+/// What a response's status line and headers say about it.
+struct Head {
+    status: u16,
+    content_type: Option<String>,
+    content_length: Option<usize>,
+    chunked: bool,
+}
+
+impl Head {
+    /// Reads the status line and headers, without the blank line after them.
+    fn parse(bites: &[u8]) -> Result<Head, DohError> {
+        let text = str::from_utf8(bites).map_err(|_| DohError::Http("headers not text"))?;
+        let mut lines = text.split("\r\n");
+        let status_line = lines.next().unwrap_or("");
+        let mut parts = status_line.splitn(3, ' ');
+        if !parts.next().unwrap_or("").starts_with("HTTP/1.") {
+            return Err(DohError::Http("not HTTP/1"));
+        }
+        let status = parts
+            .next()
+            .and_then(|s| s.parse().ok())
+            .ok_or(DohError::Http("bad status"))?;
+        let mut head = Head {
+            status,
+            content_type: None,
+            content_length: None,
+            chunked: false,
+        };
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or(DohError::Http("bad header"))?;
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-type") {
+                head.content_type = Some(String::from(value));
+            } else if name.eq_ignore_ascii_case("content-length") {
+                let len: usize = value
+                    .parse()
+                    .map_err(|_| DohError::Http("bad Content-Length"))?;
+                if len > u16::MAX as usize {
+                    return Err(DohError::Http("body too long for a DNS message"));
+                }
+                head.content_length = Some(len);
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                head.chunked = value
+                    .rsplit(',')
+                    .next()
+                    .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+            }
+        }
+        return Ok(head);
+    }
+}
+
+/// Joins the chunks of a chunked body (RFC 9112 section 7.1) at the front of `bites`. Returns
+/// the body and how many bytes it took up, trailers included, or None if it isn't all there.
+fn dechunk(bites: &[u8]) -> Result<Option<(Vec<u8>, usize)>, DohError> {
+    let line_end = |from: usize| {
+        bites[from..]
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .map(|i| from + i)
+    };
+    let mut body = Vec::new();
+    let mut at = 0;
+    loop {
+        let Some(end) = line_end(at) else {
+            return Ok(None);
+        };
+        // a chunk size in hex, maybe followed by extensions.
+        let size = str::from_utf8(&bites[at..end])
+            .ok()
+            .and_then(|line| line.split(';').next())
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+            .ok_or(DohError::Http("bad chunk size"))?;
+        at = end + 2;
+        if size == 0 {
+            break;
+        }
+        if body.len() + size > u16::MAX as usize {
+            return Err(DohError::Http("body too long for a DNS message"));
+        }
+        if bites.len() < at + size + 2 {
+            return Ok(None);
+        }
+        if &bites[at + size..at + size + 2] != b"\r\n" {
+            return Err(DohError::Http("bad chunk"));
+        }
+        body.extend_from_slice(&bites[at..at + size]);
+        at += size + 2;
+    }
+    // trailers, up to a blank line.
+    loop {
+        let Some(end) = line_end(at) else {
+            return Ok(None);
+        };
+        let blank = end == at;
+        at = end + 2;
+        if blank {
+            return Ok(Some((body, at)));
+        }
+    }
+}
+
+/// Appends `bites` as unpadded base64url (RFC 4648 section 5), as the `dns` parameter wants it.
+fn base64url(bites: &[u8], out: &mut String) {
+    for chunk in bites.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            let sextet = (n >> (18 - 6 * i)) & 0x3f;
+            out.push(BASE64URL[sextet as usize] as char);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Header, QType, Question, ResourceClass};
+    use alloc::vec;
+
+    /// The query of RFC 8484 section 4.1.1: www.example.com, type A, recursion desired.
+    fn query() -> Message {
+        let mut m = Message::new(Header::new(0x4242));
+        m.header.rd = true;
+        m.questions.push(Question {
+            name: "www.example.com".parse().unwrap(),
+            tipe: QType::A,
+            class: ResourceClass::IN,
+            unicast_response: false,
+        });
+        return m;
+    }
+
+    fn response() -> Message {
+        let mut m = query();
+        m.header.id = 0;
+        m.header.qr = true;
+        m.answers
+            .push("www.example.com. 300 IN A 192.0.2.1".parse().unwrap());
+        return m;
+    }
+
+    fn http1(head: &str, body: &[u8]) -> Vec<u8> {
+        return [head.as_bytes(), b"\r\n\r\n", body].concat();
+    }
+
+    #[test]
+    fn get_puts_the_query_in_the_url() {
+        let request = Request::new(&query(), "/dns-query", Method::Get);
+        // as in RFC 8484 section 4.1.1, id 0 included.
+        assert_eq!(
+            request.path,
+            "/dns-query?dns=AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB"
+        );
+        assert!(request.body.is_empty());
+        let with_params = Request::new(&query(), "/q?ct", Method::Get);
+        assert!(with_params.path.starts_with("/q?ct&dns=AAAB"));
+        assert_eq!(
+            request.to_http1("dns.example.com"),
+            b"GET /dns-query?dns=AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB HTTP/1.1\r\n\
+              Host: dns.example.com\r\nAccept: application/dns-message\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn post_puts_the_query_in_the_body() {
+        let request = Request::new(&query(), "/dns-query", Method::Post);
+        assert_eq!(request.path, "/dns-query");
+        let mut expected = query();
+        expected.header.id = 0;
+        assert_eq!(request.body, expected.to_bytes());
+        let http = request.to_http1("dns.example.com");
+        let head = alloc::format!(
+            "POST /dns-query HTTP/1.1\r\nHost: dns.example.com\r\nAccept: {0}\r\n\
+             Content-Type: {0}\r\nContent-Length: {1}",
+            MEDIA_TYPE,
+            request.body.len()
+        );
+        assert_eq!(http, http1(&head, &request.body));
+    }
+
+    #[test]
+    fn base64url_has_no_padding() {
+        let encode = |bites: &[u8]| {
+            let mut out = String::new();
+            base64url(bites, &mut out);
+            return out;
+        };
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(b"f"), "Zg");
+        assert_eq!(encode(b"fo"), "Zm8");
+        assert_eq!(encode(b"foo"), "Zm9v");
+        assert_eq!(encode(&[0xfb, 0xff]), "-_8");
+    }
+
+    #[test]
+    fn responses_split_across_reads() {
+        let body = response().to_bytes();
+        let head = alloc::format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: {}",
+            body.len()
+        );
+        let bites = [http1("HTTP/1.1 100 Continue", b""), http1(&head, &body)].concat();
+        let mut responses = ResponseDecoder::new();
+        for chunk in bites.chunks(7) {
+            assert!(responses.next_response().unwrap().is_none());
+            responses.push(chunk);
+        }
+        let m = responses.next_response().unwrap().unwrap();
+        assert_eq!(m.answers.len(), 1);
+        assert_eq!(responses.buffered(), 0);
+    }
+
+    #[test]
+    fn chunked_responses_are_joined() {
+        let body = response().to_bytes();
+        let (one, two) = body.split_at(10);
+        let mut chunked = alloc::format!("{:x};ext=1\r\n", one.len()).into_bytes();
+        chunked.extend_from_slice(one);
+        chunked.extend_from_slice(alloc::format!("\r\n{:X}\r\n", two.len()).as_bytes());
+        chunked.extend_from_slice(two);
+        chunked.extend_from_slice(b"\r\n0\r\nX-Trailer: 1\r\n\r\n");
+        let head = "HTTP/1.1 200 OK\r\ncontent-type: Application/DNS-Message; charset=x\r\n\
+                    Transfer-Encoding: chunked";
+        let bites = http1(head, &chunked);
+        let mut responses = ResponseDecoder::new();
+        responses.push(&bites[..bites.len() - 1]);
+        assert!(responses.next_response().unwrap().is_none());
+        responses.push(&bites[bites.len() - 1..]);
+        assert_eq!(responses.next_response().unwrap().unwrap().answers.len(), 1);
+        assert_eq!(responses.buffered(), 0);
+    }
+
+    #[test]
+    fn errors_cost_one_response() {
+        let body = response().to_bytes();
+        let ok = alloc::format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}",
+            MEDIA_TYPE,
+            body.len()
+        );
+        let bites = [
+            http1("HTTP/1.1 503 Busy\r\nContent-Length: 4", b"busy"),
+            http1(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 2",
+                b"hi",
+            ),
+            http1(&ok, &body),
+        ]
+        .concat();
+        let mut responses = ResponseDecoder::new();
+        responses.push(&bites);
+        assert!(matches!(
+            responses.next_response(),
+            Err(DohError::Status(503))
+        ));
+        assert!(matches!(
+            responses.next_response(),
+            Err(DohError::ContentType(t)) if t == "text/html"
+        ));
+        assert!(responses.next_response().unwrap().is_some());
+    }
+
+    #[test]
+    fn broken_http_is_rejected() {
+        for head in [
+            "SPDY/3 200 OK\r\nContent-Length: 0",
+            "HTTP/1.1 OK\r\nContent-Length: 0",
+            "HTTP/1.1 200 OK\r\nContent-Type: application/dns-message",
+            "HTTP/1.1 200 OK\r\nContent-Length: 65536",
+            "HTTP/1.1 200 OK\r\nno colon",
+        ] {
+            let mut responses = ResponseDecoder::new();
+            responses.push(&http1(head, b""));
+            assert!(
+                matches!(responses.next_response(), Err(DohError::Http(_))),
+                "{}",
+                head
+            );
+        }
+        let mut responses = ResponseDecoder::new();
+        responses.push(&vec![b'x'; MAX_HEAD_LEN + 1]);
+        assert!(matches!(responses.next_response(), Err(DohError::Http(_))));
+    }
+}
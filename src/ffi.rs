@@ -0,0 +1,438 @@
+//! C ABI over the message codec. See `include/dns_rs.h` for the C side of these declarations.
+//!
+//! Ownership: a `DnsRsMessage` handed out by `dns_rs_parse` is owned by the caller and must be
+//! released with `dns_rs_message_free`, which also frees every name and rdata buffer hanging off
+//! it. Messages built by C code and passed to `dns_rs_encode` stay owned by C; the encoder only
+//! reads them.
+use std::{
+    ffi::{c_char, c_int, CStr, CString},
+    ptr, slice,
+};
+
+use crate::message::{
+    Edns, Header, Message, Opcode, QType, Question, Record, ResourceClass, MDNS_CLASS_BIT, OPT_TYPE,
+};
+use crate::name::Name;
+use crate::rdata::RData;
+
+pub const DNS_RS_OK: c_int = 0;
+pub const DNS_RS_ERR_NULL: c_int = -1;
+pub const DNS_RS_ERR_PARSE: c_int = -2;
+pub const DNS_RS_ERR_BUFFER_TOO_SMALL: c_int = -3;
+pub const DNS_RS_ERR_INVALID: c_int = -4;
+
+pub const DNS_RS_SECTION_QUESTION: c_int = 0;
+pub const DNS_RS_SECTION_ANSWER: c_int = 1;
+pub const DNS_RS_SECTION_AUTHORITY: c_int = 2;
+pub const DNS_RS_SECTION_ADDITIONAL: c_int = 3;
+
+#[repr(C)]
+pub struct DnsRsHeader {
+    pub id: u16,
+    pub qr: bool,
+    pub opcode: u8,
+    pub aa: bool,
+    pub tc: bool,
+    pub rd: bool,
+    pub ra: bool,
+    pub z: u8,
+    pub rcode: u8,
+}
+
+#[repr(C)]
+pub struct DnsRsQuestion {
+    /// NUL terminated presentation form, see `Name`'s `Display`: `www.example.com.`, `.` for the
+    /// root. Dots and backslashes in labels are escaped with `\`, other bytes outside printable
+    /// ASCII written as `\DDD`. Names passed in may leave out the trailing dot.
+    pub name: *mut c_char,
+    pub qtype: u16,
+    /// As on the wire: the top bit is the mDNS unicast-response bit.
+    pub qclass: u16,
+}
+
+#[repr(C)]
+pub struct DnsRsRecord {
+    /// NUL terminated presentation form, as in `DnsRsQuestion`.
+    pub name: *mut c_char,
+    pub rtype: u16,
+    /// As on the wire: the top bit is the mDNS cache-flush bit.
+    pub rclass: u16,
+    pub ttl: u32,
+    pub rdata: *mut u8,
+    pub rdlength: usize,
+}
+
+#[repr(C)]
+pub struct DnsRsMessage {
+    pub header: DnsRsHeader,
+    pub questions: *mut DnsRsQuestion,
+    pub question_count: usize,
+    pub answers: *mut DnsRsRecord,
+    pub answer_count: usize,
+    pub authorities: *mut DnsRsRecord,
+    pub authority_count: usize,
+    /// The OPT pseudo-record, if there is one, is among these as a record of type 41.
+    pub additionals: *mut DnsRsRecord,
+    pub additional_count: usize,
+}
+
+/// Parses `len` bytes at `buf` and stores a newly allocated message in `*out`.
+///
+/// # Safety
+/// `buf` must point to `len` readable bytes and `out` must be a valid pointer to write to.
+#[no_mangle]
+pub unsafe extern "C" fn dns_rs_parse(
+    buf: *const u8,
+    len: usize,
+    out: *mut *mut DnsRsMessage,
+) -> c_int {
+    if buf.is_null() || out.is_null() {
+        return DNS_RS_ERR_NULL;
+    }
+    let bites = slice::from_raw_parts(buf, len);
+    let m = match Message::parse(bites) {
+        Ok(m) => m,
+        Err(_) => return DNS_RS_ERR_PARSE,
+    };
+    *out = Box::into_raw(Box::new(to_c_message(&m)));
+    return DNS_RS_OK;
+}
+
+/// Serializes `msg` into `buf`. On success `*written` holds the number of bytes written. If `cap`
+/// is too small nothing is written, `*written` holds the required size and
+/// `DNS_RS_ERR_BUFFER_TOO_SMALL` is returned.
+///
+/// # Safety
+/// `msg` must point to a valid message whose arrays and strings are valid for their stated
+/// lengths, `buf` must point to `cap` writable bytes and `written` must be valid to write to.
+#[no_mangle]
+pub unsafe extern "C" fn dns_rs_encode(
+    msg: *const DnsRsMessage,
+    buf: *mut u8,
+    cap: usize,
+    written: *mut usize,
+) -> c_int {
+    if msg.is_null() || buf.is_null() || written.is_null() {
+        return DNS_RS_ERR_NULL;
+    }
+    let m = match from_c_message(&*msg) {
+        Some(m) => m,
+        None => return DNS_RS_ERR_INVALID,
+    };
+    let bites = m.to_bytes();
+    *written = bites.len();
+    if bites.len() > cap {
+        return DNS_RS_ERR_BUFFER_TOO_SMALL;
+    }
+    ptr::copy_nonoverlapping(bites.as_ptr(), buf, bites.len());
+    return DNS_RS_OK;
+}
+
+/// Number of entries in `section` of `msg`, one of the `DNS_RS_SECTION_*` constants. 0 if `msg`
+/// is NULL or `section` isn't known.
+///
+/// # Safety
+/// `msg` must be NULL or point to a valid message.
+#[no_mangle]
+pub unsafe extern "C" fn dns_rs_section_count(msg: *const DnsRsMessage, section: c_int) -> usize {
+    let Some(msg) = msg.as_ref() else {
+        return 0;
+    };
+    if section == DNS_RS_SECTION_QUESTION {
+        return msg.question_count;
+    }
+    return records(msg, section).map_or(0, |(_, count)| count);
+}
+
+/// The `i`th question of `msg`, or NULL if there is none. The question belongs to `msg`.
+///
+/// # Safety
+/// `msg` must be NULL or point to a valid message.
+#[no_mangle]
+pub unsafe extern "C" fn dns_rs_question(
+    msg: *const DnsRsMessage,
+    i: usize,
+) -> *const DnsRsQuestion {
+    match msg.as_ref() {
+        Some(msg) if i < msg.question_count => msg.questions.add(i),
+        _ => ptr::null(),
+    }
+}
+
+/// The `i`th record of `section` of `msg`, or NULL if there is none. The record belongs to
+/// `msg`.
+///
+/// # Safety
+/// `msg` must be NULL or point to a valid message.
+#[no_mangle]
+pub unsafe extern "C" fn dns_rs_record(
+    msg: *const DnsRsMessage,
+    section: c_int,
+    i: usize,
+) -> *const DnsRsRecord {
+    match msg.as_ref().and_then(|msg| records(msg, section)) {
+        Some((records, count)) if i < count => records.add(i),
+        _ => ptr::null(),
+    }
+}
+
+/// Writes `record` in presentation form, as a line of a zone file, NUL terminated into `buf`.
+/// `*written` holds the length without the NUL. If `cap` can't hold the line and the NUL,
+/// nothing is written, `*written` holds the length and `DNS_RS_ERR_BUFFER_TOO_SMALL` is
+/// returned. `DNS_RS_ERR_INVALID` means the rdata doesn't fit the record type.
+///
+/// # Safety
+/// `record` must point to a valid record, `buf` must point to `cap` writable bytes and
+/// `written` must be valid to write to.
+#[no_mangle]
+pub unsafe extern "C" fn dns_rs_record_format(
+    record: *const DnsRsRecord,
+    buf: *mut c_char,
+    cap: usize,
+    written: *mut usize,
+) -> c_int {
+    if record.is_null() || buf.is_null() || written.is_null() {
+        return DNS_RS_ERR_NULL;
+    }
+    let record = match from_c_record(&*record) {
+        Some(r) => r,
+        None => return DNS_RS_ERR_INVALID,
+    };
+    let line = record.to_string();
+    *written = line.len();
+    if line.len() >= cap {
+        return DNS_RS_ERR_BUFFER_TOO_SMALL;
+    }
+    ptr::copy_nonoverlapping(line.as_ptr(), buf as *mut u8, line.len());
+    *buf.add(line.len()) = 0;
+    return DNS_RS_OK;
+}
+
+/// Frees a message returned by `dns_rs_parse`. Passing NULL is a no-op.
+///
+/// # Safety
+/// `msg` must be NULL or a pointer obtained from `dns_rs_parse` that has not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn dns_rs_message_free(msg: *mut DnsRsMessage) {
+    if msg.is_null() {
+        return;
+    }
+    let msg = Box::from_raw(msg);
+    for q in Vec::from_raw_parts(msg.questions, msg.question_count, msg.question_count) {
+        drop(CString::from_raw(q.name));
+    }
+    free_records(msg.answers, msg.answer_count);
+    free_records(msg.authorities, msg.authority_count);
+    free_records(msg.additionals, msg.additional_count);
+}
+
+unsafe fn free_records(records: *mut DnsRsRecord, count: usize) {
+    for r in Vec::from_raw_parts(records, count, count) {
+        drop(CString::from_raw(r.name));
+        drop(Vec::from_raw_parts(r.rdata, r.rdlength, r.rdlength));
+    }
+}
+
+/// The records of `section` of `msg` and how many there are. None for the question section and
+/// unknown sections.
+fn records(msg: &DnsRsMessage, section: c_int) -> Option<(*const DnsRsRecord, usize)> {
+    return match section {
+        DNS_RS_SECTION_ANSWER => Some((msg.answers, msg.answer_count)),
+        DNS_RS_SECTION_AUTHORITY => Some((msg.authorities, msg.authority_count)),
+        DNS_RS_SECTION_ADDITIONAL => Some((msg.additionals, msg.additional_count)),
+        _ => None,
+    };
+}
+
+fn to_c_name(name: &Name) -> *mut c_char {
+    // presentation form escapes NUL bytes and anything else a C string couldn't carry as is.
+    return CString::new(name.to_string()).unwrap().into_raw();
+}
+
+fn into_raw_parts<T>(v: Vec<T>) -> (*mut T, usize) {
+    let mut v = v.into_boxed_slice();
+    let len = v.len();
+    let p = v.as_mut_ptr();
+    std::mem::forget(v);
+    return (p, len);
+}
+
+fn to_c_message(m: &Message) -> DnsRsMessage {
+    let h = &m.header;
+    let questions: Vec<DnsRsQuestion> = m
+        .questions
+        .iter()
+        .map(|q| DnsRsQuestion {
+            name: to_c_name(&q.name),
+            qtype: q.tipe.value(),
+            qclass: q.class.value()
+                | if q.unicast_response {
+                    MDNS_CLASS_BIT
+                } else {
+                    0
+                },
+        })
+        .collect();
+    let opt = m.edns.as_ref().map(Edns::to_record);
+    let (questions, question_count) = into_raw_parts(questions);
+    let (answers, answer_count) = to_c_records(m.answers.iter());
+    let (authorities, authority_count) = to_c_records(m.authorities.iter());
+    let (additionals, additional_count) = to_c_records(m.additionals.iter().chain(&opt));
+    return DnsRsMessage {
+        header: DnsRsHeader {
+            id: h.id,
+            qr: h.qr,
+            opcode: h.opcode.value(),
+            aa: h.aa,
+            tc: h.tc,
+            rd: h.rd,
+            ra: h.ra,
+            z: h.z,
+            rcode: h.rcode,
+        },
+        questions,
+        question_count,
+        answers,
+        answer_count,
+        authorities,
+        authority_count,
+        additionals,
+        additional_count,
+    };
+}
+
+fn to_c_records<'a>(records: impl Iterator<Item = &'a Record>) -> (*mut DnsRsRecord, usize) {
+    let records: Vec<DnsRsRecord> = records
+        .map(|r| {
+            let (rdata, rdlength) = into_raw_parts(r.rdata.to_bytes().to_vec());
+            DnsRsRecord {
+                name: to_c_name(&r.name),
+                rtype: r.tipe.value(),
+                rclass: r.class.value() | if r.cache_flush { MDNS_CLASS_BIT } else { 0 },
+                ttl: r.ttl,
+                rdata,
+                rdlength,
+            }
+        })
+        .collect();
+    return into_raw_parts(records);
+}
+
+unsafe fn from_c_name(name: *const c_char) -> Option<Name> {
+    if name.is_null() {
+        return None;
+    }
+    let name = CStr::from_ptr(name).to_str().ok()?;
+    if name.is_empty() {
+        return Some(Name::root());
+    }
+    return name.parse().ok();
+}
+
+unsafe fn c_slice<'a, T>(p: *const T, len: usize) -> Option<&'a [T]> {
+    if len == 0 {
+        return Some(&[]);
+    }
+    if p.is_null() {
+        return None;
+    }
+    return Some(slice::from_raw_parts(p, len));
+}
+
+unsafe fn from_c_message(msg: &DnsRsMessage) -> Option<Message> {
+    let mut header = Header::new(msg.header.id);
+    header.qr = msg.header.qr;
+    header.opcode = Opcode::from_value(msg.header.opcode);
+    header.aa = msg.header.aa;
+    header.tc = msg.header.tc;
+    header.rd = msg.header.rd;
+    header.ra = msg.header.ra;
+    header.z = msg.header.z;
+    header.rcode = msg.header.rcode;
+    header.qdcount = u16::try_from(msg.question_count).ok()?;
+    header.ancount = u16::try_from(msg.answer_count).ok()?;
+    // the other counts are only set when the message is written, but must fit all the same.
+    u16::try_from(msg.authority_count).ok()?;
+    u16::try_from(msg.additional_count).ok()?;
+    let mut m = Message::new(header);
+    for q in c_slice(msg.questions, msg.question_count)? {
+        m.questions.push(Question {
+            name: from_c_name(q.name)?,
+            tipe: QType::from_value(q.qtype),
+            class: ResourceClass::from_value(q.qclass & !MDNS_CLASS_BIT),
+            unicast_response: q.qclass & MDNS_CLASS_BIT != 0,
+        });
+    }
+    for a in c_slice(msg.answers, msg.answer_count)? {
+        m.answers.push(from_c_record(a)?);
+    }
+    for a in c_slice(msg.authorities, msg.authority_count)? {
+        m.authorities.push(from_c_record(a)?);
+    }
+    for a in c_slice(msg.additionals, msg.additional_count)? {
+        if a.rtype == OPT_TYPE && from_c_name(a.name)?.is_root() {
+            if m.edns.is_some() {
+                return None;
+            }
+            let options = c_slice(a.rdata, a.rdlength)?;
+            m.edns = Some(Edns::from_record(a.rclass, a.ttl, options).ok()?);
+            continue;
+        }
+        m.additionals.push(from_c_record(a)?);
+    }
+    return Some(m);
+}
+
+unsafe fn from_c_record(r: &DnsRsRecord) -> Option<Record> {
+    let rdata = c_slice(r.rdata, r.rdlength)?;
+    u16::try_from(rdata.len()).ok()?;
+    let tipe = QType::from_value(r.rtype);
+    // names in rdata coming from C must not be compressed, so rdata is its own packet.
+    let (_, rdata) = RData::parse(&tipe, rdata, rdata).ok()?;
+    return Some(Record {
+        name: from_c_name(r.name)?,
+        tipe,
+        class: ResourceClass::from_value(r.rclass & !MDNS_CLASS_BIT),
+        ttl: r.ttl,
+        rdata,
+        cache_flush: r.rclass & MDNS_CLASS_BIT != 0,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_with_special_bytes_round_trip() {
+        let name: Name = "a\\.b\\092\\000\\255.example.".parse().unwrap();
+        let mut m = Message::new(Header::new(0x1234));
+        m.questions.push(Question {
+            name: name.clone(),
+            tipe: QType::A,
+            class: ResourceClass::IN,
+            unicast_response: false,
+        });
+        let mut answer: Record = "x.example. 300 IN A 192.0.2.1".parse().unwrap();
+        answer.name = name;
+        m.answers.push(answer);
+        let wire = m.to_bytes();
+
+        let mut parsed = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                dns_rs_parse(wire.as_ptr(), wire.len(), &mut parsed),
+                DNS_RS_OK
+            );
+            let q = &*dns_rs_question(parsed, 0);
+            let text = CStr::from_ptr(q.name).to_str().unwrap();
+            assert_eq!(text, "a\\.b\\\\\\000\\255.example.");
+            let mut buf = [0u8; 512];
+            let mut written = 0;
+            let status = dns_rs_encode(parsed, buf.as_mut_ptr(), buf.len(), &mut written);
+            assert_eq!(status, DNS_RS_OK);
+            assert_eq!(&buf[..written], &wire[..]);
+            dns_rs_message_free(parsed);
+        }
+    }
+}
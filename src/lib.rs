@@ -1 +1,49 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+#![allow(clippy::needless_return)]
+extern crate alloc;
+
+#[cfg(feature = "server")]
+pub mod acl;
+#[cfg(feature = "server")]
+pub mod bailiwick;
+#[cfg(feature = "server")]
+pub mod cache;
+#[cfg(feature = "std")]
+pub mod client;
+#[cfg(feature = "server")]
+pub mod clock;
+pub mod doh;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "server")]
+pub mod logging;
 pub mod message;
+pub mod name;
+#[cfg(feature = "server")]
+pub mod pool;
+pub mod punycode;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod rdata;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+pub mod sockets;
+#[cfg(feature = "server")]
+pub mod stats;
+pub mod stream;
+#[cfg(feature = "server")]
+pub mod tcp;
+pub mod text;
+#[cfg(feature = "server")]
+pub mod udp;
+#[cfg(feature = "server")]
+pub mod upstream;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "server")]
+pub mod zone;
+#[cfg(feature = "zonemd")]
+pub mod zonemd;
@@ -0,0 +1,47 @@
+//! Keeping logs readable when something goes wrong once per packet: a `LogLimiter` lets a
+//! message through at most so often and counts the ones it holds back.
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+pub struct LogLimiter {
+    /// least time between two messages
+    interval: Duration,
+    /// when the last message went out
+    last: Option<Instant>,
+    /// messages held back since
+    suppressed: u64,
+}
+
+impl LogLimiter {
+    pub fn new(interval: Duration) -> LogLimiter {
+        return LogLimiter {
+            interval,
+            last: None,
+            suppressed: 0,
+        };
+    }
+
+    /// Writes `message` to stderr, unless one went out less than the interval before `now`.
+    /// The first one after a quiet spell says how many were held back.
+    pub fn log(&mut self, now: Instant, message: fmt::Arguments) {
+        if self
+            .last
+            .is_some_and(|last| now.saturating_duration_since(last) < self.interval)
+        {
+            self.suppressed += 1;
+            return;
+        }
+        if self.suppressed > 0 {
+            eprintln!(
+                "{} ({} similar messages suppressed)",
+                message, self.suppressed
+            );
+        } else {
+            eprintln!("{}", message);
+        }
+        self.last = Some(now);
+        self.suppressed = 0;
+    }
+}
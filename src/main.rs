@@ -0,0 +1,929 @@
+use std::{
+    env, fs,
+    io::{self, ErrorKind},
+    net::{SocketAddr, UdpSocket},
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use dns_starter_rust::{
+    acl::Acl,
+    logging::LogLimiter,
+    server::{
+        ClientSubnetPolicy, DnsServer, MultiQuestionPolicy, OverloadAction, PaddingPolicy,
+        ServerConfig, UnknownOptionAction, UnknownOptionPolicy, ZeroQuestionPolicy,
+    },
+    stats::Stats,
+    tcp::{TcpConfig, TcpServer},
+    udp,
+    upstream::Selection,
+    zone::Zone,
+};
+use getopts::{Matches, Options};
+
+/// How often pending queries are checked against their deadlines.
+const EXPIRY_TICK: Duration = Duration::from_millis(50);
+
+/// Set by SIGINT and SIGTERM while the cache is persisted, so the serve loops can save it and
+/// return.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Longest pause after a failed receive, however many came before it.
+const MAX_RECV_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Where a server keeps its cache across restarts, and how often it saves it.
+struct CacheFile {
+    path: PathBuf,
+    interval: Duration,
+}
+
+/// Receives kept posted on the io_uring, when it is in use.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+const URING_DEPTH: u32 = 256;
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn check_uring(_: bool) {}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+fn check_uring(io_uring: bool) {
+    if io_uring {
+        eprintln!("--io-uring needs Linux and a build with the io-uring feature");
+        process::exit(2);
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn open_uring(
+    io_uring: bool,
+    socket: &UdpSocket,
+    buf_size: usize,
+) -> Option<dns_starter_rust::uring::UringUdp> {
+    if !io_uring {
+        return None;
+    }
+    match dns_starter_rust::uring::UringUdp::new(socket, URING_DEPTH, buf_size) {
+        Ok(uring) => Some(uring),
+        Err(e) => {
+            eprintln!("Failed to set up io_uring: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+fn open_uring(_: bool, _: &UdpSocket, _: usize) -> Option<()> {
+    None
+}
+
+/// Waits for datagrams on the io_uring and hands them to the server. Returns false, without
+/// doing anything, when io_uring isn't in use.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn uring_step(
+    uring: &mut Option<dns_starter_rust::uring::UringUdp>,
+    tcp: &Option<TcpServer>,
+    server: &mut DnsServer,
+    socket: &UdpSocket,
+) -> io::Result<bool> {
+    let uring = match uring {
+        Some(u) => u,
+        None => return Ok(false),
+    };
+    match tcp {
+        // datagrams that came in while sending are ready already.
+        _ if uring.has_received() => {}
+        Some(tcp) => {
+            tcp.wait_on(uring, &server.upstream_sockets(), EXPIRY_TICK);
+        }
+        None => {
+            udp::wait_on(uring, &server.upstream_sockets(), EXPIRY_TICK);
+        }
+    }
+    uring.for_each_received(|packet, source| server.handle_packet(packet, source, socket))?;
+    Ok(true)
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+fn uring_step(
+    _: &mut Option<()>,
+    _: &Option<TcpServer>,
+    _: &mut DnsServer,
+    _: &UdpSocket,
+) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Sends the UDP responses the server has queued, through the io_uring when it is in use.
+/// Fails when the io_uring can't be used any more; the responses count as failed sends then.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn flush_responses(
+    uring: &mut Option<dns_starter_rust::uring::UringUdp>,
+    server: &mut DnsServer,
+    socket: &UdpSocket,
+) -> io::Result<()> {
+    let uring = match uring {
+        Some(u) => u,
+        None => {
+            server.flush_responses(socket);
+            return Ok(());
+        }
+    };
+    let mut result = Ok(());
+    server.flush_responses_with(|responses| match uring.send_many(responses) {
+        Ok(failed) => failed,
+        Err(e) => {
+            let failed = (0..responses.len())
+                .map(|i| (i, io::Error::new(e.kind(), e.to_string())))
+                .collect();
+            result = Err(e);
+            failed
+        }
+    });
+    result
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+fn flush_responses(
+    _: &mut Option<()>,
+    server: &mut DnsServer,
+    socket: &UdpSocket,
+) -> io::Result<()> {
+    server.flush_responses(socket);
+    Ok(())
+}
+
+/// Makes SIGINT and SIGTERM set `SHUTDOWN` instead of killing the process.
+#[cfg(unix)]
+fn handle_shutdown_signals() {
+    extern "C" fn on_signal(_: libc::c_int) {
+        SHUTDOWN.store(true, Ordering::Relaxed);
+    }
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_shutdown_signals() {}
+
+/// Parses the --unknown-edns-options values, each either an action for every unknown option
+/// code or CODE=ACTION for one of them.
+fn parse_unknown_options(values: &[String]) -> UnknownOptionPolicy {
+    let mut policy = UnknownOptionPolicy::default();
+    for value in values {
+        let (code, action) = match value.split_once('=') {
+            Some((code, action)) => (Some(code), action),
+            None => (None, value.as_str()),
+        };
+        let action = match action {
+            "strip" => UnknownOptionAction::Strip,
+            "copy" => UnknownOptionAction::Copy,
+            "pass" => UnknownOptionAction::PassThrough,
+            _ => {
+                eprintln!("invalid value for --unknown-edns-options: {}", value);
+                process::exit(2);
+            }
+        };
+        match code.map(|c| c.parse::<u16>()) {
+            None => policy.default = action,
+            Some(Ok(code)) => {
+                policy.per_code.insert(code, action);
+            }
+            Some(Err(_)) => {
+                eprintln!("invalid value for --unknown-edns-options: {}", value);
+                process::exit(2);
+            }
+        }
+    }
+    policy
+}
+
+/// Parses the value of option `name`, exiting with a usage error if it is malformed.
+fn parse_opt<T: std::str::FromStr>(matches: &Matches, name: &str) -> Option<T> {
+    let value = matches.opt_str(name)?;
+    match value.parse() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            eprintln!("invalid value for --{}: {}", name, value);
+            process::exit(2);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut opts = Options::new();
+    opts.optmulti(
+        "r",
+        "resolver",
+        "forward queries to this resolver; repeat for fallbacks, tried in the order given",
+        "ADDR:PORT",
+    );
+    opts.optopt(
+        "",
+        "upstream-selection",
+        "which resolver each query goes to: priority (default, the first one up), round-robin, random or latency",
+        "STRATEGY",
+    );
+    opts.optopt(
+        "",
+        "upstream-max-failures",
+        "unanswered queries in a row before a resolver is marked down (default 3)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "upstream-probe-interval-ms",
+        "how often a resolver marked down is probed (default 5000)",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "upstream-sockets",
+        "sockets on random ports that queries to resolvers go out from, per address family (default 16)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "upstream-socket-queries",
+        "queries each of those sockets sends before moving to a new port (default 100)",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "randomize-case",
+        "send names to resolvers in random case and drop answers that don't echo it (DNS 0x20)",
+    );
+    opts.optmulti(
+        "",
+        "allow-recursion",
+        "only recurse for clients in this subnet, as ADDR/PREFIX or ADDR, refusing others; repeatable (default: every client)",
+        "SUBNET",
+    );
+    opts.optmulti(
+        "",
+        "verbatim-case",
+        "send names to this resolver as the client wrote them even with --randomize-case; repeatable",
+        "ADDR",
+    );
+    opts.optflag(
+        "",
+        "fast-forward",
+        "relay queries to the resolver without fully parsing them; turns the cache off and passes unknown EDNS options through unless told otherwise, and can't be combined with --pad, --nsid or --clamp-forwarded-ttls",
+    );
+    opts.optflag(
+        "",
+        "udp-offload",
+        "send packets to the same peer, upstream queries and batched responses alike, with UDP segmentation offload, and receive with receive offload, where available",
+    );
+    opts.optopt(
+        "",
+        "udp-batch",
+        "datagrams read, and responses sent, per syscall with recvmmsg/sendmmsg (Linux); 1 turns batching off (default 32, at most 64)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "max-inflight",
+        "most queries waiting for the resolver at once",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "max-inflight-per-client",
+        "most queries one client may have waiting for the resolver",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "drop-when-overloaded",
+        "drop queries over the in-flight limits instead of answering SERVFAIL",
+    );
+    opts.optopt(
+        "",
+        "query-budget-ms",
+        "total time a query may take before it is answered SERVFAIL (default 2000)",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "max-udp-payload",
+        "largest UDP payload to advertise and accept over EDNS (default 1232)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "recv-buffer",
+        "largest datagram handled, client query or upstream answer, up to 65535; bigger ones are dropped (default: --max-udp-payload, at least 4096)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "max-response-payload",
+        "largest UDP response to send to EDNS clients (default 4096)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "zero-question",
+        "how to answer queries without a question: edns (default), noerror or formerr",
+        "POLICY",
+    );
+    opts.optflag(
+        "",
+        "split-multi-question",
+        "forward multi-question queries one question at a time and merge the answers, instead of answering FORMERR",
+    );
+    opts.optopt(
+        "",
+        "client-subnet",
+        "what forwarded queries keep of the EDNS client subnet option: forward (default), truncate (to /24 and /56) or strip",
+        "POLICY",
+    );
+    opts.optmulti(
+        "",
+        "unknown-edns-options",
+        "what responses carry of unknown EDNS options: strip (default), copy from the query or pass from upstream; CODE=ACTION sets it for one option code",
+        "ACTION",
+    );
+    opts.optopt(
+        "",
+        "cache-size",
+        "most answers to keep in the cache, 0 to turn it off (default 4096)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "prefetch-hits",
+        "refresh cached answers served this often shortly before they expire, 0 to never (default 3)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "min-ttl",
+        "shortest TTL, in seconds, cached records are kept for (default 0)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "max-ttl",
+        "longest TTL, in seconds, cached records are kept for (default 86400)",
+        "SECONDS",
+    );
+    opts.optflag(
+        "",
+        "clamp-forwarded-ttls",
+        "apply --min-ttl and --max-ttl to the answers sent to clients too",
+    );
+    opts.optopt(
+        "",
+        "cache-file",
+        "save the cache to this file periodically and on SIGINT or SIGTERM, and load it on startup; with several workers each gets its own file, suffixed with its number",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "cache-save-interval-s",
+        "how often the cache is saved to --cache-file (default 300)",
+        "SECONDS",
+    );
+    opts.optmulti(
+        "",
+        "zone",
+        "answer with authority for the zone in this file, one record per line; repeatable",
+        "PATH",
+    );
+    opts.optflag(
+        "",
+        "authoritative-only",
+        "answer only for the --zone zones and refuse everything else, never recursing",
+    );
+    opts.optflag(
+        "",
+        "no-tcp-retry",
+        "pass truncated upstream answers on to clients instead of asking again over TCP",
+    );
+    opts.optopt(
+        "",
+        "nsid",
+        "identifier of this instance, returned to clients asking for it with the NSID option",
+        "ID",
+    );
+    opts.optflag(
+        "",
+        "pad",
+        "pad upstream queries to 128 byte blocks and EDNS responses to 468 byte blocks (RFC 8467)",
+    );
+    opts.optflag(
+        "",
+        "no-tcp",
+        "accept queries over UDP only, leaving truncated answers without a way to retry",
+    );
+    opts.optopt(
+        "",
+        "tcp-max-connections",
+        "most TCP connections open at once (default 128)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "tcp-idle-timeout-ms",
+        "close TCP connections with nothing in flight after this long (default 10000)",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "tcp-query-timeout-ms",
+        "close TCP connections that take longer than this to send a whole query (default 2000)",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "tcp-max-pipelined",
+        "most unanswered queries per TCP connection (default 16)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "max-recv-errors",
+        "receive errors in a row, transient ones aside, after which a worker gives up; 0 never does (default 100)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "workers",
+        "serve UDP from this many threads, each with its own socket on the port (SO_REUSEPORT, Linux) and its own state (default 1)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "stats-interval-s",
+        "log the packet counters, summed over all workers, this often (default never)",
+        "SECONDS",
+    );
+    opts.optflag(
+        "",
+        "io-uring",
+        "receive client UDP, and send responses batched by --udp-batch, through io_uring; upstream queries and TCP still use plain syscalls (Linux, needs the io-uring build feature)",
+    );
+    opts.optflag("h", "help", "print this help");
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    if matches.opt_present("h") {
+        print!("{}", opts.usage(&format!("Usage: {} [options]", args[0])));
+        return;
+    }
+    let mut config = ServerConfig {
+        fast_forward: matches.opt_present("fast-forward"),
+        udp_offload: matches.opt_present("udp-offload"),
+        udp_batch: parse_opt(&matches, "udp-batch").unwrap_or(32),
+        max_inflight: parse_opt(&matches, "max-inflight"),
+        max_inflight_per_client: parse_opt(&matches, "max-inflight-per-client"),
+        overload_action: if matches.opt_present("drop-when-overloaded") {
+            OverloadAction::Drop
+        } else {
+            OverloadAction::ServFail
+        },
+        ..ServerConfig::default()
+    };
+    if !(1..=udp::MAX_BATCH).contains(&config.udp_batch) {
+        eprintln!("--udp-batch must be between 1 and {}", udp::MAX_BATCH);
+        process::exit(2);
+    }
+    if let Some(ms) = parse_opt(&matches, "query-budget-ms") {
+        config.query_budget = Duration::from_millis(ms);
+    }
+    if let Some(size) = parse_opt(&matches, "max-udp-payload") {
+        config.max_udp_payload = size;
+    }
+    if let Some(size) = parse_opt(&matches, "recv-buffer") {
+        if size < config.max_udp_payload {
+            eprintln!("--recv-buffer can't be below --max-udp-payload");
+            process::exit(2);
+        }
+        config.recv_buffer_size = Some(size);
+    }
+    if let Some(size) = parse_opt(&matches, "max-response-payload") {
+        config.max_response_payload = size;
+    }
+    match matches.opt_str("zero-question").as_deref() {
+        None | Some("edns") => {}
+        Some("noerror") => config.zero_question = ZeroQuestionPolicy::NoError,
+        Some("formerr") => config.zero_question = ZeroQuestionPolicy::FormErr,
+        Some(other) => {
+            eprintln!("invalid value for --zero-question: {}", other);
+            process::exit(2);
+        }
+    }
+    match matches.opt_str("client-subnet").as_deref() {
+        None | Some("forward") => {}
+        Some("truncate") => config.client_subnet = ClientSubnetPolicy::Truncate { v4: 24, v6: 56 },
+        Some("strip") => config.client_subnet = ClientSubnetPolicy::Strip,
+        Some(other) => {
+            eprintln!("invalid value for --client-subnet: {}", other);
+            process::exit(2);
+        }
+    }
+    if matches.opt_present("pad") {
+        config.padding = PaddingPolicy::Block {
+            query: 128,
+            response: 468,
+        };
+    }
+    config.unknown_options = parse_unknown_options(&matches.opt_strs("unknown-edns-options"));
+    if config.fast_forward && !matches.opt_present("unknown-edns-options") {
+        config.unknown_options.default = UnknownOptionAction::PassThrough;
+    }
+    match matches.opt_str("upstream-selection").as_deref() {
+        None | Some("priority") => {}
+        Some("round-robin") => config.upstream_selection = Selection::RoundRobin,
+        Some("random") => config.upstream_selection = Selection::Random,
+        Some("latency") => config.upstream_selection = Selection::LowestLatency,
+        Some(other) => {
+            eprintln!("invalid value for --upstream-selection: {}", other);
+            process::exit(2);
+        }
+    }
+    if let Some(n) = parse_opt(&matches, "upstream-max-failures") {
+        config.upstream_max_failures = n;
+    }
+    if let Some(ms) = parse_opt(&matches, "upstream-probe-interval-ms") {
+        config.upstream_probe_interval = Duration::from_millis(ms);
+    }
+    if let Some(n) = parse_opt(&matches, "upstream-sockets") {
+        config.upstream_sockets = n;
+    }
+    if let Some(n) = parse_opt(&matches, "upstream-socket-queries") {
+        config.upstream_socket_queries = n;
+    }
+    config.randomize_case = matches.opt_present("randomize-case");
+    if matches.opt_present("allow-recursion") {
+        let subnets = matches
+            .opt_strs("allow-recursion")
+            .iter()
+            .map(|s| {
+                s.parse().unwrap_or_else(|_| {
+                    eprintln!("invalid value for --allow-recursion: {}", s);
+                    process::exit(2);
+                })
+            })
+            .collect();
+        config.recursion_acl = Some(Acl::new(subnets));
+    }
+    for addr in matches.opt_strs("verbatim-case") {
+        match addr.parse() {
+            Ok(addr) => config.verbatim_case_upstreams.push(addr),
+            Err(_) => {
+                eprintln!("invalid value for --verbatim-case: {}", addr);
+                process::exit(2);
+            }
+        }
+    }
+    if let Some(n) = parse_opt(&matches, "cache-size") {
+        config.cache_size = n;
+    } else if config.fast_forward {
+        config.cache_size = 0;
+    }
+    if let Some(n) = parse_opt(&matches, "prefetch-hits") {
+        config.prefetch_hits = n;
+    }
+    if let Some(n) = parse_opt(&matches, "min-ttl") {
+        config.min_ttl = n;
+    }
+    if let Some(n) = parse_opt(&matches, "max-ttl") {
+        config.max_ttl = n;
+    }
+    if config.min_ttl > config.max_ttl {
+        eprintln!("--min-ttl can't be above --max-ttl");
+        process::exit(2);
+    }
+    config.clamp_forwarded_ttls = matches.opt_present("clamp-forwarded-ttls");
+    config.authoritative_only = matches.opt_present("authoritative-only");
+    config.tcp_retry = !matches.opt_present("no-tcp-retry");
+    config.nsid = matches.opt_str("nsid").map(String::into_bytes);
+    if matches.opt_present("split-multi-question") {
+        config.multi_question = MultiQuestionPolicy::SplitAndMerge;
+    }
+    let conflicts = config.fast_path_conflicts();
+    if config.fast_forward && !conflicts.is_empty() {
+        let flags: Vec<&str> = conflicts
+            .iter()
+            .map(|field| match *field {
+                "cache_size" => "--cache-size",
+                "padding" => "--pad",
+                "nsid" => "--nsid",
+                "clamp_forwarded_ttls" => "--clamp-forwarded-ttls",
+                "unknown_options" => "--unknown-edns-options",
+                other => other,
+            })
+            .collect();
+        eprintln!("--fast-forward can't be combined with {}", flags.join(", "));
+        process::exit(2);
+    }
+    let mut tcp_config = TcpConfig::default();
+    if let Some(n) = parse_opt(&matches, "tcp-max-connections") {
+        tcp_config.max_connections = n;
+    }
+    if let Some(ms) = parse_opt(&matches, "tcp-idle-timeout-ms") {
+        tcp_config.idle_timeout = Duration::from_millis(ms);
+    }
+    if let Some(ms) = parse_opt(&matches, "tcp-query-timeout-ms") {
+        tcp_config.query_timeout = Duration::from_millis(ms);
+    }
+    if let Some(n) = parse_opt(&matches, "tcp-max-pipelined") {
+        tcp_config.max_pipelined = n;
+    }
+    let workers: usize = parse_opt(&matches, "workers").unwrap_or(1).max(1);
+    let max_recv_errors = parse_opt(&matches, "max-recv-errors").unwrap_or(100);
+    let cache_save_interval = parse_opt(&matches, "cache-save-interval-s").unwrap_or(300);
+    let stats_interval: Option<u64> = parse_opt(&matches, "stats-interval-s");
+    let cache_file = |worker: usize| {
+        let mut path = PathBuf::from(matches.opt_str("cache-file")?);
+        if workers > 1 {
+            path.as_mut_os_string().push(format!(".{}", worker));
+        }
+        Some(CacheFile {
+            path,
+            interval: Duration::from_secs(cache_save_interval),
+        })
+    };
+    if matches.opt_present("cache-file") {
+        handle_shutdown_signals();
+    }
+    let io_uring = matches.opt_present("io-uring");
+    check_uring(io_uring);
+    let upstreams: Vec<SocketAddr> = matches
+        .opt_strs("r")
+        .iter()
+        .map(|r| {
+            r.parse().unwrap_or_else(|_| {
+                eprintln!("invalid value for --resolver: {}", r);
+                process::exit(2);
+            })
+        })
+        .collect();
+    let zones: Vec<Zone> = matches
+        .opt_strs("zone")
+        .iter()
+        .map(|path| {
+            let zone = fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| Zone::parse(&text).map_err(|e| e.to_string()));
+            zone.unwrap_or_else(|e| {
+                eprintln!("Failed to load zone {}: {}", path, e);
+                process::exit(2);
+            })
+        })
+        .collect();
+    let addr: SocketAddr = "127.0.0.1:2053".parse().unwrap();
+    let mut tcp = if !matches.opt_present("no-tcp") {
+        Some(TcpServer::bind(addr, tcp_config).expect("Failed to bind TCP listener"))
+    } else {
+        None
+    };
+    if workers == 1 {
+        let udp_socket = UdpSocket::bind(addr).expect("Failed to bind to address");
+        let server = DnsServer::new(None)
+            .with_upstreams(upstreams)
+            .with_zones(zones)
+            .with_config(config);
+        if let Some(secs) = stats_interval {
+            log_stats(Arc::clone(server.stats()), Duration::from_secs(secs));
+        }
+        serve(
+            server,
+            udp_socket,
+            tcp,
+            io_uring,
+            cache_file(0),
+            max_recv_errors,
+        );
+        return;
+    }
+    // every worker owns a socket on the same port and its own server; only the counters are
+    // shared. TCP connections all go to the first worker.
+    let stats = Arc::new(Stats::new());
+    if let Some(secs) = stats_interval {
+        log_stats(Arc::clone(&stats), Duration::from_secs(secs));
+    }
+    let mut handles = vec![];
+    for worker in 0..workers {
+        let udp_socket = udp::bind_reuseport(addr).unwrap_or_else(|e| {
+            eprintln!("Failed to bind worker socket with SO_REUSEPORT: {}", e);
+            process::exit(1);
+        });
+        let server = DnsServer::new(None)
+            .with_upstreams(upstreams.clone())
+            .with_zones(zones.clone())
+            .with_config(config.clone())
+            .with_stats(Arc::clone(&stats));
+        let tcp = tcp.take();
+        let cache_file = cache_file(worker);
+        handles.push(thread::spawn(move || {
+            serve(
+                server,
+                udp_socket,
+                tcp,
+                io_uring,
+                cache_file,
+                max_recv_errors,
+            )
+        }));
+    }
+    for handle in handles {
+        let _ = handle.join();
+    }
+}
+
+/// Receive errors in the serve loop. Transient ones, like an interrupted call or an ICMP error
+/// reported for an earlier send, are shrugged off. Others are logged, at most once a second,
+/// and followed by a pause that grows the more of them come in a row.
+struct RecvErrors {
+    in_a_row: u32,
+    /// failures in a row after which the loop gives up; 0 never does
+    max: u32,
+    log: LogLimiter,
+}
+
+impl RecvErrors {
+    fn new(max: u32) -> RecvErrors {
+        RecvErrors {
+            in_a_row: 0,
+            max,
+            log: LogLimiter::new(Duration::from_secs(1)),
+        }
+    }
+
+    fn succeeded(&mut self) {
+        self.in_a_row = 0;
+    }
+
+    /// Deals with `e`. Returns whether to give up.
+    fn failed(&mut self, e: &io::Error) -> bool {
+        let transient = matches!(
+            e.kind(),
+            ErrorKind::WouldBlock
+                | ErrorKind::TimedOut
+                | ErrorKind::Interrupted
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionRefused
+        );
+        if transient {
+            return false;
+        }
+        self.in_a_row += 1;
+        self.log
+            .log(Instant::now(), format_args!("Error receiving data: {}", e));
+        if self.max > 0 && self.in_a_row >= self.max {
+            eprintln!("Giving up after {} receive errors in a row", self.in_a_row);
+            return true;
+        }
+        let backoff = Duration::from_millis(1 << self.in_a_row.min(10));
+        thread::sleep(backoff.min(MAX_RECV_BACKOFF));
+        false
+    }
+}
+
+/// Runs one server on `udp_socket`, and on `tcp` if given, until a shutdown signal arrives or
+/// `max_recv_errors` receives in a row fail. With a `cache_file`, the cache is loaded from it
+/// first and saved to it periodically and on the way out.
+fn serve(
+    mut server: DnsServer,
+    udp_socket: UdpSocket,
+    mut tcp: Option<TcpServer>,
+    io_uring: bool,
+    cache_file: Option<CacheFile>,
+    max_recv_errors: u32,
+) {
+    if let Some(file) = &cache_file {
+        match server.load_cache(&file.path) {
+            Ok(n) => eprintln!("Loaded {} cached answers from {}", n, file.path.display()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Failed to load cache from {}: {}", file.path.display(), e),
+        }
+    }
+    udp_socket
+        .set_read_timeout(Some(EXPIRY_TICK))
+        .expect("Failed to set socket read timeout");
+    let config = server.config();
+    // with GRO one receive can carry many coalesced datagrams. The io_uring path reads plain
+    // datagrams only.
+    let gro = config.udp_offload && !io_uring && udp::enable_gro(&udp_socket);
+    let buf_size = if gro {
+        udp::MAX_UDP_PAYLOAD
+    } else {
+        // a byte to spare, so handle_packet can tell datagrams that didn't fit.
+        server.recv_buffer_size() + 1
+    };
+    // GRO coalesces datagrams into one buffer instead.
+    let batch = if gro { 1 } else { config.udp_batch };
+    let mut bufs = vec![vec![0; buf_size]; batch];
+    let mut datagrams = Vec::with_capacity(batch);
+    let mut uring = open_uring(io_uring, &udp_socket, buf_size);
+    let mut last_expiry = Instant::now();
+    let mut last_save = Instant::now();
+    let mut recv_errors = RecvErrors::new(max_recv_errors);
+    while !SHUTDOWN.load(Ordering::Relaxed) {
+        let used_uring = match uring_step(&mut uring, &tcp, &mut server, &udp_socket) {
+            Ok(used) => {
+                if used {
+                    recv_errors.succeeded();
+                }
+                used
+            }
+            Err(e) if recv_errors.failed(&e) => break,
+            Err(_) => true,
+        };
+        // wait on every socket at once, upstream ones included; the UDP read below then
+        // doesn't block.
+        let udp_ready = match &tcp {
+            _ if used_uring => false,
+            Some(tcp) => tcp.wait(&udp_socket, &server.upstream_sockets(), EXPIRY_TICK),
+            None => udp::wait(&udp_socket, &server.upstream_sockets(), EXPIRY_TICK),
+        };
+        let received = if !udp_ready {
+            Err(ErrorKind::WouldBlock.into())
+        } else if gro {
+            udp::recv_segments(&udp_socket, &mut bufs[0]).map(|(size, source, segment_size)| {
+                datagrams.clear();
+                datagrams.push((size, source));
+                segment_size
+            })
+        } else {
+            // a datagram per buffer, so none needs splitting.
+            udp::recv_many(&udp_socket, &mut bufs, &mut datagrams).map(|_| buf_size)
+        };
+        match received {
+            Ok(segment_size) => {
+                recv_errors.succeeded();
+                for (buf, &(size, source)) in bufs.iter_mut().zip(&datagrams) {
+                    for packet in buf[..size].chunks_mut(segment_size.max(1)) {
+                        server.handle_packet(packet, source, &udp_socket);
+                    }
+                }
+            }
+            Err(e) if recv_errors.failed(&e) => break,
+            Err(_) => {}
+        }
+        server.poll_upstreams(&udp_socket);
+        if let Some(tcp) = tcp.as_mut() {
+            for (conn, peer, frame) in tcp.read_queries(Instant::now()) {
+                server.handle_tcp_query(&frame, conn, peer, &udp_socket);
+            }
+        }
+        if last_expiry.elapsed() >= EXPIRY_TICK {
+            server.expire(&udp_socket);
+            if let Some(tcp) = tcp.as_mut() {
+                tcp.expire(Instant::now());
+            }
+            last_expiry = Instant::now();
+        }
+        server.poll_tcp_retries(&udp_socket);
+        if let Some(tcp) = tcp.as_mut() {
+            server.flush_tcp_responses(|conn, response| match response {
+                Some(response) => tcp.send(conn, response, Instant::now()),
+                None => tcp.close(conn),
+            });
+        }
+        if let Err(e) = flush_responses(&mut uring, &mut server, &udp_socket) {
+            eprintln!("Giving up on io_uring: {}", e);
+            break;
+        }
+        if let Some(file) = cache_file
+            .as_ref()
+            .filter(|f| last_save.elapsed() >= f.interval)
+        {
+            save_cache(&server, file);
+            last_save = Instant::now();
+        }
+    }
+    if let Some(file) = &cache_file {
+        save_cache(&server, file);
+    }
+}
+
+/// Logs the counters in `stats` every `interval`, from a thread of its own.
+fn log_stats(stats: Arc<Stats>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval.max(Duration::from_secs(1)));
+        eprintln!("Stats: {}", stats.snapshot());
+    });
+}
+
+fn save_cache(server: &DnsServer, file: &CacheFile) {
+    if let Err(e) = server.save_cache(&file.path) {
+        eprintln!("Failed to save cache to {}: {}", file.path.display(), e);
+    }
+}
@@ -1,117 +1,804 @@
-use anyhow::{bail, Result};
+use alloc::{collections::BTreeMap, string::String, vec, vec::Vec};
+use bytes::{BufMut, Bytes, BytesMut};
+use core::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
 use nom::{
     bytes::complete::take,
     number::complete::{be_u16, be_u32, be_u8},
     IResult,
 };
-use std::collections::HashMap;
+
+use crate::name::{Name, MAX_NAME_LEN};
+use crate::rdata::RData;
+use crate::text::{self, Fields, TextError};
+
+/// Response codes, as carried in `Header::rcode`.
+pub mod rcode {
+    pub const NOERROR: u8 = 0;
+    pub const FORMERR: u8 = 1;
+    pub const SERVFAIL: u8 = 2;
+    pub const NXDOMAIN: u8 = 3;
+    pub const NOTIMP: u8 = 4;
+    pub const REFUSED: u8 = 5;
+    /// An extended rcode (RFC 6891): the EDNS version of the query isn't supported. Only the low
+    /// 4 bits fit in the header, the rest goes in the OPT record, see `Edns::set_rcode`.
+    pub const BADVERS: u8 = 16;
+
+    /// Mnemonic of a (possibly extended) rcode, e.g. `NXDOMAIN`.
+    pub fn name(code: u16) -> Option<&'static str> {
+        let name = match code {
+            0 => "NOERROR",
+            1 => "FORMERR",
+            2 => "SERVFAIL",
+            3 => "NXDOMAIN",
+            4 => "NOTIMP",
+            5 => "REFUSED",
+            6 => "YXDOMAIN",
+            7 => "YXRRSET",
+            8 => "NXRRSET",
+            9 => "NOTAUTH",
+            10 => "NOTZONE",
+            16 => "BADVERS",
+            23 => "BADCOOKIE",
+            _ => return None,
+        };
+        return Some(name);
+    }
+}
+
+/// Type code of the OPT pseudo-record.
+pub(crate) const OPT_TYPE: u16 = 41;
+
+/// Length of the fixed header every message starts with.
+const HEADER_LEN: usize = 12;
+
+/// Top bit of the class field, which multicast DNS (RFC 6762 sections 5.4 and 10.2) takes for
+/// the unicast-response bit in questions and the cache-flush bit in records.
+pub(crate) const MDNS_CLASS_BIT: u16 = 0x8000;
+
+/// Most compression pointers followed while reading one name. Real names need a handful at most.
+const MAX_POINTERS: usize = 16;
+
+/// A section of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Question,
+    Answer,
+    Authority,
+    Additional,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Section::Question => "question",
+            Section::Answer => "answer",
+            Section::Authority => "authority",
+            Section::Additional => "additional",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Why a message couldn't be decoded. Offsets count bytes from the start of the message and
+/// point at the header field, label or rdata that is wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum MessageError {
+    #[error("message is shorter than its 12 byte header")]
+    TruncatedHeader,
+    #[error("{section} section ends early at byte {offset}")]
+    TruncatedSection { section: Section, offset: usize },
+    #[error("bad compression pointer at byte {offset}")]
+    BadPointer { offset: usize },
+    #[error("reserved label type at byte {offset}")]
+    BadLabel { offset: usize },
+    #[error("name longer than 255 bytes at byte {offset}")]
+    NameTooLong { offset: usize },
+    #[error("record at byte {offset} has type {value}, which only questions may carry")]
+    UnknownType { value: u16, offset: usize },
+    #[error("malformed rdata at byte {offset}")]
+    BadRdata { offset: usize },
+    #[error("malformed EDNS option at byte {offset}")]
+    BadOption { offset: usize },
+    #[error("second OPT record at byte {offset}")]
+    DuplicateOpt { offset: usize },
+    #[error("trailing data at byte {offset}")]
+    TrailingData { offset: usize },
+}
+
+impl MessageError {
+    /// Turns an error from the nom parsers below into one that says where in `packet` things
+    /// went wrong. The parsers pick the error kind to tell the cases apart: `Tag` and `Count`
+    /// for pointers, `Char` for label types, `TooLarge` for long names, `LengthValue` for rdata
+    /// and `Eof` for running out of bytes.
+    fn from_nom(packet: &[u8], section: Section, e: nom::Err<nom::error::Error<&[u8]>>) -> Self {
+        let e = match e {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e,
+            nom::Err::Incomplete(_) => {
+                return MessageError::TruncatedSection {
+                    section,
+                    offset: packet.len(),
+                }
+            }
+        };
+        // every slice the parsers hand around lies within the packet.
+        let offset = (e.input.as_ptr() as usize)
+            .wrapping_sub(packet.as_ptr() as usize)
+            .min(packet.len());
+        return match e.code {
+            nom::error::ErrorKind::Tag | nom::error::ErrorKind::Count => {
+                MessageError::BadPointer { offset }
+            }
+            nom::error::ErrorKind::Char => MessageError::BadLabel { offset },
+            nom::error::ErrorKind::TooLarge => MessageError::NameTooLong { offset },
+            nom::error::ErrorKind::Eof => MessageError::TruncatedSection { section, offset },
+            _ => MessageError::BadRdata { offset },
+        };
+    }
+}
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Message {
     pub header: Header,
     pub questions: Vec<Question>,
-    pub answers: Vec<Answer>,
-    label_offsets: HashMap<u32, String>,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    /// The additional section, minus the OPT pseudo-record, which is kept in `edns`.
+    pub additionals: Vec<Record>,
+    /// The OPT pseudo-record from the additional section, if the sender speaks EDNS.
+    pub edns: Option<Edns>,
 }
 
 impl Message {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bites = self.header.to_bytes();
-        bites.extend(self.questions.iter().flat_map(|q| q.to_bytes()));
-        bites.extend(self.answers.iter().flat_map(|a| a.to_bytes()));
-        return bites;
-    }
-
-    pub fn parse(bites: &[u8]) -> IResult<&[u8], Message> {
-        let mut parse_offset: u32 = 0;
-        let (mut bites, header) = Header::parse(bites)?;
-        parse_offset += 12;
-        let mut m = Message {
+    /// An empty message with the given header. Section counts are left as they are in `header`.
+    pub fn new(header: Header) -> Message {
+        return Message {
             header,
             questions: vec![],
             answers: vec![],
-            label_offsets: HashMap::new(),
+            authorities: vec![],
+            additionals: vec![],
+            edns: None,
+        };
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        self.write(&mut buf);
+        return buf.freeze();
+    }
+
+    /// The length of the wire form `write` produces, names compressed the same way, worked out
+    /// without writing anything.
+    pub fn encoded_len(&self) -> usize {
+        let mut names = NameCompressor::new(0);
+        let mut len = HEADER_LEN;
+        for q in &self.questions {
+            len += q.compressed_len(&mut names, len);
+        }
+        for r in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            len += r.compressed_len(&mut names, len);
+        }
+        if let Some(edns) = &self.edns {
+            len += edns.encoded_len();
+        }
+        return len;
+    }
+
+    /// Appends the wire form of the message to `buf`. The section counts are written to match
+    /// what is actually emitted. Names are compressed against names written
+    /// earlier in the message.
+    pub fn write(&self, buf: &mut BytesMut) {
+        let mut names = NameCompressor::new(buf.len());
+        let mut header = self.header.clone();
+        header.qdcount = self.questions.len() as u16;
+        header.ancount = self.answers.len() as u16;
+        header.nscount = self.authorities.len() as u16;
+        header.arcount = self.additionals.len() as u16 + if self.edns.is_some() { 1 } else { 0 };
+        header.write(buf);
+        for q in &self.questions {
+            q.write(buf, &mut names);
+        }
+        for r in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            r.write(buf, &mut names);
+        }
+        if let Some(edns) = &self.edns {
+            edns.write(buf);
+        }
+    }
+
+    /// Drops whole RRsets from the end until the message fits in `max_len` bytes: additional
+    /// records first, then authority records, then answers. An RRset is never sent in part
+    /// (RFC 2181 section 9), so when its last record goes, the rest of it goes too. TC is set if
+    /// anything beyond the additional section had to go. The OPT record and the questions are
+    /// kept, and the header counts are updated to what is left. Returns whether TC was set.
+    pub fn truncate(&mut self, max_len: usize) -> bool {
+        let mut len = self.encoded_len();
+        let mut truncated = false;
+        // what a record costs is measured once and taken off as it goes. That is exact for
+        // records dropped from the end, but with compression a name may point into a member of
+        // a dropped RRset further up, and then costs more than it did. So the estimate is
+        // checked, and in that rare case the records left are measured again.
+        while len > max_len {
+            let [answers, authorities, additionals] = self.record_lens();
+            let (left, dropped_additionals) =
+                Message::drop_rrsets(&mut self.additionals, additionals, len, max_len);
+            let (left, dropped_authorities) =
+                Message::drop_rrsets(&mut self.authorities, authorities, left, max_len);
+            let (_, dropped_answers) =
+                Message::drop_rrsets(&mut self.answers, answers, left, max_len);
+            truncated |= dropped_authorities || dropped_answers;
+            if !(dropped_additionals || dropped_authorities || dropped_answers) {
+                break;
+            }
+            len = self.encoded_len();
+        }
+        self.header.qdcount = self.questions.len() as u16;
+        self.header.ancount = self.answers.len() as u16;
+        self.header.nscount = self.authorities.len() as u16;
+        self.header.arcount = self.additionals.len() as u16 + u16::from(self.edns.is_some());
+        self.header.tc |= truncated;
+        return truncated;
+    }
+
+    /// What each record adds to `encoded_len`, going by the names before it: for the answer,
+    /// authority and additional sections in turn.
+    fn record_lens(&self) -> [Vec<usize>; 3] {
+        let mut names = NameCompressor::new(0);
+        let mut at = HEADER_LEN;
+        for q in &self.questions {
+            at += q.compressed_len(&mut names, at);
+        }
+        let mut lens = [vec![], vec![], vec![]];
+        let sections = [&self.answers, &self.authorities, &self.additionals];
+        for (lens, records) in lens.iter_mut().zip(sections) {
+            for r in records {
+                let len = r.compressed_len(&mut names, at);
+                lens.push(len);
+                at += len;
+            }
+        }
+        return lens;
+    }
+
+    /// Removes RRsets from `records`, the RRset of the last record each time, wherever its
+    /// members sit, until `len` less what they took, by `lens`, fits in `max_len`. Returns that
+    /// length and whether anything was removed.
+    fn drop_rrsets(
+        records: &mut Vec<Record>,
+        mut lens: Vec<usize>,
+        mut len: usize,
+        max_len: usize,
+    ) -> (usize, bool) {
+        let mut dropped = false;
+        while len > max_len {
+            let Some(last) = records.last() else {
+                break;
+            };
+            let keep: Vec<bool> = records
+                .iter()
+                .map(|r| r.tipe != last.tipe || r.class != last.class || r.name != last.name)
+                .collect();
+            for (l, _) in lens.iter().zip(&keep).filter(|(_, keep)| !**keep) {
+                len -= l;
+            }
+            let mut kept = keep.iter();
+            records.retain(|_| *kept.next().unwrap());
+            let mut kept = keep.iter();
+            lens.retain(|_| *kept.next().unwrap());
+            dropped = true;
+        }
+        return (len, dropped);
+    }
+
+    /// Replaces any padding option (RFC 7830) with one that brings the message to a multiple of
+    /// `block_size` bytes, but no further than `max_len`. Messages without EDNS are left alone,
+    /// as padding needs an OPT record to live in.
+    pub fn pad(&mut self, block_size: usize, max_len: usize) {
+        let Some(edns) = self.edns.as_mut() else {
+            return;
         };
+        edns.options
+            .retain(|o| !matches!(o, EdnsOption::Padding(_)));
+        if block_size == 0 {
+            return;
+        }
+        // the option code and length take 4 bytes before any padding.
+        let unpadded = self.encoded_len() + 4;
+        let padded = unpadded.next_multiple_of(block_size).min(max_len);
+        if padded < unpadded {
+            return;
+        }
+        if let Some(edns) = self.edns.as_mut() {
+            edns.options
+                .push(EdnsOption::Padding(vec![0; padded - unpadded]));
+        }
+    }
+
+    /// Decodes a whole message. Besides malformed records, it fails on anything RFC 1035 and
+    /// RFC 6891 rule out that a server should answer with FORMERR: names longer than 255 bytes,
+    /// section counts that don't match what follows the header, bytes after the last section,
+    /// query-only types outside the question section and more than one OPT record.
+    pub fn parse(packet: &[u8]) -> Result<Message, MessageError> {
+        let (mut bites, header) =
+            Header::parse(packet).map_err(|_| MessageError::TruncatedHeader)?;
+        let mut m = Message::new(header);
         let mut question: Question;
         for _ in 0..m.header.qdcount {
-            (bites, question) = Question::parse(&mut m, bites, &mut parse_offset)?;
+            (bites, question) = Question::parse(packet, bites)
+                .map_err(|e| MessageError::from_nom(packet, Section::Question, e))?;
             m.questions.push(question);
         }
-        let mut answer: Answer;
+        let mut record: Record;
         for _ in 0..m.header.ancount {
-            (bites, answer) = Answer::parse(&mut m, bites, &mut parse_offset)?;
-            m.answers.push(answer);
+            (bites, record) = Message::parse_section_record(packet, bites, Section::Answer)?;
+            m.answers.push(record);
+        }
+        for _ in 0..m.header.nscount {
+            (bites, record) = Message::parse_section_record(packet, bites, Section::Authority)?;
+            m.authorities.push(record);
+        }
+        for _ in 0..m.header.arcount {
+            let offset = packet.len() - bites.len();
+            let (rest, raw) = Message::parse_raw_record(packet, bites)
+                .map_err(|e| MessageError::from_nom(packet, Section::Additional, e))?;
+            if raw.tipe == OPT_TYPE && raw.name.is_root() {
+                if m.edns.is_some() {
+                    return Err(MessageError::DuplicateOpt { offset });
+                }
+                let edns = Edns::from_record(raw.class, raw.ttl, raw.rdata).map_err(|e| {
+                    match MessageError::from_nom(packet, Section::Additional, e) {
+                        MessageError::BadRdata { offset }
+                        | MessageError::TruncatedSection { offset, .. } => {
+                            MessageError::BadOption { offset }
+                        }
+                        other => other,
+                    }
+                })?;
+                m.edns = Some(edns);
+                bites = rest;
+                continue;
+            }
+            record = Message::section_record(packet, raw, Section::Additional, offset)?;
+            m.additionals.push(record);
+            bites = rest;
+        }
+        if !bites.is_empty() {
+            return Err(MessageError::TrailingData {
+                offset: packet.len() - bites.len(),
+            });
         }
-        return Ok((bites, m));
+        return Ok(m);
+    }
+
+    /// Reads a record of the answer, authority or additional section, where query-only types
+    /// like ANY have no place.
+    fn parse_section_record<'a>(
+        packet: &'a [u8],
+        bites: &'a [u8],
+        section: Section,
+    ) -> Result<(&'a [u8], Record), MessageError> {
+        let offset = packet.len() - bites.len();
+        let (rest, raw) = Message::parse_raw_record(packet, bites)
+            .map_err(|e| MessageError::from_nom(packet, section, e))?;
+        return Ok((rest, Message::section_record(packet, raw, section, offset)?));
+    }
+
+    /// Interprets a record read from `section` at `offset`, which may not have a query-only type.
+    fn section_record(
+        packet: &[u8],
+        raw: RawRecord,
+        section: Section,
+        offset: usize,
+    ) -> Result<Record, MessageError> {
+        let record = Record::from_raw(packet, raw)
+            .map_err(|e| MessageError::from_nom(packet, section, e))?;
+        if record.tipe.is_query_only() {
+            return Err(MessageError::UnknownType {
+                value: record.tipe.value(),
+                offset,
+            });
+        }
+        return Ok(record);
+    }
+
+    /// Reads a resource record without interpreting its type, class or rdata.
+    fn parse_raw_record<'a>(packet: &'a [u8], bites: &'a [u8]) -> IResult<&'a [u8], RawRecord<'a>> {
+        let (bites, name) = Message::parse_label_seq(packet, bites)?;
+        let (bites, tipe) = be_u16(bites)?;
+        let (bites, class) = be_u16(bites)?;
+        let (bites, ttl) = be_u32(bites)?;
+        let (bites, rdlength) = be_u16(bites)?;
+        let (bites, rdata) = take(rdlength)(bites)?;
+        return Ok((
+            bites,
+            RawRecord {
+                name,
+                tipe,
+                class,
+                ttl,
+                rdata,
+            },
+        ));
     }
 
     fn is_compressed_label(bite: u8) -> bool {
         return bite & 0b11000000 == 0b11000000;
     }
 
-    fn parse_label_seq<'a>(
-        &mut self,
+    /// Reads the name at the front of `bites`, which must lie within `packet`. Compression
+    /// pointers are followed by offset into `packet`, backwards or forwards, and may lead to
+    /// names that are themselves compressed. A pointer back to where an earlier one led is a
+    /// loop and rejected, and at most `MAX_POINTERS` are followed. Together with the limit on
+    /// steps, that keeps hostile packets from looping or fanning out. Names that add up to more
+    /// than 255 bytes are rejected.
+    pub(crate) fn parse_label_seq<'a>(
+        packet: &'a [u8],
         bites: &'a [u8],
-        parse_offset: &mut u32,
-    ) -> IResult<&'a [u8], Vec<String>> {
-        let mut name : Vec<String> = vec![];
-        let mut name_map: HashMap<u32, String> = HashMap::new();
-        let (mut bites, mut lable_len) = be_u8(bites)?;
-        *parse_offset += 1;
-        let mut label_bites: &[u8];
+    ) -> IResult<&'a [u8], Name> {
+        let mut name = Name::root();
+        // where the caller continues: right after the first pointer, or after the terminating
+        // zero if the name has no pointer.
+        let mut rest: Option<&'a [u8]> = None;
+        let mut cursor = bites;
+        // every label and pointer takes at least one byte of the 255 a name may span. Anything
+        // longer is a pointer loop.
+        let mut steps = 0;
+        let mut pointers = 0;
+        // where each pointer followed so far led.
+        let mut targets = [0usize; MAX_POINTERS];
+        // wire length of the name so far, counting the terminating zero.
+        let mut name_len = 1;
+        let fail = |at: &'a [u8], kind: nom::error::ErrorKind| {
+            nom::Err::Failure(nom::error::Error::new(at, kind))
+        };
         loop {
+            steps += 1;
+            if steps > 255 {
+                return Err(fail(cursor, nom::error::ErrorKind::Count));
+            }
+            let (after_len, lable_len) = be_u8(cursor)?;
             if Message::is_compressed_label(lable_len) {
-                let offset: u8;
-                (bites, offset) = be_u8(bites)?;
-                *parse_offset += 1;
-                let offset = ((lable_len as u16 & 0b00111111) << 8) | offset as u16;
-                if let Some(labels) = self.label_offsets.get(&(offset as u32)) {
-                    let labels :Vec<String> = labels.split(".").map(|s| s.to_string()).collect();
-                    name.extend(labels);
-                    name_map.insert(*parse_offset - 1, name.join("."));
-                    break;
-                } else {
-                    return Err(nom::Err::Failure(nom::error::Error::new(
-                        bites,
-                        nom::error::ErrorKind::Tag,
-                    )));
+                let (after_ptr, low) = be_u8(after_len)?;
+                if rest.is_none() {
+                    rest = Some(after_ptr);
                 }
-            } else {
-                (bites, label_bites) = take(lable_len)(bites)?;
-                let label = String::from_utf8_lossy(label_bites).to_string();
-                name.push(label.clone());
-                for (_k, v) in name_map.iter_mut() {
-                    v.push_str(&format!(".{}", label));
+                let offset = ((lable_len as usize & 0b00111111) << 8) | low as usize;
+                if offset >= packet.len() || targets[..pointers].contains(&offset) {
+                    return Err(fail(cursor, nom::error::ErrorKind::Tag));
+                }
+                if pointers == MAX_POINTERS {
+                    return Err(fail(cursor, nom::error::ErrorKind::Count));
                 }
-                name_map.insert(*parse_offset - 1, label.clone());
-                *parse_offset += lable_len as u32;
-                (bites, lable_len) = be_u8(bites)?;
-                *parse_offset += 1;
-                if lable_len == 0 {
-                    break;
+                targets[pointers] = offset;
+                pointers += 1;
+                cursor = &packet[offset..];
+            } else if lable_len & 0b11000000 != 0 {
+                // 0b01 and 0b10 prefixes are extended and reserved label types (RFC 6891).
+                return Err(fail(cursor, nom::error::ErrorKind::Char));
+            } else if lable_len == 0 {
+                return Ok((rest.unwrap_or(after_len), name));
+            } else {
+                name_len += lable_len as usize + 1;
+                if name_len > MAX_NAME_LEN {
+                    return Err(fail(cursor, nom::error::ErrorKind::TooLarge));
                 }
+                let label_bites: &[u8];
+                (cursor, label_bites) = take(lable_len)(after_len)?;
+                name.push_wire_label(label_bites);
             }
         }
-        for (k, v) in name_map.iter() {
-            self.label_offsets.insert(*k, v.clone());
+    }
+}
+
+/// A message decoded no further than its header. Questions and records are decoded one at a time
+/// as the section iterators reach them, so looking at the first question doesn't pay for the
+/// rest of the message. Unlike `Message::parse`, nothing checks that the sections add up to the
+/// whole packet.
+#[derive(Debug, Clone)]
+pub struct LazyMessage<'a> {
+    pub header: Header,
+    packet: &'a [u8],
+}
+
+impl<'a> LazyMessage<'a> {
+    pub fn parse(packet: &'a [u8]) -> Result<LazyMessage<'a>, MessageError> {
+        let (_, header) = Header::parse(packet).map_err(|_| MessageError::TruncatedHeader)?;
+        return Ok(LazyMessage { header, packet });
+    }
+
+    pub fn questions(&self) -> Questions<'a> {
+        return Questions {
+            packet: self.packet,
+            bites: &self.packet[HEADER_LEN..],
+            left: self.header.qdcount,
+        };
+    }
+
+    pub fn answers(&self) -> Records<'a> {
+        return self.records(Section::Answer, self.header.ancount);
+    }
+
+    pub fn authorities(&self) -> Records<'a> {
+        return self.records(Section::Authority, self.header.nscount);
+    }
+
+    /// The additional section as it is on the wire, the OPT pseudo-record included.
+    pub fn additionals(&self) -> Records<'a> {
+        return self.records(Section::Additional, self.header.arcount);
+    }
+
+    fn records(&self, section: Section, count: u16) -> Records<'a> {
+        return Records {
+            packet: self.packet,
+            header: self.header.clone(),
+            section,
+            bites: None,
+            left: count,
+        };
+    }
+}
+
+/// The questions of a `LazyMessage`, decoded as they are iterated. Stops after the first error.
+pub struct Questions<'a> {
+    packet: &'a [u8],
+    bites: &'a [u8],
+    left: u16,
+}
+
+impl Iterator for Questions<'_> {
+    type Item = Result<Question, MessageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 {
+            return None;
+        }
+        match Question::parse(self.packet, self.bites) {
+            Ok((rest, question)) => {
+                self.bites = rest;
+                self.left -= 1;
+                return Some(Ok(question));
+            }
+            Err(e) => {
+                self.left = 0;
+                return Some(Err(MessageError::from_nom(
+                    self.packet,
+                    Section::Question,
+                    e,
+                )));
+            }
+        }
+    }
+}
+
+/// The records of one section of a `LazyMessage`, decoded as they are iterated. The sections
+/// before it are stepped over on the first call, without decoding their names or rdata. Stops
+/// after the first error.
+pub struct Records<'a> {
+    packet: &'a [u8],
+    header: Header,
+    section: Section,
+    /// where the next record starts, once the earlier sections have been stepped over
+    bites: Option<&'a [u8]>,
+    left: u16,
+}
+
+impl<'a> Records<'a> {
+    /// Where the section starts in the packet. A packet cut short before it fails with the
+    /// section the bytes ran out in.
+    fn start(&self) -> Result<&'a [u8], MessageError> {
+        let packet = self.packet;
+        let fail = |section| move |e| MessageError::from_nom(packet, section, e);
+        let mut bites = &packet[HEADER_LEN..];
+        for _ in 0..self.header.qdcount {
+            (bites, _) = Records::skip_name(bites).map_err(fail(Section::Question))?;
+            (bites, _) = take(4usize)(bites).map_err(fail(Section::Question))?;
+        }
+        let before: &[(Section, u16)] = match self.section {
+            Section::Question | Section::Answer => &[],
+            Section::Authority => &[(Section::Answer, self.header.ancount)],
+            Section::Additional => &[
+                (Section::Answer, self.header.ancount),
+                (Section::Authority, self.header.nscount),
+            ],
+        };
+        for &(section, count) in before {
+            for _ in 0..count {
+                let rdlength: u16;
+                (bites, _) = Records::skip_name(bites).map_err(fail(section))?;
+                (bites, _) = take(8usize)(bites).map_err(fail(section))?;
+                (bites, rdlength) = be_u16(bites).map_err(fail(section))?;
+                (bites, _) = take(rdlength)(bites).map_err(fail(section))?;
+            }
+        }
+        return Ok(bites);
+    }
+
+    /// Steps over the name at the front of `bites`. Pointers end a name, so they aren't followed.
+    fn skip_name(mut bites: &[u8]) -> IResult<&[u8], ()> {
+        loop {
+            let (rest, lable_len) = be_u8(bites)?;
+            if Message::is_compressed_label(lable_len) {
+                let (rest, _) = be_u8(rest)?;
+                return Ok((rest, ()));
+            } else if lable_len & 0b11000000 != 0 {
+                return Err(nom::Err::Failure(nom::error::Error::new(
+                    bites,
+                    nom::error::ErrorKind::Char,
+                )));
+            } else if lable_len == 0 {
+                return Ok((rest, ()));
+            }
+            (bites, _) = take(lable_len)(rest)?;
+        }
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<Record, MessageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 {
+            return None;
+        }
+        let parsed = match self.bites {
+            Some(bites) => Ok(bites),
+            None => self.start(),
+        }
+        .and_then(|bites| Message::parse_section_record(self.packet, bites, self.section));
+        match parsed {
+            Ok((rest, record)) => {
+                self.bites = Some(rest);
+                self.left -= 1;
+                return Some(Ok(record));
+            }
+            Err(e) => {
+                self.left = 0;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Remembers where names were written in a message, so that later names sharing a suffix with
+/// them can end in a pointer instead.
+pub(crate) struct NameCompressor<'a> {
+    /// where the message starts in the buffer; pointers are relative to it
+    start: usize,
+    /// name suffix in wire form -> offset of its first label in the message. The suffixes are
+    /// borrowed from the names being written, so remembering one doesn't allocate.
+    offsets: BTreeMap<&'a [u8], u16>,
+    enabled: bool,
+}
+
+impl<'a> NameCompressor<'a> {
+    pub(crate) fn new(start: usize) -> NameCompressor<'a> {
+        return NameCompressor {
+            start,
+            offsets: BTreeMap::new(),
+            enabled: true,
+        };
+    }
+
+    /// A compressor that writes every name in full, for rdata encoded outside a message.
+    pub(crate) fn disabled() -> NameCompressor<'a> {
+        return NameCompressor {
+            start: 0,
+            offsets: BTreeMap::new(),
+            enabled: false,
+        };
+    }
+
+    /// Writes `name`, ending in a pointer at the longest suffix already in the message. Suffixes
+    /// match case-sensitively, so compression never changes how a name is spelled.
+    pub(crate) fn write_name(&mut self, name: &'a Name, buf: &mut BytesMut) {
+        let (full, pointer) = self.compress(name, buf.len() - self.start);
+        buf.put_slice(&name.suffix_octets(0)[..full]);
+        match pointer {
+            Some(offset) => buf.put_u16(0b11000000_00000000 | offset),
+            None => buf.put_u8(0),
+        }
+    }
+
+    /// How many bytes `write_name` would take for `name` at offset `at` of the message. The name
+    /// is remembered just as if it had been written.
+    pub(crate) fn name_len(&mut self, name: &'a Name, at: usize) -> usize {
+        let (full, pointer) = self.compress(name, at);
+        return full + if pointer.is_some() { 2 } else { 1 };
+    }
+
+    /// Works out how `name` goes out at offset `at`: how many bytes of its labels are written in
+    /// full, and the offset of the suffix a pointer stands in for after them, if any. The
+    /// suffixes written in full are remembered.
+    fn compress(&mut self, name: &'a Name, at: usize) -> (usize, Option<u16>) {
+        let whole = name.suffix_octets(0);
+        if !self.enabled {
+            return (whole.len(), None);
+        }
+        for i in 0..name.label_count() {
+            let suffix = name.suffix_octets(i);
+            let full = whole.len() - suffix.len();
+            if let Some(&pointer) = self.offsets.get(suffix) {
+                return (full, Some(pointer));
+            }
+            // pointers only have 14 bits.
+            if at + full < 0b01000000_00000000 {
+                self.offsets.insert(suffix, (at + full) as u16);
+            }
+        }
+        return (whole.len(), None);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Opcode {
+    /// A standard query
+    QUERY,
+    /// An inverse query, obsoleted by RFC 3425
+    IQUERY,
+    /// A server status request
+    STATUS,
+    /// A zone change notification (RFC 1996)
+    NOTIFY,
+    /// A dynamic update (RFC 2136)
+    UPDATE,
+    /// Any other opcode, by its value
+    Reserved(u8),
+}
+
+impl Opcode {
+    pub fn value(&self) -> u8 {
+        match self {
+            Opcode::QUERY => 0,
+            Opcode::IQUERY => 1,
+            Opcode::STATUS => 2,
+            Opcode::NOTIFY => 4,
+            Opcode::UPDATE => 5,
+            Opcode::Reserved(value) => *value,
+        }
+    }
+
+    pub fn from_value(value: u8) -> Opcode {
+        match value {
+            0 => Opcode::QUERY,
+            1 => Opcode::IQUERY,
+            2 => Opcode::STATUS,
+            4 => Opcode::NOTIFY,
+            5 => Opcode::UPDATE,
+            _ => Opcode::Reserved(value),
         }
-        return Ok((bites, name));
     }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Header {
     pub id: u16,
     /// query or response: 0 for question, 1 for reply
     pub qr: bool,
     /// specifies the type of query in a message
-    pub opcode: u8,
+    pub opcode: Opcode,
     /// authoritative answer: 1 if the responding server is authoritative for/ owns the domain name in question
     pub aa: bool,
     /// truncation: 1 is message was larger than 512 bytes, and was truncated
     pub tc: bool,
     /// recursion desired: 1 if the client wants the server to recursively resolve the query
-    rd: bool,
+    pub rd: bool,
     /// recursion available: server sets this to 1 if it supports recursion
     pub ra: bool,
     /// Reserved: Used by DNSSEC queries.
@@ -129,39 +816,61 @@ pub struct Header {
 }
 
 impl Header {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bites = vec![];
-        bites.push((self.id >> 8) as u8);
-        bites.push(self.id as u8);
-        bites.push(
+    /// The authentic data bit in `z` (RFC 4035): every record in the answer has been validated.
+    pub const AUTHENTIC_DATA: u8 = 0b010;
+    /// The checking disabled bit in `z` (RFC 4035): the client validates for itself.
+    pub const CHECKING_DISABLED: u8 = 0b001;
+
+    /// A standard query header with the given id: every flag cleared and every count zero.
+    pub fn new(id: u16) -> Header {
+        return Header {
+            id,
+            qr: false,
+            opcode: Opcode::QUERY,
+            aa: false,
+            tc: false,
+            rd: false,
+            ra: false,
+            z: 0,
+            rcode: 0,
+            qdcount: 0,
+            ancount: 0,
+            nscount: 0,
+            arcount: 0,
+        };
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(12);
+        self.write(&mut buf);
+        return buf.freeze();
+    }
+
+    pub fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.id);
+        buf.put_u8(
             (if self.qr { 1 << 7 } else { 0 })
-                | ((self.opcode << 3) & 0b01111000)
+                | ((self.opcode.value() << 3) & 0b01111000)
                 | (if self.aa { 1 << 2 } else { 0 })
                 | (if self.tc { 1 << 1 } else { 0 })
                 | (if self.rd { 1 } else { 0 }),
         );
-        bites.push(
+        buf.put_u8(
             (if self.ra { 1 << 7 } else { 0 })
                 | ((self.z << 4) & 0b01110000)
                 | (self.rcode & 0b00001111),
         );
-        bites.push((self.qdcount >> 8) as u8);
-        bites.push(self.qdcount as u8);
-        bites.push((self.ancount >> 8) as u8);
-        bites.push(self.ancount as u8);
-        bites.push((self.nscount >> 8) as u8);
-        bites.push(self.nscount as u8);
-        bites.push((self.arcount >> 8) as u8);
-
-        bites.push(self.arcount as u8);
-        return bites;
+        buf.put_u16(self.qdcount);
+        buf.put_u16(self.ancount);
+        buf.put_u16(self.nscount);
+        buf.put_u16(self.arcount);
     }
 
-    fn parse(bites: &[u8]) -> IResult<&[u8], Header> {
+    pub fn parse(bites: &[u8]) -> IResult<&[u8], Header> {
         let (bites, id) = be_u16(bites)?;
         let (bites, sec_bite) = be_u8(bites)?;
         let qr = sec_bite & 0b10000000 == 128;
-        let opcode = (sec_bite & 0b01111000) >> 3;
+        let opcode = Opcode::from_value((sec_bite & 0b01111000) >> 3);
         let aa = sec_bite & 0b00000100 == 4;
         let tc = sec_bite & 0b00000010 == 2;
         let rd = sec_bite & 0b00000001 == 1;
@@ -194,7 +903,8 @@ impl Header {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum QType {
     /// A host address
     A,
@@ -228,10 +938,80 @@ pub enum QType {
     MX,
     /// Text strings
     TXT,
+    /// Responsible person
+    RP,
+    /// AFS database location
+    AFSDB,
+    /// An IPv6 host address
+    AAAA,
+    /// Server selection
+    SRV,
+    /// Key exchanger
+    KX,
+    /// A certificate or certificate revocation list
+    CERT,
+    /// Address prefix lists
+    APL,
+    /// Delegation signer
+    DS,
+    /// Keying material for IPsec
+    IPSECKEY,
+    /// Signature over an RRset
+    RRSIG,
+    /// Next secure record, authenticated denial of existence
+    NSEC,
+    /// A zone signing key
+    DNSKEY,
+    /// Ties a name to the DHCP client that registered it
+    DHCID,
+    /// Hashed next secure record
+    NSEC3,
+    /// Parameters a zone uses for NSEC3
+    NSEC3PARAM,
+    /// TLS certificate association, for DANE
+    TLSA,
+    /// S/MIME certificate association, for DANE
+    SMIMEA,
+    /// Host identity protocol
+    HIP,
+    /// A DS record the child zone wants its parent to publish
+    CDS,
+    /// A DNSKEY the child zone wants its parent to publish a DS for
+    CDNSKEY,
+    /// An OpenPGP public key, for DANE
+    OPENPGPKEY,
+    /// Records the parent zone should copy from the child
+    CSYNC,
+    /// A digest over the whole zone
+    ZONEMD,
+    /// General purpose service binding
+    SVCB,
+    /// Service binding for HTTPS origins
+    HTTPS,
+    /// Transaction key establishment
+    TKEY,
+    /// Transaction signature
+    TSIG,
+    /// A 48 bit extended unique identifier, e.g. a MAC address
+    EUI48,
+    /// A 64 bit extended unique identifier
+    EUI64,
+    /// Incremental zone transfer. Query only.
+    IXFR,
+    /// Full zone transfer. Query only.
+    AXFR,
+    /// Mailbox related records (MB, MG or MR). Query only.
+    MAILB,
+    /// Records of every type, `*` in RFC 1035. Query only.
+    ANY,
+    /// A URI for a service
+    URI,
+    /// Any other type, by its code (RFC 3597). Its rdata is carried as opaque bytes.
+    Unknown(u16),
 }
 
 impl QType {
-    fn value(&self) -> u16 {
+    pub fn value(&self) -> u16 {
         match self {
             QType::A => 1,
             QType::NS => 2,
@@ -249,33 +1029,116 @@ impl QType {
             QType::MINFO => 14,
             QType::MX => 15,
             QType::TXT => 16,
+            QType::RP => 17,
+            QType::AFSDB => 18,
+            QType::AAAA => 28,
+            QType::SRV => 33,
+            QType::KX => 36,
+            QType::CERT => 37,
+            QType::APL => 42,
+            QType::DS => 43,
+            QType::IPSECKEY => 45,
+            QType::RRSIG => 46,
+            QType::NSEC => 47,
+            QType::DNSKEY => 48,
+            QType::DHCID => 49,
+            QType::NSEC3 => 50,
+            QType::NSEC3PARAM => 51,
+            QType::TLSA => 52,
+            QType::SMIMEA => 53,
+            QType::HIP => 55,
+            QType::CDS => 59,
+            QType::CDNSKEY => 60,
+            QType::OPENPGPKEY => 61,
+            QType::CSYNC => 62,
+            QType::ZONEMD => 63,
+            QType::SVCB => 64,
+            QType::HTTPS => 65,
+            QType::TKEY => 249,
+            QType::TSIG => 250,
+            QType::EUI48 => 108,
+            QType::EUI64 => 109,
+            QType::IXFR => 251,
+            QType::AXFR => 252,
+            QType::MAILB => 253,
+            QType::ANY => 255,
+            QType::URI => 256,
+            QType::Unknown(value) => *value,
         }
     }
 
-    fn from_value(value: u16) -> Result<QType> {
+    pub fn from_value(value: u16) -> QType {
         match value {
-            1 => Ok(QType::A),
-            2 => Ok(QType::NS),
-            3 => Ok(QType::MD),
-            4 => Ok(QType::MF),
-            5 => Ok(QType::CNAME),
-            6 => Ok(QType::SOA),
-            7 => Ok(QType::MB),
-            8 => Ok(QType::MG),
-            9 => Ok(QType::MR),
-            10 => Ok(QType::NULL),
-            11 => Ok(QType::WKS),
-            12 => Ok(QType::PTR),
-            13 => Ok(QType::HINFO),
-            14 => Ok(QType::MINFO),
-            15 => Ok(QType::MX),
-            16 => Ok(QType::TXT),
-            _ => bail!("Unknown QType value: {}", value),
+            1 => QType::A,
+            2 => QType::NS,
+            3 => QType::MD,
+            4 => QType::MF,
+            5 => QType::CNAME,
+            6 => QType::SOA,
+            7 => QType::MB,
+            8 => QType::MG,
+            9 => QType::MR,
+            10 => QType::NULL,
+            11 => QType::WKS,
+            12 => QType::PTR,
+            13 => QType::HINFO,
+            14 => QType::MINFO,
+            15 => QType::MX,
+            16 => QType::TXT,
+            17 => QType::RP,
+            18 => QType::AFSDB,
+            28 => QType::AAAA,
+            33 => QType::SRV,
+            36 => QType::KX,
+            37 => QType::CERT,
+            42 => QType::APL,
+            43 => QType::DS,
+            45 => QType::IPSECKEY,
+            46 => QType::RRSIG,
+            47 => QType::NSEC,
+            48 => QType::DNSKEY,
+            49 => QType::DHCID,
+            50 => QType::NSEC3,
+            51 => QType::NSEC3PARAM,
+            52 => QType::TLSA,
+            53 => QType::SMIMEA,
+            55 => QType::HIP,
+            59 => QType::CDS,
+            60 => QType::CDNSKEY,
+            61 => QType::OPENPGPKEY,
+            62 => QType::CSYNC,
+            63 => QType::ZONEMD,
+            64 => QType::SVCB,
+            65 => QType::HTTPS,
+            249 => QType::TKEY,
+            250 => QType::TSIG,
+            108 => QType::EUI48,
+            109 => QType::EUI64,
+            251 => QType::IXFR,
+            252 => QType::AXFR,
+            253 => QType::MAILB,
+            255 => QType::ANY,
+            256 => QType::URI,
+            _ => QType::Unknown(value),
         }
     }
+
+    /// Whether this type only appears in questions, selecting records rather than naming a type
+    /// of record (RFC 1035 section 3.2.3).
+    pub fn is_query_only(&self) -> bool {
+        return matches!(self, QType::IXFR | QType::AXFR | QType::MAILB | QType::ANY);
+    }
+
+    /// Whether this is a meta-type (RFC 6895 section 3.1): records that only carry information
+    /// about the transaction they are in, and are never cached or kept in a zone. OPT is one
+    /// too, but it lives in `Message::edns`.
+    pub fn is_meta(&self) -> bool {
+        return matches!(self, QType::TKEY | QType::TSIG);
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ResourceClass {
     /// the Internet
     IN,
@@ -285,160 +1148,1098 @@ pub enum ResourceClass {
     CH,
     /// Hesiod [Dyer 87]
     HS,
+    /// No class, used by UPDATE to delete an RR from an RRset (RFC 2136)
+    NONE,
+    /// Any class, `*` in RFC 1035. In UPDATE it marks RRset deletions and prerequisites
+    ANY,
+    /// Any other class, by its code (RFC 3597)
+    Unknown(u16),
 }
 
 impl ResourceClass {
-    fn from_value(value: u16) -> Result<ResourceClass> {
+    pub fn from_value(value: u16) -> ResourceClass {
         match value {
-            1 => Ok(ResourceClass::IN),
-            2 => Ok(ResourceClass::CS),
-            3 => Ok(ResourceClass::CH),
-            4 => Ok(ResourceClass::HS),
-            _ => bail!("Unknown ResourseClass value: {}", value),
+            1 => ResourceClass::IN,
+            2 => ResourceClass::CS,
+            3 => ResourceClass::CH,
+            4 => ResourceClass::HS,
+            254 => ResourceClass::NONE,
+            255 => ResourceClass::ANY,
+            _ => ResourceClass::Unknown(value),
         }
     }
-    fn value(&self) -> u16 {
+    pub fn value(&self) -> u16 {
         match self {
             ResourceClass::IN => 1,
             ResourceClass::CS => 2,
             ResourceClass::CH => 3,
             ResourceClass::HS => 4,
+            ResourceClass::NONE => 254,
+            ResourceClass::ANY => 255,
+            ResourceClass::Unknown(value) => *value,
         }
     }
+
+    /// Whether this is a meta class, which selects or qualifies records instead of holding data
+    /// (RFC 6895 section 3.2).
+    pub fn is_meta(&self) -> bool {
+        return matches!(self, ResourceClass::NONE | ResourceClass::ANY);
+    }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Question {
     pub tipe: QType,
     pub class: ResourceClass,
-    pub name: Vec<String>,
+    pub name: Name,
+    /// mDNS: the querier would like a unicast response. Always false in unicast DNS.
+    pub unicast_response: bool,
 }
 
 impl Question {
-    fn parse<'a>(
-        m: &mut Message,
-        bites: &'a [u8],
-        parse_offset: &mut u32,
-    ) -> IResult<&'a [u8], Question> {
-        let (bites, name) = m.parse_label_seq(bites, parse_offset)?;
+    fn parse<'a>(packet: &'a [u8], bites: &'a [u8]) -> IResult<&'a [u8], Question> {
+        let (bites, name) = Message::parse_label_seq(packet, bites)?;
         let (bites, tipe) = be_u16(bites)?;
-        *parse_offset += 2;
-        let tipe = match QType::from_value(tipe) {
-            Ok(t) => t,
-            Err(_e) => {
-                return Err(nom::Err::Failure(nom::error::Error::new(
-                    bites,
-                    nom::error::ErrorKind::Tag,
-                )))
-            }
-        };
+        let tipe = QType::from_value(tipe);
         let (bites, class) = be_u16(bites)?;
-        *parse_offset += 2;
-        let class = match ResourceClass::from_value(class) {
-            Ok(c) => c,
-            Err(_e) => {
-                return Err(nom::Err::Failure(nom::error::Error::new(
-                    bites,
-                    nom::error::ErrorKind::Tag,
-                )))
-            }
-        };
-        return Ok((bites, Question { tipe, class, name }));
+        let unicast_response = class & MDNS_CLASS_BIT != 0;
+        let class = ResourceClass::from_value(class & !MDNS_CLASS_BIT);
+        return Ok((
+            bites,
+            Question {
+                tipe,
+                class,
+                name,
+                unicast_response,
+            },
+        ));
     }
 
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bites = vec![];
-        for label in &self.name {
-            bites.push(label.len() as u8);
-            bites.extend(label.as_bytes());
-        }
-        bites.push(0);
-        let tipe_val = self.tipe.value();
-        bites.push((tipe_val >> 8) as u8);
-        bites.push(tipe_val as u8);
-        let class_val = self.class.value();
-        bites.push((class_val >> 8) as u8);
-        bites.push(class_val as u8);
-        return bites;
+    /// What `write` takes at offset `at` of the message.
+    fn compressed_len<'a>(&'a self, names: &mut NameCompressor<'a>, at: usize) -> usize {
+        return names.name_len(&self.name, at) + 4;
     }
+
+    fn write<'a>(&'a self, buf: &mut BytesMut, names: &mut NameCompressor<'a>) {
+        names.write_name(&self.name, buf);
+        buf.put_u16(self.tipe.value());
+        let flag = if self.unicast_response {
+            MDNS_CLASS_BIT
+        } else {
+            0
+        };
+        buf.put_u16(self.class.value() | flag);
+    }
+}
+
+/// A record as it sits on the wire, before its type, class and rdata are interpreted.
+struct RawRecord<'a> {
+    name: Name,
+    tipe: u16,
+    class: u16,
+    ttl: u32,
+    rdata: &'a [u8],
 }
 
 #[derive(Debug, Clone)]
-pub struct Answer {
-    pub name: Vec<String>,
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Record {
+    pub name: Name,
     pub tipe: QType,
     pub class: ResourceClass,
     pub ttl: u32,
-    pub rdlength: u16,
-    pub rdata: Vec<u8>,
+    pub rdata: RData,
+    /// mDNS: this record replaces any cached records of its RRset. Always false in unicast DNS.
+    pub cache_flush: bool,
 }
 
-impl Answer {
-    fn parse<'a>(
-        m: &mut Message,
-        bites: &'a [u8],
-        parse_offset: &mut u32,
-    ) -> IResult<&'a [u8], Answer> {
-        let (bites, name) = m.parse_label_seq(bites, parse_offset)?;
-        let (bites, tipe) = be_u16(bites)?;
-        *parse_offset += 2;
-        let tipe = match QType::from_value(tipe) {
-            Ok(t) => t,
-            Err(_e) => {
-                return Err(nom::Err::Failure(nom::error::Error::new(
-                    bites,
-                    nom::error::ErrorKind::Tag,
-                )))
+impl Record {
+    /// Interprets the type, class and rdata of a record read by `Message::parse_raw_record`.
+    fn from_raw<'a>(
+        packet: &'a [u8],
+        raw: RawRecord<'a>,
+    ) -> Result<Record, nom::Err<nom::error::Error<&'a [u8]>>> {
+        let RawRecord {
+            name,
+            tipe,
+            class,
+            ttl,
+            rdata,
+        } = raw;
+        let tipe = QType::from_value(tipe);
+        let cache_flush = class & MDNS_CLASS_BIT != 0;
+        let class = ResourceClass::from_value(class & !MDNS_CLASS_BIT);
+        // rdata running out before its fields do is bad rdata, not a short section.
+        let (_, rdata) = RData::parse(&tipe, packet, rdata).map_err(|e| {
+            e.map(|e| match e.code {
+                nom::error::ErrorKind::Eof => {
+                    nom::error::Error::new(e.input, nom::error::ErrorKind::LengthValue)
+                }
+                _ => e,
+            })
+        })?;
+        return Ok(Record {
+            name,
+            tipe,
+            class,
+            ttl,
+            rdata,
+            cache_flush,
+        });
+    }
+
+    /// The canonical form of the record (RFC 4034 section 6.2): owner name and the names in
+    /// its rdata lowercased. Written out with `write_canonical` this is what gets signed; the TTL
+    /// there must be the original TTL from the RRSIG, which is up to the caller.
+    pub fn to_canonical(&self) -> Record {
+        return Record {
+            name: self.name.to_lowercase(),
+            rdata: self.rdata.to_canonical(),
+            ..self.clone()
+        };
+    }
+
+    /// Appends the canonical wire form: canonical names, none of them compressed.
+    pub fn write_canonical(&self, buf: &mut BytesMut) {
+        self.to_canonical()
+            .write(buf, &mut NameCompressor::disabled());
+    }
+
+    /// Puts an RRset in canonical order (RFC 4034 section 6.3), by the canonical wire form of
+    /// each rdata, and drops duplicate records, which an RRset can't hold.
+    pub fn sort_rrset(rrset: &mut Vec<Record>) {
+        let mut keyed: Vec<(Bytes, Record)> = rrset
+            .drain(..)
+            .map(|r| (r.rdata.to_canonical().to_bytes(), r))
+            .collect();
+        keyed.sort_by(|a, b| a.0.cmp(&b.0));
+        keyed.dedup_by(|a, b| a.0 == b.0);
+        rrset.extend(keyed.into_iter().map(|(_, r)| r));
+    }
+
+    /// Length of the record on the wire with no name compressed, the most it can add to a
+    /// message.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        return self.name.wire_len() + 10 + self.rdata.encoded_len();
+    }
+
+    /// What `write` takes at offset `at` of the message.
+    fn compressed_len<'a>(&'a self, names: &mut NameCompressor<'a>, at: usize) -> usize {
+        let name_len = names.name_len(&self.name, at);
+        // type, class, TTL and rdlength come between the name and the rdata.
+        return name_len + 10 + self.rdata.compressed_len(names, at + name_len + 10);
+    }
+
+    fn write<'a>(&'a self, buf: &mut BytesMut, names: &mut NameCompressor<'a>) {
+        names.write_name(&self.name, buf);
+        buf.put_u16(self.tipe.value());
+        let flag = if self.cache_flush { MDNS_CLASS_BIT } else { 0 };
+        buf.put_u16(self.class.value() | flag);
+        buf.put_u32(self.ttl);
+        // rdlength is only known once the rdata is written.
+        let at = buf.len();
+        buf.put_u16(0);
+        self.rdata.write_compressed(buf, names);
+        let rdlength = (buf.len() - at - 2) as u16;
+        buf[at..at + 2].copy_from_slice(&rdlength.to_be_bytes());
+    }
+}
+
+/// EDNS(0) parameters, carried on the wire as the OPT pseudo-record in the additional section.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Edns {
+    /// largest UDP payload the sender can reassemble
+    pub udp_payload_size: u16,
+    /// upper 8 bits of the 12 bit extended rcode
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// the flag bits of the OPT TTL field, DO included
+    pub flags: u16,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    /// The DNSSEC OK bit in `flags` (RFC 3225).
+    pub const DNSSEC_OK: u16 = 0x8000;
+
+    /// An EDNS(0) record advertising `udp_payload_size`, with no flags or options.
+    pub fn new(udp_payload_size: u16) -> Edns {
+        return Edns {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            flags: 0,
+            options: vec![],
+        };
+    }
+
+    pub fn dnssec_ok(&self) -> bool {
+        return self.flags & Edns::DNSSEC_OK != 0;
+    }
+
+    pub fn set_dnssec_ok(&mut self, on: bool) {
+        if on {
+            self.flags |= Edns::DNSSEC_OK;
+        } else {
+            self.flags &= !Edns::DNSSEC_OK;
+        }
+    }
+
+    /// The full 12 bit rcode, given the 4 bits carried in the header.
+    pub fn rcode(&self, header_rcode: u8) -> u16 {
+        return (self.extended_rcode as u16) << 4 | (header_rcode & 0b00001111) as u16;
+    }
+
+    /// Splits a 12 bit rcode between the OPT record and the header. Returns the header part.
+    pub fn set_rcode(&mut self, rcode: u16) -> u8 {
+        self.extended_rcode = (rcode >> 4) as u8;
+        return (rcode & 0b00001111) as u8;
+    }
+
+    /// The OPT pseudo-record carrying these parameters, as it sits in the additional section on
+    /// the wire.
+    pub fn to_record(&self) -> Record {
+        let mut options = BytesMut::new();
+        for option in &self.options {
+            option.write(&mut options);
+        }
+        return Record {
+            name: Name::root(),
+            tipe: QType::from_value(OPT_TYPE),
+            class: ResourceClass::from_value(self.udp_payload_size & !MDNS_CLASS_BIT),
+            ttl: (self.extended_rcode as u32) << 24
+                | (self.version as u32) << 16
+                | self.flags as u32,
+            rdata: RData::Unknown(OPT_TYPE, options.to_vec()),
+            cache_flush: self.udp_payload_size & MDNS_CLASS_BIT != 0,
+        };
+    }
+
+    pub(crate) fn from_record(
+        class: u16,
+        ttl: u32,
+        rdata: &[u8],
+    ) -> Result<Edns, nom::Err<nom::error::Error<&[u8]>>> {
+        let mut options = Vec::new();
+        let mut rest = rdata;
+        while !rest.is_empty() {
+            let option: EdnsOption;
+            (rest, option) = EdnsOption::parse(rest)?;
+            options.push(option);
+        }
+        return Ok(Edns {
+            udp_payload_size: class,
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            flags: ttl as u16,
+            options,
+        });
+    }
+
+    /// Length of the OPT record on the wire.
+    pub fn encoded_len(&self) -> usize {
+        // root owner name, type, class, TTL and rdlength, then each option behind its code and
+        // length.
+        let options: usize = self.options.iter().map(|o| 4 + o.data_len()).sum();
+        return 11 + options;
+    }
+
+    fn write(&self, buf: &mut BytesMut) {
+        buf.put_u8(0);
+        buf.put_u16(OPT_TYPE);
+        buf.put_u16(self.udp_payload_size);
+        buf.put_u8(self.extended_rcode);
+        buf.put_u8(self.version);
+        buf.put_u16(self.flags);
+        // the option length is only known once the options are written.
+        let at = buf.len();
+        buf.put_u16(0);
+        for option in &self.options {
+            option.write(buf);
+        }
+        let rdlength = (buf.len() - at - 2) as u16;
+        buf[at..at + 2].copy_from_slice(&rdlength.to_be_bytes());
+    }
+}
+
+/// An option carried in the OPT record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EdnsOption {
+    /// Name server identifier (RFC 5001): empty in queries asking for it, an opaque identifier
+    /// of the answering server instance in responses
+    Nsid(Vec<u8>),
+    /// EDNS client subnet (RFC 7871): the network a query originates from, as `source_prefix`
+    /// leading bits of `address`. `scope_prefix` is the part the answer covers, 0 in queries.
+    /// Only the leading bits are sent, so the rest of `address` is ignored.
+    ClientSubnet {
+        source_prefix: u8,
+        scope_prefix: u8,
+        address: IpAddr,
+    },
+    /// DNS cookie (RFC 7873): an 8 byte client cookie, optionally followed by a server cookie of
+    /// 8 to 32 bytes
+    Cookie { client: [u8; 8], server: Vec<u8> },
+    /// edns-tcp-keepalive (RFC 7828): idle timeout in units of 100ms, absent in queries
+    TcpKeepalive(Option<u16>),
+    /// Padding (RFC 7830), to hide the size of the message. Kept byte for byte as received,
+    /// though senders fill it with zeros.
+    Padding(Vec<u8>),
+    /// Extended DNS error (RFC 8914). The extra text should be UTF-8, but is kept as the bytes
+    /// received so relayed options come out unchanged.
+    ExtendedError { info_code: u16, extra_text: Vec<u8> },
+    /// code and data of an option without a structured form here
+    Unknown(u16, Vec<u8>),
+}
+
+impl EdnsOption {
+    pub fn code(&self) -> u16 {
+        match self {
+            EdnsOption::Nsid(_) => 3,
+            EdnsOption::ClientSubnet { .. } => 8,
+            EdnsOption::Cookie { .. } => 10,
+            EdnsOption::TcpKeepalive(_) => 11,
+            EdnsOption::Padding(_) => 12,
+            EdnsOption::ExtendedError { .. } => 15,
+            EdnsOption::Unknown(code, _) => *code,
+        }
+    }
+
+    fn parse(bites: &[u8]) -> IResult<&[u8], EdnsOption> {
+        let (bites, code) = be_u16(bites)?;
+        let (bites, len) = be_u16(bites)?;
+        let (rest, data) = take(len)(bites)?;
+        let malformed = || {
+            nom::Err::Failure(nom::error::Error::new(
+                data,
+                nom::error::ErrorKind::LengthValue,
+            ))
+        };
+        let option = match code {
+            8 => {
+                if data.len() < 4 {
+                    return Err(malformed());
+                }
+                let family = u16::from_be_bytes([data[0], data[1]]);
+                let (source_prefix, scope_prefix) = (data[2], data[3]);
+                let bits = &data[4..];
+                // the address is cut to the bytes the source prefix covers.
+                if bits.len() != source_prefix.div_ceil(8) as usize {
+                    return Err(malformed());
+                }
+                let address = match family {
+                    1 if source_prefix <= 32 => {
+                        let mut octets = [0; 4];
+                        octets[..bits.len()].copy_from_slice(bits);
+                        IpAddr::V4(Ipv4Addr::from(octets))
+                    }
+                    2 if source_prefix <= 128 => {
+                        let mut octets = [0; 16];
+                        octets[..bits.len()].copy_from_slice(bits);
+                        IpAddr::V6(Ipv6Addr::from(octets))
+                    }
+                    _ => return Err(malformed()),
+                };
+                EdnsOption::ClientSubnet {
+                    source_prefix,
+                    scope_prefix,
+                    address,
+                }
             }
+            3 => EdnsOption::Nsid(data.to_vec()),
+            10 => {
+                if data.len() != 8 && !(16..=40).contains(&data.len()) {
+                    return Err(malformed());
+                }
+                EdnsOption::Cookie {
+                    client: data[..8].try_into().unwrap(),
+                    server: data[8..].to_vec(),
+                }
+            }
+            11 => match data.len() {
+                0 => EdnsOption::TcpKeepalive(None),
+                2 => EdnsOption::TcpKeepalive(Some(u16::from_be_bytes([data[0], data[1]]))),
+                _ => return Err(malformed()),
+            },
+            12 => EdnsOption::Padding(data.to_vec()),
+            15 => {
+                if data.len() < 2 {
+                    return Err(malformed());
+                }
+                EdnsOption::ExtendedError {
+                    info_code: u16::from_be_bytes([data[0], data[1]]),
+                    extra_text: data[2..].to_vec(),
+                }
+            }
+            _ => EdnsOption::Unknown(code, data.to_vec()),
         };
-        let (bites, class) = be_u16(bites)?;
-        *parse_offset += 2;
-        let class = match ResourceClass::from_value(class) {
-            Ok(c) => c,
-            Err(_e) => {
-                return Err(nom::Err::Failure(nom::error::Error::new(
-                    bites,
-                    nom::error::ErrorKind::Tag,
-                )))
+        return Ok((rest, option));
+    }
+
+    /// Length of the option data, without the code and length in front of it.
+    fn data_len(&self) -> usize {
+        match self {
+            EdnsOption::Nsid(id) => id.len(),
+            EdnsOption::ClientSubnet {
+                source_prefix,
+                address,
+                ..
+            } => {
+                let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+                4 + (*source_prefix).min(max_prefix).div_ceil(8) as usize
+            }
+            EdnsOption::Cookie { client, server } => client.len() + server.len(),
+            EdnsOption::TcpKeepalive(timeout) => {
+                if timeout.is_some() {
+                    2
+                } else {
+                    0
+                }
+            }
+            EdnsOption::Padding(padding) => padding.len(),
+            EdnsOption::ExtendedError { extra_text, .. } => 2 + extra_text.len(),
+            EdnsOption::Unknown(_, data) => data.len(),
+        }
+    }
+
+    /// Appends the option to `buf`: code, length, then data.
+    pub fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.code());
+        let at = buf.len();
+        buf.put_u16(0);
+        match self {
+            EdnsOption::Nsid(id) => buf.put_slice(id),
+            EdnsOption::ClientSubnet {
+                source_prefix,
+                scope_prefix,
+                address,
+            } => {
+                let (family, octets): (u16, Vec<u8>) = match address {
+                    IpAddr::V4(a) => (1, a.octets().to_vec()),
+                    IpAddr::V6(a) => (2, a.octets().to_vec()),
+                };
+                let prefix = (*source_prefix).min(octets.len() as u8 * 8);
+                buf.put_u16(family);
+                buf.put_u8(prefix);
+                buf.put_u8(*scope_prefix);
+                let len = prefix.div_ceil(8) as usize;
+                buf.put_slice(&octets[..len]);
+                // bits past the prefix must be zero.
+                if prefix % 8 != 0 {
+                    let last = buf.len() - 1;
+                    buf[last] &= 0xff << (8 - prefix % 8);
+                }
+            }
+            EdnsOption::Cookie { client, server } => {
+                buf.put_slice(client);
+                buf.put_slice(server);
+            }
+            EdnsOption::TcpKeepalive(timeout) => {
+                if let Some(timeout) = timeout {
+                    buf.put_u16(*timeout);
+                }
+            }
+            EdnsOption::Padding(padding) => buf.put_slice(padding),
+            EdnsOption::ExtendedError {
+                info_code,
+                extra_text,
+            } => {
+                buf.put_u16(*info_code);
+                buf.put_slice(extra_text);
             }
+            EdnsOption::Unknown(_, data) => buf.put_slice(data),
+        }
+        let len = (buf.len() - at - 2) as u16;
+        buf[at..at + 2].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+/// Roughly what dig prints: the header and flag lines, the OPT pseudo-section, then every
+/// non-empty section with one record per line.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let h = &self.header;
+        let code = match &self.edns {
+            Some(edns) => edns.rcode(h.rcode),
+            None => h.rcode as u16,
         };
-        let (bites, ttl) = be_u32(bites)?;
-        let (bites, rdlength) = be_u16(bites)?;
-        let (bites, rdata) = take(rdlength)(bites)?;
-        *parse_offset += 6 + rdlength as u32;
-        return Ok((
-            bites,
-            Answer {
-                name,
-                tipe,
-                class,
-                ttl,
-                rdlength,
-                rdata: rdata.to_vec(),
-            },
-        ));
+        write!(f, ";; ->>HEADER<<- opcode: {}, status: ", h.opcode)?;
+        match rcode::name(code) {
+            Some(name) => f.write_str(name)?,
+            None => write!(f, "RCODE{}", code)?,
+        }
+        writeln!(f, ", id: {}", h.id)?;
+        f.write_str(";; flags:")?;
+        let flags = [
+            (h.qr, "qr"),
+            (h.aa, "aa"),
+            (h.tc, "tc"),
+            (h.rd, "rd"),
+            (h.ra, "ra"),
+            (h.z & Header::AUTHENTIC_DATA != 0, "ad"),
+            (h.z & Header::CHECKING_DISABLED != 0, "cd"),
+        ];
+        for (set, flag) in flags {
+            if set {
+                write!(f, " {}", flag)?;
+            }
+        }
+        writeln!(
+            f,
+            "; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.additionals.len() + self.edns.is_some() as usize
+        )?;
+        if let Some(edns) = &self.edns {
+            write!(f, "\n;; OPT PSEUDOSECTION:\n{}", edns)?;
+        }
+        if !self.questions.is_empty() {
+            f.write_str("\n;; QUESTION SECTION:\n")?;
+            for q in &self.questions {
+                writeln!(f, "{}", q)?;
+            }
+        }
+        let sections = [
+            ("ANSWER", &self.answers),
+            ("AUTHORITY", &self.authorities),
+            ("ADDITIONAL", &self.additionals),
+        ];
+        for (section, records) in sections {
+            if !records.is_empty() {
+                writeln!(f, "\n;; {} SECTION:", section)?;
+                for r in records {
+                    writeln!(f, "{}", r)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// A question line as dig prints it, commented out: `;example.com. IN A`.
+impl fmt::Display for Question {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, ";{}\t\t{}\t{}", self.name, self.class, self.tipe)
+    }
+}
+
+/// Master file form: `example.com. 300 IN A 1.2.3.4`, tab separated.
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            self.name, self.ttl, self.class, self.tipe, self.rdata
+        )
+    }
+}
+
+/// The OPT pseudo-section lines, each starting with `; `.
+impl fmt::Display for Edns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "; EDNS: version: {}, flags:", self.version)?;
+        if self.dnssec_ok() {
+            f.write_str(" do")?;
+        }
+        writeln!(f, "; udp: {}", self.udp_payload_size)?;
+        for option in &self.options {
+            match option {
+                EdnsOption::Nsid(id) => {
+                    f.write_str("; NSID: ")?;
+                    text::write_hex(f, id)?;
+                    // identifiers are usually host names, worth showing as text too.
+                    if !id.is_empty() && id.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+                        write!(f, " (\"{}\")", String::from_utf8_lossy(id))?;
+                    }
+                    writeln!(f)?;
+                }
+                EdnsOption::ClientSubnet {
+                    source_prefix,
+                    scope_prefix,
+                    address,
+                } => writeln!(
+                    f,
+                    "; CLIENT-SUBNET: {}/{}/{}",
+                    address, source_prefix, scope_prefix
+                )?,
+                EdnsOption::Cookie { client, server } => {
+                    f.write_str("; COOKIE: ")?;
+                    text::write_hex(f, client)?;
+                    text::write_hex(f, server)?;
+                    writeln!(f)?;
+                }
+                EdnsOption::TcpKeepalive(Some(timeout)) => {
+                    writeln!(f, "; TCP-KEEPALIVE: {}.{} secs", timeout / 10, timeout % 10)?
+                }
+                EdnsOption::TcpKeepalive(None) => writeln!(f, "; TCP-KEEPALIVE")?,
+                EdnsOption::Padding(padding) => writeln!(f, "; PADDING: {} bytes", padding.len())?,
+                EdnsOption::ExtendedError {
+                    info_code,
+                    extra_text,
+                } => {
+                    write!(f, "; EDE: {}", info_code)?;
+                    if !extra_text.is_empty() {
+                        write!(f, " ({})", String::from_utf8_lossy(extra_text))?;
+                    }
+                    writeln!(f)?;
+                }
+                EdnsOption::Unknown(code, data) => {
+                    write!(f, "; OPT={}: ", code)?;
+                    text::write_hex(f, data)?;
+                    writeln!(f)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// The mnemonic, or `TYPE` and the code for types without one (RFC 3597).
+impl fmt::Display for QType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            QType::A => "A",
+            QType::NS => "NS",
+            QType::MD => "MD",
+            QType::MF => "MF",
+            QType::CNAME => "CNAME",
+            QType::SOA => "SOA",
+            QType::MB => "MB",
+            QType::MG => "MG",
+            QType::MR => "MR",
+            QType::NULL => "NULL",
+            QType::WKS => "WKS",
+            QType::PTR => "PTR",
+            QType::HINFO => "HINFO",
+            QType::MINFO => "MINFO",
+            QType::MX => "MX",
+            QType::TXT => "TXT",
+            QType::RP => "RP",
+            QType::AFSDB => "AFSDB",
+            QType::AAAA => "AAAA",
+            QType::SRV => "SRV",
+            QType::KX => "KX",
+            QType::CERT => "CERT",
+            QType::APL => "APL",
+            QType::DS => "DS",
+            QType::IPSECKEY => "IPSECKEY",
+            QType::RRSIG => "RRSIG",
+            QType::NSEC => "NSEC",
+            QType::DNSKEY => "DNSKEY",
+            QType::DHCID => "DHCID",
+            QType::NSEC3 => "NSEC3",
+            QType::NSEC3PARAM => "NSEC3PARAM",
+            QType::TLSA => "TLSA",
+            QType::SMIMEA => "SMIMEA",
+            QType::HIP => "HIP",
+            QType::CDS => "CDS",
+            QType::CDNSKEY => "CDNSKEY",
+            QType::OPENPGPKEY => "OPENPGPKEY",
+            QType::CSYNC => "CSYNC",
+            QType::ZONEMD => "ZONEMD",
+            QType::SVCB => "SVCB",
+            QType::HTTPS => "HTTPS",
+            QType::TKEY => "TKEY",
+            QType::TSIG => "TSIG",
+            QType::EUI48 => "EUI48",
+            QType::EUI64 => "EUI64",
+            QType::IXFR => "IXFR",
+            QType::AXFR => "AXFR",
+            QType::MAILB => "MAILB",
+            QType::ANY => "ANY",
+            QType::URI => "URI",
+            QType::Unknown(value) => return write!(f, "TYPE{}", value),
+        };
+        f.write_str(name)
+    }
+}
+
+/// The mnemonic, or `CLASS` and the code for classes without one (RFC 3597).
+impl fmt::Display for ResourceClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ResourceClass::IN => "IN",
+            ResourceClass::CS => "CS",
+            ResourceClass::CH => "CH",
+            ResourceClass::HS => "HS",
+            ResourceClass::NONE => "NONE",
+            ResourceClass::ANY => "ANY",
+            ResourceClass::Unknown(value) => return write!(f, "CLASS{}", value),
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Opcode::QUERY => "QUERY",
+            Opcode::IQUERY => "IQUERY",
+            Opcode::STATUS => "STATUS",
+            Opcode::NOTIFY => "NOTIFY",
+            Opcode::UPDATE => "UPDATE",
+            Opcode::Reserved(value) => return write!(f, "RESERVED{}", value),
+        };
+        f.write_str(name)
+    }
+}
+
+/// Reads a record in master file form, e.g. `www.example.com. 3600 IN MX 10 mail.example.com.`.
+/// The TTL is required; the class after it may be left out and defaults to IN. A mnemonic that
+/// is both a class and a type, like ANY, is the class only if a type follows it. Names
+/// are taken as absolute whether or not they end in a dot, since there is no origin to append.
+/// Any type also accepts the generic `\# length hex` rdata of RFC 3597.
+impl FromStr for Record {
+    type Err = TextError;
+
+    fn from_str(s: &str) -> Result<Record, TextError> {
+        let tokens = text::tokenize(s)?;
+        let mut fields = Fields::new(&tokens);
+        let name = fields.name()?;
+        let ttl = fields.number("ttl")?;
+        let mut class = ResourceClass::IN;
+        let field = fields.next()?;
+        let tipe: QType = match (field.parse::<ResourceClass>(), fields.peek()) {
+            (Ok(c), Some(next)) if next.parse::<QType>().is_ok() => {
+                class = c;
+                fields.next()?.parse()?
+            }
+            _ => field.parse()?,
+        };
+        let rdata = RData::from_text(&tipe, &mut fields)?;
+        fields.finish()?;
+        return Ok(Record {
+            name,
+            tipe,
+            class,
+            ttl,
+            rdata,
+            cache_flush: false,
+        });
+    }
+}
+
+/// A mnemonic in any case, or `TYPE` followed by the code.
+impl FromStr for QType {
+    type Err = TextError;
+
+    fn from_str(s: &str) -> Result<QType, TextError> {
+        let bad = TextError::BadField("type");
+        let upper = s.to_ascii_uppercase();
+        let tipe = match upper.as_str() {
+            "A" => QType::A,
+            "NS" => QType::NS,
+            "MD" => QType::MD,
+            "MF" => QType::MF,
+            "CNAME" => QType::CNAME,
+            "SOA" => QType::SOA,
+            "MB" => QType::MB,
+            "MG" => QType::MG,
+            "MR" => QType::MR,
+            "NULL" => QType::NULL,
+            "WKS" => QType::WKS,
+            "PTR" => QType::PTR,
+            "HINFO" => QType::HINFO,
+            "MINFO" => QType::MINFO,
+            "MX" => QType::MX,
+            "TXT" => QType::TXT,
+            "RP" => QType::RP,
+            "AFSDB" => QType::AFSDB,
+            "AAAA" => QType::AAAA,
+            "SRV" => QType::SRV,
+            "KX" => QType::KX,
+            "CERT" => QType::CERT,
+            "APL" => QType::APL,
+            "DS" => QType::DS,
+            "IPSECKEY" => QType::IPSECKEY,
+            "RRSIG" => QType::RRSIG,
+            "NSEC" => QType::NSEC,
+            "DNSKEY" => QType::DNSKEY,
+            "DHCID" => QType::DHCID,
+            "NSEC3" => QType::NSEC3,
+            "NSEC3PARAM" => QType::NSEC3PARAM,
+            "TLSA" => QType::TLSA,
+            "SMIMEA" => QType::SMIMEA,
+            "HIP" => QType::HIP,
+            "CDS" => QType::CDS,
+            "CDNSKEY" => QType::CDNSKEY,
+            "OPENPGPKEY" => QType::OPENPGPKEY,
+            "CSYNC" => QType::CSYNC,
+            "ZONEMD" => QType::ZONEMD,
+            "SVCB" => QType::SVCB,
+            "HTTPS" => QType::HTTPS,
+            "TKEY" => QType::TKEY,
+            "TSIG" => QType::TSIG,
+            "EUI48" => QType::EUI48,
+            "EUI64" => QType::EUI64,
+            "IXFR" => QType::IXFR,
+            "AXFR" => QType::AXFR,
+            "MAILB" => QType::MAILB,
+            "ANY" => QType::ANY,
+            "URI" => QType::URI,
+            _ => {
+                let code = upper.strip_prefix("TYPE").ok_or(bad)?;
+                QType::from_value(code.parse().map_err(|_| bad)?)
+            }
+        };
+        return Ok(tipe);
+    }
+}
+
+/// A mnemonic in any case, or `CLASS` followed by the code.
+impl FromStr for ResourceClass {
+    type Err = TextError;
+
+    fn from_str(s: &str) -> Result<ResourceClass, TextError> {
+        let bad = TextError::BadField("class");
+        let upper = s.to_ascii_uppercase();
+        let class = match upper.as_str() {
+            "IN" => ResourceClass::IN,
+            "CS" => ResourceClass::CS,
+            "CH" => ResourceClass::CH,
+            "HS" => ResourceClass::HS,
+            "NONE" => ResourceClass::NONE,
+            "ANY" => ResourceClass::ANY,
+            _ => {
+                let code = upper.strip_prefix("CLASS").ok_or(bad)?;
+                ResourceClass::from_value(code.parse().map_err(|_| bad)?)
+            }
+        };
+        return Ok(class);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn extended_error_text_is_relayed_byte_for_byte() {
+        let packet: &[u8] = &[
+            0x12, 0x34, 0x81, 0x80, 0, 0, 0, 0, 0, 0, 0, 1, // header, one additional
+            0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 8, // OPT record, 8 bytes of options
+            0, 15, 0, 4, 0, 18, 0xff, 0xfe, // EDE 18 with text that isn't UTF-8
+        ];
+        let m = Message::parse(packet).unwrap();
+        let edns = m.edns.as_ref().unwrap();
+        assert_eq!(
+            edns.options,
+            [EdnsOption::ExtendedError {
+                info_code: 18,
+                extra_text: vec![0xff, 0xfe],
+            }]
+        );
+        assert_eq!(&m.to_bytes()[..], packet);
+    }
+
+    #[test]
+    fn additionals_are_split_into_records_and_edns() {
+        let mut m = Message::new(Header::new(7));
+        m.additionals.push(record("ns.example. 60 IN A 192.0.2.1"));
+        m.edns = Some(Edns::new(1232));
+        let bites = m.to_bytes();
+        let parsed = Message::parse(&bites).unwrap();
+        assert_eq!(parsed.additionals.len(), 1);
+        assert_eq!(
+            parsed.additionals[0].to_string(),
+            m.additionals[0].to_string()
+        );
+        assert_eq!(parsed.edns.unwrap().udp_payload_size, 1232);
+
+        // a query-only type is as wrong among the additionals as anywhere else.
+        let mut bites = bites.to_vec();
+        let at = 12 + "\x02ns\x07example\x00".len();
+        bites[at..at + 2].copy_from_slice(&255u16.to_be_bytes());
+        assert_eq!(
+            Message::parse(&bites).unwrap_err(),
+            MessageError::UnknownType {
+                value: 255,
+                offset: 12
+            }
+        );
+    }
+
+    /// A query for a name at offset 12, followed by `tail`, which the name's pointers lead into.
+    fn packet_with_name(name: &[u8], tail: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(name);
+        packet.extend_from_slice(tail);
+        return packet;
+    }
+
+    fn name_at_12(packet: &[u8]) -> Option<Name> {
+        let (_, name) = Message::parse_label_seq(packet, &packet[12..]).ok()?;
+        return Some(name);
+    }
+
+    #[test]
+    fn compressed_names_parse() {
+        let packet: &[u8] = &[
+            0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0, // header
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0, 1, 0, 1, 3,
+            b'w', b'w', b'w', 0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1,
+        ];
+        let m = Message::parse(packet).unwrap();
+        assert_eq!(m.answers[0].name.to_string(), "www.example.com.");
+        assert_eq!(&m.to_bytes()[..], packet);
+    }
+
+    #[test]
+    fn forward_pointers_are_followed() {
+        // the name at 12 is "a" and a pointer to "b" after it, at 16.
+        let packet = packet_with_name(&[1, b'a', 0xc0, 16], &[1, b'b', 0]);
+        assert_eq!(name_at_12(&packet).unwrap().to_string(), "a.b.");
+    }
+
+    #[test]
+    fn pointer_loops_are_rejected() {
+        // a pointer to itself.
+        assert!(name_at_12(&packet_with_name(&[0xc0, 12], &[])).is_none());
+        // two labels pointing at each other.
+        let packet = packet_with_name(&[1, b'a', 0xc0, 16], &[1, b'b', 0xc0, 12]);
+        assert!(name_at_12(&packet).is_none());
+    }
+
+    #[test]
+    fn too_many_pointers_are_rejected() {
+        // a chain of pointers, each to the next, ending in the root.
+        let chain = |hops: usize| {
+            let mut name = vec![];
+            for i in 1..=hops {
+                let next = 12 + 2 * i as u16;
+                name.extend_from_slice(&(0xc000 | next).to_be_bytes());
+            }
+            return packet_with_name(&name, &[0]);
+        };
+        assert_eq!(name_at_12(&chain(MAX_POINTERS)), Some(Name::root()));
+        assert!(name_at_12(&chain(MAX_POINTERS + 1)).is_none());
+    }
+
+    #[test]
+    fn names_over_255_bytes_are_rejected() {
+        // 4 labels of 63 bytes make 257 with their length bytes and the root, split by a
+        // pointer so no single stretch of the packet is too long.
+        let label = |c: u8| [&[63][..], &[c; 63]].concat();
+        let mut name = [label(b'a'), label(b'b')].concat();
+        let tail_at = 12 + name.len() as u16 + 2;
+        name.extend_from_slice(&(0xc000 | tail_at).to_be_bytes());
+        let tail = [label(b'c'), label(b'd'), vec![0]].concat();
+        assert!(name_at_12(&packet_with_name(&name, &tail)).is_none());
+        // three of them fit.
+        let tail = [label(b'c'), vec![0]].concat();
+        assert!(name_at_12(&packet_with_name(&name, &tail)).is_some());
+    }
+
+    fn record(text: &str) -> Record {
+        return text.parse().unwrap();
+    }
+
+    #[test]
+    fn records_parse_from_presentation_form() {
+        let mx = record("www.example.com. 3600 IN MX 10 mail.example.com.");
+        assert_eq!(mx.name.to_string(), "www.example.com.");
+        assert_eq!(mx.ttl, 3600);
+        assert_eq!(mx.class, ResourceClass::IN);
+        assert_eq!(mx.tipe, QType::MX);
+        assert_eq!(
+            mx.to_string(),
+            "www.example.com.\t3600\tIN\tMX\t10 mail.example.com."
+        );
+        // the class defaults to IN.
+        assert_eq!(record("a.example. 60 A 192.0.2.1").class, ResourceClass::IN);
+        assert_eq!(
+            record("a.example. 60 CH A 192.0.2.1").class,
+            ResourceClass::CH
+        );
+        // any type takes generic rdata.
+        let generic = record("a.example. 60 IN TYPE65280 \\# 2 abcd");
+        assert_eq!(generic.tipe.value(), 65280);
+        assert_eq!(&generic.rdata.to_bytes()[..], [0xab, 0xcd]);
+    }
+
+    #[test]
+    fn any_is_a_class_only_when_a_type_follows() {
+        let class = record("a.example. 60 ANY A 192.0.2.1");
+        assert_eq!(class.class, ResourceClass::ANY);
+        assert_eq!(class.tipe, QType::A);
+        let tipe = record("a.example. 60 ANY \\# 0");
+        assert_eq!(tipe.class, ResourceClass::IN);
+        assert_eq!(tipe.tipe, QType::ANY);
+        let both = record("a.example. 60 ANY ANY \\# 0");
+        assert_eq!(both.class, ResourceClass::ANY);
+        assert_eq!(both.tipe, QType::ANY);
+    }
+
+    #[test]
+    fn malformed_records_are_rejected() {
+        for line in [
+            // no TTL, or not where it belongs.
+            "a.example. IN A 192.0.2.1",
+            "a.example. IN 60 A 192.0.2.1",
+            "a.example. 60 IN",
+            "a.example. 60 IN BOGUS 1",
+            "a.example. 60 IN A 192.0.2.1 extra",
+            "a.example. 60 IN A not-an-address",
+        ] {
+            assert!(line.parse::<Record>().is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn truncate_drops_additionals_quietly_and_answers_with_tc() {
+        let mut m = Message::new(Header::new(1));
+        m.header.qr = true;
+        for i in 0..20 {
+            m.additionals.push(record(&alloc::format!(
+                "ns{}.example. 60 IN A 192.0.2.{}",
+                i,
+                i
+            )));
+        }
+        assert!(!m.truncate(200));
+        assert!(!m.header.tc);
+        assert!(m.encoded_len() <= 200);
+        assert!(!m.additionals.is_empty());
+
+        for i in 0..100 {
+            m.answers.push(record(&alloc::format!(
+                "host{}.example. 60 IN A 192.0.2.{}",
+                i,
+                i
+            )));
+        }
+        assert!(m.truncate(512));
+        assert!(m.header.tc);
+        assert!(m.additionals.is_empty());
+        let bites = m.to_bytes();
+        assert!(bites.len() <= 512);
+        // as full as the limit allows: one more record wouldn't have fit.
+        assert!(bites.len() > 512 - 30);
+        let parsed = Message::parse(&bites).unwrap();
+        assert_eq!(parsed.answers.len(), m.answers.len());
     }
 
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut bites = vec![];
-        for label in &self.name {
-            bites.push(label.len() as u8);
-            bites.extend(label.as_bytes());
-        }
-        bites.push(0);
-        let tipe_val = self.tipe.value();
-        bites.push((tipe_val >> 8) as u8);
-        bites.push(tipe_val as u8);
-        let class_val = self.class.value();
-        bites.push((class_val >> 8) as u8);
-        bites.push(class_val as u8);
-        bites.push((self.ttl >> 24) as u8);
-        bites.push((self.ttl >> 16) as u8);
-        bites.push((self.ttl >> 8) as u8);
-        bites.push(self.ttl as u8);
-        bites.push((self.rdlength >> 8) as u8);
-        bites.push(self.rdlength as u8);
-        bites.extend(&self.rdata);
-        return bites;
+    #[test]
+    fn truncate_measures_again_when_names_lose_their_pointers() {
+        let mut m = Message::new(Header::new(1));
+        m.answers = vec![
+            record("a-rather-long-label.example. 60 IN A 192.0.2.1"),
+            // compressed against the record above, which goes with its RRset.
+            record("www.a-rather-long-label.example. 60 IN A 192.0.2.2"),
+            record("a-rather-long-label.example. 60 IN A 192.0.2.3"),
+        ];
+        // just enough room for the www record as it was measured, compressed.
+        let [lens, _, _] = m.record_lens();
+        let limit = m.encoded_len() - lens[0] - lens[2];
+        assert!(m.truncate(limit));
+        // written out in full it doesn't fit, so it goes too.
+        assert!(m.answers.is_empty());
+        assert!(m.to_bytes().len() <= limit);
     }
 }
@@ -0,0 +1,413 @@
+//! Domain names. A `Name` is a list of labels, root label excluded, that compares and hashes
+//! case-insensitively (RFC 4343) but keeps every octet it was created with, case included.
+//! Labels are stored together in their wire form, inline for names of up to 32 bytes, so
+//! decoding a name off the wire usually doesn't allocate.
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use bytes::{BufMut, BytesMut};
+use core::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+use smallvec::SmallVec;
+
+use crate::punycode;
+
+/// Longest label, in bytes.
+pub const MAX_LABEL_LEN: usize = 63;
+/// Longest name in wire form, length bytes and root label included.
+pub const MAX_NAME_LEN: usize = 255;
+/// Prefix marking a label as punycode encoded (RFC 5890).
+const ACE_PREFIX: &str = "xn--";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameError {
+    /// a label other than the root label is empty, e.g. `a..b`
+    EmptyLabel,
+    /// a label is longer than 63 bytes
+    LabelTooLong,
+    /// the wire form would be longer than 255 bytes
+    NameTooLong,
+    /// a `\` escape in presentation form is incomplete or out of range
+    BadEscape,
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self {
+            NameError::EmptyLabel => "empty label",
+            NameError::LabelTooLong => "label longer than 63 bytes",
+            NameError::NameTooLong => "name longer than 255 bytes",
+            NameError::BadEscape => "bad escape sequence",
+        };
+        f.write_str(what)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Name {
+    /// every label behind its length byte, as on the wire, without the terminating root label
+    octets: SmallVec<[u8; 32]>,
+    /// where each label's length byte is in `octets`
+    starts: SmallVec<[u8; 8]>,
+}
+
+impl Name {
+    /// The root name, `.`.
+    pub fn root() -> Name {
+        return Name::default();
+    }
+
+    /// A name from its labels, leftmost first and without the root label.
+    pub fn from_labels<I, S>(labels: I) -> Result<Name, NameError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        let mut name = Name::root();
+        for label in labels {
+            name.push_label(label.as_ref())?;
+        }
+        return Ok(name);
+    }
+
+    /// Appends `label` at the right end of the name.
+    fn push_label(&mut self, label: &[u8]) -> Result<(), NameError> {
+        if label.is_empty() {
+            return Err(NameError::EmptyLabel);
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return Err(NameError::LabelTooLong);
+        }
+        if self.wire_len() + label.len() + 1 > MAX_NAME_LEN {
+            return Err(NameError::NameTooLong);
+        }
+        self.push_wire_label(label);
+        return Ok(());
+    }
+
+    /// Appends a label that came off the wire. The parser has already checked the limits: a
+    /// length byte can't exceed 63, and it stops at 255 bytes.
+    pub(crate) fn push_wire_label(&mut self, label: &[u8]) {
+        self.starts.push(self.octets.len() as u8);
+        self.octets.push(label.len() as u8);
+        self.octets.extend_from_slice(label);
+    }
+
+    /// The labels as the octets they are made of, leftmost first. They needn't be text, let
+    /// alone UTF-8.
+    pub fn labels(&self) -> impl DoubleEndedIterator<Item = &[u8]> + ExactSizeIterator + '_ {
+        return (0..self.starts.len()).map(|i| self.label(i));
+    }
+
+    /// The `i`th label from the left.
+    fn label(&self, i: usize) -> &[u8] {
+        let start = self.starts[i] as usize;
+        return &self.octets[start + 1..start + 1 + self.octets[start] as usize];
+    }
+
+    /// The wire form of the name from its `i`th label on, root label excluded. Empty for
+    /// `i == label_count()`.
+    pub(crate) fn suffix_octets(&self, i: usize) -> &[u8] {
+        let start = self
+            .starts
+            .get(i)
+            .map_or(self.octets.len(), |&s| s as usize);
+        return &self.octets[start..];
+    }
+
+    pub fn is_root(&self) -> bool {
+        return self.starts.is_empty();
+    }
+
+    /// Number of labels, root label excluded.
+    pub fn label_count(&self) -> usize {
+        return self.starts.len();
+    }
+
+    /// Length of the uncompressed wire form.
+    pub fn wire_len(&self) -> usize {
+        return self.octets.len() + 1;
+    }
+
+    /// The name with its leftmost label removed, or None for the root.
+    pub fn parent(&self) -> Option<Name> {
+        let &first = self.starts.get(1)?;
+        return Some(Name {
+            octets: SmallVec::from_slice(&self.octets[first as usize..]),
+            starts: self.starts[1..].iter().map(|s| s - first).collect(),
+        });
+    }
+
+    /// The name with `label` prepended.
+    pub fn child(&self, label: &[u8]) -> Result<Name, NameError> {
+        let mut name = Name::root();
+        name.push_label(label)?;
+        for l in self.labels() {
+            name.push_label(l)?;
+        }
+        return Ok(name);
+    }
+
+    /// Whether this name is `other` or lies below it, ignoring case.
+    pub fn is_subdomain_of(&self, other: &Name) -> bool {
+        if other.label_count() > self.label_count() {
+            return false;
+        }
+        // length bytes never exceed 63, so ignoring ASCII case only touches label octets.
+        let skip = self.label_count() - other.label_count();
+        return self.suffix_octets(skip).eq_ignore_ascii_case(&other.octets);
+    }
+
+    /// The canonical form: every ASCII letter lowercased (RFC 4034 section 6.2).
+    pub fn to_lowercase(&self) -> Name {
+        let mut name = self.clone();
+        name.octets.make_ascii_lowercase();
+        return name;
+    }
+
+    /// A name from its Unicode form, e.g. `bücher.example`. Labels that aren't plain ASCII are
+    /// lowercased and punycode encoded into `xn--` A-labels; ASCII labels are kept as they are.
+    /// The trailing dot is optional, and the ideographic full stops count as dots too. This is
+    /// only the encoding step of IDNA: no Unicode normalization or mapping table is applied.
+    pub fn from_unicode(s: &str) -> Result<Name, NameError> {
+        let is_dot = |c: char| matches!(c, '.' | '\u{3002}' | '\u{ff0e}' | '\u{ff61}');
+        let s = s.strip_suffix(is_dot).unwrap_or(s);
+        if s.is_empty() {
+            return Ok(Name::root());
+        }
+        let mut labels: Vec<String> = Vec::new();
+        for label in s.split(is_dot) {
+            if label.is_ascii() {
+                labels.push(String::from(label));
+                continue;
+            }
+            let lower: String = label.chars().flat_map(char::to_lowercase).collect();
+            let encoded = punycode::encode(&lower).ok_or(NameError::LabelTooLong)?;
+            labels.push([ACE_PREFIX, &encoded].concat());
+        }
+        return Name::from_labels(labels);
+    }
+
+    /// The Unicode form, with `xn--` labels decoded. Labels that aren't valid punycode are kept
+    /// as they are, and octets that aren't UTF-8 become U+FFFD. Like `Display`, it ends in a dot.
+    pub fn to_unicode(&self) -> String {
+        if self.is_root() {
+            return String::from(".");
+        }
+        let mut out = String::new();
+        for label in self.labels() {
+            let label = String::from_utf8_lossy(label);
+            let decoded = label
+                .get(..ACE_PREFIX.len())
+                .filter(|prefix| prefix.eq_ignore_ascii_case(ACE_PREFIX))
+                .and_then(|_| punycode::decode(&label[ACE_PREFIX.len()..]));
+            out.push_str(decoded.as_deref().unwrap_or(&label));
+            out.push('.');
+        }
+        return out;
+    }
+
+    /// The name PTR records for `ip` live at: the bytes of an IPv4 address reversed under
+    /// `in-addr.arpa` (RFC 1035 section 3.5), the nibbles of an IPv6 address reversed under
+    /// `ip6.arpa` (RFC 3596 section 2.5).
+    pub fn from_reverse(ip: IpAddr) -> Name {
+        let mut name = Name::root();
+        match ip {
+            IpAddr::V4(v4) => {
+                for b in v4.octets().iter().rev() {
+                    name.push_wire_label(b.to_string().as_bytes());
+                }
+                name.push_wire_label(b"in-addr");
+            }
+            IpAddr::V6(v6) => {
+                for b in v6.octets().iter().rev() {
+                    name.push_wire_label(format!("{:x}", b & 0x0f).as_bytes());
+                    name.push_wire_label(format!("{:x}", b >> 4).as_bytes());
+                }
+                name.push_wire_label(b"ip6");
+            }
+        }
+        name.push_wire_label(b"arpa");
+        return name;
+    }
+
+    /// The address a full reverse name stands for, the inverse of `from_reverse`. None for
+    /// any other name, including the shorter reverse names of whole networks.
+    pub fn to_ip(&self) -> Option<IpAddr> {
+        let (suffix, count): (&[u8], usize) = match self.label_count() {
+            6 => (b"in-addr", 4),
+            34 => (b"ip6", 32),
+            _ => return None,
+        };
+        if !self.label(count).eq_ignore_ascii_case(suffix)
+            || !self.label(count + 1).eq_ignore_ascii_case(b"arpa")
+        {
+            return None;
+        }
+        let digits = self.labels().take(count).rev();
+        if count == 4 {
+            let mut octets = [0u8; 4];
+            for (octet, label) in octets.iter_mut().zip(digits) {
+                // no sign, and no leading zeros, so every address has one reverse name.
+                if label.starts_with(b"+") || (label.len() > 1 && label.starts_with(b"0")) {
+                    return None;
+                }
+                *octet = core::str::from_utf8(label).ok()?.parse().ok()?;
+            }
+            return Some(IpAddr::V4(Ipv4Addr::from(octets)));
+        }
+        let mut bits: u128 = 0;
+        for label in digits {
+            let [digit] = label[..] else {
+                return None;
+            };
+            let nibble = (digit as char).to_digit(16)?;
+            bits = bits << 4 | nibble as u128;
+        }
+        return Some(IpAddr::V6(Ipv6Addr::from(bits)));
+    }
+
+    /// Appends the uncompressed wire form.
+    pub fn write(&self, buf: &mut BytesMut) {
+        buf.put_slice(&self.octets);
+        buf.put_u8(0);
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Name) -> bool {
+        // the length bytes keep label boundaries apart, and are never letters.
+        return self.octets.eq_ignore_ascii_case(&other.octets);
+    }
+}
+
+impl Eq for Name {}
+
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for b in &self.octets {
+            state.write_u8(b.to_ascii_lowercase());
+        }
+        state.write_u8(0);
+    }
+}
+
+/// Canonical DNS name order (RFC 4034 section 6.1): names compare label by label from the
+/// right, labels as lowercased octet strings, and a name sorts before the names below it.
+impl Ord for Name {
+    fn cmp(&self, other: &Name) -> Ordering {
+        let ours = self.labels().rev();
+        let theirs = other.labels().rev();
+        for (a, b) in ours.zip(theirs) {
+            let a = a.iter().map(|b| b.to_ascii_lowercase());
+            let b = b.iter().map(|b| b.to_ascii_lowercase());
+            match a.cmp(b) {
+                Ordering::Equal => {}
+                unequal => return unequal,
+            }
+        }
+        return self.label_count().cmp(&other.label_count());
+    }
+}
+
+impl PartialOrd for Name {
+    fn partial_cmp(&self, other: &Name) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+/// Presentation form: labels separated by dots with a trailing dot, `.` for the root. Dots and
+/// backslashes inside labels are escaped with `\`, bytes outside printable ASCII as `\DDD`.
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return f.write_str(".");
+        }
+        for label in self.labels() {
+            for &b in label {
+                match b {
+                    b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => {
+                        write!(f, "\\{}", b as char)?
+                    }
+                    0x21..=0x7e => write!(f, "{}", b as char)?,
+                    _ => write!(f, "\\{:03}", b)?,
+                }
+            }
+            f.write_str(".")?;
+        }
+        return Ok(());
+    }
+}
+
+/// Parses presentation form. The trailing dot is optional; `.` alone is the root.
+impl FromStr for Name {
+    type Err = NameError;
+
+    fn from_str(s: &str) -> Result<Name, NameError> {
+        if s == "." {
+            return Ok(Name::root());
+        }
+        let mut labels: Vec<Vec<u8>> = Vec::new();
+        let mut label: Vec<u8> = Vec::new();
+        let mut bites = s.bytes();
+        let mut ended_with_dot = false;
+        while let Some(b) = bites.next() {
+            ended_with_dot = false;
+            match b {
+                b'\\' => {
+                    let first = bites.next().ok_or(NameError::BadEscape)?;
+                    if first.is_ascii_digit() {
+                        let second = bites.next().ok_or(NameError::BadEscape)?;
+                        let third = bites.next().ok_or(NameError::BadEscape)?;
+                        if !second.is_ascii_digit() || !third.is_ascii_digit() {
+                            return Err(NameError::BadEscape);
+                        }
+                        let value = (first - b'0') as u16 * 100
+                            + (second - b'0') as u16 * 10
+                            + (third - b'0') as u16;
+                        label.push(u8::try_from(value).map_err(|_| NameError::BadEscape)?);
+                    } else {
+                        label.push(first);
+                    }
+                }
+                b'.' => {
+                    if label.is_empty() {
+                        return Err(NameError::EmptyLabel);
+                    }
+                    labels.push(core::mem::take(&mut label));
+                    ended_with_dot = true;
+                }
+                _ => label.push(b),
+            }
+        }
+        if !ended_with_dot {
+            if label.is_empty() {
+                return Err(NameError::EmptyLabel);
+            }
+            labels.push(label);
+        }
+        return Name::from_labels(labels);
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Name {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Name {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Name, D::Error> {
+        let s = <alloc::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,43 @@
+//! Buffers for packets on their way out. Responses, over UDP and TCP alike, and upstream
+//! queries are written into buffers taken from a `BufferPool` and given back once sent, so once
+//! the pool has warmed up no packet gets a buffer of its own. Only these output buffers are
+//! pooled: parsing a packet into a `Message`, the records a cached answer is made of, and the
+//! table of names kept to compress them still allocate, if little each.
+use bytes::BytesMut;
+
+pub struct BufferPool {
+    free: Vec<BytesMut>,
+    /// room a new buffer starts out with
+    size: usize,
+    /// most buffers kept for reuse; any given back beyond that are dropped
+    max_free: usize,
+}
+
+impl BufferPool {
+    /// A pool handing out buffers with room for `size` bytes, keeping up to `max_free` of those
+    /// given back.
+    pub fn new(size: usize, max_free: usize) -> BufferPool {
+        return BufferPool {
+            free: Vec::with_capacity(max_free),
+            size,
+            max_free,
+        };
+    }
+
+    /// An empty buffer, a reused one if any is free.
+    pub fn take(&mut self) -> BytesMut {
+        return match self.free.pop() {
+            Some(buf) => buf,
+            None => BytesMut::with_capacity(self.size),
+        };
+    }
+
+    /// Hands `buf` back for reuse.
+    pub fn give(&mut self, mut buf: BytesMut) {
+        if self.free.len() >= self.max_free {
+            return;
+        }
+        buf.clear();
+        self.free.push(buf);
+    }
+}
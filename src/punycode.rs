@@ -0,0 +1,217 @@
+//! Punycode (RFC 3492), the encoding behind the `xn--` A-labels of internationalized domain
+//! names.
+use alloc::{string::String, vec::Vec};
+
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+/// Encodes `input` as punycode, without the `xn--` prefix. None if the input is too long for the
+/// arithmetic to stay in range.
+pub fn encode(input: &str) -> Option<String> {
+    let chars: Vec<u32> = input.chars().map(|c| c as u32).collect();
+    let mut output: String = input.chars().filter(char::is_ascii).collect();
+    let basic = output.len() as u32;
+    let mut handled = basic;
+    if basic > 0 {
+        output.push('-');
+    }
+    let mut n = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    while (handled as usize) < chars.len() {
+        // every code point below n is already in the output.
+        let m = *chars.iter().filter(|&&c| c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+        for &c in &chars {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = threshold(k, bias);
+                    if q < t {
+                        break;
+                    }
+                    output.push(digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(digit(q));
+                bias = adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta = delta.checked_add(1)?;
+        n += 1;
+    }
+    return Some(output);
+}
+
+/// Decodes punycode, without the `xn--` prefix. None if `input` isn't valid punycode.
+pub fn decode(input: &str) -> Option<String> {
+    let (basic, extended) = match input.rfind('-') {
+        Some(at) => (&input[..at], &input[at + 1..]),
+        None => ("", input),
+    };
+    if !basic.is_ascii() {
+        return None;
+    }
+    let mut output: Vec<char> = basic.chars().collect();
+    let mut n = INITIAL_N;
+    let mut i: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut digits = extended.bytes();
+    while digits.len() > 0 {
+        let old_i = i;
+        let mut w: u32 = 1;
+        let mut k = BASE;
+        loop {
+            let d = digit_value(digits.next()?)?;
+            i = i.checked_add(d.checked_mul(w)?)?;
+            let t = threshold(k, bias);
+            if d < t {
+                break;
+            }
+            w = w.checked_mul(BASE - t)?;
+            k += BASE;
+        }
+        let len = output.len() as u32 + 1;
+        bias = adapt(i - old_i, len, old_i == 0);
+        n = n.checked_add(i / len)?;
+        i %= len;
+        output.insert(i as usize, char::from_u32(n)?);
+        i += 1;
+    }
+    return Some(output.into_iter().collect());
+}
+
+fn threshold(k: u32, bias: u32) -> u32 {
+    if k <= bias {
+        return TMIN;
+    }
+    if k >= bias + TMAX {
+        return TMAX;
+    }
+    return k - bias;
+}
+
+fn adapt(delta: u32, points: u32, first: bool) -> u32 {
+    let mut delta = if first { delta / DAMP } else { delta / 2 };
+    delta += delta / points;
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+    return k + (BASE - TMIN + 1) * delta / (delta + SKEW);
+}
+
+fn digit(d: u32) -> char {
+    if d < 26 {
+        return (b'a' + d as u8) as char;
+    }
+    return (b'0' + (d - 26) as u8) as char;
+}
+
+fn digit_value(b: u8) -> Option<u32> {
+    match b {
+        b'a'..=b'z' => Some((b - b'a') as u32),
+        b'A'..=b'Z' => Some((b - b'A') as u32),
+        b'0'..=b'9' => Some((b - b'0') as u32 + 26),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The samples of RFC 3492 section 7.1: Unicode, then punycode.
+    const SAMPLES: &[(&str, &str)] = &[
+        // (A) Arabic (Egyptian)
+        ("ليهمابتكلموشعربي؟", "egbpdaj6bu4bxfgehfvwxn"),
+        // (B) Chinese (simplified)
+        ("他们为什么不说中文", "ihqwcrb4cv8a8dqg056pqjye"),
+        // (C) Chinese (traditional)
+        ("他們爲什麽不說中文", "ihqwctvzc91f659drss3x8bo0yb"),
+        // (D) Czech
+        ("Pročprostěnemluvíčesky", "Proprostnemluvesky-uyb24dma41a"),
+        // (E) Hebrew
+        ("למההםפשוטלאמדבריםעברית", "4dbcagdahymbxekheh6e0a7fei0b"),
+        // (F) Hindi
+        (
+            "यहलोगहिन्दीक्योंनहींबोलसकतेहैं",
+            "i1baa7eci9glrd9b2ae1bj0hfcgg6iyaf8o0a1dig0cd",
+        ),
+        // (G) Japanese
+        (
+            "なぜみんな日本語を話してくれないのか",
+            "n8jok5ay5dzabd5bym9f0cm5685rrjetr6pdxa",
+        ),
+        // (H) Korean
+        (
+            "세계의모든사람들이한국어를이해한다면얼마나좋을까",
+            "989aomsvi5e83db1d2a355cv1e0vak1dwrv93d5xbh15a0dt30a5jpsd879ccm6fea98c",
+        ),
+        // (I) Russian
+        (
+            "почемужеонинеговорятпорусски",
+            "b1abfaaepdrnnbgefbadotcwatmq2g4l",
+        ),
+        // (J) Spanish
+        (
+            "PorquénopuedensimplementehablarenEspañol",
+            "PorqunopuedensimplementehablarenEspaol-fmd56a",
+        ),
+        // (K) Vietnamese
+        (
+            "TạisaohọkhôngthểchỉnóitiếngViệt",
+            "TisaohkhngthchnitingVit-kjcr8268qyxafd2f1b9g",
+        ),
+        // (L)
+        ("3年B組金八先生", "3B-ww4c5e180e575a65lsy2b"),
+        // (M)
+        (
+            "安室奈美恵-with-SUPER-MONKEYS",
+            "-with-SUPER-MONKEYS-pc58ag80a8qai00g7n9n",
+        ),
+        // (N)
+        (
+            "Hello-Another-Way-それぞれの場所",
+            "Hello-Another-Way--fc4qua05auwb3674vfr0b",
+        ),
+        // (O)
+        ("ひとつ屋根の下2", "2-u9tlzr9756bt3uc0v"),
+        // (P)
+        ("MajiでKoiする5秒前", "MajiKoi5-783gue6qz075azm5e"),
+        // (Q)
+        ("パフィーdeルンバ", "de-jg4avhby1noc0d"),
+        // (R)
+        ("そのスピードで", "d9juau41awczczp"),
+        // (S)
+        ("-> $1.00 <-", "-> $1.00 <--"),
+    ];
+
+    #[test]
+    fn rfc_3492_samples_encode() {
+        for (unicode, punycode) in SAMPLES {
+            assert_eq!(encode(unicode).as_deref(), Some(*punycode), "{}", unicode);
+        }
+    }
+
+    #[test]
+    fn rfc_3492_samples_decode() {
+        for (unicode, punycode) in SAMPLES {
+            assert_eq!(decode(punycode).as_deref(), Some(*unicode), "{}", punycode);
+        }
+    }
+}
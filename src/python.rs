@@ -0,0 +1,321 @@
+use bytes::BytesMut;
+use pyo3::{exceptions::PyValueError, prelude::*, types::PyBytes};
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use crate::client::{self, ClientError};
+use crate::message::{Edns, Header, Message, QType, Question, Record, ResourceClass};
+use crate::name::Name;
+
+#[pyclass(name = "Question", frozen)]
+pub struct PyQuestion {
+    #[pyo3(get)]
+    name: String,
+    #[pyo3(get)]
+    qtype: u16,
+    #[pyo3(get)]
+    qclass: u16,
+    #[pyo3(get)]
+    unicast_response: bool,
+}
+
+impl From<&Question> for PyQuestion {
+    fn from(q: &Question) -> Self {
+        PyQuestion {
+            name: q.name.to_string(),
+            qtype: q.tipe.value(),
+            qclass: q.class.value(),
+            unicast_response: q.unicast_response,
+        }
+    }
+}
+
+#[pymethods]
+impl PyQuestion {
+    fn __repr__(&self) -> String {
+        format!(
+            "Question(name={:?}, qtype={}, qclass={})",
+            self.name, self.qtype, self.qclass
+        )
+    }
+}
+
+#[pyclass(name = "Record", frozen)]
+pub struct PyRecord {
+    #[pyo3(get)]
+    name: String,
+    #[pyo3(get)]
+    rtype: u16,
+    #[pyo3(get)]
+    rclass: u16,
+    #[pyo3(get)]
+    ttl: u32,
+    #[pyo3(get)]
+    cache_flush: bool,
+    rdata: Vec<u8>,
+}
+
+impl From<&Record> for PyRecord {
+    fn from(a: &Record) -> Self {
+        PyRecord {
+            name: a.name.to_string(),
+            rtype: a.tipe.value(),
+            rclass: a.class.value(),
+            ttl: a.ttl,
+            cache_flush: a.cache_flush,
+            rdata: a.rdata.to_bytes().to_vec(),
+        }
+    }
+}
+
+#[pymethods]
+impl PyRecord {
+    #[getter]
+    fn rdata<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.rdata)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Record(name={:?}, rtype={}, rclass={}, ttl={})",
+            self.name, self.rtype, self.rclass, self.ttl
+        )
+    }
+}
+
+/// The OPT record of a message. Options are (code, data) pairs as on the wire.
+#[pyclass(name = "Edns", frozen)]
+pub struct PyEdns {
+    #[pyo3(get)]
+    udp_payload_size: u16,
+    #[pyo3(get)]
+    extended_rcode: u8,
+    #[pyo3(get)]
+    version: u8,
+    #[pyo3(get)]
+    flags: u16,
+    #[pyo3(get)]
+    dnssec_ok: bool,
+    options: Vec<(u16, Vec<u8>)>,
+}
+
+impl From<&Edns> for PyEdns {
+    fn from(edns: &Edns) -> Self {
+        let options = edns
+            .options
+            .iter()
+            .map(|o| {
+                let mut buf = BytesMut::new();
+                o.write(&mut buf);
+                // behind the code and length.
+                (o.code(), buf[4..].to_vec())
+            })
+            .collect();
+        PyEdns {
+            udp_payload_size: edns.udp_payload_size,
+            extended_rcode: edns.extended_rcode,
+            version: edns.version,
+            flags: edns.flags,
+            dnssec_ok: edns.dnssec_ok(),
+            options,
+        }
+    }
+}
+
+#[pymethods]
+impl PyEdns {
+    #[getter]
+    fn options<'py>(&self, py: Python<'py>) -> Vec<(u16, Bound<'py, PyBytes>)> {
+        self.options
+            .iter()
+            .map(|(code, data)| (*code, PyBytes::new(py, data)))
+            .collect()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Edns(udp_payload_size={}, version={}, flags={:#06x}, options={})",
+            self.udp_payload_size,
+            self.version,
+            self.flags,
+            self.options.len()
+        )
+    }
+}
+
+/// A record type, as its number or its mnemonic such as "AAAA" or "TYPE65".
+#[derive(FromPyObject)]
+enum TypeArg {
+    Code(u16),
+    Mnemonic(String),
+}
+
+impl TypeArg {
+    fn qtype(self) -> PyResult<QType> {
+        match self {
+            TypeArg::Code(code) => Ok(QType::from_value(code)),
+            TypeArg::Mnemonic(s) => s
+                .parse()
+                .map_err(|e| PyValueError::new_err(format!("invalid type: {}", e))),
+        }
+    }
+}
+
+/// A recursive query for `name` and `qtype`, with an EDNS record advertising 1232 bytes.
+fn build_query(id: u16, name: &str, qtype: TypeArg, dnssec_ok: bool) -> PyResult<Message> {
+    let name: Name = name
+        .parse()
+        .map_err(|e| PyValueError::new_err(format!("invalid name: {}", e)))?;
+    let mut header = Header::new(id);
+    header.rd = true;
+    let mut m = Message::new(header);
+    m.questions.push(Question {
+        name,
+        tipe: qtype.qtype()?,
+        class: ResourceClass::IN,
+        unicast_response: false,
+    });
+    let mut edns = Edns::new(1232);
+    edns.set_dnssec_ok(dnssec_ok);
+    m.edns = Some(edns);
+    Ok(m)
+}
+
+/// Sends a recursive query for `name` and `qtype` to `server`, an address with an optional port
+/// (53 by default), and returns the response. Goes over UDP and retries over TCP if the response
+/// is truncated, or goes straight to TCP with `tcp`. Raises TimeoutError, or another OSError,
+/// if there is no response, and ValueError if it is malformed.
+#[pyfunction]
+#[pyo3(signature = (server, name, qtype = TypeArg::Mnemonic("A".to_string()), timeout = 2.0, dnssec_ok = false, tcp = false))]
+fn query(
+    py: Python<'_>,
+    server: &str,
+    name: &str,
+    qtype: TypeArg,
+    timeout: f64,
+    dnssec_ok: bool,
+    tcp: bool,
+) -> PyResult<PyMessage> {
+    let server: SocketAddr = match server.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, 53),
+        Err(_) => server
+            .parse()
+            .map_err(|_| PyValueError::new_err(format!("invalid server address: {}", server)))?,
+    };
+    let timeout = Duration::try_from_secs_f64(timeout)
+        .map_err(|_| PyValueError::new_err("invalid timeout"))?;
+    let id = RandomState::new().hash_one(name) as u16;
+    let m = build_query(id, name, qtype, dnssec_ok)?;
+    let response = py.allow_threads(|| {
+        if tcp {
+            client::query_tcp(server, &m, timeout)
+        } else {
+            client::query(server, &m, timeout)
+        }
+    });
+    match response {
+        Ok(inner) => Ok(PyMessage { inner }),
+        Err(ClientError::Io(e)) => Err(e.into()),
+        Err(e) => Err(PyValueError::new_err(e.to_string())),
+    }
+}
+
+#[pyclass(name = "Message")]
+pub struct PyMessage {
+    inner: Message,
+}
+
+#[pymethods]
+impl PyMessage {
+    /// Parses a raw DNS packet, raising ValueError if it is malformed.
+    #[staticmethod]
+    fn parse(data: &[u8]) -> PyResult<Self> {
+        match Message::parse(data) {
+            Ok(inner) => Ok(PyMessage { inner }),
+            Err(e) => Err(PyValueError::new_err(format!("invalid DNS packet: {}", e))),
+        }
+    }
+
+    /// Builds a recursive query for `name` and `qtype`, with an EDNS record advertising 1232
+    /// bytes, e.g. to send with a socket of your own.
+    #[staticmethod]
+    #[pyo3(signature = (name, qtype = TypeArg::Mnemonic("A".to_string()), id = 0, dnssec_ok = false))]
+    fn query(name: &str, qtype: TypeArg, id: u16, dnssec_ok: bool) -> PyResult<Self> {
+        Ok(PyMessage {
+            inner: build_query(id, name, qtype, dnssec_ok)?,
+        })
+    }
+
+    fn to_bytes<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.inner.to_bytes())
+    }
+
+    #[getter]
+    fn id(&self) -> u16 {
+        self.inner.header.id
+    }
+
+    #[getter]
+    fn qr(&self) -> bool {
+        self.inner.header.qr
+    }
+
+    #[getter]
+    fn opcode(&self) -> u8 {
+        self.inner.header.opcode.value()
+    }
+
+    #[getter]
+    fn rcode(&self) -> u8 {
+        self.inner.header.rcode
+    }
+
+    #[getter]
+    fn tc(&self) -> bool {
+        self.inner.header.tc
+    }
+
+    /// The OPT record, or None if the message has none.
+    #[getter]
+    fn edns(&self) -> Option<PyEdns> {
+        self.inner.edns.as_ref().map(PyEdns::from)
+    }
+
+    #[getter]
+    fn questions(&self) -> Vec<PyQuestion> {
+        self.inner.questions.iter().map(PyQuestion::from).collect()
+    }
+
+    #[getter]
+    fn answers(&self) -> Vec<PyRecord> {
+        self.inner.answers.iter().map(PyRecord::from).collect()
+    }
+
+    #[getter]
+    fn authorities(&self) -> Vec<PyRecord> {
+        self.inner.authorities.iter().map(PyRecord::from).collect()
+    }
+
+    #[getter]
+    fn additionals(&self) -> Vec<PyRecord> {
+        self.inner.additionals.iter().map(PyRecord::from).collect()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.inner)
+    }
+}
+
+#[pymodule]
+fn dns_rs(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyMessage>()?;
+    m.add_class::<PyQuestion>()?;
+    m.add_class::<PyRecord>()?;
+    m.add_class::<PyEdns>()?;
+    m.add_function(wrap_pyfunction!(query, m)?)?;
+    Ok(())
+}
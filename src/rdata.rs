@@ -0,0 +1,2656 @@
+//! Typed record data. Names inside rdata may be compressed on the wire, so they are decompressed
+//! against the whole packet on parse and always written out in full.
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use core::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+use nom::{
+    bytes::complete::take,
+    number::complete::{be_u16, be_u32, be_u8},
+    IResult,
+};
+
+use crate::message::{Message, NameCompressor, QType};
+use crate::name::Name;
+use crate::text::{self, Fields, TextError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RData {
+    /// A host address
+    A(Ipv4Addr),
+    /// An IPv6 host address
+    AAAA(Ipv6Addr),
+    /// An authoritative name server
+    NS(Name),
+    /// The canonical name for an alias
+    CNAME(Name),
+    /// A domain name pointer
+    PTR(Name),
+    /// Marks the start of a zone of authority
+    SOA(Soa),
+    /// Host information: the CPU and operating system of a host
+    HINFO {
+        cpu: CharacterString,
+        os: CharacterString,
+    },
+    /// Mailbox or mail list information
+    MINFO {
+        /// mailbox responsible for the mailing list or mailbox
+        rmailbx: Name,
+        /// mailbox that receives error messages about it
+        emailbx: Name,
+    },
+    /// Mail exchange
+    MX {
+        /// lower values are preferred
+        preference: u16,
+        exchange: Name,
+    },
+    /// Text strings, one per character-string on the wire, as the bytes they hold: they need not
+    /// be UTF-8. Strings longer than 255 bytes are split into several character-strings when
+    /// written. No strings at all make empty rdata.
+    TXT(Vec<Vec<u8>>),
+    /// Responsible person (RFC 1183)
+    RP {
+        /// mailbox of the person, with the `@` as the first dot; the root if there is none
+        mbox: Name,
+        /// owner of TXT records with more information; the root if there are none
+        txt: Name,
+    },
+    /// AFS database location (RFC 1183)
+    AFSDB {
+        /// 1 for an AFS cell database server, 2 for a DCE name server
+        subtype: u16,
+        hostname: Name,
+    },
+    /// Server selection (RFC 2782)
+    SRV {
+        /// lower values are tried first
+        priority: u16,
+        /// relative share among targets of the same priority
+        weight: u16,
+        port: u16,
+        target: Name,
+    },
+    /// Key exchanger (RFC 2230)
+    KX {
+        /// lower values are preferred
+        preference: u16,
+        exchanger: Name,
+    },
+    /// A certificate or certificate revocation list (RFC 4398)
+    CERT {
+        /// format of `certificate`, e.g. 1 for X.509 (PKIX) or 3 for OpenPGP (PGP)
+        cert_type: u16,
+        key_tag: u16,
+        algorithm: u8,
+        certificate: Vec<u8>,
+    },
+    /// Address prefix list (RFC 3123)
+    APL(Vec<AplItem>),
+    /// Delegation signer (RFC 4034): a digest of a DNSKEY of the child zone
+    DS {
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+    },
+    /// Keying material for IPsec (RFC 4025)
+    IPSECKEY(Ipseckey),
+    /// Signature over an RRset (RFC 4034)
+    RRSIG(Rrsig),
+    /// Next secure record (RFC 4034)
+    NSEC {
+        /// next owner name in the zone, in canonical order
+        next_domain: Name,
+        /// type codes present at the owner name, decoded from the type bitmap
+        types: Vec<u16>,
+    },
+    /// A zone signing key (RFC 4034)
+    DNSKEY {
+        /// bit 7 is the zone key flag, bit 15 secure entry point
+        flags: u16,
+        /// always 3
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+    },
+    /// DHCP information (RFC 4701): a digest identifying the DHCP client that registered the
+    /// owner name, opaque to DNS
+    DHCID(Vec<u8>),
+    /// Hashed next secure record (RFC 5155)
+    NSEC3(Nsec3),
+    /// NSEC3 parameters of a zone (RFC 5155)
+    NSEC3PARAM {
+        hash_algorithm: u8,
+        /// always 0 here; the opt-out flag only exists on NSEC3 records
+        flags: u8,
+        /// additional hash iterations
+        iterations: u16,
+        salt: Vec<u8>,
+    },
+    /// TLS certificate association (RFC 6698)
+    TLSA {
+        /// which certificates the association constrains, e.g. 3 for DANE-EE
+        cert_usage: u8,
+        /// 0 for the full certificate, 1 for its SubjectPublicKeyInfo
+        selector: u8,
+        /// 0 for exact match, 1 for SHA-256, 2 for SHA-512
+        matching_type: u8,
+        cert_data: Vec<u8>,
+    },
+    /// S/MIME certificate association (RFC 8162), with the fields of TLSA
+    SMIMEA {
+        cert_usage: u8,
+        selector: u8,
+        matching_type: u8,
+        cert_data: Vec<u8>,
+    },
+    /// Host identity protocol (RFC 8005)
+    HIP(Hip),
+    /// Child DS (RFC 7344): a DS record the child zone wants its parent to publish
+    CDS {
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+    },
+    /// Child DNSKEY (RFC 7344): a key the child zone wants its parent to publish a DS for
+    CDNSKEY {
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+    },
+    /// OpenPGP transferable public key (RFC 7929)
+    OPENPGPKEY(Vec<u8>),
+    /// Child-to-parent synchronization (RFC 7477): which records the parent should copy
+    CSYNC {
+        /// SOA serial of the child zone the records were taken from
+        serial: u32,
+        /// bit 0 is immediate, bit 1 soaminimum
+        flags: u16,
+        /// type codes of the records to copy
+        types: Vec<u16>,
+    },
+    /// Message digest of the zone (RFC 8976), published at the apex
+    ZONEMD {
+        /// SOA serial of the zone the digest was computed over
+        serial: u32,
+        /// how the zone is fed to the hash; 1 is SIMPLE, the only one defined
+        scheme: u8,
+        /// 1 for SHA-384, 2 for SHA-512
+        hash_algorithm: u8,
+        digest: Vec<u8>,
+    },
+    /// General purpose service binding (RFC 9460)
+    SVCB(Svcb),
+    /// Service binding for HTTPS origins (RFC 9460)
+    HTTPS(Svcb),
+    /// A 48 bit extended unique identifier, e.g. a MAC address (RFC 7043)
+    EUI48([u8; 6]),
+    /// A 64 bit extended unique identifier (RFC 7043)
+    EUI64([u8; 8]),
+    /// Transaction key establishment (RFC 2930). Meta-type.
+    TKEY(Tkey),
+    /// Transaction signature (RFC 8945). Meta-type.
+    TSIG(Tsig),
+    /// A URI for a service (RFC 7553), selected the way SRV targets are
+    URI {
+        /// lower values are tried first
+        priority: u16,
+        /// relative share among targets of the same priority
+        weight: u16,
+        /// the URI itself, taking up the rest of the rdata, as the bytes it was given in
+        target: Vec<u8>,
+    },
+    /// Record type code and rdata of a type without a structured form here, kept as it was on
+    /// the wire.
+    Unknown(u16, Vec<u8>),
+}
+
+/// SOA rdata. The timers are in seconds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Soa {
+    /// primary name server of the zone
+    pub mname: Name,
+    /// mailbox of the person responsible for the zone, with the `@` as the first dot
+    pub rname: Name,
+    pub serial: u32,
+    /// how often secondaries check for a new serial
+    pub refresh: u32,
+    /// how long secondaries wait to retry a failed refresh
+    pub retry: u32,
+    /// how long secondaries keep serving the zone without a successful refresh
+    pub expire: u32,
+    /// TTL of negative answers from the zone (RFC 2308)
+    pub minimum: u32,
+}
+
+impl Soa {
+    fn parse<'a>(packet: &'a [u8], rdata: &'a [u8]) -> IResult<&'a [u8], Soa> {
+        let (rest, mname) = Message::parse_label_seq(packet, rdata)?;
+        let (rest, rname) = Message::parse_label_seq(packet, rest)?;
+        let (rest, serial) = be_u32(rest)?;
+        let (rest, refresh) = be_u32(rest)?;
+        let (rest, retry) = be_u32(rest)?;
+        let (rest, expire) = be_u32(rest)?;
+        let (rest, minimum) = be_u32(rest)?;
+        return Ok((
+            rest,
+            Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            },
+        ));
+    }
+
+    fn write<'a>(&'a self, buf: &mut BytesMut, names: &mut NameCompressor<'a>) {
+        names.write_name(&self.mname, buf);
+        names.write_name(&self.rname, buf);
+        buf.put_u32(self.serial);
+        buf.put_u32(self.refresh);
+        buf.put_u32(self.retry);
+        buf.put_u32(self.expire);
+        buf.put_u32(self.minimum);
+    }
+}
+
+/// RRSIG rdata. Times are seconds since the epoch, modulo 2^32.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rrsig {
+    /// type code of the RRset the signature covers
+    pub type_covered: u16,
+    pub algorithm: u8,
+    /// number of labels in the original owner name, wildcard label excluded
+    pub labels: u8,
+    pub original_ttl: u32,
+    pub expiration: u32,
+    pub inception: u32,
+    /// key tag of the DNSKEY that made the signature
+    pub key_tag: u16,
+    pub signer_name: Name,
+    pub signature: Vec<u8>,
+}
+
+impl Rrsig {
+    fn parse<'a>(packet: &'a [u8], rdata: &'a [u8]) -> IResult<&'a [u8], Rrsig> {
+        let (rest, type_covered) = be_u16(rdata)?;
+        let (rest, algorithm) = be_u8(rest)?;
+        let (rest, labels) = be_u8(rest)?;
+        let (rest, original_ttl) = be_u32(rest)?;
+        let (rest, expiration) = be_u32(rest)?;
+        let (rest, inception) = be_u32(rest)?;
+        let (rest, key_tag) = be_u16(rest)?;
+        let (rest, signer_name) = Message::parse_label_seq(packet, rest)?;
+        let (rest, signature) = take_rest(rest);
+        return Ok((
+            rest,
+            Rrsig {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer_name,
+                signature,
+            },
+        ));
+    }
+
+    fn wire_len(&self) -> usize {
+        return 18 + self.signer_name.wire_len() + self.signature.len();
+    }
+
+    fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.type_covered);
+        buf.put_u8(self.algorithm);
+        buf.put_u8(self.labels);
+        buf.put_u32(self.original_ttl);
+        buf.put_u32(self.expiration);
+        buf.put_u32(self.inception);
+        buf.put_u16(self.key_tag);
+        self.signer_name.write(buf);
+        buf.put_slice(&self.signature);
+    }
+}
+
+/// IPSECKEY rdata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Ipseckey {
+    /// lower values are preferred
+    pub precedence: u8,
+    pub gateway: Gateway,
+    /// 0 if there is no key, 1 for DSA, 2 for RSA
+    pub algorithm: u8,
+    pub public_key: Vec<u8>,
+}
+
+/// Where an IPsec tunnel ends, with the gateway type on the wire following from the variant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Gateway {
+    /// type 0, `.` in presentation form
+    None,
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    /// never compressed on the wire
+    Name(Name),
+}
+
+impl Ipseckey {
+    fn parse<'a>(packet: &'a [u8], rdata: &'a [u8]) -> IResult<&'a [u8], Ipseckey> {
+        let (rest, precedence) = be_u8(rdata)?;
+        let (rest, gateway_type) = be_u8(rest)?;
+        let (rest, algorithm) = be_u8(rest)?;
+        let (rest, gateway) = match gateway_type {
+            0 => (rest, Gateway::None),
+            1 => {
+                let (rest, octets) = take(4usize)(rest)?;
+                let octets: [u8; 4] = octets.try_into().unwrap();
+                (rest, Gateway::Ipv4(Ipv4Addr::from(octets)))
+            }
+            2 => {
+                let (rest, octets) = take(16usize)(rest)?;
+                let octets: [u8; 16] = octets.try_into().unwrap();
+                (rest, Gateway::Ipv6(Ipv6Addr::from(octets)))
+            }
+            3 => {
+                let (rest, name) = Message::parse_label_seq(packet, rest)?;
+                (rest, Gateway::Name(name))
+            }
+            _ => {
+                return Err(nom::Err::Failure(nom::error::Error::new(
+                    rest,
+                    nom::error::ErrorKind::LengthValue,
+                )))
+            }
+        };
+        let (rest, public_key) = take_rest(rest);
+        return Ok((
+            rest,
+            Ipseckey {
+                precedence,
+                gateway,
+                algorithm,
+                public_key,
+            },
+        ));
+    }
+
+    fn wire_len(&self) -> usize {
+        let gateway = match &self.gateway {
+            Gateway::None => 0,
+            Gateway::Ipv4(_) => 4,
+            Gateway::Ipv6(_) => 16,
+            Gateway::Name(name) => name.wire_len(),
+        };
+        return 3 + gateway + self.public_key.len();
+    }
+
+    fn write(&self, buf: &mut BytesMut) {
+        buf.put_u8(self.precedence);
+        let gateway_type = match self.gateway {
+            Gateway::None => 0,
+            Gateway::Ipv4(_) => 1,
+            Gateway::Ipv6(_) => 2,
+            Gateway::Name(_) => 3,
+        };
+        buf.put_u8(gateway_type);
+        buf.put_u8(self.algorithm);
+        match &self.gateway {
+            Gateway::None => {}
+            Gateway::Ipv4(addr) => buf.put_slice(&addr.octets()),
+            Gateway::Ipv6(addr) => buf.put_slice(&addr.octets()),
+            Gateway::Name(name) => name.write(buf),
+        }
+        buf.put_slice(&self.public_key);
+    }
+
+    /// `precedence gateway-type algorithm gateway [public-key]`.
+    fn from_text(fields: &mut Fields) -> Result<Ipseckey, TextError> {
+        let precedence = fields.number("precedence")?;
+        let gateway_type: u8 = fields.number("gateway type")?;
+        let algorithm = fields.number("algorithm")?;
+        let gateway = fields.next()?;
+        let gateway = match gateway_type {
+            0 if gateway == "." => Gateway::None,
+            1 => Gateway::Ipv4(
+                gateway
+                    .parse()
+                    .map_err(|_| TextError::BadField("gateway"))?,
+            ),
+            2 => Gateway::Ipv6(
+                gateway
+                    .parse()
+                    .map_err(|_| TextError::BadField("gateway"))?,
+            ),
+            3 => Gateway::Name(gateway.parse()?),
+            _ => return Err(TextError::BadField("gateway")),
+        };
+        return Ok(Ipseckey {
+            precedence,
+            gateway,
+            algorithm,
+            public_key: text::parse_base64(&fields.joined_rest())?,
+        });
+    }
+}
+
+impl fmt::Display for Ipseckey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (gateway_type, gateway) = match &self.gateway {
+            Gateway::None => (0, String::from(".")),
+            Gateway::Ipv4(addr) => (1, addr.to_string()),
+            Gateway::Ipv6(addr) => (2, addr.to_string()),
+            Gateway::Name(name) => (3, name.to_string()),
+        };
+        write!(
+            f,
+            "{} {} {} {}",
+            self.precedence, gateway_type, self.algorithm, gateway
+        )?;
+        if !self.public_key.is_empty() {
+            f.write_str(" ")?;
+            text::write_base64(f, &self.public_key)?;
+        }
+        Ok(())
+    }
+}
+
+/// TKEY rdata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Tkey {
+    /// the algorithm the key is for, as a name such as `hmac-sha256.`; never compressed
+    pub algorithm: Name,
+    /// validity period of the key, in seconds since the epoch
+    pub inception: u32,
+    pub expiration: u32,
+    /// how the key is established, e.g. 3 for Diffie-Hellman or 5 for deletion
+    pub mode: u16,
+    /// an extended rcode such as BADKEY, 0 in requests
+    pub error: u16,
+    pub key: Vec<u8>,
+    pub other: Vec<u8>,
+}
+
+impl Tkey {
+    fn parse<'a>(packet: &'a [u8], rdata: &'a [u8]) -> IResult<&'a [u8], Tkey> {
+        let (rest, algorithm) = Message::parse_label_seq(packet, rdata)?;
+        let (rest, inception) = be_u32(rest)?;
+        let (rest, expiration) = be_u32(rest)?;
+        let (rest, mode) = be_u16(rest)?;
+        let (rest, error) = be_u16(rest)?;
+        let (rest, key) = take_u16_prefixed(rest)?;
+        let (rest, other) = take_u16_prefixed(rest)?;
+        return Ok((
+            rest,
+            Tkey {
+                algorithm,
+                inception,
+                expiration,
+                mode,
+                error,
+                key,
+                other,
+            },
+        ));
+    }
+
+    fn wire_len(&self) -> usize {
+        return self.algorithm.wire_len() + 16 + self.key.len() + self.other.len();
+    }
+
+    fn write(&self, buf: &mut BytesMut) {
+        self.algorithm.write(buf);
+        buf.put_u32(self.inception);
+        buf.put_u32(self.expiration);
+        buf.put_u16(self.mode);
+        buf.put_u16(self.error);
+        buf.put_u16(self.key.len() as u16);
+        buf.put_slice(&self.key);
+        buf.put_u16(self.other.len() as u16);
+        buf.put_slice(&self.other);
+    }
+}
+
+/// TSIG rdata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Tsig {
+    /// the MAC algorithm, as a name such as `hmac-sha256.`; never compressed
+    pub algorithm: Name,
+    /// seconds since the epoch; 48 bits on the wire
+    pub time_signed: u64,
+    /// seconds of clock skew allowed either side of `time_signed`
+    pub fudge: u16,
+    pub mac: Vec<u8>,
+    /// message ID before any forwarder changed it
+    pub original_id: u16,
+    /// an extended rcode such as BADSIG or BADTIME, 0 in requests
+    pub error: u16,
+    /// the server's time, on BADTIME errors
+    pub other: Vec<u8>,
+}
+
+impl Tsig {
+    fn parse<'a>(packet: &'a [u8], rdata: &'a [u8]) -> IResult<&'a [u8], Tsig> {
+        let (rest, algorithm) = Message::parse_label_seq(packet, rdata)?;
+        let (rest, high) = be_u16(rest)?;
+        let (rest, low) = be_u32(rest)?;
+        let (rest, fudge) = be_u16(rest)?;
+        let (rest, mac) = take_u16_prefixed(rest)?;
+        let (rest, original_id) = be_u16(rest)?;
+        let (rest, error) = be_u16(rest)?;
+        let (rest, other) = take_u16_prefixed(rest)?;
+        return Ok((
+            rest,
+            Tsig {
+                algorithm,
+                time_signed: (high as u64) << 32 | low as u64,
+                fudge,
+                mac,
+                original_id,
+                error,
+                other,
+            },
+        ));
+    }
+
+    fn wire_len(&self) -> usize {
+        return self.algorithm.wire_len() + 16 + self.mac.len() + self.other.len();
+    }
+
+    fn write(&self, buf: &mut BytesMut) {
+        self.algorithm.write(buf);
+        buf.put_u16((self.time_signed >> 32) as u16);
+        buf.put_u32(self.time_signed as u32);
+        buf.put_u16(self.fudge);
+        buf.put_u16(self.mac.len() as u16);
+        buf.put_slice(&self.mac);
+        buf.put_u16(self.original_id);
+        buf.put_u16(self.error);
+        buf.put_u16(self.other.len() as u16);
+        buf.put_slice(&self.other);
+    }
+}
+
+/// HIP rdata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Hip {
+    /// algorithm of `public_key`, numbered as for IPSECKEY
+    pub algorithm: u8,
+    /// host identity tag, a hash of the public key
+    pub hit: Vec<u8>,
+    pub public_key: Vec<u8>,
+    /// where the host can be reached through, in order of preference; never compressed
+    pub rendezvous_servers: Vec<Name>,
+}
+
+impl Hip {
+    fn parse<'a>(packet: &'a [u8], rdata: &'a [u8]) -> IResult<&'a [u8], Hip> {
+        let (rest, hit_len) = be_u8(rdata)?;
+        let (rest, algorithm) = be_u8(rest)?;
+        let (rest, key_len) = be_u16(rest)?;
+        let (rest, hit) = take(hit_len)(rest)?;
+        let (mut rest, public_key) = take(key_len)(rest)?;
+        let mut rendezvous_servers = Vec::new();
+        while !rest.is_empty() {
+            let server: Name;
+            (rest, server) = Message::parse_label_seq(packet, rest)?;
+            rendezvous_servers.push(server);
+        }
+        return Ok((
+            rest,
+            Hip {
+                algorithm,
+                hit: hit.to_vec(),
+                public_key: public_key.to_vec(),
+                rendezvous_servers,
+            },
+        ));
+    }
+
+    fn wire_len(&self) -> usize {
+        let servers: usize = self.rendezvous_servers.iter().map(Name::wire_len).sum();
+        return 4 + self.hit.len() + self.public_key.len() + servers;
+    }
+
+    fn write(&self, buf: &mut BytesMut) {
+        buf.put_u8(self.hit.len() as u8);
+        buf.put_u8(self.algorithm);
+        buf.put_u16(self.public_key.len() as u16);
+        buf.put_slice(&self.hit);
+        buf.put_slice(&self.public_key);
+        for server in &self.rendezvous_servers {
+            server.write(buf);
+        }
+    }
+
+    /// `algorithm hit public-key rendezvous-server...`, with the HIT in hex and the key in
+    /// base64.
+    fn from_text(fields: &mut Fields) -> Result<Hip, TextError> {
+        let algorithm = fields.number("algorithm")?;
+        let hit = text::parse_hex(fields.next()?)?;
+        let public_key = text::parse_base64(fields.next()?)?;
+        if hit.len() > u8::MAX as usize || public_key.len() > u16::MAX as usize {
+            return Err(TextError::BadField("hip"));
+        }
+        let mut rendezvous_servers = Vec::new();
+        while !fields.is_empty() {
+            rendezvous_servers.push(fields.name()?);
+        }
+        return Ok(Hip {
+            algorithm,
+            hit,
+            public_key,
+            rendezvous_servers,
+        });
+    }
+}
+
+impl fmt::Display for Hip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.algorithm)?;
+        text::write_hex(f, &self.hit)?;
+        f.write_str(" ")?;
+        text::write_base64(f, &self.public_key)?;
+        for server in &self.rendezvous_servers {
+            write!(f, " {}", server)?;
+        }
+        Ok(())
+    }
+}
+
+/// NSEC3 rdata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Nsec3 {
+    pub hash_algorithm: u8,
+    /// bit 0 is the opt-out flag
+    pub flags: u8,
+    /// additional hash iterations
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+    /// hash of the next owner name in hash order, unencoded (not base32)
+    pub next_hashed_owner: Vec<u8>,
+    /// type codes present at the original owner name
+    pub types: Vec<u16>,
+}
+
+impl Nsec3 {
+    fn parse(rdata: &[u8]) -> IResult<&[u8], Nsec3> {
+        let (rest, hash_algorithm) = be_u8(rdata)?;
+        let (rest, flags) = be_u8(rest)?;
+        let (rest, iterations) = be_u16(rest)?;
+        let (rest, salt) = take_u8_prefixed(rest)?;
+        let (rest, next_hashed_owner) = take_u8_prefixed(rest)?;
+        let (rest, types) = parse_type_bitmap(rest)?;
+        return Ok((
+            rest,
+            Nsec3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed_owner,
+                types,
+            },
+        ));
+    }
+
+    fn wire_len(&self) -> usize {
+        return 6 + self.salt.len() + self.next_hashed_owner.len() + type_bitmap_len(&self.types);
+    }
+
+    fn write(&self, buf: &mut BytesMut) {
+        buf.put_u8(self.hash_algorithm);
+        buf.put_u8(self.flags);
+        buf.put_u16(self.iterations);
+        buf.put_u8(self.salt.len() as u8);
+        buf.put_slice(&self.salt);
+        buf.put_u8(self.next_hashed_owner.len() as u8);
+        buf.put_slice(&self.next_hashed_owner);
+        write_type_bitmap(&self.types, buf);
+    }
+}
+
+/// One prefix of an APL record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AplItem {
+    /// the prefix is excluded from the list rather than included, `!` in presentation form
+    pub negate: bool,
+    /// number of leading bits of `address` that count
+    pub prefix: u8,
+    pub address: IpAddr,
+}
+
+impl AplItem {
+    fn parse(rdata: &[u8]) -> IResult<&[u8], AplItem> {
+        let (rest, family) = be_u16(rdata)?;
+        let (rest, prefix) = be_u8(rest)?;
+        let (rest, len) = be_u8(rest)?;
+        let at = rest;
+        // trailing zero bytes of the address are left out on the wire.
+        let (rest, bits) = take(len & 0x7f)(rest)?;
+        let malformed = || {
+            nom::Err::Failure(nom::error::Error::new(
+                at,
+                nom::error::ErrorKind::LengthValue,
+            ))
+        };
+        let address = match family {
+            1 if prefix <= 32 && bits.len() <= 4 => {
+                let mut octets = [0; 4];
+                octets[..bits.len()].copy_from_slice(bits);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            2 if prefix <= 128 && bits.len() <= 16 => {
+                let mut octets = [0; 16];
+                octets[..bits.len()].copy_from_slice(bits);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return Err(malformed()),
+        };
+        return Ok((
+            rest,
+            AplItem {
+                negate: len & 0x80 != 0,
+                prefix,
+                address,
+            },
+        ));
+    }
+
+    fn wire_len(&self) -> usize {
+        let octets = match self.address {
+            IpAddr::V4(a) => a.octets().to_vec(),
+            IpAddr::V6(a) => a.octets().to_vec(),
+        };
+        return 4 + octets.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    }
+
+    fn write(&self, buf: &mut BytesMut) {
+        let (family, octets) = match self.address {
+            IpAddr::V4(a) => (1, a.octets().to_vec()),
+            IpAddr::V6(a) => (2, a.octets().to_vec()),
+        };
+        let len = octets.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        buf.put_u16(family);
+        buf.put_u8(self.prefix);
+        buf.put_u8(len as u8 | if self.negate { 0x80 } else { 0 });
+        buf.put_slice(&octets[..len]);
+    }
+
+    /// `[!]family:address/prefix`, e.g. `!1:192.168.38.0/28`.
+    fn from_text(field: &str) -> Result<AplItem, TextError> {
+        let bad = TextError::BadField("apl item");
+        let (negate, field) = match field.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, field),
+        };
+        let (family, rest) = field.split_once(':').ok_or(bad)?;
+        let (address, prefix) = rest.rsplit_once('/').ok_or(bad)?;
+        let prefix: u8 = prefix.parse().map_err(|_| bad)?;
+        let address: IpAddr = match family {
+            "1" => IpAddr::V4(address.parse().map_err(|_| bad)?),
+            "2" => IpAddr::V6(address.parse().map_err(|_| bad)?),
+            _ => return Err(bad),
+        };
+        if prefix > if address.is_ipv4() { 32 } else { 128 } {
+            return Err(bad);
+        }
+        return Ok(AplItem {
+            negate,
+            prefix,
+            address,
+        });
+    }
+}
+
+impl fmt::Display for AplItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let family = if self.address.is_ipv4() { 1 } else { 2 };
+        let negate = if self.negate { "!" } else { "" };
+        write!(f, "{}{}:{}/{}", negate, family, self.address, self.prefix)
+    }
+}
+
+/// SVCB and HTTPS rdata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Svcb {
+    /// 0 for alias mode, otherwise lower values are preferred
+    pub priority: u16,
+    /// the root name (empty) means the owner name itself
+    pub target: Name,
+    /// in the order they appeared, which on the wire is ascending by key
+    pub params: Vec<SvcParam>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SvcParam {
+    /// keys the client must understand to use this record
+    Mandatory(Vec<u16>),
+    /// application protocol ids, e.g. "h2" or "h3", which are opaque octets
+    Alpn(Vec<Vec<u8>>),
+    /// the default protocol (http/1.1 for HTTPS) isn't supported
+    NoDefaultAlpn,
+    Port(u16),
+    Ipv4Hint(Vec<Ipv4Addr>),
+    /// an ECHConfigList
+    Ech(Vec<u8>),
+    Ipv6Hint(Vec<Ipv6Addr>),
+    /// key and value of a parameter without a structured form here
+    Unknown(u16, Vec<u8>),
+}
+
+impl Svcb {
+    fn parse<'a>(packet: &'a [u8], rdata: &'a [u8]) -> IResult<&'a [u8], Svcb> {
+        let (mut rest, priority) = be_u16(rdata)?;
+        // RFC 9460 forbids compressing the target, but be lenient.
+        let target: Name;
+        (rest, target) = Message::parse_label_seq(packet, rest)?;
+        let mut params = Vec::new();
+        while !rest.is_empty() {
+            let (after_key, key) = be_u16(rest)?;
+            let (after_len, len) = be_u16(after_key)?;
+            let value: &[u8];
+            (rest, value) = take(len)(after_len)?;
+            params.push(SvcParam::parse(key, value)?);
+        }
+        return Ok((
+            rest,
+            Svcb {
+                priority,
+                target,
+                params,
+            },
+        ));
+    }
+
+    fn from_text(fields: &mut Fields) -> Result<Svcb, TextError> {
+        let priority = fields.number("priority")?;
+        let target = fields.name()?;
+        let mut params = Vec::new();
+        while !fields.is_empty() {
+            params.push(SvcParam::from_text(fields.next()?)?);
+        }
+        return Ok(Svcb {
+            priority,
+            target,
+            params,
+        });
+    }
+
+    fn wire_len(&self) -> usize {
+        let params: usize = self.params.iter().map(|p| 4 + p.value_len()).sum();
+        return 2 + self.target.wire_len() + params;
+    }
+
+    fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.priority);
+        self.target.write(buf);
+        for param in &self.params {
+            param.write(buf);
+        }
+    }
+}
+
+impl SvcParam {
+    pub fn key(&self) -> u16 {
+        match self {
+            SvcParam::Mandatory(_) => 0,
+            SvcParam::Alpn(_) => 1,
+            SvcParam::NoDefaultAlpn => 2,
+            SvcParam::Port(_) => 3,
+            SvcParam::Ipv4Hint(_) => 4,
+            SvcParam::Ech(_) => 5,
+            SvcParam::Ipv6Hint(_) => 6,
+            SvcParam::Unknown(key, _) => *key,
+        }
+    }
+
+    /// Reads one `key=value` field, the inverse of `Display`.
+    fn from_text(field: &str) -> Result<SvcParam, TextError> {
+        let (key, value) = field.split_once('=').unwrap_or((field, ""));
+        let value = text::unquote(value)?;
+        // alpn ids needn't be text, and may hold commas; every other value is plain text.
+        if key == "alpn" {
+            let ids = split_value_list(&value)?;
+            return Ok(SvcParam::Alpn(
+                ids.into_iter().filter(|id| !id.is_empty()).collect(),
+            ));
+        }
+        let value = core::str::from_utf8(&value).map_err(|_| TextError::BadField("svc param"))?;
+        let list = || value.split(',').filter(|v| !v.is_empty());
+        let param = match key {
+            "mandatory" => {
+                SvcParam::Mandatory(list().map(svc_param_key_value).collect::<Result<_, _>>()?)
+            }
+            "no-default-alpn" => SvcParam::NoDefaultAlpn,
+            "port" => SvcParam::Port(value.parse().map_err(|_| TextError::BadField("port"))?),
+            "ipv4hint" => SvcParam::Ipv4Hint(
+                list()
+                    .map(|a| a.parse().map_err(|_| TextError::BadField("ipv4hint")))
+                    .collect::<Result<_, _>>()?,
+            ),
+            "ech" => SvcParam::Ech(text::parse_base64(value)?),
+            "ipv6hint" => SvcParam::Ipv6Hint(
+                list()
+                    .map(|a| a.parse().map_err(|_| TextError::BadField("ipv6hint")))
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => SvcParam::Unknown(
+                svc_param_key_value(key)?,
+                text::unquote(field.split_once('=').map_or("", |(_, v)| v))?,
+            ),
+        };
+        return Ok(param);
+    }
+
+    /// Decodes the value of the parameter with the given key. The whole value must be used up.
+    fn parse(key: u16, value: &[u8]) -> Result<SvcParam, nom::Err<nom::error::Error<&[u8]>>> {
+        let mut rest = value;
+        let param = match key {
+            0 => {
+                let mut keys = Vec::new();
+                while !rest.is_empty() {
+                    let k: u16;
+                    (rest, k) = be_u16(rest)?;
+                    keys.push(k);
+                }
+                SvcParam::Mandatory(keys)
+            }
+            1 => {
+                let mut ids = Vec::new();
+                while !rest.is_empty() {
+                    let id: Vec<u8>;
+                    (rest, id) = take_u8_prefixed(rest)?;
+                    ids.push(id);
+                }
+                SvcParam::Alpn(ids)
+            }
+            2 => SvcParam::NoDefaultAlpn,
+            3 => {
+                let port: u16;
+                (rest, port) = be_u16(rest)?;
+                SvcParam::Port(port)
+            }
+            4 => {
+                let mut addrs = Vec::new();
+                while !rest.is_empty() {
+                    let octets: &[u8];
+                    (rest, octets) = take(4usize)(rest)?;
+                    let octets: [u8; 4] = octets.try_into().unwrap();
+                    addrs.push(Ipv4Addr::from(octets));
+                }
+                SvcParam::Ipv4Hint(addrs)
+            }
+            5 => {
+                let ech: Vec<u8>;
+                (rest, ech) = take_rest(rest);
+                SvcParam::Ech(ech)
+            }
+            6 => {
+                let mut addrs = Vec::new();
+                while !rest.is_empty() {
+                    let octets: &[u8];
+                    (rest, octets) = take(16usize)(rest)?;
+                    let octets: [u8; 16] = octets.try_into().unwrap();
+                    addrs.push(Ipv6Addr::from(octets));
+                }
+                SvcParam::Ipv6Hint(addrs)
+            }
+            _ => {
+                let bites: Vec<u8>;
+                (rest, bites) = take_rest(rest);
+                SvcParam::Unknown(key, bites)
+            }
+        };
+        if !rest.is_empty() {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                rest,
+                nom::error::ErrorKind::LengthValue,
+            )));
+        }
+        return Ok(param);
+    }
+
+    /// Length of the value, without the key and length in front of it.
+    fn value_len(&self) -> usize {
+        match self {
+            SvcParam::Mandatory(keys) => 2 * keys.len(),
+            SvcParam::Alpn(ids) => ids.iter().map(|id| 1 + id.len()).sum(),
+            SvcParam::NoDefaultAlpn => 0,
+            SvcParam::Port(_) => 2,
+            SvcParam::Ipv4Hint(addrs) => 4 * addrs.len(),
+            SvcParam::Ipv6Hint(addrs) => 16 * addrs.len(),
+            SvcParam::Ech(bites) | SvcParam::Unknown(_, bites) => bites.len(),
+        }
+    }
+
+    fn write(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.key());
+        // the value length is only known once the value is written.
+        let at = buf.len();
+        buf.put_u16(0);
+        match self {
+            SvcParam::Mandatory(keys) => {
+                for k in keys {
+                    buf.put_u16(*k);
+                }
+            }
+            SvcParam::Alpn(ids) => {
+                for id in ids {
+                    buf.put_u8(id.len() as u8);
+                    buf.put_slice(id);
+                }
+            }
+            SvcParam::NoDefaultAlpn => {}
+            SvcParam::Port(port) => buf.put_u16(*port),
+            SvcParam::Ipv4Hint(addrs) => {
+                for addr in addrs {
+                    buf.put_slice(&addr.octets());
+                }
+            }
+            SvcParam::Ipv6Hint(addrs) => {
+                for addr in addrs {
+                    buf.put_slice(&addr.octets());
+                }
+            }
+            SvcParam::Ech(bites) | SvcParam::Unknown(_, bites) => buf.put_slice(bites),
+        }
+        let len = (buf.len() - at - 2) as u16;
+        buf[at..at + 2].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+/// A field preceded by its length in one byte.
+fn take_u8_prefixed(bites: &[u8]) -> IResult<&[u8], Vec<u8>> {
+    let (rest, len) = be_u8(bites)?;
+    let (rest, field) = take(len)(rest)?;
+    return Ok((rest, field.to_vec()));
+}
+
+/// A character-string (RFC 1035 section 3.3): up to 255 bytes, which need not be UTF-8.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "Vec<u8>", into = "Vec<u8>"))]
+pub struct CharacterString(Vec<u8>);
+
+/// Longest character-string, in bytes.
+pub const MAX_CHARACTER_STRING_LEN: usize = 255;
+
+/// More bytes than a character-string holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("character-string longer than 255 bytes")]
+pub struct CharacterStringTooLong;
+
+impl CharacterString {
+    pub fn as_bytes(&self) -> &[u8] {
+        return &self.0;
+    }
+
+    /// Appends the string behind its length byte.
+    fn write(&self, buf: &mut BytesMut) {
+        buf.put_u8(self.0.len() as u8);
+        buf.put_slice(&self.0);
+    }
+
+    fn wire_len(&self) -> usize {
+        return 1 + self.0.len();
+    }
+}
+
+impl TryFrom<Vec<u8>> for CharacterString {
+    type Error = CharacterStringTooLong;
+
+    fn try_from(bites: Vec<u8>) -> Result<CharacterString, CharacterStringTooLong> {
+        if bites.len() > MAX_CHARACTER_STRING_LEN {
+            return Err(CharacterStringTooLong);
+        }
+        return Ok(CharacterString(bites));
+    }
+}
+
+impl From<CharacterString> for Vec<u8> {
+    fn from(s: CharacterString) -> Vec<u8> {
+        return s.0;
+    }
+}
+
+/// A field preceded by its length in two bytes.
+fn take_u16_prefixed(bites: &[u8]) -> IResult<&[u8], Vec<u8>> {
+    let (rest, len) = be_u16(bites)?;
+    let (rest, field) = take(len)(rest)?;
+    return Ok((rest, field.to_vec()));
+}
+
+/// Everything left in `bites`, for fields that run to the end of the rdata.
+fn take_rest(bites: &[u8]) -> (&[u8], Vec<u8>) {
+    return (&bites[bites.len()..], bites.to_vec());
+}
+
+/// Decodes an NSEC style type bitmap (RFC 4034 section 4.1.2) into the type codes it lists, in
+/// ascending order.
+fn parse_type_bitmap(mut bites: &[u8]) -> IResult<&[u8], Vec<u16>> {
+    let mut types = Vec::new();
+    while !bites.is_empty() {
+        let (rest, window) = be_u8(bites)?;
+        let (rest, len) = be_u8(rest)?;
+        if len == 0 || len > 32 {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                bites,
+                nom::error::ErrorKind::LengthValue,
+            )));
+        }
+        let bitmap: &[u8];
+        (bites, bitmap) = take(len)(rest)?;
+        for (i, byte) in bitmap.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push((window as u16) << 8 | (i * 8 + bit) as u16);
+                }
+            }
+        }
+    }
+    return Ok((bites, types));
+}
+
+/// Length of the bitmap `write_type_bitmap` writes for `types`: a window number, a length and
+/// up to 32 bytes of bits for each block of 256 types in use.
+fn type_bitmap_len(types: &[u16]) -> usize {
+    let mut types = types.to_vec();
+    types.sort_unstable();
+    types.dedup();
+    let mut len = 0;
+    for (i, &t) in types.iter().enumerate() {
+        let last_in_window = types.get(i + 1).is_none_or(|next| next >> 8 != t >> 8);
+        if last_in_window {
+            len += 2 + (t & 0xff) as usize / 8 + 1;
+        }
+    }
+    return len;
+}
+
+fn write_type_bitmap(types: &[u16], buf: &mut BytesMut) {
+    let mut types = types.to_vec();
+    types.sort_unstable();
+    types.dedup();
+    let mut i = 0;
+    while i < types.len() {
+        let window = types[i] >> 8;
+        let mut bitmap = [0u8; 32];
+        let mut len = 0;
+        while i < types.len() && types[i] >> 8 == window {
+            let low = (types[i] & 0xff) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+            len = low / 8 + 1;
+            i += 1;
+        }
+        buf.put_u8(window as u8);
+        buf.put_u8(len as u8);
+        buf.put_slice(&bitmap[..len]);
+    }
+}
+
+impl RData {
+    /// Decodes `rdata`, the rdata of a record of type `tipe` found in `packet`. The whole of
+    /// `rdata` must be used up.
+    pub fn parse<'a>(tipe: &QType, packet: &'a [u8], rdata: &'a [u8]) -> IResult<&'a [u8], RData> {
+        let (rest, data) = match tipe {
+            QType::A => {
+                let (rest, octets) = take(4usize)(rdata)?;
+                let octets: [u8; 4] = octets.try_into().unwrap();
+                (rest, RData::A(Ipv4Addr::from(octets)))
+            }
+            QType::AAAA => {
+                let (rest, octets) = take(16usize)(rdata)?;
+                let octets: [u8; 16] = octets.try_into().unwrap();
+                (rest, RData::AAAA(Ipv6Addr::from(octets)))
+            }
+            QType::NS => {
+                let (rest, name) = Message::parse_label_seq(packet, rdata)?;
+                (rest, RData::NS(name))
+            }
+            QType::CNAME => {
+                let (rest, name) = Message::parse_label_seq(packet, rdata)?;
+                (rest, RData::CNAME(name))
+            }
+            QType::PTR => {
+                let (rest, name) = Message::parse_label_seq(packet, rdata)?;
+                (rest, RData::PTR(name))
+            }
+            QType::SOA => {
+                let (rest, soa) = Soa::parse(packet, rdata)?;
+                (rest, RData::SOA(soa))
+            }
+            QType::HINFO => {
+                // a length byte can't say more than 255.
+                let (rest, cpu) = take_u8_prefixed(rdata)?;
+                let (rest, os) = take_u8_prefixed(rest)?;
+                (
+                    rest,
+                    RData::HINFO {
+                        cpu: CharacterString(cpu),
+                        os: CharacterString(os),
+                    },
+                )
+            }
+            QType::MINFO => {
+                let (rest, rmailbx) = Message::parse_label_seq(packet, rdata)?;
+                let (rest, emailbx) = Message::parse_label_seq(packet, rest)?;
+                (rest, RData::MINFO { rmailbx, emailbx })
+            }
+            QType::MX => {
+                let (rest, preference) = be_u16(rdata)?;
+                let (rest, exchange) = Message::parse_label_seq(packet, rest)?;
+                (
+                    rest,
+                    RData::MX {
+                        preference,
+                        exchange,
+                    },
+                )
+            }
+            QType::TXT => {
+                let mut strings = Vec::new();
+                let mut rest = rdata;
+                while !rest.is_empty() {
+                    let (after_len, len) = be_u8(rest)?;
+                    let bites: &[u8];
+                    (rest, bites) = take(len)(after_len)?;
+                    strings.push(bites.to_vec());
+                }
+                (rest, RData::TXT(strings))
+            }
+            QType::RP => {
+                let (rest, mbox) = Message::parse_label_seq(packet, rdata)?;
+                let (rest, txt) = Message::parse_label_seq(packet, rest)?;
+                (rest, RData::RP { mbox, txt })
+            }
+            QType::AFSDB => {
+                let (rest, subtype) = be_u16(rdata)?;
+                let (rest, hostname) = Message::parse_label_seq(packet, rest)?;
+                (rest, RData::AFSDB { subtype, hostname })
+            }
+            QType::SRV => {
+                let (rest, priority) = be_u16(rdata)?;
+                let (rest, weight) = be_u16(rest)?;
+                let (rest, port) = be_u16(rest)?;
+                // RFC 2782 forbids compressing the target, but some servers do it anyway.
+                let (rest, target) = Message::parse_label_seq(packet, rest)?;
+                (
+                    rest,
+                    RData::SRV {
+                        priority,
+                        weight,
+                        port,
+                        target,
+                    },
+                )
+            }
+            QType::KX => {
+                let (rest, preference) = be_u16(rdata)?;
+                let (rest, exchanger) = Message::parse_label_seq(packet, rest)?;
+                (
+                    rest,
+                    RData::KX {
+                        preference,
+                        exchanger,
+                    },
+                )
+            }
+            QType::CERT => {
+                let (rest, cert_type) = be_u16(rdata)?;
+                let (rest, key_tag) = be_u16(rest)?;
+                let (rest, algorithm) = be_u8(rest)?;
+                let (rest, certificate) = take_rest(rest);
+                (
+                    rest,
+                    RData::CERT {
+                        cert_type,
+                        key_tag,
+                        algorithm,
+                        certificate,
+                    },
+                )
+            }
+            QType::APL => {
+                let mut items = Vec::new();
+                let mut rest = rdata;
+                while !rest.is_empty() {
+                    let item: AplItem;
+                    (rest, item) = AplItem::parse(rest)?;
+                    items.push(item);
+                }
+                (rest, RData::APL(items))
+            }
+            QType::DS => {
+                let (rest, key_tag) = be_u16(rdata)?;
+                let (rest, algorithm) = be_u8(rest)?;
+                let (rest, digest_type) = be_u8(rest)?;
+                let (rest, digest) = take_rest(rest);
+                (
+                    rest,
+                    RData::DS {
+                        key_tag,
+                        algorithm,
+                        digest_type,
+                        digest,
+                    },
+                )
+            }
+            QType::IPSECKEY => {
+                let (rest, ipseckey) = Ipseckey::parse(packet, rdata)?;
+                (rest, RData::IPSECKEY(ipseckey))
+            }
+            QType::RRSIG => {
+                let (rest, rrsig) = Rrsig::parse(packet, rdata)?;
+                (rest, RData::RRSIG(rrsig))
+            }
+            QType::NSEC => {
+                // RFC 4034 forbids compressing the next domain name, but be lenient.
+                let (rest, next_domain) = Message::parse_label_seq(packet, rdata)?;
+                let (rest, types) = parse_type_bitmap(rest)?;
+                (rest, RData::NSEC { next_domain, types })
+            }
+            QType::DNSKEY => {
+                let (rest, flags) = be_u16(rdata)?;
+                let (rest, protocol) = be_u8(rest)?;
+                let (rest, algorithm) = be_u8(rest)?;
+                let (rest, public_key) = take_rest(rest);
+                (
+                    rest,
+                    RData::DNSKEY {
+                        flags,
+                        protocol,
+                        algorithm,
+                        public_key,
+                    },
+                )
+            }
+            QType::DHCID => {
+                let (rest, digest) = take_rest(rdata);
+                (rest, RData::DHCID(digest))
+            }
+            QType::NSEC3 => {
+                let (rest, nsec3) = Nsec3::parse(rdata)?;
+                (rest, RData::NSEC3(nsec3))
+            }
+            QType::NSEC3PARAM => {
+                let (rest, hash_algorithm) = be_u8(rdata)?;
+                let (rest, flags) = be_u8(rest)?;
+                let (rest, iterations) = be_u16(rest)?;
+                let (rest, salt) = take_u8_prefixed(rest)?;
+                (
+                    rest,
+                    RData::NSEC3PARAM {
+                        hash_algorithm,
+                        flags,
+                        iterations,
+                        salt,
+                    },
+                )
+            }
+            QType::TLSA => {
+                let (rest, cert_usage) = be_u8(rdata)?;
+                let (rest, selector) = be_u8(rest)?;
+                let (rest, matching_type) = be_u8(rest)?;
+                let (rest, cert_data) = take_rest(rest);
+                (
+                    rest,
+                    RData::TLSA {
+                        cert_usage,
+                        selector,
+                        matching_type,
+                        cert_data,
+                    },
+                )
+            }
+            QType::SMIMEA => {
+                let (rest, cert_usage) = be_u8(rdata)?;
+                let (rest, selector) = be_u8(rest)?;
+                let (rest, matching_type) = be_u8(rest)?;
+                let (rest, cert_data) = take_rest(rest);
+                (
+                    rest,
+                    RData::SMIMEA {
+                        cert_usage,
+                        selector,
+                        matching_type,
+                        cert_data,
+                    },
+                )
+            }
+            QType::HIP => {
+                let (rest, hip) = Hip::parse(packet, rdata)?;
+                (rest, RData::HIP(hip))
+            }
+            QType::CDS => {
+                let (rest, key_tag) = be_u16(rdata)?;
+                let (rest, algorithm) = be_u8(rest)?;
+                let (rest, digest_type) = be_u8(rest)?;
+                let (rest, digest) = take_rest(rest);
+                (
+                    rest,
+                    RData::CDS {
+                        key_tag,
+                        algorithm,
+                        digest_type,
+                        digest,
+                    },
+                )
+            }
+            QType::CDNSKEY => {
+                let (rest, flags) = be_u16(rdata)?;
+                let (rest, protocol) = be_u8(rest)?;
+                let (rest, algorithm) = be_u8(rest)?;
+                let (rest, public_key) = take_rest(rest);
+                (
+                    rest,
+                    RData::CDNSKEY {
+                        flags,
+                        protocol,
+                        algorithm,
+                        public_key,
+                    },
+                )
+            }
+            QType::OPENPGPKEY => {
+                let (rest, key) = take_rest(rdata);
+                (rest, RData::OPENPGPKEY(key))
+            }
+            QType::CSYNC => {
+                let (rest, serial) = be_u32(rdata)?;
+                let (rest, flags) = be_u16(rest)?;
+                let (rest, types) = parse_type_bitmap(rest)?;
+                (
+                    rest,
+                    RData::CSYNC {
+                        serial,
+                        flags,
+                        types,
+                    },
+                )
+            }
+            QType::ZONEMD => {
+                let (rest, serial) = be_u32(rdata)?;
+                let (rest, scheme) = be_u8(rest)?;
+                let (rest, hash_algorithm) = be_u8(rest)?;
+                let (rest, digest) = take_rest(rest);
+                (
+                    rest,
+                    RData::ZONEMD {
+                        serial,
+                        scheme,
+                        hash_algorithm,
+                        digest,
+                    },
+                )
+            }
+            QType::SVCB => {
+                let (rest, svcb) = Svcb::parse(packet, rdata)?;
+                (rest, RData::SVCB(svcb))
+            }
+            QType::HTTPS => {
+                let (rest, svcb) = Svcb::parse(packet, rdata)?;
+                (rest, RData::HTTPS(svcb))
+            }
+            QType::EUI48 => {
+                let (rest, octets) = take(6usize)(rdata)?;
+                (rest, RData::EUI48(octets.try_into().unwrap()))
+            }
+            QType::EUI64 => {
+                let (rest, octets) = take(8usize)(rdata)?;
+                (rest, RData::EUI64(octets.try_into().unwrap()))
+            }
+            QType::TKEY => {
+                let (rest, tkey) = Tkey::parse(packet, rdata)?;
+                (rest, RData::TKEY(tkey))
+            }
+            QType::TSIG => {
+                let (rest, tsig) = Tsig::parse(packet, rdata)?;
+                (rest, RData::TSIG(tsig))
+            }
+            QType::URI => {
+                let (rest, priority) = be_u16(rdata)?;
+                let (rest, weight) = be_u16(rest)?;
+                // unlike a character-string, the target has no length byte.
+                let (rest, target) = take_rest(rest);
+                (
+                    rest,
+                    RData::URI {
+                        priority,
+                        weight,
+                        target,
+                    },
+                )
+            }
+            _ => {
+                let (rest, bites) = take_rest(rdata);
+                (rest, RData::Unknown(tipe.value(), bites))
+            }
+        };
+        if !rest.is_empty() {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                rest,
+                nom::error::ErrorKind::LengthValue,
+            )));
+        }
+        return Ok((rest, data));
+    }
+
+    /// Reads the rdata fields of a record of type `tipe` in presentation form, the inverse of
+    /// `Display`. Leaves anything after the rdata in `fields`.
+    pub(crate) fn from_text(tipe: &QType, fields: &mut Fields) -> Result<RData, TextError> {
+        if fields.peek() == Some("\\#") {
+            fields.next()?;
+            let len: usize = fields.number("rdata length")?;
+            let bites = text::parse_hex(&fields.joined_rest())?;
+            if bites.len() != len {
+                return Err(TextError::BadField("rdata length"));
+            }
+            let (_, rdata) =
+                RData::parse(tipe, &bites, &bites).map_err(|_| TextError::BadField("rdata"))?;
+            return Ok(rdata);
+        }
+        let rdata = match tipe {
+            QType::A => RData::A(fields.number("address")?),
+            QType::AAAA => RData::AAAA(fields.number("address")?),
+            QType::NS => RData::NS(fields.name()?),
+            QType::CNAME => RData::CNAME(fields.name()?),
+            QType::PTR => RData::PTR(fields.name()?),
+            QType::SOA => RData::SOA(Soa {
+                mname: fields.name()?,
+                rname: fields.name()?,
+                serial: fields.number("serial")?,
+                refresh: fields.number("refresh")?,
+                retry: fields.number("retry")?,
+                expire: fields.number("expire")?,
+                minimum: fields.number("minimum")?,
+            }),
+            QType::HINFO => RData::HINFO {
+                cpu: CharacterString(parse_character_string(fields.next()?)?),
+                os: CharacterString(parse_character_string(fields.next()?)?),
+            },
+            QType::MINFO => RData::MINFO {
+                rmailbx: fields.name()?,
+                emailbx: fields.name()?,
+            },
+            QType::MX => RData::MX {
+                preference: fields.number("preference")?,
+                exchange: fields.name()?,
+            },
+            QType::TXT => {
+                let mut strings = Vec::new();
+                while !fields.is_empty() {
+                    strings.push(parse_character_string(fields.next()?)?);
+                }
+                if strings.is_empty() {
+                    return Err(TextError::MissingField);
+                }
+                RData::TXT(strings)
+            }
+            QType::RP => RData::RP {
+                mbox: fields.name()?,
+                txt: fields.name()?,
+            },
+            QType::AFSDB => RData::AFSDB {
+                subtype: fields.number("subtype")?,
+                hostname: fields.name()?,
+            },
+            QType::SRV => RData::SRV {
+                priority: fields.number("priority")?,
+                weight: fields.number("weight")?,
+                port: fields.number("port")?,
+                target: fields.name()?,
+            },
+            QType::KX => RData::KX {
+                preference: fields.number("preference")?,
+                exchanger: fields.name()?,
+            },
+            QType::CERT => RData::CERT {
+                cert_type: cert_type_value(fields.next()?)?,
+                key_tag: fields.number("key tag")?,
+                algorithm: fields.number("algorithm")?,
+                certificate: text::parse_base64(&fields.joined_rest())?,
+            },
+            QType::APL => {
+                let mut items = Vec::new();
+                while !fields.is_empty() {
+                    items.push(AplItem::from_text(fields.next()?)?);
+                }
+                RData::APL(items)
+            }
+            QType::DS => RData::DS {
+                key_tag: fields.number("key tag")?,
+                algorithm: fields.number("algorithm")?,
+                digest_type: fields.number("digest type")?,
+                digest: text::parse_hex(&fields.joined_rest())?,
+            },
+            QType::IPSECKEY => RData::IPSECKEY(Ipseckey::from_text(fields)?),
+            QType::RRSIG => RData::RRSIG(Rrsig {
+                type_covered: fields.next()?.parse::<QType>()?.value(),
+                algorithm: fields.number("algorithm")?,
+                labels: fields.number("labels")?,
+                original_ttl: fields.number("original ttl")?,
+                expiration: text::parse_timestamp(fields.next()?)?,
+                inception: text::parse_timestamp(fields.next()?)?,
+                key_tag: fields.number("key tag")?,
+                signer_name: fields.name()?,
+                signature: text::parse_base64(&fields.joined_rest())?,
+            }),
+            QType::NSEC => RData::NSEC {
+                next_domain: fields.name()?,
+                types: parse_type_list(fields)?,
+            },
+            QType::DNSKEY => RData::DNSKEY {
+                flags: fields.number("flags")?,
+                protocol: fields.number("protocol")?,
+                algorithm: fields.number("algorithm")?,
+                public_key: text::parse_base64(&fields.joined_rest())?,
+            },
+            QType::DHCID => RData::DHCID(text::parse_base64(&fields.joined_rest())?),
+            QType::NSEC3 => RData::NSEC3(Nsec3 {
+                hash_algorithm: fields.number("hash algorithm")?,
+                flags: fields.number("flags")?,
+                iterations: fields.number("iterations")?,
+                salt: parse_salt(fields.next()?)?,
+                next_hashed_owner: text::parse_base32hex(fields.next()?)?,
+                types: parse_type_list(fields)?,
+            }),
+            QType::NSEC3PARAM => RData::NSEC3PARAM {
+                hash_algorithm: fields.number("hash algorithm")?,
+                flags: fields.number("flags")?,
+                iterations: fields.number("iterations")?,
+                salt: parse_salt(fields.next()?)?,
+            },
+            QType::TLSA => RData::TLSA {
+                cert_usage: fields.number("certificate usage")?,
+                selector: fields.number("selector")?,
+                matching_type: fields.number("matching type")?,
+                cert_data: text::parse_hex(&fields.joined_rest())?,
+            },
+            QType::SMIMEA => RData::SMIMEA {
+                cert_usage: fields.number("certificate usage")?,
+                selector: fields.number("selector")?,
+                matching_type: fields.number("matching type")?,
+                cert_data: text::parse_hex(&fields.joined_rest())?,
+            },
+            QType::HIP => RData::HIP(Hip::from_text(fields)?),
+            QType::CDS => RData::CDS {
+                key_tag: fields.number("key tag")?,
+                algorithm: fields.number("algorithm")?,
+                digest_type: fields.number("digest type")?,
+                digest: text::parse_hex(&fields.joined_rest())?,
+            },
+            QType::CDNSKEY => RData::CDNSKEY {
+                flags: fields.number("flags")?,
+                protocol: fields.number("protocol")?,
+                algorithm: fields.number("algorithm")?,
+                public_key: text::parse_base64(&fields.joined_rest())?,
+            },
+            QType::OPENPGPKEY => RData::OPENPGPKEY(text::parse_base64(&fields.joined_rest())?),
+            QType::CSYNC => RData::CSYNC {
+                serial: fields.number("serial")?,
+                flags: fields.number("flags")?,
+                types: parse_type_list(fields)?,
+            },
+            QType::ZONEMD => RData::ZONEMD {
+                serial: fields.number("serial")?,
+                scheme: fields.number("scheme")?,
+                hash_algorithm: fields.number("hash algorithm")?,
+                digest: text::parse_hex(&fields.joined_rest())?,
+            },
+            QType::SVCB => RData::SVCB(Svcb::from_text(fields)?),
+            QType::HTTPS => RData::HTTPS(Svcb::from_text(fields)?),
+            QType::EUI48 => RData::EUI48(parse_eui(fields.next()?)?),
+            QType::EUI64 => RData::EUI64(parse_eui(fields.next()?)?),
+            QType::TKEY => RData::TKEY(Tkey {
+                algorithm: fields.name()?,
+                inception: text::parse_timestamp(fields.next()?)?,
+                expiration: text::parse_timestamp(fields.next()?)?,
+                mode: fields.number("mode")?,
+                error: fields.number("error")?,
+                key: parse_sized_base64(fields, "key")?,
+                other: parse_sized_base64(fields, "other data")?,
+            }),
+            QType::TSIG => RData::TSIG(Tsig {
+                algorithm: fields.name()?,
+                time_signed: fields
+                    .number("time signed")
+                    .ok()
+                    .filter(|t| *t < 1 << 48)
+                    .ok_or(TextError::BadField("time signed"))?,
+                fudge: fields.number("fudge")?,
+                mac: parse_sized_base64(fields, "mac")?,
+                original_id: fields.number("original id")?,
+                error: fields.number("error")?,
+                other: parse_sized_base64(fields, "other data")?,
+            }),
+            QType::URI => RData::URI {
+                priority: fields.number("priority")?,
+                weight: fields.number("weight")?,
+                target: text::unquote(fields.next()?)?,
+            },
+            // anything else only has the generic form.
+            _ => return Err(TextError::BadField("rdata")),
+        };
+        return Ok(rdata);
+    }
+
+    /// The canonical form of the rdata (RFC 4034 section 6.2): names lowercased in the types
+    /// that list requires, as amended by RFC 6840 section 5.1, which takes NSEC off it. Every
+    /// other type is returned unchanged.
+    pub fn to_canonical(&self) -> RData {
+        match self {
+            RData::NS(name) => RData::NS(name.to_lowercase()),
+            RData::CNAME(name) => RData::CNAME(name.to_lowercase()),
+            RData::PTR(name) => RData::PTR(name.to_lowercase()),
+            RData::SOA(soa) => RData::SOA(Soa {
+                mname: soa.mname.to_lowercase(),
+                rname: soa.rname.to_lowercase(),
+                ..soa.clone()
+            }),
+            RData::MINFO { rmailbx, emailbx } => RData::MINFO {
+                rmailbx: rmailbx.to_lowercase(),
+                emailbx: emailbx.to_lowercase(),
+            },
+            RData::MX {
+                preference,
+                exchange,
+            } => RData::MX {
+                preference: *preference,
+                exchange: exchange.to_lowercase(),
+            },
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => RData::SRV {
+                priority: *priority,
+                weight: *weight,
+                port: *port,
+                target: target.to_lowercase(),
+            },
+            RData::RP { mbox, txt } => RData::RP {
+                mbox: mbox.to_lowercase(),
+                txt: txt.to_lowercase(),
+            },
+            RData::AFSDB { subtype, hostname } => RData::AFSDB {
+                subtype: *subtype,
+                hostname: hostname.to_lowercase(),
+            },
+            RData::KX {
+                preference,
+                exchanger,
+            } => RData::KX {
+                preference: *preference,
+                exchanger: exchanger.to_lowercase(),
+            },
+            RData::RRSIG(rrsig) => RData::RRSIG(Rrsig {
+                signer_name: rrsig.signer_name.to_lowercase(),
+                ..rrsig.clone()
+            }),
+            _ => self.clone(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        self.write(&mut buf);
+        return buf.freeze();
+    }
+
+    /// Length of the wire form `write` produces, without writing it.
+    pub fn encoded_len(&self) -> usize {
+        return self.compressed_len(&mut NameCompressor::disabled(), 0);
+    }
+
+    /// What `write_compressed` takes at offset `at` of the message. Names are remembered in
+    /// `names` just as if they had been written.
+    pub(crate) fn compressed_len<'a>(&'a self, names: &mut NameCompressor<'a>, at: usize) -> usize {
+        match self {
+            RData::A(_) => 4,
+            RData::AAAA(_) => 16,
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => names.name_len(name, at),
+            RData::SOA(soa) => {
+                let mname = names.name_len(&soa.mname, at);
+                mname + names.name_len(&soa.rname, at + mname) + 20
+            }
+            RData::HINFO { cpu, os } => cpu.wire_len() + os.wire_len(),
+            RData::MINFO { rmailbx, emailbx } => {
+                let rmailbx = names.name_len(rmailbx, at);
+                rmailbx + names.name_len(emailbx, at + rmailbx)
+            }
+            RData::MX { exchange, .. } => 2 + names.name_len(exchange, at + 2),
+            RData::TXT(strings) => strings
+                .iter()
+                .map(|s| s.len() + s.len().div_ceil(255).max(1))
+                .sum(),
+            RData::RP { mbox, txt } => mbox.wire_len() + txt.wire_len(),
+            RData::AFSDB { hostname, .. } => 2 + hostname.wire_len(),
+            RData::SRV { target, .. } => 6 + target.wire_len(),
+            RData::KX { exchanger, .. } => 2 + exchanger.wire_len(),
+            RData::CERT { certificate, .. } => 5 + certificate.len(),
+            RData::APL(items) => items.iter().map(AplItem::wire_len).sum(),
+            RData::DS { digest, .. } | RData::CDS { digest, .. } => 4 + digest.len(),
+            RData::IPSECKEY(ipseckey) => ipseckey.wire_len(),
+            RData::RRSIG(rrsig) => rrsig.wire_len(),
+            RData::NSEC { next_domain, types } => next_domain.wire_len() + type_bitmap_len(types),
+            RData::DNSKEY { public_key, .. } | RData::CDNSKEY { public_key, .. } => {
+                4 + public_key.len()
+            }
+            RData::DHCID(digest) => digest.len(),
+            RData::NSEC3(nsec3) => nsec3.wire_len(),
+            RData::NSEC3PARAM { salt, .. } => 5 + salt.len(),
+            RData::TLSA { cert_data, .. } | RData::SMIMEA { cert_data, .. } => 3 + cert_data.len(),
+            RData::OPENPGPKEY(key) => key.len(),
+            RData::HIP(hip) => hip.wire_len(),
+            RData::CSYNC { types, .. } => 6 + type_bitmap_len(types),
+            RData::ZONEMD { digest, .. } => 6 + digest.len(),
+            RData::SVCB(svcb) | RData::HTTPS(svcb) => svcb.wire_len(),
+            RData::EUI48(_) => 6,
+            RData::EUI64(_) => 8,
+            RData::TKEY(tkey) => tkey.wire_len(),
+            RData::TSIG(tsig) => tsig.wire_len(),
+            RData::URI { target, .. } => 4 + target.len(),
+            RData::Unknown(_, bites) => bites.len(),
+        }
+    }
+
+    /// Appends the wire form of the rdata, without the length prefix. Names are written in full.
+    pub fn write(&self, buf: &mut BytesMut) {
+        self.write_compressed(buf, &mut NameCompressor::disabled());
+    }
+
+    /// Same as `write`, but names of the types RFC 1035 allows to be compressed go through
+    /// `names`. Every other type is written uncompressed, as RFC 3597 requires.
+    pub(crate) fn write_compressed<'a>(
+        &'a self,
+        buf: &mut BytesMut,
+        names: &mut NameCompressor<'a>,
+    ) {
+        match self {
+            RData::A(addr) => buf.extend_from_slice(&addr.octets()),
+            RData::AAAA(addr) => buf.extend_from_slice(&addr.octets()),
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => names.write_name(name, buf),
+            RData::SOA(soa) => soa.write(buf, names),
+            RData::HINFO { cpu, os } => {
+                cpu.write(buf);
+                os.write(buf);
+            }
+            RData::MINFO { rmailbx, emailbx } => {
+                names.write_name(rmailbx, buf);
+                names.write_name(emailbx, buf);
+            }
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                buf.put_u16(*preference);
+                names.write_name(exchange, buf);
+            }
+            RData::TXT(strings) => {
+                for s in strings {
+                    if s.is_empty() {
+                        buf.put_u8(0);
+                    }
+                    for chunk in s.chunks(255) {
+                        buf.put_u8(chunk.len() as u8);
+                        buf.put_slice(chunk);
+                    }
+                }
+            }
+            RData::RP { mbox, txt } => {
+                mbox.write(buf);
+                txt.write(buf);
+            }
+            RData::AFSDB { subtype, hostname } => {
+                buf.put_u16(*subtype);
+                hostname.write(buf);
+            }
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                buf.put_u16(*priority);
+                buf.put_u16(*weight);
+                buf.put_u16(*port);
+                target.write(buf);
+            }
+            RData::KX {
+                preference,
+                exchanger,
+            } => {
+                buf.put_u16(*preference);
+                exchanger.write(buf);
+            }
+            RData::CERT {
+                cert_type,
+                key_tag,
+                algorithm,
+                certificate,
+            } => {
+                buf.put_u16(*cert_type);
+                buf.put_u16(*key_tag);
+                buf.put_u8(*algorithm);
+                buf.put_slice(certificate);
+            }
+            RData::APL(items) => {
+                for item in items {
+                    item.write(buf);
+                }
+            }
+            RData::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            }
+            | RData::CDS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            } => {
+                buf.put_u16(*key_tag);
+                buf.put_u8(*algorithm);
+                buf.put_u8(*digest_type);
+                buf.put_slice(digest);
+            }
+            RData::IPSECKEY(ipseckey) => ipseckey.write(buf),
+            RData::RRSIG(rrsig) => rrsig.write(buf),
+            RData::NSEC { next_domain, types } => {
+                next_domain.write(buf);
+                write_type_bitmap(types, buf);
+            }
+            RData::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            }
+            | RData::CDNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            } => {
+                buf.put_u16(*flags);
+                buf.put_u8(*protocol);
+                buf.put_u8(*algorithm);
+                buf.put_slice(public_key);
+            }
+            RData::DHCID(digest) => buf.put_slice(digest),
+            RData::NSEC3(nsec3) => nsec3.write(buf),
+            RData::NSEC3PARAM {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+            } => {
+                buf.put_u8(*hash_algorithm);
+                buf.put_u8(*flags);
+                buf.put_u16(*iterations);
+                buf.put_u8(salt.len() as u8);
+                buf.put_slice(salt);
+            }
+            RData::TLSA {
+                cert_usage,
+                selector,
+                matching_type,
+                cert_data,
+            }
+            | RData::SMIMEA {
+                cert_usage,
+                selector,
+                matching_type,
+                cert_data,
+            } => {
+                buf.put_u8(*cert_usage);
+                buf.put_u8(*selector);
+                buf.put_u8(*matching_type);
+                buf.put_slice(cert_data);
+            }
+            RData::OPENPGPKEY(key) => buf.put_slice(key),
+            RData::HIP(hip) => hip.write(buf),
+            RData::CSYNC {
+                serial,
+                flags,
+                types,
+            } => {
+                buf.put_u32(*serial);
+                buf.put_u16(*flags);
+                write_type_bitmap(types, buf);
+            }
+            RData::ZONEMD {
+                serial,
+                scheme,
+                hash_algorithm,
+                digest,
+            } => {
+                buf.put_u32(*serial);
+                buf.put_u8(*scheme);
+                buf.put_u8(*hash_algorithm);
+                buf.put_slice(digest);
+            }
+            RData::SVCB(svcb) | RData::HTTPS(svcb) => svcb.write(buf),
+            RData::EUI48(octets) => buf.put_slice(octets),
+            RData::EUI64(octets) => buf.put_slice(octets),
+            RData::TKEY(tkey) => tkey.write(buf),
+            RData::TSIG(tsig) => tsig.write(buf),
+            RData::URI {
+                priority,
+                weight,
+                target,
+            } => {
+                buf.put_u16(*priority);
+                buf.put_u16(*weight);
+                buf.put_slice(target);
+            }
+            RData::Unknown(_, bites) => buf.extend_from_slice(bites),
+        }
+    }
+}
+
+/// Presentation form, as in a zone file: the fields after the record type. Types without a
+/// structured form use the generic `\# length hex` syntax of RFC 3597.
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RData::A(addr) => write!(f, "{}", addr),
+            RData::AAAA(addr) => write!(f, "{}", addr),
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => write!(f, "{}", name),
+            RData::SOA(soa) => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                soa.mname, soa.rname, soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum
+            ),
+            RData::HINFO { cpu, os } => {
+                text::write_quoted(f, cpu.as_bytes())?;
+                f.write_str(" ")?;
+                text::write_quoted(f, os.as_bytes())
+            }
+            RData::MINFO { rmailbx, emailbx } => write!(f, "{} {}", rmailbx, emailbx),
+            RData::MX {
+                preference,
+                exchange,
+            } => write!(f, "{} {}", preference, exchange),
+            RData::TXT(strings) => {
+                let mut chunks = strings.iter().flat_map(|s| {
+                    // an empty string is still one (empty) character-string.
+                    s.chunks(255).chain(s.is_empty().then_some(s.as_slice()))
+                });
+                match chunks.next() {
+                    Some(chunk) => text::write_quoted(f, chunk)?,
+                    // nothing to quote, so only the generic form (RFC 3597) can say it.
+                    None => return f.write_str("\\# 0"),
+                }
+                for chunk in chunks {
+                    f.write_str(" ")?;
+                    text::write_quoted(f, chunk)?;
+                }
+                Ok(())
+            }
+            RData::RP { mbox, txt } => write!(f, "{} {}", mbox, txt),
+            RData::AFSDB { subtype, hostname } => write!(f, "{} {}", subtype, hostname),
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "{} {} {} {}", priority, weight, port, target),
+            RData::KX {
+                preference,
+                exchanger,
+            } => write!(f, "{} {}", preference, exchanger),
+            RData::CERT {
+                cert_type,
+                key_tag,
+                algorithm,
+                certificate,
+            } => {
+                write!(
+                    f,
+                    "{} {} {} ",
+                    cert_type_name(*cert_type),
+                    key_tag,
+                    algorithm
+                )?;
+                text::write_base64(f, certificate)
+            }
+            RData::APL(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { " " } else { "" }, item)?;
+                }
+                Ok(())
+            }
+            RData::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            }
+            | RData::CDS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            } => {
+                write!(f, "{} {} {} ", key_tag, algorithm, digest_type)?;
+                text::write_hex(f, digest)
+            }
+            RData::IPSECKEY(ipseckey) => write!(f, "{}", ipseckey),
+            RData::RRSIG(rrsig) => {
+                write!(
+                    f,
+                    "{} {} {} {} ",
+                    QType::from_value(rrsig.type_covered),
+                    rrsig.algorithm,
+                    rrsig.labels,
+                    rrsig.original_ttl
+                )?;
+                text::write_timestamp(f, rrsig.expiration)?;
+                f.write_str(" ")?;
+                text::write_timestamp(f, rrsig.inception)?;
+                write!(f, " {} {} ", rrsig.key_tag, rrsig.signer_name)?;
+                text::write_base64(f, &rrsig.signature)
+            }
+            RData::NSEC { next_domain, types } => {
+                write!(f, "{}", next_domain)?;
+                write_type_list(f, types)
+            }
+            RData::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            }
+            | RData::CDNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            } => {
+                write!(f, "{} {} {} ", flags, protocol, algorithm)?;
+                text::write_base64(f, public_key)
+            }
+            RData::DHCID(digest) => text::write_base64(f, digest),
+            RData::NSEC3(nsec3) => {
+                write!(
+                    f,
+                    "{} {} {} ",
+                    nsec3.hash_algorithm, nsec3.flags, nsec3.iterations
+                )?;
+                write_salt(f, &nsec3.salt)?;
+                f.write_str(" ")?;
+                text::write_base32hex(f, &nsec3.next_hashed_owner)?;
+                write_type_list(f, &nsec3.types)
+            }
+            RData::NSEC3PARAM {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+            } => {
+                write!(f, "{} {} {} ", hash_algorithm, flags, iterations)?;
+                write_salt(f, salt)
+            }
+            RData::TLSA {
+                cert_usage,
+                selector,
+                matching_type,
+                cert_data,
+            }
+            | RData::SMIMEA {
+                cert_usage,
+                selector,
+                matching_type,
+                cert_data,
+            } => {
+                write!(f, "{} {} {} ", cert_usage, selector, matching_type)?;
+                text::write_hex(f, cert_data)
+            }
+            RData::OPENPGPKEY(key) => text::write_base64(f, key),
+            RData::HIP(hip) => write!(f, "{}", hip),
+            RData::CSYNC {
+                serial,
+                flags,
+                types,
+            } => {
+                write!(f, "{} {}", serial, flags)?;
+                write_type_list(f, types)
+            }
+            RData::ZONEMD {
+                serial,
+                scheme,
+                hash_algorithm,
+                digest,
+            } => {
+                write!(f, "{} {} {} ", serial, scheme, hash_algorithm)?;
+                text::write_hex(f, digest)
+            }
+            RData::SVCB(svcb) | RData::HTTPS(svcb) => {
+                write!(f, "{} {}", svcb.priority, svcb.target)?;
+                for param in &svcb.params {
+                    write!(f, " {}", param)?;
+                }
+                Ok(())
+            }
+            RData::EUI48(octets) => write_eui(f, octets),
+            RData::EUI64(octets) => write_eui(f, octets),
+            RData::TKEY(tkey) => {
+                write!(f, "{} ", tkey.algorithm)?;
+                text::write_timestamp(f, tkey.inception)?;
+                f.write_str(" ")?;
+                text::write_timestamp(f, tkey.expiration)?;
+                write!(f, " {} {} ", tkey.mode, tkey.error)?;
+                write_sized_base64(f, &tkey.key)?;
+                f.write_str(" ")?;
+                write_sized_base64(f, &tkey.other)
+            }
+            RData::TSIG(tsig) => {
+                write!(f, "{} {} {} ", tsig.algorithm, tsig.time_signed, tsig.fudge)?;
+                write_sized_base64(f, &tsig.mac)?;
+                write!(f, " {} {} ", tsig.original_id, tsig.error)?;
+                write_sized_base64(f, &tsig.other)
+            }
+            RData::URI {
+                priority,
+                weight,
+                target,
+            } => {
+                write!(f, "{} {} ", priority, weight)?;
+                text::write_quoted(f, target)
+            }
+            RData::Unknown(_, bites) => {
+                write!(f, "\\# {}", bites.len())?;
+                if !bites.is_empty() {
+                    f.write_str(" ")?;
+                    text::write_hex(f, bites)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// `key=value` as in RFC 9460 section 2.1.
+impl fmt::Display for SvcParam {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SvcParam::Mandatory(keys) => {
+                f.write_str("mandatory=")?;
+                for (i, key) in keys.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    f.write_str(&svc_param_key_name(*key))?;
+                }
+                Ok(())
+            }
+            SvcParam::Alpn(ids) => {
+                f.write_str("alpn=")?;
+                let mut list = Vec::new();
+                for (i, id) in ids.iter().enumerate() {
+                    if i > 0 {
+                        list.push(b',');
+                    }
+                    for &b in id {
+                        if b == b',' || b == b'\\' {
+                            list.push(b'\\');
+                        }
+                        list.push(b);
+                    }
+                }
+                text::write_quoted(f, &list)
+            }
+            SvcParam::NoDefaultAlpn => f.write_str("no-default-alpn"),
+            SvcParam::Port(port) => write!(f, "port={}", port),
+            SvcParam::Ipv4Hint(addrs) => {
+                f.write_str("ipv4hint=")?;
+                for (i, addr) in addrs.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { "," } else { "" }, addr)?;
+                }
+                Ok(())
+            }
+            SvcParam::Ech(ech) => {
+                f.write_str("ech=")?;
+                text::write_base64(f, ech)
+            }
+            SvcParam::Ipv6Hint(addrs) => {
+                f.write_str("ipv6hint=")?;
+                for (i, addr) in addrs.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { "," } else { "" }, addr)?;
+                }
+                Ok(())
+            }
+            SvcParam::Unknown(key, value) => {
+                write!(f, "key{}=", key)?;
+                text::write_quoted(f, value)
+            }
+        }
+    }
+}
+
+/// Name of an SvcParamKey in presentation form.
+fn svc_param_key_name(key: u16) -> String {
+    let name = match key {
+        0 => "mandatory",
+        1 => "alpn",
+        2 => "no-default-alpn",
+        3 => "port",
+        4 => "ipv4hint",
+        5 => "ech",
+        6 => "ipv6hint",
+        _ => return format!("key{}", key),
+    };
+    return String::from(name);
+}
+
+/// Code of an SvcParamKey in presentation form, the inverse of `svc_param_key_name`.
+fn svc_param_key_value(name: &str) -> Result<u16, TextError> {
+    let key = match name {
+        "mandatory" => 0,
+        "alpn" => 1,
+        "no-default-alpn" => 2,
+        "port" => 3,
+        "ipv4hint" => 4,
+        "ech" => 5,
+        "ipv6hint" => 6,
+        _ => name
+            .strip_prefix("key")
+            .and_then(|k| k.parse().ok())
+            .ok_or(TextError::BadField("svc param key"))?,
+    };
+    return Ok(key);
+}
+
+/// Mnemonic of a CERT certificate type (RFC 4398 section 2.1), or the number for types without
+/// one.
+fn cert_type_name(cert_type: u16) -> String {
+    let name = match cert_type {
+        1 => "PKIX",
+        2 => "SPKI",
+        3 => "PGP",
+        4 => "IPKIX",
+        5 => "ISPKI",
+        6 => "IPGP",
+        7 => "ACPKIX",
+        8 => "IACPKIX",
+        253 => "URI",
+        254 => "OID",
+        _ => return cert_type.to_string(),
+    };
+    return String::from(name);
+}
+
+/// A CERT certificate type as a mnemonic in any case or a number, the inverse of
+/// `cert_type_name`.
+fn cert_type_value(field: &str) -> Result<u16, TextError> {
+    let cert_type = match field.to_ascii_uppercase().as_str() {
+        "PKIX" => 1,
+        "SPKI" => 2,
+        "PGP" => 3,
+        "IPKIX" => 4,
+        "ISPKI" => 5,
+        "IPGP" => 6,
+        "ACPKIX" => 7,
+        "IACPKIX" => 8,
+        "URI" => 253,
+        "OID" => 254,
+        _ => field
+            .parse()
+            .map_err(|_| TextError::BadField("certificate type"))?,
+    };
+    return Ok(cert_type);
+}
+
+/// A length followed by that many bytes in base64, with nothing after the length if it is 0, as
+/// TKEY and TSIG fields are shown.
+fn parse_sized_base64(fields: &mut Fields, what: &'static str) -> Result<Vec<u8>, TextError> {
+    let len: usize = fields.number(what)?;
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    let bites = text::parse_base64(fields.next()?)?;
+    if bites.len() != len {
+        return Err(TextError::BadField(what));
+    }
+    return Ok(bites);
+}
+
+/// Reads the rest of an NSEC, NSEC3 or CSYNC record as type mnemonics.
+fn parse_type_list(fields: &mut Fields) -> Result<Vec<u16>, TextError> {
+    let mut types = Vec::new();
+    while !fields.is_empty() {
+        types.push(fields.next()?.parse::<QType>()?.value());
+    }
+    return Ok(types);
+}
+
+/// The items of a value-list (RFC 9460 appendix A.1), with the character-string escapes of
+/// `value` already resolved. Items are split at commas; `\,` and `\\` put a comma or a backslash
+/// into an item.
+fn split_value_list(value: &[u8]) -> Result<Vec<Vec<u8>>, TextError> {
+    let mut items = Vec::new();
+    let mut item = Vec::new();
+    let mut bites = value.iter();
+    while let Some(&b) = bites.next() {
+        match b {
+            b'\\' => item.push(*bites.next().ok_or(TextError::BadField("value-list"))?),
+            b',' => items.push(core::mem::take(&mut item)),
+            _ => item.push(b),
+        }
+    }
+    items.push(item);
+    return Ok(items);
+}
+
+/// A character-string field, quoted or not, of at most 255 bytes.
+fn parse_character_string(field: &str) -> Result<Vec<u8>, TextError> {
+    let bites = text::unquote(field)?;
+    if bites.len() > MAX_CHARACTER_STRING_LEN {
+        return Err(TextError::BadField("character-string"));
+    }
+    return Ok(bites);
+}
+
+/// An EUI48 or EUI64 address: pairs of hex digits separated by `-` as in RFC 7043, or by `:`
+/// as MAC addresses usually are.
+fn parse_eui<const N: usize>(field: &str) -> Result<[u8; N], TextError> {
+    let bad = TextError::BadField("address");
+    let mut octets = [0u8; N];
+    let mut pairs = field.split(['-', ':']);
+    for octet in octets.iter_mut() {
+        let pair = pairs
+            .next()
+            .filter(|p| p.len() == 2 && p.bytes().all(|b| b.is_ascii_hexdigit()))
+            .ok_or(bad)?;
+        *octet = u8::from_str_radix(pair, 16).map_err(|_| bad)?;
+    }
+    if pairs.next().is_some() {
+        return Err(bad);
+    }
+    return Ok(octets);
+}
+
+/// An NSEC3 salt in hex, `-` for none.
+fn parse_salt(field: &str) -> Result<Vec<u8>, TextError> {
+    if field == "-" {
+        return Ok(Vec::new());
+    }
+    return text::parse_hex(field);
+}
+
+/// The types of an NSEC, NSEC3 or CSYNC bitmap, each preceded by a space.
+fn write_type_list(f: &mut fmt::Formatter<'_>, types: &[u16]) -> fmt::Result {
+    for t in types {
+        write!(f, " {}", QType::from_value(*t))?;
+    }
+    return Ok(());
+}
+
+/// An EUI48 or EUI64 address in the form of RFC 7043: lowercase hex pairs separated by `-`.
+fn write_eui(f: &mut fmt::Formatter<'_>, octets: &[u8]) -> fmt::Result {
+    for (i, b) in octets.iter().enumerate() {
+        write!(f, "{}{:02x}", if i > 0 { "-" } else { "" }, b)?;
+    }
+    return Ok(());
+}
+
+/// The length of `bites`, then `bites` in base64 unless there are none.
+fn write_sized_base64(f: &mut fmt::Formatter<'_>, bites: &[u8]) -> fmt::Result {
+    write!(f, "{}", bites.len())?;
+    if !bites.is_empty() {
+        f.write_str(" ")?;
+        text::write_base64(f, bites)?;
+    }
+    return Ok(());
+}
+
+/// An NSEC3 salt in hex, or `-` if it is empty.
+fn write_salt(f: &mut fmt::Formatter<'_>, salt: &[u8]) -> fmt::Result {
+    if salt.is_empty() {
+        return f.write_str("-");
+    }
+    return text::write_hex(f, salt);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Record;
+    use alloc::{string::ToString, vec};
+
+    /// Parses `rdata` as `tipe` and writes it back out.
+    fn wire_round_trip(tipe: QType, rdata: &[u8]) -> (RData, Bytes) {
+        let (rest, parsed) = RData::parse(&tipe, rdata, rdata).unwrap();
+        assert!(rest.is_empty());
+        let written = parsed.to_bytes();
+        return (parsed, written);
+    }
+
+    /// Writes `rdata` in presentation form as part of a record, and reads it back.
+    fn text_round_trip(tipe: &str, rdata: &RData) -> RData {
+        let line = alloc::format!("example.com. 300 IN {} {}", tipe, rdata);
+        return line.parse::<Record>().unwrap().rdata;
+    }
+
+    #[test]
+    fn txt_keeps_bytes_that_are_not_utf8() {
+        let (txt, written) = wire_round_trip(QType::TXT, b"\x03\xff\xfe\x80\x02hi");
+        assert_eq!(&written[..], b"\x03\xff\xfe\x80\x02hi");
+        assert_eq!(txt.to_string(), "\"\\255\\254\\128\" \"hi\"");
+        assert_eq!(text_round_trip("TXT", &txt), txt);
+    }
+
+    #[test]
+    fn txt_keeps_a_full_character_string_whole() {
+        let mut rdata = vec![255];
+        rdata.extend([0xe9; 255]);
+        let (_, written) = wire_round_trip(QType::TXT, &rdata);
+        assert_eq!(&written[..], &rdata[..]);
+    }
+
+    #[test]
+    fn txt_without_strings_is_empty_rdata() {
+        let (txt, written) = wire_round_trip(QType::TXT, b"");
+        assert_eq!(txt, RData::TXT(vec![]));
+        assert!(written.is_empty());
+        assert_eq!(txt.encoded_len(), 0);
+        assert_eq!(txt.to_string(), "\\# 0");
+        assert_eq!(text_round_trip("TXT", &txt), txt);
+    }
+
+    #[test]
+    fn character_strings_over_255_bytes_are_rejected() {
+        assert!(CharacterString::try_from(vec![b'x'; 255]).is_ok());
+        assert_eq!(
+            CharacterString::try_from(vec![b'x'; 256]),
+            Err(CharacterStringTooLong)
+        );
+        let long = alloc::format!("example.com. 300 IN HINFO {} Linux", "x".repeat(256));
+        assert!(long.parse::<Record>().is_err());
+    }
+
+    #[test]
+    fn hinfo_keeps_bytes_that_are_not_utf8() {
+        let mut rdata = vec![255];
+        rdata.extend([0xff; 255]);
+        rdata.extend(b"\x05Linux");
+        let (hinfo, written) = wire_round_trip(QType::HINFO, &rdata);
+        assert_eq!(&written[..], &rdata[..]);
+        assert_eq!(text_round_trip("HINFO", &hinfo), hinfo);
+    }
+
+    #[test]
+    fn uri_keeps_bytes_that_are_not_utf8() {
+        let rdata = b"\x00\x01\x00\x02https://example.com/\xe9t\xe9";
+        let (uri, written) = wire_round_trip(QType::URI, rdata);
+        assert_eq!(&written[..], &rdata[..]);
+        assert_eq!(text_round_trip("URI", &uri), uri);
+    }
+
+    #[test]
+    fn alpn_keeps_bytes_that_are_not_utf8() {
+        // priority 1, target ".", alpn = ["h2", "\xfe\xff"]
+        let rdata = b"\x00\x01\x00\x00\x01\x00\x06\x02h2\x02\xfe\xff";
+        let (svcb, written) = wire_round_trip(QType::SVCB, rdata);
+        assert_eq!(&written[..], &rdata[..]);
+        assert_eq!(text_round_trip("SVCB", &svcb), svcb);
+    }
+
+    #[test]
+    fn alpn_ids_may_hold_commas() {
+        // RFC 9460 appendix D.2, figure 9: "f\oo,bar" and "h2".
+        let rdata = b"\x00\x10\x03foo\x07example\x03org\x00\x00\x01\x00\x0c\x08f\\oo,bar\x02h2";
+        let expected = RData::SVCB(Svcb {
+            priority: 16,
+            target: "foo.example.org".parse().unwrap(),
+            params: vec![SvcParam::Alpn(vec![b"f\\oo,bar".to_vec(), b"h2".to_vec()])],
+        });
+        for text in [
+            r#"16 foo.example.org. alpn="f\\\\oo\\,bar,h2""#,
+            r#"16 foo.example.org. alpn=f\\\092oo\092,bar,h2"#,
+        ] {
+            let line = alloc::format!("example.com. 300 IN SVCB {}", text);
+            assert_eq!(line.parse::<Record>().unwrap().rdata, expected);
+        }
+        let (svcb, written) = wire_round_trip(QType::SVCB, rdata);
+        assert_eq!(svcb, expected);
+        assert_eq!(&written[..], &rdata[..]);
+        assert_eq!(
+            svcb.to_string(),
+            r#"16 foo.example.org. alpn="f\\\\oo\\,bar,h2""#
+        );
+        assert_eq!(text_round_trip("SVCB", &svcb), svcb);
+    }
+}
@@ -1,75 +1,1821 @@
+use bytes::{Bytes, BytesMut};
 use std::{
-    collections::HashMap,
-    net::{SocketAddr, UdpSocket},
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::message::{Answer, Message, QType, ResourceClass};
+use crate::acl::Acl;
+use crate::bailiwick;
+use crate::cache::{self, Asked, Cache};
+use crate::clock::{Clock, Deadline, SystemClock};
+use crate::logging::LogLimiter;
+use crate::message::{
+    rcode, Edns, EdnsOption, Header, LazyMessage, Message, Opcode, QType, Question, Record,
+    ResourceClass,
+};
+use crate::name::Name;
+use crate::pool::BufferPool;
+use crate::rdata::RData;
+use crate::sockets::UpstreamSockets;
+use crate::stats::{Counter, Stats};
+use crate::tcp::{self, ConnId};
+use crate::udp;
+use crate::upstream::{Selection, Upstreams};
+use crate::zone::{Zone, Zones};
+
+/// Smallest receive buffer when it is sized from `max_udp_payload`: EDNS clients may send
+/// queries well over 512 bytes, e.g. with padding, whatever payload size we advertise.
+const MIN_RECV_BUFFER: usize = 4096;
+
+/// Least time between two warnings about single packets, which a flood could otherwise turn
+/// into a flood of log lines.
+const PACKET_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most packet buffers kept for reuse, enough for a few batches of responses.
+const MAX_FREE_BUFFERS: usize = 256;
+
+/// Room a new packet buffer starts out with, enough for a response without EDNS.
+const BUFFER_SIZE: usize = 512;
+
+/// What to do with a query that arrives while the server is at one of its in-flight limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverloadAction {
+    /// Answer SERVFAIL right away, so the client can move on to another server.
+    #[default]
+    ServFail,
+    /// Don't answer at all. Queries over TCP still get SERVFAIL, since a connection only makes
+    /// progress while its queries are being answered.
+    Drop,
+}
+
+/// How to answer a query without a question, e.g. a cookie-only probe or a keepalive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ZeroQuestionPolicy {
+    /// Empty NOERROR if the query carries an OPT record, FORMERR otherwise.
+    #[default]
+    NoErrorWithEdns,
+    /// Always an empty NOERROR.
+    NoError,
+    /// Always FORMERR.
+    FormErr,
+}
+
+/// What the forwarding path does with a query carrying more than one question.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MultiQuestionPolicy {
+    /// Answer FORMERR, like virtually every real resolver.
+    #[default]
+    FormErr,
+    /// Compatibility mode: send one upstream query per question and merge the answers into a
+    /// single response. Its rcode is the first non-NOERROR rcode among the upstream responses.
+    SplitAndMerge,
+}
+
+/// What the forwarding path does with an EDNS client subnet option (RFC 7871) in a client's
+/// query before sending it upstream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientSubnetPolicy {
+    /// Pass the option on as the client sent it.
+    #[default]
+    Forward,
+    /// Pass it on with the source prefix cut to at most this many bits, for IPv4 and IPv6
+    /// respectively. RFC 7871 recommends 24 and 56.
+    Truncate { v4: u8, v6: u8 },
+    /// Remove the option, so upstreams learn nothing about the client's network.
+    Strip,
+}
+
+impl ClientSubnetPolicy {
+    /// Applies the policy to the options of a query about to go upstream.
+    fn apply(&self, options: &mut Vec<EdnsOption>) {
+        match *self {
+            ClientSubnetPolicy::Forward => {}
+            ClientSubnetPolicy::Truncate { v4, v6 } => {
+                for option in options.iter_mut() {
+                    if let EdnsOption::ClientSubnet {
+                        source_prefix,
+                        address,
+                        ..
+                    } = option
+                    {
+                        let max = if address.is_ipv4() { v4 } else { v6 };
+                        *source_prefix = (*source_prefix).min(max);
+                    }
+                }
+            }
+            ClientSubnetPolicy::Strip => {
+                options.retain(|o| !matches!(o, EdnsOption::ClientSubnet { .. }))
+            }
+        }
+    }
+}
+
+/// What a response to the client carries of an EDNS option this crate has no structured form
+/// for. Queries always take such options upstream unchanged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownOptionAction {
+    /// Leave the option out of the response.
+    #[default]
+    Strip,
+    /// Echo the option back as the client sent it in the query.
+    Copy,
+    /// Relay the option as the upstream resolver sent it in its response.
+    PassThrough,
+}
+
+/// Per option code forwarding policy for unknown EDNS options, see `UnknownOptionAction`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnknownOptionPolicy {
+    /// action for option codes without an entry in `per_code`
+    pub default: UnknownOptionAction,
+    pub per_code: BTreeMap<u16, UnknownOptionAction>,
+}
+
+impl UnknownOptionPolicy {
+    pub fn action(&self, code: u16) -> UnknownOptionAction {
+        return self.per_code.get(&code).copied().unwrap_or(self.default);
+    }
+
+    /// The unknown options of an upstream response worth keeping for the client.
+    fn relayed(&self, options: &[EdnsOption]) -> Vec<EdnsOption> {
+        return options
+            .iter()
+            .filter(|o| {
+                matches!(o, EdnsOption::Unknown(code, _)
+                    if self.action(*code) == UnknownOptionAction::PassThrough)
+            })
+            .cloned()
+            .collect();
+    }
+
+    /// The unknown options of the client's query that are echoed in the response.
+    fn copied(&self, options: &[EdnsOption]) -> Vec<EdnsOption> {
+        return options
+            .iter()
+            .filter(|o| {
+                matches!(o, EdnsOption::Unknown(code, _)
+                    if self.action(*code) == UnknownOptionAction::Copy)
+            })
+            .cloned()
+            .collect();
+    }
+}
+
+/// Whether messages get the EDNS padding option (RFC 7830). Padding only hides message sizes on
+/// encrypted transports; in the clear it just costs bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PaddingPolicy {
+    /// Leave messages unpadded. Padding in forwarded queries is passed on as received.
+    #[default]
+    Off,
+    /// Pad queries sent upstream and responses to EDNS clients to a multiple of these sizes.
+    /// RFC 8467 recommends 128 for queries and 468 for responses.
+    Block { query: u16, response: u16 },
+}
+
+/// Where a query came from, and so how its response goes back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Client {
+    Udp(SocketAddr),
+    /// A query read off a TCP connection. Its response is queued for the TCP layer, see
+    /// `DnsServer::take_tcp_responses`.
+    Tcp(ConnId, SocketAddr),
+}
+
+impl Client {
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            Client::Udp(addr) | Client::Tcp(_, addr) => *addr,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Relay single-question queries to the resolver after only rewriting the header id, without
+    /// parsing or re-encoding them. Anything the fast path can't handle falls back to `process`,
+    /// as do queries with additional records, like OPT, unless `client_subnet` forwards the
+    /// option as it is. Ignored while any of `fast_path_conflicts` is set.
+    pub fast_forward: bool,
+    /// Hand batches of packets for the same destination to the kernel in one send using UDP
+    /// segmentation offload, where the platform supports it: the queries a client query splits
+    /// into, and with `udp_batch` above 1, the responses queued for the same client.
+    pub udp_offload: bool,
+    /// Datagrams read off a socket, and responses written to the client socket, per syscall,
+    /// using recvmmsg and sendmmsg where the platform has them. Above 1, UDP responses are
+    /// queued until `DnsServer::flush_responses`; 1 sends each as soon as it is ready.
+    pub udp_batch: usize,
+    /// Cap on client queries waiting for the upstream resolver at any one time.
+    pub max_inflight: Option<usize>,
+    /// Cap on queries a single client address may have waiting for the upstream resolver.
+    pub max_inflight_per_client: Option<usize>,
+    /// What happens to queries over either cap.
+    pub overload_action: OverloadAction,
+    /// Total time a client query may take, across every upstream attempt. Queries still
+    /// unanswered when it runs out get SERVFAIL and their state is dropped.
+    pub query_budget: Duration,
+    /// Largest UDP payload we advertise and accept over EDNS, which also bounds what upstream
+    /// resolvers may send us.
+    pub max_udp_payload: u16,
+    /// Largest datagram handled, client query or upstream answer. None sizes it from
+    /// `max_udp_payload`, and at least 4096 bytes. Datagrams that don't fit are dropped rather
+    /// than handled cut short.
+    pub recv_buffer_size: Option<u16>,
+    /// Largest UDP response we send to an EDNS client. Answers are built up to the size the
+    /// client advertised, clamped to this, and only truncated beyond it. Clients without EDNS
+    /// get 512 bytes.
+    pub max_response_payload: u16,
+    /// How queries with no question are answered.
+    pub zero_question: ZeroQuestionPolicy,
+    /// How forwarded queries with several questions are handled.
+    pub multi_question: MultiQuestionPolicy,
+    /// What forwarded queries carry of the client subnet option.
+    pub client_subnet: ClientSubnetPolicy,
+    /// Block padding for queries sent upstream and responses.
+    pub padding: PaddingPolicy,
+    /// Which unknown EDNS options make it back to the client.
+    pub unknown_options: UnknownOptionPolicy,
+    /// Identifier of this server instance, returned in the NSID option (RFC 5001) to clients
+    /// that ask for it. None leaves requests for it unanswered.
+    pub nsid: Option<Vec<u8>>,
+    /// Ask the upstream resolver again over TCP when its UDP answer comes back truncated. When
+    /// off, or when the retry fails, the client gets the truncated answer with TC set and can
+    /// retry over TCP itself. The fast path always relays truncated answers as they are.
+    pub tcp_retry: bool,
+    /// Queries in a row an upstream may leave unanswered before it is marked down and queries
+    /// go to the other upstreams.
+    pub upstream_max_failures: u32,
+    /// How often an upstream marked down is probed to see whether it is back.
+    pub upstream_probe_interval: Duration,
+    /// Which of the upstreams that are up each query goes to.
+    pub upstream_selection: Selection,
+    /// Sockets, each on a random port, upstream queries go out from, per address family. The
+    /// port is one more thing an off-path attacker has to guess to spoof an answer.
+    pub upstream_sockets: usize,
+    /// Queries each of those sockets sends before it is replaced by one on a new port.
+    pub upstream_socket_queries: u32,
+    /// Flip the case of letters in names sent upstream at random (DNS 0x20) and drop answers
+    /// that don't echo it back exactly, so spoofing an answer takes guessing that too. Clients
+    /// get their names back the way they wrote them.
+    pub randomize_case: bool,
+    /// Upstreams that get names as the client wrote them even with `randomize_case` on, for
+    /// the few servers that don't preserve case in their answers.
+    pub verbatim_case_upstreams: Vec<SocketAddr>,
+    /// Clients allowed recursion, i.e. to have queries forwarded upstream and answered from the
+    /// cache; any other client gets REFUSED. None allows every client.
+    pub recursion_acl: Option<Acl>,
+    /// Answer only for the zones given to `DnsServer::with_zones`, REFUSED for anything else:
+    /// nothing is forwarded or answered from the cache, and RA is never set. For running as a
+    /// public authoritative server.
+    pub authoritative_only: bool,
+    /// Most answers kept in the cache; 0 turns caching off.
+    pub cache_size: usize,
+    /// Times a cached answer must be served before it is refreshed from upstream shortly ahead
+    /// of its expiry, so clients of popular names never see a miss; 0 turns prefetching off.
+    pub prefetch_hits: u32,
+    /// Bounds, in seconds, the TTLs of cached records are clamped to, e.g. to keep answers
+    /// with TTLs of a second or two cached a little longer, or to cap week long TTLs.
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+    /// Clamp the TTLs of answers forwarded to clients to the same bounds, so clients don't
+    /// cache them any longer or shorter than we do.
+    pub clamp_forwarded_ttls: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            fast_forward: false,
+            udp_offload: false,
+            udp_batch: 1,
+            max_inflight: None,
+            max_inflight_per_client: None,
+            overload_action: OverloadAction::default(),
+            query_budget: Duration::from_secs(2),
+            max_udp_payload: 1232,
+            recv_buffer_size: None,
+            max_response_payload: 4096,
+            zero_question: ZeroQuestionPolicy::default(),
+            multi_question: MultiQuestionPolicy::default(),
+            client_subnet: ClientSubnetPolicy::default(),
+            padding: PaddingPolicy::default(),
+            unknown_options: UnknownOptionPolicy::default(),
+            nsid: None,
+            tcp_retry: true,
+            upstream_max_failures: 3,
+            upstream_probe_interval: Duration::from_secs(5),
+            upstream_selection: Selection::default(),
+            upstream_sockets: 16,
+            upstream_socket_queries: 100,
+            randomize_case: false,
+            verbatim_case_upstreams: Vec::new(),
+            recursion_acl: None,
+            authoritative_only: false,
+            cache_size: 4096,
+            prefetch_hits: 3,
+            min_ttl: 0,
+            max_ttl: 86400,
+            clamp_forwarded_ttls: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The settings in effect that only the full path honors, by field name. While there are any,
+    /// `fast_forward` is ignored and every query takes the full path.
+    pub fn fast_path_conflicts(&self) -> Vec<&'static str> {
+        let mut conflicts = Vec::new();
+        if self.cache_size > 0 {
+            conflicts.push("cache_size");
+        }
+        if self.padding != PaddingPolicy::Off {
+            conflicts.push("padding");
+        }
+        if self.nsid.is_some() {
+            conflicts.push("nsid");
+        }
+        if self.clamp_forwarded_ttls {
+            conflicts.push("clamp_forwarded_ttls");
+        }
+        // the fast path relays whatever options the upstream answers with.
+        let relays = |action: &UnknownOptionAction| *action == UnknownOptionAction::PassThrough;
+        if !relays(&self.unknown_options.default)
+            || !self.unknown_options.per_code.values().all(relays)
+        {
+            conflicts.push("unknown_options");
+        }
+        return conflicts;
+    }
+}
+
+/// A client query on the full path, waiting for upstream answers.
+struct Pending {
+    /// the client's query, with the client's id, collecting the answers as they arrive
+    query: Message,
+    client: Client,
+    /// where the upstream queries went, from which of the upstream sockets, and when
+    upstream: SocketAddr,
+    slot: usize,
+    sent_at: Instant,
+    /// questions still waiting for an upstream answer, as they were sent; each one went up in
+    /// its own query
+    unanswered: Vec<Question>,
+    /// whether their names went up in randomized case, which answers must then echo exactly
+    randomized_case: bool,
+    deadline: Deadline,
+    /// unknown EDNS options from upstream answers to pass on to the client
+    relayed_options: Vec<EdnsOption>,
+    /// queries from other clients asking the same thing, answered along with this one
+    waiters: Vec<(Message, Client)>,
+}
+
+impl Pending {
+    /// Which of the unanswered questions `answer` is the answer to: the one in its question
+    /// section. A truncated answer may leave the section empty, and then stands for the first.
+    fn answered(&self, answer: &Message) -> Option<usize> {
+        return match answer.questions.as_slice() {
+            [] if answer.header.tc && !self.unanswered.is_empty() => Some(0),
+            [a] => self.unanswered.iter().position(|q| {
+                same_question(q, a) && (!self.randomized_case || same_case(&q.name, &a.name))
+            }),
+            _ => None,
+        };
+    }
+}
+
+/// A query refreshing a cached answer about to expire, waiting for the upstream answer.
+struct Prefetch {
+    question: Question,
+    /// how the query that set it off asked the question
+    asked: Asked,
+    /// the name as sent, if its case was randomized
+    sent_name: Option<Name>,
+    upstream: SocketAddr,
+    slot: usize,
+    sent_at: Instant,
+    deadline: Deadline,
+}
+
+/// A query relayed by the fast path, waiting for the upstream answer.
+struct Relayed {
+    /// header and question of the client's query, kept to answer SERVFAIL with if the upstream
+    /// never answers
+    query: Message,
+    client: SocketAddr,
+    /// the name as sent, if its case was randomized
+    sent_name: Option<Name>,
+    upstream: SocketAddr,
+    slot: usize,
+    sent_at: Instant,
+    deadline: Deadline,
+}
 
 pub struct DnsServer {
-    resolver: Option<SocketAddr>,
-    source_map: HashMap<u16, (u16, SocketAddr)>,
-    orig_messages: HashMap<u16, Message>,
+    /// where queries are forwarded to; without any, queries are answered locally
+    upstreams: Upstreams,
+    /// zones answered for with authority, whatever the upstreams say
+    zones: Zones,
+    config: ServerConfig,
+    /// whether queries may take the fast path: `fast_forward` is on and nothing configured
+    /// needs the full path
+    fast_path: bool,
+    /// upstream id -> client query waiting for its answers. Upstream ids are picked fresh, so
+    /// clients that happen to use the same id don't get in each other's way.
+    pending: HashMap<u16, Pending>,
+    /// question -> upstream id of the pending query asking it alone, for identical queries to
+    /// wait on instead of going upstream again
+    asking: HashMap<(Name, u16, u16), u16>,
+    /// results of TCP retries running on worker threads: the truncated UDP answer, and the
+    /// answer read over TCP
+    tcp_retry_tx: Sender<(Message, io::Result<Bytes>)>,
+    tcp_retry_rx: Receiver<(Message, io::Result<Bytes>)>,
+    /// upstream id -> query relayed by the fast path
+    relay_map: HashMap<u16, Relayed>,
+    /// upstream id -> (upstream, socket slot, deadline for the answer), for probes of upstreams
+    /// marked down
+    probes: HashMap<u16, (SocketAddr, usize, Deadline)>,
+    /// the sockets upstream queries go out on, and answers come back on
+    sockets: UpstreamSockets,
+    /// where answers read off `sockets` land, one datagram per buffer, and the length and
+    /// sender of each
+    upstream_bufs: Vec<Vec<u8>>,
+    upstream_received: Vec<(usize, SocketAddr)>,
+    cache: Cache,
+    /// upstream id -> prefetch of a cached answer
+    prefetches: HashMap<u16, Prefetch>,
+    /// client address -> queries from it waiting for the upstream resolver
+    client_inflight: HashMap<IpAddr, usize>,
+    /// responses for TCP clients, or None to close the connection, waiting to be picked up
+    tcp_outbox: Vec<(ConnId, Option<BytesMut>)>,
+    /// responses for UDP clients waiting for `flush_responses`, when batching
+    udp_outbox: Vec<(BytesMut, SocketAddr)>,
+    /// what responses and upstream queries are written into; the only packet memory reused
+    buffers: BufferPool,
+    clock: Box<dyn Clock>,
+    stats: Arc<Stats>,
+    /// warnings about single packets
+    packet_log: LogLimiter,
 }
 
 impl DnsServer {
     pub fn new(resolver: Option<String>) -> Self {
-        if resolver.is_none() {
-            return DnsServer {
-                resolver: None,
-                source_map: HashMap::new(),
-                orig_messages: HashMap::new(),
-            };
-        }
-        let resolver = resolver.unwrap();
-        let resolver: SocketAddr = resolver.parse().unwrap();
+        Self::with_clock(resolver, Box::new(SystemClock))
+    }
+
+    /// Same as `new`, but reads time from `clock`, so timeouts and expiry can be driven by a
+    /// `MockClock` in tests.
+    pub fn with_clock(resolver: Option<String>, clock: Box<dyn Clock>) -> Self {
+        let upstreams = resolver.map(|r| r.parse().unwrap()).into_iter().collect();
+        let (tcp_retry_tx, tcp_retry_rx) = mpsc::channel();
         DnsServer {
-            resolver: Some(resolver),
-            source_map: HashMap::new(),
-            orig_messages: HashMap::new(),
+            upstreams: Upstreams::new(upstreams),
+            zones: Zones::default(),
+            config: ServerConfig::default(),
+            fast_path: false,
+            pending: HashMap::new(),
+            asking: HashMap::new(),
+            tcp_retry_tx,
+            tcp_retry_rx,
+            relay_map: HashMap::new(),
+            probes: HashMap::new(),
+            sockets: UpstreamSockets::new(
+                ServerConfig::default().upstream_sockets,
+                ServerConfig::default().upstream_socket_queries,
+            ),
+            upstream_bufs: Vec::new(),
+            upstream_received: Vec::new(),
+            cache: Cache::new(ServerConfig::default().cache_size),
+            prefetches: HashMap::new(),
+            client_inflight: HashMap::new(),
+            tcp_outbox: Vec::new(),
+            udp_outbox: Vec::new(),
+            buffers: BufferPool::new(BUFFER_SIZE, MAX_FREE_BUFFERS),
+            clock,
+            stats: Arc::new(Stats::new()),
+            packet_log: LogLimiter::new(PACKET_LOG_INTERVAL),
+        }
+    }
+
+    /// Forward to `upstreams` instead of the resolver given to `new`. Which one each query goes
+    /// to is up to `ServerConfig::upstream_selection`.
+    pub fn with_upstreams(mut self, upstreams: Vec<SocketAddr>) -> Self {
+        self.upstreams = Upstreams::new(upstreams);
+        self
+    }
+
+    /// Answer for `zones` from their records, never asking upstream about names in them.
+    pub fn with_zones(mut self, zones: Vec<Zone>) -> Self {
+        self.zones = Zones::new(zones);
+        self
+    }
+
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.cache = Cache::new(config.cache_size).with_ttl_limits(config.min_ttl, config.max_ttl);
+        self.sockets =
+            UpstreamSockets::new(config.upstream_sockets, config.upstream_socket_queries);
+        self.fast_path = config.fast_forward && config.fast_path_conflicts().is_empty();
+        self.config = config;
+        self
+    }
+
+    /// Report into `stats` instead of a private set of counters, e.g. to share one set between
+    /// several servers.
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    /// Writes the cache to `path`, for `load_cache` to read back e.g. after a restart. The file
+    /// is written under a temporary name and renamed into place, so a crash halfway leaves the
+    /// previous one intact.
+    pub fn save_cache(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        self.cache
+            .save(&mut file, self.clock.now(), SystemTime::now())?;
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        return fs::rename(&tmp, path);
+    }
+
+    /// Fills the cache from a file written by `save_cache`, leaving out answers that have
+    /// expired since. Returns how many answers were loaded.
+    pub fn load_cache(&mut self, path: &Path) -> io::Result<usize> {
+        let mut file = BufReader::new(File::open(path)?);
+        return self
+            .cache
+            .load(&mut file, self.clock.now(), SystemTime::now());
+    }
+
+    /// Largest datagram handled. Datagrams should be received into buffers at least a byte
+    /// larger, so that `handle_packet` can tell the ones that didn't fit.
+    pub fn recv_buffer_size(&self) -> usize {
+        return match self.config.recv_buffer_size {
+            Some(size) => size as usize,
+            None => (self.config.max_udp_payload as usize).max(MIN_RECV_BUFFER),
+        };
+    }
+
+    /// Entry point for a datagram straight off the socket. Takes the fast path when it is enabled
+    /// and applicable, otherwise parses the packet and hands it to `process`. A datagram longer
+    /// than `recv_buffer_size` is taken to be cut short by the receive, and dropped.
+    pub fn handle_packet(&mut self, packet: &mut [u8], source: SocketAddr, socket: &UdpSocket) {
+        if packet.len() > self.recv_buffer_size() {
+            self.dropped_oversized(source);
+            return;
+        }
+        if self.fast_path {
+            let id = packet.get(..2).map(|id| [id[0], id[1]]);
+            match self.guarded(|s| s.try_fast_forward(packet, source)) {
+                Some(true) => return,
+                Some(false) => {}
+                None => {
+                    // the packet may carry the upstream id by now.
+                    if let Some(id) = id {
+                        packet[..2].copy_from_slice(&id);
+                    }
+                    self.answer_servfail(packet, Client::Udp(source), socket);
+                    return;
+                }
+            }
+        }
+        match Message::parse(packet) {
+            Ok(m) => self.process_or_fail(m, packet, Client::Udp(source), socket),
+            Err(e) => {
+                self.stats.incr(Counter::ParseErrors);
+                if !self.answer_format_error(packet, Client::Udp(source), socket) {
+                    let now = self.clock.now();
+                    self.packet_log.log(
+                        now,
+                        format_args!("Dropping unparseable packet from {}: {}", source, e),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Entry point for one length-delimited message read off TCP connection `conn`. Upstream
+    /// queries still go out over `socket`. Anything that isn't a parseable query gets the
+    /// connection closed rather than an answer.
+    pub fn handle_tcp_query(
+        &mut self,
+        frame: &[u8],
+        conn: ConnId,
+        peer: SocketAddr,
+        socket: &UdpSocket,
+    ) {
+        match Message::parse(frame) {
+            Ok(m) if !m.header.qr => {
+                self.process_or_fail(m, frame, Client::Tcp(conn, peer), socket)
+            }
+            Ok(_) => self.tcp_outbox.push((conn, None)),
+            Err(e) => {
+                self.stats.incr(Counter::ParseErrors);
+                if !self.answer_format_error(frame, Client::Tcp(conn, peer), socket) {
+                    let now = self.clock.now();
+                    self.packet_log.log(
+                        now,
+                        format_args!(
+                            "Closing connection from {} after unparseable message: {}",
+                            peer, e
+                        ),
+                    );
+                    self.tcp_outbox.push((conn, None));
+                }
+            }
+        }
+    }
+
+    /// Counts and logs a datagram from `source` too big for the receive buffer.
+    fn dropped_oversized(&mut self, source: SocketAddr) {
+        self.stats.incr(Counter::ParseErrors);
+        let now = self.clock.now();
+        let max = self.recv_buffer_size();
+        self.packet_log.log(
+            now,
+            format_args!("Dropping datagram over {} bytes from {}", max, source),
+        );
+    }
+
+    /// Runs `process` on `m`, parsed from `packet`. Should that panic, the query is answered
+    /// SERVFAIL instead.
+    fn process_or_fail(&mut self, m: Message, packet: &[u8], client: Client, socket: &UdpSocket) {
+        if self.guarded(|s| s.process(m, client, socket)).is_none() {
+            self.answer_servfail(packet, client, socket);
+        }
+    }
+
+    /// Runs `f`, catching a panic in it, so a bug costs the packet or query being worked on
+    /// rather than taking the server down. None if it panicked; the panic message is already
+    /// out on stderr.
+    fn guarded<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> Option<T> {
+        return panic::catch_unwind(AssertUnwindSafe(|| f(self))).ok();
+    }
+
+    /// Answers the query in `packet` SERVFAIL after handling it panicked. Anything else is
+    /// dropped, and so is the query if answering it panics too.
+    fn answer_servfail(&mut self, packet: &[u8], client: Client, socket: &UdpSocket) {
+        let _ = self.guarded(|s| match Message::parse(packet) {
+            Ok(m) if !m.header.qr => {
+                let response = Self::error_response(m, rcode::SERVFAIL);
+                s.send_response(response, client, socket);
+            }
+            _ => {}
+        });
+    }
+
+    /// Hands `send` each response for a TCP client produced since the last call, in the order
+    /// they became ready. None means the connection should be closed. The responses are only
+    /// borrowed; their buffers are reused afterwards.
+    pub fn flush_tcp_responses(&mut self, mut send: impl FnMut(ConnId, Option<&[u8]>)) {
+        for (conn, response) in self.tcp_outbox.drain(..) {
+            send(conn, response.as_deref());
+            if let Some(buf) = response {
+                self.buffers.give(buf);
+            }
+        }
+    }
+
+    /// The sockets upstream queries went out on. When one is readable, `poll_upstreams` has
+    /// answers to read.
+    pub fn upstream_sockets(&self) -> Vec<&UdpSocket> {
+        return self.sockets.iter().map(|(_, s)| s).collect();
+    }
+
+    /// Reads every answer waiting on the upstream sockets and hands each to the query it
+    /// belongs to. Call this regularly, e.g. once per pass of the main loop, which should also
+    /// wake up when one of `upstream_sockets` is readable.
+    pub fn poll_upstreams(&mut self, socket: &UdpSocket) {
+        let ready = udp::readable(&self.upstream_sockets());
+        if !ready.contains(&true) {
+            return;
+        }
+        let mut bufs = std::mem::take(&mut self.upstream_bufs);
+        let max = self.recv_buffer_size();
+        bufs.resize(self.config.udp_batch.clamp(1, udp::MAX_BATCH), Vec::new());
+        for buf in bufs.iter_mut() {
+            // a byte to spare, to tell answers that didn't fit.
+            buf.resize(max + 1, 0);
+        }
+        let mut received = std::mem::take(&mut self.upstream_received);
+        for (slot, _) in ready.into_iter().enumerate().filter(|(_, ready)| *ready) {
+            loop {
+                let Some((_, upstream_socket)) = self.sockets.iter().nth(slot) else {
+                    break;
+                };
+                // with nothing left to read, or an error reported for an earlier send.
+                if udp::recv_many(upstream_socket, &mut bufs, &mut received).is_err() {
+                    break;
+                }
+                for (buf, &(size, upstream)) in bufs.iter_mut().zip(&received) {
+                    if size > max {
+                        self.dropped_oversized(upstream);
+                        continue;
+                    }
+                    // an answer that panics is dropped; its query times out.
+                    let _ = self.guarded(|s| {
+                        s.handle_upstream_packet(&mut buf[..size], upstream, slot, socket)
+                    });
+                }
+            }
+        }
+        self.upstream_bufs = bufs;
+        self.upstream_received = received;
+    }
+
+    /// Entry point for a datagram read off upstream socket `slot`. Answers the fast path
+    /// relayed are passed on as they are; anything else is parsed and matched against the
+    /// queries waiting on the full path.
+    fn handle_upstream_packet(
+        &mut self,
+        packet: &mut [u8],
+        upstream: SocketAddr,
+        slot: usize,
+        socket: &UdpSocket,
+    ) {
+        if self.relay_answer(packet, upstream, slot, socket) {
+            return;
+        }
+        match Message::parse(packet) {
+            Ok(m) if m.header.qr => self.handle_answer(m, upstream, slot, socket),
+            Ok(_) => {}
+            Err(_) => self.stats.incr(Counter::ParseErrors),
+        }
+    }
+
+    /// Passes on the answer in `packet` to the fast path query it belongs to, only rewriting
+    /// its id unless records out of bailiwick have to go. Returns false if no such query is
+    /// waiting.
+    fn relay_answer(
+        &mut self,
+        packet: &mut [u8],
+        upstream: SocketAddr,
+        slot: usize,
+        socket: &UdpSocket,
+    ) -> bool {
+        let answer = match LazyMessage::parse(packet) {
+            Ok(answer) if answer.header.qr => answer,
+            _ => return false,
+        };
+        let id = answer.header.id;
+        let Some(relayed) = self.relay_map.get(&id) else {
+            return false;
+        };
+        let asked = answer.header.qdcount == 1
+            && matches!(answer.questions().next(),
+                Some(Ok(a)) if same_question(&relayed.query.questions[0], &a));
+        // the client gets its name back in its own case, which also checks ours was echoed.
+        let echoed = match &relayed.sent_name {
+            Some(sent) => asked && replace_qname(packet, sent, &relayed.query.questions[0].name),
+            None => true,
+        };
+        if relayed.upstream != upstream || relayed.slot != slot || !asked || !echoed {
+            // an answer to something we didn't ask, most likely spoofed. The real one may still
+            // come.
+            self.stats.incr(Counter::Mismatched);
+            return true;
+        }
+        // an answer we can't check for records out of bailiwick doesn't get passed on; the
+        // client gets SERVFAIL once the query times out.
+        let Ok(mut scrubbed) = Message::parse(packet) else {
+            self.stats.incr(Counter::ParseErrors);
+            return true;
+        };
+        let relayed = self.relay_map.remove(&id).unwrap();
+        let (client_id, client) = (relayed.query.header.id, relayed.client);
+        let rtt = self.clock.now().saturating_duration_since(relayed.sent_at);
+        self.upstreams.success(upstream, Some(rtt));
+        self.sockets.answered(slot);
+        self.release(client.ip());
+        let mut buf = self.buffers.take();
+        if bailiwick::scrub(&mut scrubbed) == 0 {
+            packet[..2].copy_from_slice(&client_id.to_be_bytes());
+            // the upstream's RA speaks for it; we did recurse for this client.
+            packet[3] |= 0b1000_0000;
+            buf.extend_from_slice(packet);
+        } else {
+            // only an answer that lost records is re-encoded.
+            scrubbed.header.id = client_id;
+            scrubbed.header.ra = true;
+            scrubbed.write(&mut buf);
         }
+        if !self.send_udp(buf, client, socket) {
+            return true;
+        }
+        self.stats.incr(Counter::FastForwarded);
+        self.stats.incr(Counter::UpstreamResponses);
+        self.stats.incr(Counter::Responses);
+        return true;
+    }
+
+    /// Relays `packet` without decoding more than its header. Returns false if the packet needs
+    /// the full parse path instead.
+    fn try_fast_forward(&mut self, packet: &mut [u8], source: SocketAddr) -> bool {
+        if self.upstreams.is_empty() || self.config.authoritative_only {
+            return false;
+        }
+        let query = match LazyMessage::parse(packet) {
+            Ok(query) => query,
+            Err(_) => return false,
+        };
+        if query.header.qr {
+            return false;
+        }
+        if query.header.opcode != Opcode::QUERY || query.header.qdcount != 1 {
+            return false;
+        }
+        // the full path answers queries not asking for recursion, or not allowed it.
+        if !query.header.rd || !self.recursion_available(source.ip()) {
+            return false;
+        }
+        // only the full path can hold back the client's subnet, which would be in the OPT
+        // record, the additional record a query carries.
+        if self.config.client_subnet != ClientSubnetPolicy::Forward
+            && query.additionals().next().is_some()
+        {
+            return false;
+        }
+        // only the full path turns zone transfers away, and answers from our zones.
+        let question = match query.questions().next() {
+            Some(Ok(q)) if !matches!(q.tipe, QType::AXFR | QType::IXFR) => q,
+            _ => return false,
+        };
+        if self.zones.find(&question.name).is_some() {
+            return false;
+        }
+        let mut kept = Message::new(query.header.clone());
+        kept.questions.push(question);
+        if !self.admit(source.ip()) {
+            return false;
+        }
+        let upstream = self
+            .upstreams
+            .select(self.config.upstream_selection)
+            .unwrap();
+        let upstream_id = self.fresh_upstream_id();
+        let randomizes_case = self.randomizes_case(upstream);
+        // the full path gets the packet as the client sent it if there's no socket to send on.
+        let Ok((slot, upstream_socket)) = self.sockets.pick(upstream) else {
+            return false;
+        };
+        // the answer must fit our receive buffer, whatever the client advertised.
+        Self::clamp_opt_payload(packet, self.config.max_udp_payload);
+        let mut sent_name = None;
+        if randomizes_case {
+            let name = randomize_case(&kept.questions[0].name);
+            if replace_qname(packet, &kept.questions[0].name, &name) {
+                sent_name = Some(name);
+            }
+        }
+        packet[..2].copy_from_slice(&upstream_id.to_be_bytes());
+        // a query that can't go out gets SERVFAIL when it times out.
+        let _ = upstream_socket.send_to(packet, upstream);
+        let deadline = Deadline::after(self.clock.as_ref(), self.config.query_budget);
+        self.relay_map.insert(
+            upstream_id,
+            Relayed {
+                query: kept,
+                client: source,
+                sent_name,
+                upstream,
+                slot,
+                sent_at: self.clock.now(),
+                deadline,
+            },
+        );
+        self.track(source.ip());
+        self.stats.incr(Counter::FastForwarded);
+        self.stats.incr(Counter::Queries);
+        self.stats.incr(Counter::UpstreamQueries);
+        return true;
     }
 
-    pub fn process(&mut self, mut m: Message, source: SocketAddr, socket: &UdpSocket) {
-        if self.resolver.is_none() {
+    pub fn process(&mut self, mut m: Message, source: Client, socket: &UdpSocket) {
+        if !m.header.qr && m.header.opcode != Opcode::QUERY {
+            self.stats.incr(Counter::Queries);
+            self.send_response(Self::error_response(m, rcode::NOTIMP), source, socket);
+            return;
+        }
+        // we only speak EDNS version 0 (RFC 6891 section 6.1.3).
+        if !m.header.qr && m.edns.as_ref().is_some_and(|edns| edns.version > 0) {
+            self.stats.incr(Counter::Queries);
+            self.send_response(Self::error_response(m, rcode::BADVERS), source, socket);
+            return;
+        }
+        if !m.header.qr && m.questions.is_empty() {
+            self.stats.incr(Counter::Queries);
+            let code = match self.config.zero_question {
+                ZeroQuestionPolicy::NoErrorWithEdns if m.edns.is_some() => rcode::NOERROR,
+                ZeroQuestionPolicy::NoErrorWithEdns => rcode::FORMERR,
+                ZeroQuestionPolicy::NoError => rcode::NOERROR,
+                ZeroQuestionPolicy::FormErr => rcode::FORMERR,
+            };
+            // the OPT record of the query is answered with ours in send_response.
+            self.send_response(Self::error_response(m, code), source, socket);
+            return;
+        }
+        if !m.header.qr {
+            if let Some(response) = self.answer_from_zones(&m) {
+                self.stats.incr(Counter::Queries);
+                self.send_response(response, source, socket);
+                return;
+            }
+            if self.config.authoritative_only {
+                self.stats.incr(Counter::Queries);
+                self.stats.incr(Counter::Refused);
+                self.send_response(Self::error_response(m, rcode::REFUSED), source, socket);
+                return;
+            }
+        }
+        if self.upstreams.is_empty() {
+            self.stats.incr(Counter::Queries);
             let m = Self::update_message(m);
-            socket.send_to(&m.to_bytes(), source).unwrap();
+            self.send_response(m, source, socket);
             return;
         }
+        // answers only count on the sockets their queries went out on, see `poll_upstreams`.
         if m.header.qr {
-            self.source_map
-                .entry(m.header.id)
-                .and_modify(|(cnt, _addr)| *cnt -= 1);
-            self.orig_messages.entry(m.header.id).and_modify(|msg| {
-                msg.header.ancount += 1;
-                msg.header.qr = true;
-                msg.header.rcode = 4;
-                msg.answers.extend(m.answers);
+            return;
+        }
+        self.stats.incr(Counter::Queries);
+        if !self.recursion_available(source.addr().ip()) {
+            self.stats.incr(Counter::Refused);
+            self.send_response(Self::error_response(m, rcode::REFUSED), source, socket);
+            return;
+        }
+        if m.questions.len() > 1 && self.config.multi_question == MultiQuestionPolicy::FormErr {
+            self.send_response(Self::error_response(m, rcode::FORMERR), source, socket);
+            return;
+        }
+        // a zone transfer spans many messages, which relaying single responses can't carry.
+        if m.questions
+            .iter()
+            .any(|q| matches!(q.tipe, QType::AXFR | QType::IXFR))
+        {
+            self.send_response(Self::error_response(m, rcode::NOTIMP), source, socket);
+            return;
+        }
+        if self.answer_from_cache(&mut m) {
+            self.stats.incr(Counter::CacheHits);
+            self.prefetch(&m);
+            self.send_response(m, source, socket);
+            return;
+        }
+        // without RD the client asks for what we know, and only the cache knows anything.
+        if !m.header.rd {
+            self.stats.incr(Counter::Refused);
+            self.send_response(Self::error_response(m, rcode::REFUSED), source, socket);
+            return;
+        }
+        if !self.admit(source.addr().ip()) {
+            self.stats.incr(Counter::Overloaded);
+            if self.config.overload_action == OverloadAction::ServFail
+                || matches!(source, Client::Tcp(..))
+            {
+                self.send_response(Self::error_response(m, rcode::SERVFAIL), source, socket);
+            }
+            return;
+        }
+        if let Some(id) = self.joinable(&m) {
+            self.track(source.addr().ip());
+            self.stats.incr(Counter::Coalesced);
+            self.pending.get_mut(&id).unwrap().waiters.push((m, source));
+            return;
+        }
+        let upstream = self
+            .upstreams
+            .select(self.config.upstream_selection)
+            .unwrap();
+        let deadline = Deadline::after(self.clock.as_ref(), self.config.query_budget);
+        let upstream_id = self.fresh_upstream_id();
+        let randomized_case = self.randomizes_case(upstream);
+        let sent: Vec<Question> = m
+            .questions
+            .iter()
+            .map(|q| Question {
+                name: if randomized_case {
+                    randomize_case(&q.name)
+                } else {
+                    q.name.clone()
+                },
+                ..q.clone()
+            })
+            .collect();
+        let mut queries = Vec::with_capacity(sent.len());
+        for q in sent.iter() {
+            let mut buf = self.buffers.take();
+            self.upstream_query(&m, q, upstream_id).write(&mut buf);
+            queries.push(buf);
+        }
+        let (slot, upstream_socket) = match self.sockets.pick(upstream) {
+            Ok(picked) => picked,
+            Err(e) => {
+                let now = self.clock.now();
+                self.packet_log.log(
+                    now,
+                    format_args!("Failed to open a socket for upstream queries: {}", e),
+                );
+                for q in queries {
+                    self.buffers.give(q);
+                }
+                self.send_response(Self::error_response(m, rcode::SERVFAIL), source, socket);
+                return;
+            }
+        };
+        // a query that can't go out gets SERVFAIL when it times out.
+        if self.config.udp_offload {
+            let packets: Vec<&[u8]> = queries.iter().map(|q| &q[..]).collect();
+            let _ = udp::send_batch(upstream_socket, upstream, &packets);
+        } else {
+            for q in queries.iter() {
+                let _ = upstream_socket.send_to(q, upstream);
+            }
+        }
+        self.track(source.addr().ip());
+        self.stats
+            .add(Counter::UpstreamQueries, queries.len() as u64);
+        for q in queries {
+            self.buffers.give(q);
+        }
+        m.answers.clear();
+        m.authorities.clear();
+        m.additionals.clear();
+        if let [q] = m.questions.as_slice() {
+            self.asking.insert(question_key(q), upstream_id);
+        }
+        self.pending.insert(
+            upstream_id,
+            Pending {
+                unanswered: sent,
+                randomized_case,
+                query: m,
+                client: source,
+                upstream,
+                slot,
+                sent_at: self.clock.now(),
+                deadline,
+                relayed_options: Vec::new(),
+                waiters: Vec::new(),
+            },
+        );
+    }
+
+    /// Hands the answer `m`, read from `upstream` off socket `slot`, to the probe, prefetch or
+    /// full path query it belongs to. An answer that doesn't match the query in every respect
+    /// is dropped, and the query keeps waiting.
+    fn handle_answer(
+        &mut self,
+        mut m: Message,
+        upstream: SocketAddr,
+        slot: usize,
+        socket: &UdpSocket,
+    ) {
+        self.stats.incr(Counter::UpstreamResponses);
+        let id = m.header.id;
+        if let Some((probed, probe_slot, _)) = self.probes.get(&id) {
+            let asked = matches!(m.questions.as_slice(),
+                [q] if q.name.is_root() && q.tipe == QType::NS);
+            if *probed != upstream || *probe_slot != slot || !asked {
+                self.stats.incr(Counter::Mismatched);
+                return;
+            }
+            self.upstreams.success(upstream, None);
+            self.probes.remove(&id);
+            self.sockets.answered(slot);
+            return;
+        }
+        if let Some(prefetch) = self.prefetches.get(&id) {
+            let asked = matches!(m.questions.as_slice(),
+                [a] if same_question(&prefetch.question, a)
+                    && prefetch.sent_name.as_ref().is_none_or(|n| same_case(n, &a.name)));
+            if prefetch.upstream != upstream || prefetch.slot != slot || !asked {
+                self.stats.incr(Counter::Mismatched);
+                return;
+            }
+            let rtt = self.clock.now().saturating_duration_since(prefetch.sent_at);
+            self.upstreams.success(upstream, Some(rtt));
+            let prefetch = self.prefetches.remove(&id).unwrap();
+            self.sockets.answered(slot);
+            restore_case(&mut m, &prefetch.question.name);
+            bailiwick::scrub(&mut m);
+            self.cache.insert(&m, prefetch.asked, self.clock.now());
+            return;
+        }
+        match self.pending.get(&id) {
+            // nobody is waiting for this one anymore, most likely it timed out.
+            None => return,
+            // an answer to something we didn't ask, most likely spoofed. The real one may
+            // still come.
+            Some(pending)
+                if pending.upstream != upstream
+                    || pending.slot != slot
+                    || pending.answered(&m).is_none() =>
+            {
+                self.stats.incr(Counter::Mismatched);
+                return;
+            }
+            Some(pending) => {
+                let rtt = self.clock.now().saturating_duration_since(pending.sent_at);
+                self.upstreams.success(upstream, Some(rtt));
+            }
+        }
+        if m.header.tc && self.config.tcp_retry && self.retry_over_tcp(&m) {
+            return;
+        }
+        self.merge_upstream_response(m, socket);
+    }
+
+    /// The upstream id of a pending query `m` can wait on instead of going upstream itself: one
+    /// asking the same single question, the same way.
+    fn joinable(&self, m: &Message) -> Option<u16> {
+        let [q] = m.questions.as_slice() else {
+            return None;
+        };
+        let id = *self.asking.get(&question_key(q))?;
+        let pending = self.pending.get(&id)?;
+        // the answer may depend on the flags, DNSSEC OK and the client's subnet.
+        let asked_as = |m: &Message| {
+            let edns = m.edns.as_ref().map(|edns| {
+                let subnet = edns
+                    .options
+                    .iter()
+                    .find(|o| matches!(o, EdnsOption::ClientSubnet { .. }))
+                    .cloned();
+                (edns.flags & Edns::DNSSEC_OK, subnet)
             });
-            if self.source_map.get(&m.header.id).unwrap().0 <= 0 {
-                let (_, source) = self.source_map.get(&m.header.id).unwrap();
-                if let Some(mut m) = self.orig_messages.remove(&m.header.id) {
-                    m.header.ancount = m.answers.len() as u16;
-                    socket.send_to(&m.to_bytes(), source).unwrap();
+            (m.header.rd, m.header.z, edns)
+        };
+        if asked_as(&pending.query) != asked_as(m) {
+            return None;
+        }
+        return Some(id);
+    }
+
+    /// Removes the pending query with upstream id `id`.
+    fn take_pending(&mut self, id: u16) -> Option<Pending> {
+        let pending = self.pending.remove(&id)?;
+        self.sockets.answered(pending.slot);
+        if let [q] = pending.query.questions.as_slice() {
+            let key = question_key(q);
+            if self.asking.get(&key) == Some(&id) {
+                self.asking.remove(&key);
+            }
+        }
+        return Some(pending);
+    }
+
+    /// The response to the single-question query `m` from the zone its name is in, if any.
+    fn answer_from_zones(&self, m: &Message) -> Option<Message> {
+        let [q] = m.questions.as_slice() else {
+            return None;
+        };
+        let answer = self.zones.find(&q.name)?.answer(q);
+        let mut response = m.clone();
+        response.header.qr = true;
+        response.header.aa = answer.authoritative;
+        // we don't validate, so can't vouch for our own data either.
+        response.header.z &= Header::CHECKING_DISABLED;
+        response.header.rcode = answer.rcode;
+        response.answers = answer.answers;
+        response.authorities = answer.authorities;
+        response.additionals = answer.additionals;
+        return Some(response);
+    }
+
+    /// Turns the single-question query `m` into its response from the cache, in place, if the
+    /// answer is there; returns whether it was. The records carry what is left of their TTLs,
+    /// not the TTLs they came in with.
+    fn answer_from_cache(&mut self, m: &mut Message) -> bool {
+        let [q] = m.questions.as_slice() else {
+            return false;
+        };
+        let now = self.clock.now();
+        let Some(entry) = self.cache.get(q, Asked::of(m), now) else {
+            return false;
+        };
+        let ad = entry.authentic && asks_for_ad(m);
+        m.header.qr = true;
+        // cached data is never authoritative (RFC 1035 section 6.1).
+        m.header.aa = false;
+        m.header.rcode = entry.rcode;
+        m.header.z &= Header::CHECKING_DISABLED;
+        if ad {
+            m.header.z |= Header::AUTHENTIC_DATA;
+        }
+        m.answers = entry.answers.clone();
+        m.authorities = entry.authorities.clone();
+        m.additionals = entry.additionals.clone();
+        let age = entry.age(now);
+        for r in m
+            .answers
+            .iter_mut()
+            .chain(&mut m.authorities)
+            .chain(&mut m.additionals)
+        {
+            r.ttl = r.ttl.saturating_sub(age);
+        }
+        return true;
+    }
+
+    /// Refreshes the cached answer to a single-question query if it is popular and about to
+    /// expire; `m` is the response just made for it from the cache. The answer goes straight
+    /// into the cache.
+    fn prefetch(&mut self, m: &Message) {
+        let q = &m.questions[0];
+        let asked = Asked::of(m);
+        let now = self.clock.now();
+        if !self
+            .cache
+            .prefetch_due(q, asked, self.config.prefetch_hits, now)
+            || self
+                .prefetches
+                .values()
+                .any(|p| same_question(&p.question, q) && p.asked == asked)
+        {
+            return;
+        }
+        let upstream = self
+            .upstreams
+            .select(self.config.upstream_selection)
+            .unwrap();
+        let id = self.fresh_upstream_id();
+        let sent_name = self
+            .randomizes_case(upstream)
+            .then(|| randomize_case(&q.name));
+        let sent = Question {
+            name: sent_name.clone().unwrap_or_else(|| q.name.clone()),
+            ..q.clone()
+        };
+        let query = self.upstream_query(m, &sent, id);
+        let Ok((slot, upstream_socket)) = self.sockets.pick(upstream) else {
+            return;
+        };
+        let mut buf = self.buffers.take();
+        query.write(&mut buf);
+        let _ = upstream_socket.send_to(&buf, upstream);
+        self.buffers.give(buf);
+        let deadline = Deadline::after(self.clock.as_ref(), self.config.query_budget);
+        self.prefetches.insert(
+            id,
+            Prefetch {
+                question: q.clone(),
+                asked,
+                sent_name,
+                upstream,
+                slot,
+                sent_at: now,
+                deadline,
+            },
+        );
+        self.stats.incr(Counter::Prefetches);
+        self.stats.incr(Counter::UpstreamQueries);
+    }
+
+    /// Whether we recurse for `client`: there are upstreams to forward to, and the ACL allows
+    /// it. Decides RA in responses.
+    fn recursion_available(&self, client: IpAddr) -> bool {
+        let allowed = match &self.config.recursion_acl {
+            Some(acl) => acl.allows(client),
+            None => true,
+        };
+        return !self.config.authoritative_only && !self.upstreams.is_empty() && allowed;
+    }
+
+    /// Whether names sent to `upstream` go in randomized case.
+    fn randomizes_case(&self, upstream: SocketAddr) -> bool {
+        return self.config.randomize_case
+            && !self.config.verbatim_case_upstreams.contains(&upstream);
+    }
+
+    /// A random id for a query going upstream, not used by any query waiting there on either
+    /// path.
+    fn fresh_upstream_id(&self) -> u16 {
+        loop {
+            let candidate: u16 = rand::random();
+            if !self.pending.contains_key(&candidate)
+                && !self.relay_map.contains_key(&candidate)
+                && !self.probes.contains_key(&candidate)
+                && !self.prefetches.contains_key(&candidate)
+            {
+                return candidate;
+            }
+        }
+    }
+
+    /// Folds one upstream answer into the response for the client query it belongs to, and
+    /// sends that response, to its client and any waiting with the same query, once every
+    /// question has been answered. A truncated answer leaves the response truncated too. Names
+    /// sent in randomized case get the client's case back, and records out of bailiwick are
+    /// dropped, before the answer is cached.
+    fn merge_upstream_response(&mut self, mut m: Message, socket: &UdpSocket) {
+        let id = m.header.id;
+        let Some(pending) = self.pending.get_mut(&id) else {
+            return;
+        };
+        let Some(answered) = pending.answered(&m) else {
+            return;
+        };
+        let sent = pending.unanswered.remove(answered);
+        let query = &mut pending.query;
+        if let Some(asked) = query.questions.iter().find(|q| same_question(q, &sent)) {
+            restore_case(&mut m, &asked.name);
+        }
+        bailiwick::scrub(&mut m);
+        self.cache.insert(&m, Asked::of(query), self.clock.now());
+        // AA and AD hold for the response only if they hold for every answer merged into it.
+        let first = query.questions.len() - pending.unanswered.len() == 1;
+        let ad = m.header.z & Header::AUTHENTIC_DATA != 0;
+        let was_ad = query.header.z & Header::AUTHENTIC_DATA != 0;
+        query.header.qr = true;
+        query.header.tc |= m.header.tc;
+        query.header.aa = m.header.aa && (first || query.header.aa);
+        query.header.z &= Header::CHECKING_DISABLED;
+        if ad && (first || was_ad) {
+            query.header.z |= Header::AUTHENTIC_DATA;
+        }
+        if query.header.rcode == rcode::NOERROR {
+            let code = match &m.edns {
+                Some(edns) => edns.rcode(m.header.rcode),
+                None => m.header.rcode as u16,
+            };
+            // `send_response` splits it up again.
+            query.header.rcode = u8::try_from(code).unwrap_or(rcode::SERVFAIL);
+        }
+        query.answers.extend(m.answers);
+        query.authorities.extend(m.authorities);
+        query.additionals.extend(m.additionals);
+        if let Some(edns) = &m.edns {
+            let relayed = self.config.unknown_options.relayed(&edns.options);
+            pending.relayed_options.extend(relayed);
+        }
+        if !pending.unanswered.is_empty() {
+            return;
+        }
+        let pending = self.take_pending(id).unwrap();
+        let mut response = pending.query;
+        response.header.ancount = response.answers.len() as u16;
+        if self.config.clamp_forwarded_ttls {
+            cache::clamp_ttls(&mut response, self.config.min_ttl, self.config.max_ttl);
+        }
+        for (mut query, client) in pending.waiters {
+            query.header.qr = true;
+            query.header.aa = response.header.aa;
+            query.header.tc = response.header.tc;
+            // waiters asked with the same flags, see `joinable`.
+            query.header.z = response.header.z;
+            query.header.rcode = response.header.rcode;
+            query.header.ancount = response.header.ancount;
+            query.answers = response.answers.clone();
+            query.authorities = response.authorities.clone();
+            query.additionals = response.additionals.clone();
+            let relayed = pending.relayed_options.clone();
+            self.send_response_with(query, relayed, client, socket);
+            self.release(client.addr().ip());
+        }
+        self.send_response_with(response, pending.relayed_options, pending.client, socket);
+        self.release(pending.client.addr().ip());
+    }
+
+    /// Sends the query behind the truncated upstream answer `m` again over TCP, on a worker
+    /// thread; `poll_tcp_retries` picks up the result. Returns false if the query can't be
+    /// retried.
+    fn retry_over_tcp(&mut self, m: &Message) -> bool {
+        let Some(pending) = self.pending.get(&m.header.id) else {
+            return false;
+        };
+        let upstream = pending.upstream;
+        let orig = &pending.query;
+        let Some(answered) = pending.answered(m) else {
+            return false;
+        };
+        let question = &pending.unanswered[answered];
+        let timeout = pending.deadline.remaining(self.clock.as_ref());
+        if timeout.is_zero() {
+            return false;
+        }
+        // by now `orig` may hold what was merged from answers to its other questions, which
+        // `upstream_query` leaves out.
+        let query = self.upstream_query(orig, question, m.header.id).to_bytes();
+        let results = self.tcp_retry_tx.clone();
+        let truncated = m.clone();
+        self.stats.incr(Counter::TcpRetries);
+        thread::spawn(move || {
+            let answer = tcp::exchange(upstream, &query, timeout);
+            // the server may be gone by now, and nobody left to care.
+            let _ = results.send((truncated, answer));
+        });
+        return true;
+    }
+
+    /// Hands the results of finished TCP retries to the queries waiting for them. Call this
+    /// regularly, e.g. once per pass of the main loop.
+    pub fn poll_tcp_retries(&mut self, socket: &UdpSocket) {
+        while let Ok((truncated, answer)) = self.tcp_retry_rx.try_recv() {
+            // a result that panics is dropped like an upstream answer that does.
+            let _ = self.guarded(|s| s.finish_tcp_retry(truncated, answer, socket));
+        }
+    }
+
+    /// Hands the result of a TCP retry of the truncated answer `truncated` to its query.
+    fn finish_tcp_retry(
+        &mut self,
+        truncated: Message,
+        answer: io::Result<Bytes>,
+        socket: &UdpSocket,
+    ) {
+        let Some(pending) = self.pending.get(&truncated.header.id) else {
+            return;
+        };
+        // the answer must be to the same question as the truncated one.
+        let answer = answer
+            .ok()
+            .and_then(|bites| Message::parse(&bites).ok())
+            .filter(|a| a.header.id == truncated.header.id && a.header.qr)
+            .filter(|a| {
+                !a.questions.is_empty() && pending.answered(a) == pending.answered(&truncated)
+            });
+        self.stats.incr(Counter::UpstreamResponses);
+        self.merge_upstream_response(answer.unwrap_or(truncated), socket);
+    }
+
+    /// The query sent upstream, with id `id`, for question `q` of the client query `m`. Only the
+    /// header flags and the OPT record are taken from `m`, which may be a response by now.
+    fn upstream_query(&self, m: &Message, q: &Question, id: u16) -> Message {
+        let mut m2 = Message::new(m.header.clone());
+        m2.header.id = id;
+        m2.header.qr = false;
+        m2.header.aa = false;
+        m2.header.tc = false;
+        m2.header.rcode = rcode::NOERROR;
+        // prefetches may be set off by queries without RD, but need recursion all the same.
+        m2.header.rd = true;
+        m2.questions.push(q.clone());
+        m2.edns = m.edns.clone();
+        if let Some(edns) = m2.edns.as_mut() {
+            edns.udp_payload_size = self.config.max_udp_payload;
+            self.config.client_subnet.apply(&mut edns.options);
+        }
+        if let PaddingPolicy::Block { query, .. } = self.config.padding {
+            m2.pad(query as usize, self.config.max_udp_payload as usize);
+        }
+        return m2;
+    }
+
+    /// Fails every pending query whose budget has run out: its client gets SERVFAIL and its state
+    /// is dropped. Also gives back memory the tables kept from a burst of queries. Call this
+    /// regularly, e.g. whenever the socket read times out.
+    pub fn expire(&mut self, socket: &UdpSocket) {
+        // a panic leaves the rest for the next call.
+        let _ = self.guarded(|s| s.expire_unguarded(socket));
+    }
+
+    fn expire_unguarded(&mut self, socket: &UdpSocket) {
+        let now = self.clock.now();
+        let expired: Vec<u16> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline.at() <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            // each query is taken off the table first, so one that panics isn't failed again.
+            let _ = self.guarded(|s| s.fail_pending(id, socket));
+        }
+        let expired: Vec<u16> = self
+            .relay_map
+            .iter()
+            .filter(|(_, relayed)| relayed.deadline.at() <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            let _ = self.guarded(|s| s.fail_relayed(id, socket));
+        }
+        let expired: Vec<u16> = self
+            .probes
+            .iter()
+            .filter(|(_, (_, _, deadline))| deadline.at() <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            let (_, slot, _) = self.probes.remove(&id).unwrap();
+            self.sockets.answered(slot);
+        }
+        let expired: Vec<u16> = self
+            .prefetches
+            .iter()
+            .filter(|(_, prefetch)| prefetch.deadline.at() <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            let prefetch = self.prefetches.remove(&id).unwrap();
+            self.sockets.answered(prefetch.slot);
+            self.upstream_failed(prefetch.upstream);
+        }
+        for upstream in self
+            .upstreams
+            .due_probes(self.config.upstream_probe_interval, now)
+        {
+            self.probe(upstream);
+        }
+        self.cache.purge(now);
+        shrink(&mut self.pending);
+        shrink(&mut self.asking);
+        shrink(&mut self.relay_map);
+        shrink(&mut self.prefetches);
+        shrink(&mut self.client_inflight);
+    }
+
+    /// Answers SERVFAIL to the client of the pending query `id`, which ran out of time, and to
+    /// those waiting on it.
+    fn fail_pending(&mut self, id: u16, socket: &UdpSocket) {
+        let pending = self.take_pending(id).unwrap();
+        self.upstream_failed(pending.upstream);
+        let clients = pending.waiters.into_iter();
+        for (query, client) in clients.chain([(pending.query, pending.client)]) {
+            self.release(client.addr().ip());
+            self.stats.incr(Counter::TimedOut);
+            let response = Self::error_response(query, rcode::SERVFAIL);
+            self.send_response(response, client, socket);
+        }
+    }
+
+    /// Answers SERVFAIL to the client of the fast path query `id`, which ran out of time.
+    fn fail_relayed(&mut self, id: u16, socket: &UdpSocket) {
+        let relayed = self.relay_map.remove(&id).unwrap();
+        self.sockets.answered(relayed.slot);
+        self.upstream_failed(relayed.upstream);
+        self.release(relayed.client.ip());
+        self.stats.incr(Counter::TimedOut);
+        let response = Self::error_response(relayed.query, rcode::SERVFAIL);
+        self.send_response(response, Client::Udp(relayed.client), socket);
+    }
+
+    /// Counts a query `upstream` left unanswered against it.
+    fn upstream_failed(&mut self, upstream: SocketAddr) {
+        let now = self.clock.now();
+        let (max, interval) = (
+            self.config.upstream_max_failures,
+            self.config.upstream_probe_interval,
+        );
+        if self.upstreams.failure(upstream, max, interval, now) {
+            eprintln!("Upstream {} isn't answering, marking it down", upstream);
+        }
+    }
+
+    /// Asks `upstream`, which is marked down, for the root name servers. Any answer brings it
+    /// back up.
+    fn probe(&mut self, upstream: SocketAddr) {
+        let id = self.fresh_upstream_id();
+        let mut query = Message::new(Header::new(id));
+        query.header.rd = true;
+        query.questions.push(Question {
+            tipe: QType::NS,
+            class: ResourceClass::IN,
+            name: Name::root(),
+            unicast_response: false,
+        });
+        let Ok((slot, upstream_socket)) = self.sockets.pick(upstream) else {
+            return;
+        };
+        // an unreachable upstream is just what a probe expects to find.
+        let _ = upstream_socket.send_to(&query.to_bytes(), upstream);
+        let deadline = Deadline::after(self.clock.as_ref(), self.config.query_budget);
+        self.probes.insert(id, (upstream, slot, deadline));
+        self.stats.incr(Counter::UpstreamQueries);
+    }
+
+    /// The earliest deadline among pending queries, i.e. the next time `expire` has work to do.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let full = self.pending.values().map(|p| p.deadline.at());
+        let fast = self.relay_map.values().map(|r| r.deadline.at());
+        full.chain(fast).min()
+    }
+
+    /// Whether another query from `client` fits under the in-flight limits.
+    fn admit(&self, client: IpAddr) -> bool {
+        let inflight = self.pending.len() + self.relay_map.len();
+        // the fast path needs spare upstream ids, so never let it fill the id space.
+        if inflight >= u16::MAX as usize / 2 {
+            return false;
+        }
+        if let Some(max) = self.config.max_inflight {
+            if inflight >= max {
+                return false;
+            }
+        }
+        if let Some(max) = self.config.max_inflight_per_client {
+            if self.client_inflight.get(&client).copied().unwrap_or(0) >= max {
+                return false;
+            }
+        }
+        return true;
+    }
+
+    fn track(&mut self, client: IpAddr) {
+        *self.client_inflight.entry(client).or_insert(0) += 1;
+    }
+
+    fn release(&mut self, client: IpAddr) {
+        if let Some(n) = self.client_inflight.get_mut(&client) {
+            *n -= 1;
+            if *n == 0 {
+                self.client_inflight.remove(&client);
+            }
+        }
+    }
+
+    /// Sends the response `m` to `client`, sized for what the client can take. `m.edns` is
+    /// expected to still hold the client's OPT record from the query; it is replaced with ours,
+    /// which carries the upper bits of an extended rcode in `m.header.rcode`.
+    /// Over TCP only the 2 byte length prefix limits the size.
+    fn send_response(&mut self, m: Message, client: Client, socket: &UdpSocket) {
+        self.send_response_with(m, Vec::new(), client, socket);
+    }
+
+    /// Same as `send_response`, adding `relayed` to the options of the response. They are
+    /// dropped, like any other option, for clients without EDNS.
+    fn send_response_with(
+        &mut self,
+        mut m: Message,
+        relayed: Vec<EdnsOption>,
+        client: Client,
+        socket: &UdpSocket,
+    ) {
+        let max_response_payload = self.config.max_response_payload.max(512);
+        let limit = match (&m.edns, client) {
+            (_, Client::Tcp(..)) => u16::MAX,
+            (Some(edns), _) => edns.udp_payload_size.clamp(512, max_response_payload),
+            (None, _) => 512,
+        };
+        if let Some(query_edns) = &m.edns {
+            let mut edns = Edns::new(self.config.max_udp_payload.max(512));
+            // RFC 3225: the DO bit of the query is copied into the response.
+            edns.set_dnssec_ok(query_edns.dnssec_ok());
+            edns.options = self.config.unknown_options.copied(&query_edns.options);
+            edns.options.extend(relayed);
+            let nsid_requested = query_edns
+                .options
+                .iter()
+                .any(|o| matches!(o, EdnsOption::Nsid(_)));
+            if let (true, Some(nsid)) = (nsid_requested, &self.config.nsid) {
+                edns.options.push(EdnsOption::Nsid(nsid.clone()));
+            }
+            // an extended rcode goes partly in the OPT record.
+            m.header.rcode = edns.set_rcode(m.header.rcode as u16);
+            m.edns = Some(edns);
+        } else if m.header.rcode > 0b1111 {
+            // without an OPT record, an extended rcode can't be told.
+            m.header.rcode = rcode::SERVFAIL;
+        }
+        m.header.ra = self.recursion_available(client.addr().ip());
+        m.truncate(limit as usize);
+        if let PaddingPolicy::Block { response, .. } = self.config.padding {
+            m.pad(response as usize, limit as usize);
+        }
+        let mut buf = self.buffers.take();
+        m.write(&mut buf);
+        match client {
+            Client::Udp(addr) => {
+                if !self.send_udp(buf, addr, socket) {
+                    return;
                 }
-                self.source_map.remove(&m.header.id);
             }
+            Client::Tcp(conn, _) => self.tcp_outbox.push((conn, Some(buf))),
+        }
+        self.stats.incr(Counter::Responses);
+    }
+
+    /// Sends `packet`, taken from `buffers`, to UDP client `client`, or queues it for
+    /// `flush_responses` when batching. Returns false, having logged why, if it couldn't be sent.
+    fn send_udp(&mut self, packet: BytesMut, client: SocketAddr, socket: &UdpSocket) -> bool {
+        if self.config.udp_batch > 1 {
+            self.udp_outbox.push((packet, client));
+            return true;
+        }
+        let sent = socket.send_to(&packet, client);
+        self.buffers.give(packet);
+        if let Err(e) = sent {
+            let now = self.clock.now();
+            self.packet_log.log(
+                now,
+                format_args!("Failed to send response to {}: {}", client, e),
+            );
+            return false;
+        }
+        return true;
+    }
+
+    /// Sends the UDP responses queued since the last call, in as few syscalls as the platform
+    /// allows. Call this once per pass of the main loop when `udp_batch` is above 1.
+    pub fn flush_responses(&mut self, socket: &UdpSocket) {
+        let offload = self.config.udp_offload;
+        self.flush_responses_with(|responses| {
+            if offload {
+                return send_offloaded(socket, responses);
+            }
+            return udp::send_many(socket, responses);
+        });
+    }
+
+    /// Same as `flush_responses`, but hands the queued responses and their clients to `send`,
+    /// e.g. to send them through an io_uring. `send` returns the index and error of each it
+    /// couldn't send.
+    pub fn flush_responses_with(
+        &mut self,
+        send: impl FnOnce(&mut [(BytesMut, SocketAddr)]) -> Vec<(usize, io::Error)>,
+    ) {
+        if self.udp_outbox.is_empty() {
+            return;
+        }
+        let now = self.clock.now();
+        for (i, e) in send(&mut self.udp_outbox) {
+            let client = self.udp_outbox[i].1;
+            self.packet_log.log(
+                now,
+                format_args!("Failed to send response to {}: {}", client, e),
+            );
+        }
+        for (packet, _) in self.udp_outbox.drain(..) {
+            self.buffers.give(packet);
+        }
+    }
+
+    /// Lowers the payload size of the OPT record of a single-question query to at most `max`,
+    /// in place. Packets of any other shape are left alone.
+    fn clamp_opt_payload(packet: &mut [u8], max: u16) {
+        let counts = |i: usize| u16::from_be_bytes([packet[i], packet[i + 1]]);
+        if counts(6) != 0 || counts(8) != 0 || counts(10) != 1 {
             return;
         }
-        let resolver = self.resolver.as_ref().unwrap();
-        self.source_map
-            .insert(m.header.id, (m.questions.len() as u16, source));
-        for q in m.questions.iter() {
-            let mut m2 = m.clone();
-            m2.header.qdcount = 1;
-            m2.questions = vec![q.clone()];
-            socket.send_to(&m2.to_bytes(), resolver).unwrap();
+        let mut i = 12;
+        while i < packet.len() && packet[i] != 0 {
+            if packet[i] & 0b11000000 != 0 {
+                return;
+            }
+            i += packet[i] as usize + 1;
         }
+        // root byte, qtype and qclass, then the OPT owner name (root) and type.
+        let opt = i + 5;
+        if opt + 5 > packet.len() || packet[opt] != 0 || packet[opt + 1..opt + 3] != [0, 41] {
+            return;
+        }
+        let size = u16::from_be_bytes([packet[opt + 3], packet[opt + 4]]);
+        if size > max {
+            packet[opt + 3..opt + 5].copy_from_slice(&max.to_be_bytes());
+        }
+    }
+
+    /// Answers a malformed query with FORMERR, if at least its header is readable and says it is
+    /// a query. Only the header is echoed, since the rest can't be trusted. Returns whether an
+    /// answer went out.
+    fn answer_format_error(&mut self, packet: &[u8], client: Client, socket: &UdpSocket) -> bool {
+        let mut header = match Header::parse(packet) {
+            Ok((_, header)) if !header.qr => header,
+            _ => return false,
+        };
+        header.aa = false;
+        header.tc = false;
+        header.z = 0;
+        let m = Self::error_response(Message::new(header), rcode::FORMERR);
+        self.send_response(m, client, socket);
+        return true;
+    }
+
+    /// Turns the query `m` into a response with no records and the given rcode.
+    fn error_response(mut m: Message, code: u8) -> Message {
+        m.header.qr = true;
+        m.header.z &= Header::CHECKING_DISABLED;
+        m.header.rcode = code;
+        m.header.qdcount = m.questions.len() as u16;
+        m.header.ancount = 0;
         m.answers.clear();
-        self.orig_messages.insert(m.header.id, m);
+        m.authorities.clear();
+        m.additionals.clear();
+        m
     }
 
+    /// The preferred upstream among those that are up, or an empty string without upstreams.
     pub fn resolver(&self) -> String {
-        if let Some(resolver) = &self.resolver {
+        if let Some(resolver) = self.upstreams.first_up() {
             resolver.to_string()
         } else {
             "".to_string()
@@ -85,19 +1831,592 @@ impl DnsServer {
         m.header.qdcount = m.questions.len() as u16;
         m.header.ancount = m.questions.len() as u16;
         m.answers = Vec::new();
+        m.authorities = Vec::new();
+        m.additionals = Vec::new();
         for q in m.questions.iter_mut() {
             q.tipe = QType::A;
             q.class = ResourceClass::IN;
-            let ans = Answer {
+            let ans = Record {
                 name: q.name.clone(),
                 tipe: QType::A,
                 class: ResourceClass::IN,
                 ttl: 60,
-                rdlength: 4,
-                rdata: vec![127, 0, 0, 1],
+                rdata: RData::A(Ipv4Addr::LOCALHOST),
+                cache_flush: false,
             };
             m.answers.push(ans);
         }
         m
     }
 }
+
+/// Sends `responses`, sorted by client on the way, with those to the same client in one GSO
+/// send and the others in one batch. Returns the index and error of each that couldn't be sent;
+/// a failed GSO send counts once, for the first response to its client.
+fn send_offloaded(
+    socket: &UdpSocket,
+    responses: &mut [(BytesMut, SocketAddr)],
+) -> Vec<(usize, io::Error)> {
+    responses.sort_unstable_by_key(|(_, client)| *client);
+    let mut failed = Vec::new();
+    let mut singles = Vec::new();
+    let mut start = 0;
+    for run in responses.chunk_by(|a, b| a.1 == b.1) {
+        if let [(packet, client)] = run {
+            singles.push((start, (&packet[..], *client)));
+        } else {
+            let packets: Vec<&[u8]> = run.iter().map(|(packet, _)| &packet[..]).collect();
+            if let Err(e) = udp::send_batch(socket, run[0].1, &packets) {
+                failed.push((start, e));
+            }
+        }
+        start += run.len();
+    }
+    let batch: Vec<(&[u8], SocketAddr)> = singles.iter().map(|(_, single)| *single).collect();
+    for (i, e) in udp::send_many(socket, &batch) {
+        failed.push((singles[i].0, e));
+    }
+    return failed;
+}
+
+/// Whether `a` and `b` ask the same thing, ignoring the mDNS unicast bit.
+fn same_question(a: &Question, b: &Question) -> bool {
+    return a.name == b.name && a.tipe == b.tipe && a.class == b.class;
+}
+
+/// Whether the client behind query `m` may be told AD: only if it set AD or DO itself, as one
+/// that understands it (RFC 6840 section 5.8).
+fn asks_for_ad(m: &Message) -> bool {
+    let dnssec_ok = m.edns.as_ref().is_some_and(|edns| edns.dnssec_ok());
+    return m.header.z & Header::AUTHENTIC_DATA != 0 || dnssec_ok;
+}
+
+/// Whether `a` and `b` are the same name down to the case of every letter.
+fn same_case(a: &Name, b: &Name) -> bool {
+    return a.labels().eq(b.labels());
+}
+
+/// `name` with each of its letters in upper or lower case at random (DNS 0x20).
+fn randomize_case(name: &Name) -> Name {
+    let labels = name.labels().map(|label| {
+        let flip = |b: &u8| {
+            if b.is_ascii_alphabetic() && rand::random() {
+                b ^ 0x20
+            } else {
+                *b
+            }
+        };
+        label.iter().map(flip).collect::<Vec<u8>>()
+    });
+    // the same labels, so the same lengths.
+    return Name::from_labels(labels).unwrap();
+}
+
+/// Sets every name in the question and on records of `m` that equals `name` but for case to
+/// `name`, undoing `randomize_case` on an answer.
+fn restore_case(m: &mut Message, name: &Name) {
+    let questions = m.questions.iter_mut().map(|q| &mut q.name);
+    let owners = m
+        .answers
+        .iter_mut()
+        .chain(&mut m.authorities)
+        .chain(&mut m.additionals)
+        .map(|r| &mut r.name);
+    for n in questions.chain(owners) {
+        if n == name {
+            *n = name.clone();
+        }
+    }
+}
+
+/// Overwrites the name of the first question in `packet` with `new`, which must be `old` in
+/// another case, in place. Returns false, leaving the packet alone, unless the name is there
+/// uncompressed and exactly as `old`. Names compressed against it change along with it.
+fn replace_qname(packet: &mut [u8], old: &Name, new: &Name) -> bool {
+    let (old, new) = (old.suffix_octets(0), new.suffix_octets(0));
+    let end = 12 + old.len();
+    if old.len() != new.len() || packet.get(12..end) != Some(old) || packet.get(end) != Some(&0) {
+        return false;
+    }
+    packet[12..end].copy_from_slice(new);
+    return true;
+}
+
+fn question_key(q: &Question) -> (Name, u16, u16) {
+    return (q.name.clone(), q.tipe.value(), q.class.value());
+}
+
+/// Shrinks `map` once most of its capacity is unused, so a burst of queries doesn't pin its
+/// memory for good.
+fn shrink<K: Eq + std::hash::Hash, V>(map: &mut HashMap<K, V>) {
+    if map.capacity() > 64 && map.capacity() > map.len() * 4 {
+        map.shrink_to(map.len() * 2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::tcp::{TcpConfig, TcpServer};
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+        io::Write,
+        net::TcpStream,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    /// Counts the allocations made on each thread, so tests running alongside don't interfere.
+    struct CountingAlloc;
+
+    thread_local! {
+        /// allocations so far, and how many of those were as big as a pooled buffer
+        static ALLOCATIONS: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let big = layout.size() >= BUFFER_SIZE;
+            let _ = ALLOCATIONS.try_with(|a| {
+                let (all, bigs) = a.get();
+                a.set((all + 1, bigs + big as usize));
+            });
+            return System.alloc(layout);
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    /// Allocations `f` makes on this thread, all of them and those as big as a pooled buffer.
+    fn allocations(f: impl FnOnce()) -> (usize, usize) {
+        let (all, big) = ALLOCATIONS.with(Cell::get);
+        f();
+        let (all_after, big_after) = ALLOCATIONS.with(Cell::get);
+        return (all_after - all, big_after - big);
+    }
+
+    /// A server forwarding to a fake upstream, all on localhost, with its clock in the test's
+    /// hands.
+    struct Harness {
+        server: DnsServer,
+        clock: MockClock,
+        /// what the server answers clients on
+        socket: UdpSocket,
+        client: UdpSocket,
+        upstream: UdpSocket,
+    }
+
+    impl Harness {
+        fn new(config: ServerConfig) -> Harness {
+            let bind = || UdpSocket::bind("127.0.0.1:0").unwrap();
+            let (socket, client, upstream) = (bind(), bind(), bind());
+            client.set_nonblocking(true).unwrap();
+            upstream
+                .set_read_timeout(Some(Duration::from_secs(2)))
+                .unwrap();
+            let clock = MockClock::new();
+            let server = DnsServer::with_clock(None, Box::new(clock.clone()))
+                .with_upstreams(vec![upstream.local_addr().unwrap()])
+                .with_config(config);
+            return Harness {
+                server,
+                clock,
+                socket,
+                client,
+                upstream,
+            };
+        }
+
+        /// Hands the server a query for `name` from `client`.
+        fn ask(&mut self, id: u16, name: &str, client: &UdpSocket) {
+            let mut query = Message::new(Header::new(id));
+            query.header.rd = true;
+            query.questions.push(Question {
+                name: name.parse().unwrap(),
+                tipe: QType::A,
+                class: ResourceClass::IN,
+                unicast_response: false,
+            });
+            let mut packet = query.to_bytes().to_vec();
+            let source = client.local_addr().unwrap();
+            self.server.handle_packet(&mut packet, source, &self.socket);
+        }
+
+        /// The next query the server sent upstream, and where it came from.
+        fn upstream_query(&self) -> (Message, SocketAddr) {
+            let mut buf = [0; 4096];
+            let (len, from) = self.upstream.recv_from(&mut buf).unwrap();
+            return (Message::parse(&buf[..len]).unwrap(), from);
+        }
+
+        /// Answers `query` with an A record under `name`, with a TTL of `ttl`.
+        fn answer(&self, mut query: Message, name: &Name, ttl: u32, to: SocketAddr) {
+            let mut record: Record = "answer. 0 IN A 192.0.2.1".parse().unwrap();
+            record.name = name.clone();
+            record.ttl = ttl;
+            query.header.qr = true;
+            query.header.ra = true;
+            query.answers.push(record);
+            self.upstream.send_to(&query.to_bytes(), to).unwrap();
+        }
+
+        /// The response `client` got, reading upstream answers until it has one.
+        fn response(&mut self, client: &UdpSocket) -> Message {
+            let mut buf = [0; 4096];
+            for _ in 0..200 {
+                if let Ok(len) = client.recv(&mut buf) {
+                    return Message::parse(&buf[..len]).unwrap();
+                }
+                self.server.poll_upstreams(&self.socket);
+                thread::sleep(Duration::from_millis(5));
+            }
+            panic!("no response");
+        }
+
+        /// Whether `client` got a response already.
+        fn responded(&self, client: &UdpSocket) -> bool {
+            return client.recv(&mut [0; 4096]).is_ok();
+        }
+    }
+
+    #[test]
+    fn cached_answers_count_down_and_expire() {
+        let mut h = Harness::new(ServerConfig::default());
+        let client = h.client.try_clone().unwrap();
+        h.ask(1, "example.com", &client);
+        let (query, from) = h.upstream_query();
+        let name = query.questions[0].name.clone();
+        h.answer(query, &name, 300, from);
+        let response = h.response(&client);
+        assert_eq!(response.header.id, 1);
+        assert_eq!(response.answers[0].ttl, 300);
+
+        // answered from the cache, which counts down with the clock.
+        h.clock.advance(Duration::from_secs(100));
+        h.ask(2, "example.com", &client);
+        let response = h.response(&client);
+        assert_eq!(response.header.id, 2);
+        assert_eq!(response.answers[0].ttl, 200);
+
+        h.clock.advance(Duration::from_secs(200));
+        h.ask(3, "example.com", &client);
+        assert!(!h.responded(&client));
+        let (query, _) = h.upstream_query();
+        assert_eq!(query.questions[0].name, name);
+    }
+
+    #[test]
+    fn unanswered_queries_fail_when_their_budget_runs_out() {
+        let config = ServerConfig::default();
+        let budget = config.query_budget;
+        let mut h = Harness::new(config);
+        let client = h.client.try_clone().unwrap();
+        h.ask(7, "example.com", &client);
+        h.upstream_query();
+
+        h.clock.advance(budget - Duration::from_millis(1));
+        h.server.expire(&h.socket);
+        assert!(!h.responded(&client));
+
+        h.clock.advance(Duration::from_millis(1));
+        h.server.expire(&h.socket);
+        let response = h.response(&client);
+        assert_eq!(response.header.id, 7);
+        assert_eq!(response.header.rcode, rcode::SERVFAIL);
+        assert_eq!(h.server.stats().get(Counter::TimedOut), 1);
+    }
+
+    #[test]
+    fn clients_with_the_same_id_get_their_own_answers() {
+        let mut h = Harness::new(ServerConfig::default());
+        let first = h.client.try_clone().unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+        second.set_nonblocking(true).unwrap();
+        h.ask(5, "one.example", &first);
+        h.ask(5, "two.example", &second);
+        let (one, one_from) = h.upstream_query();
+        let (two, two_from) = h.upstream_query();
+        assert_ne!(one.header.id, two.header.id);
+
+        // answered the other way round.
+        let name = two.questions[0].name.clone();
+        h.answer(two, &name, 60, two_from);
+        let name = one.questions[0].name.clone();
+        h.answer(one, &name, 60, one_from);
+        let response = h.response(&second);
+        assert_eq!(response.header.id, 5);
+        assert_eq!(response.answers[0].name, "two.example".parse().unwrap());
+        let response = h.response(&first);
+        assert_eq!(response.header.id, 5);
+        assert_eq!(response.answers[0].name, "one.example".parse().unwrap());
+    }
+
+    #[test]
+    fn answers_must_echo_the_case_sent_upstream() {
+        let mut h = Harness::new(ServerConfig {
+            randomize_case: true,
+            ..ServerConfig::default()
+        });
+        let client = h.client.try_clone().unwrap();
+        let asked = "abcdefghijklmnopqrstuvwxyz.example.com";
+        h.ask(9, asked, &client);
+        let (query, from) = h.upstream_query();
+        let sent = query.questions[0].name.clone();
+        assert_ne!(sent.to_string(), asked.to_string() + ".");
+
+        // an answer with the name as the client wrote it didn't see our query.
+        let mut spoofed = query.clone();
+        spoofed.questions[0].name = asked.parse().unwrap();
+        h.answer(spoofed, &asked.parse().unwrap(), 60, from);
+        for _ in 0..20 {
+            h.server.poll_upstreams(&h.socket);
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!h.responded(&client));
+        assert_eq!(h.server.stats().get(Counter::Mismatched), 1);
+
+        h.answer(query, &sent, 60, from);
+        let response = h.response(&client);
+        assert_eq!(
+            response.questions[0].name.to_string(),
+            asked.to_string() + "."
+        );
+        assert_eq!(
+            response.answers[0].name.to_string(),
+            asked.to_string() + "."
+        );
+    }
+
+    #[test]
+    fn fast_path_conflicts_with_full_path_settings() {
+        let config = ServerConfig {
+            fast_forward: true,
+            ..ServerConfig::default()
+        };
+        // on by default: the cache and stripping unknown options.
+        assert_eq!(
+            config.fast_path_conflicts(),
+            ["cache_size", "unknown_options"]
+        );
+        let mut config = ServerConfig {
+            fast_forward: true,
+            cache_size: 0,
+            ..ServerConfig::default()
+        };
+        config.unknown_options.default = UnknownOptionAction::PassThrough;
+        assert!(config.fast_path_conflicts().is_empty());
+        assert!(DnsServer::new(None).with_config(config.clone()).fast_path);
+        config
+            .unknown_options
+            .per_code
+            .insert(65001, UnknownOptionAction::Copy);
+        config.nsid = Some(b"ns1".to_vec());
+        assert_eq!(config.fast_path_conflicts(), ["nsid", "unknown_options"]);
+        assert!(!DnsServer::new(None).with_config(config).fast_path);
+    }
+
+    /// A clock that panics the first time it is read after being armed, standing in for a bug
+    /// anywhere on the packet path.
+    struct ArmedClock {
+        clock: MockClock,
+        armed: Arc<AtomicBool>,
+    }
+
+    impl Clock for ArmedClock {
+        fn now(&self) -> Instant {
+            if self.armed.swap(false, Ordering::Relaxed) {
+                panic!("injected panic");
+            }
+            return self.clock.now();
+        }
+    }
+
+    #[test]
+    fn panics_cost_one_packet_not_the_server() {
+        let mut h = Harness::new(ServerConfig::default());
+        let armed = Arc::new(AtomicBool::new(false));
+        h.server.clock = Box::new(ArmedClock {
+            clock: h.clock.clone(),
+            armed: armed.clone(),
+        });
+        let arm = || armed.store(true, Ordering::Relaxed);
+        let new_client = || {
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            client.set_nonblocking(true).unwrap();
+            return client;
+        };
+        let upstream_query_for = |h: &Harness, name: &str| loop {
+            let (query, from) = h.upstream_query();
+            if query.questions[0]
+                .name
+                .to_string()
+                .eq_ignore_ascii_case(name)
+            {
+                return (query, from);
+            }
+        };
+
+        // on the way in, the client gets SERVFAIL.
+        let client = new_client();
+        arm();
+        h.ask(1, "one.example", &client);
+        let response = h.response(&client);
+        assert_eq!(response.header.id, 1);
+        assert_eq!(response.header.rcode, rcode::SERVFAIL);
+
+        // on the way back, the answer is dropped.
+        let client = new_client();
+        h.ask(2, "two.example", &client);
+        let (query, from) = upstream_query_for(&h, "two.example.");
+        let name = query.questions[0].name.clone();
+        arm();
+        h.answer(query, &name, 60, from);
+        for _ in 0..200 {
+            h.server.poll_upstreams(&h.socket);
+            if !armed.load(Ordering::Relaxed) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!armed.load(Ordering::Relaxed));
+        assert!(!h.responded(&client));
+
+        // while expiring; the next round still fails what timed out.
+        arm();
+        h.server.expire(&h.socket);
+        let client = new_client();
+        h.ask(3, "three.example", &client);
+        upstream_query_for(&h, "three.example.");
+        h.clock.advance(Duration::from_secs(3));
+        h.server.expire(&h.socket);
+        let response = h.response(&client);
+        assert_eq!(response.header.id, 3);
+        assert_eq!(response.header.rcode, rcode::SERVFAIL);
+    }
+
+    #[test]
+    fn authoritative_only_refuses_names_outside_its_zones() {
+        let zone =
+            "example.org. 3600 IN SOA ns1.example.org. admin.example.org. 1 7200 3600 1209600 300
+            www.example.org. 3600 IN A 192.0.2.10";
+        let config = ServerConfig {
+            authoritative_only: true,
+            ..ServerConfig::default()
+        };
+        let mut h = Harness::new(config);
+        h.server.zones = Zones::new(vec![Zone::parse(zone).unwrap()]);
+        let client = h.client.try_clone().unwrap();
+
+        h.ask(1, "www.example.org", &client);
+        let response = h.response(&client);
+        assert!(response.header.aa);
+        assert!(!response.header.ra);
+        assert_eq!(response.answers.len(), 1);
+
+        h.ask(2, "example.com", &client);
+        let response = h.response(&client);
+        assert_eq!(response.header.rcode, rcode::REFUSED);
+        assert!(!response.header.ra);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn cache_hits_reuse_response_buffers() {
+        let mut h = Harness::new(ServerConfig::default());
+        let client = h.client.try_clone().unwrap();
+        h.ask(1, "pooled.example", &client);
+        let (query, from) = h.upstream_query();
+        let name = query.questions[0].name.clone();
+        h.answer(query, &name, 300, from);
+        h.response(&client);
+        // the first hit fills the pool.
+        h.ask(2, "pooled.example", &client);
+        h.response(&client);
+        for id in 3..20 {
+            let (_, big) = allocations(|| h.ask(id, "pooled.example", &client));
+            assert_eq!(big, 0);
+            assert_eq!(h.response(&client).header.id, id);
+        }
+
+        let mut tcp =
+            TcpServer::bind("127.0.0.1:0".parse().unwrap(), TcpConfig::default()).unwrap();
+        let mut stream = TcpStream::connect(tcp.local_addr().unwrap()).unwrap();
+        let mut query = Message::new(Header::new(0));
+        query.header.rd = true;
+        query.questions.push(Question {
+            name,
+            tipe: QType::A,
+            class: ResourceClass::IN,
+            unicast_response: false,
+        });
+        let bites = query.to_bytes();
+        let mut answered = 0;
+        let mut first_at = None;
+        for _ in 0..200 {
+            stream
+                .write_all(&(bites.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&bites).unwrap();
+            let mut queries = vec![];
+            while queries.is_empty() {
+                queries = tcp.read_queries(Instant::now());
+            }
+            let (conn, peer, frame) = queries.pop().unwrap();
+            let mut written_at = None;
+            let (_, big) = allocations(|| {
+                h.server.handle_tcp_query(&frame, conn, peer, &h.socket);
+                h.server.flush_tcp_responses(|conn, response| {
+                    let response = response.unwrap();
+                    written_at = Some(response.as_ptr());
+                    tcp.send(conn, response, Instant::now())
+                });
+            });
+            // every response is written into the same buffer.
+            if answered > 0 {
+                assert_eq!(big, 0);
+                assert_eq!(written_at, first_at);
+            }
+            first_at = first_at.or(written_at);
+            answered += 1;
+            if answered == 3 {
+                break;
+            }
+        }
+        assert_eq!(answered, 3);
+    }
+
+    #[test]
+    fn upstream_queries_leave_the_client_records_behind() {
+        let server = DnsServer::new(None);
+        let mut small: Message = Message::new(Header::new(1));
+        small.questions.push(Question {
+            name: "example.com".parse().unwrap(),
+            tipe: QType::A,
+            class: ResourceClass::IN,
+            unicast_response: false,
+        });
+        let mut big = small.clone();
+        for _ in 0..20 {
+            big.additionals.push(
+                "extra.example. 60 IN TXT \"not sent upstream\""
+                    .parse()
+                    .unwrap(),
+            );
+        }
+        let q = small.questions[0].clone();
+        let (small_allocations, _) = allocations(|| drop(server.upstream_query(&small, &q, 2)));
+        let mut query = None;
+        let (big_allocations, _) = allocations(|| query = Some(server.upstream_query(&big, &q, 2)));
+        assert_eq!(small_allocations, big_allocations);
+        assert!(query.unwrap().additionals.is_empty());
+    }
+}
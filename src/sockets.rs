@@ -0,0 +1,102 @@
+//! The sockets upstream queries go out on. Sent from the listening socket, every query would
+//! carry the same source port, leaving an off-path attacker only the 16 bit query id to guess.
+//! Sent from sockets bound to random ports, the port has to be guessed as well. A few such
+//! sockets are kept per address family, and each is swapped for a fresh one, on a new random
+//! port, once it has sent its share of queries and has no answers left to wait for.
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+};
+
+struct Slot {
+    socket: UdpSocket,
+    v6: bool,
+    /// queries sent since the socket was opened
+    queries: u32,
+    /// queries sent from it still waiting for an answer
+    inflight: usize,
+}
+
+pub struct UpstreamSockets {
+    /// slots are only ever replaced in place, so an index keeps naming the same slot
+    slots: Vec<Slot>,
+    /// sockets kept per address family
+    per_family: usize,
+    /// queries a socket sends before it is replaced
+    max_queries: u32,
+}
+
+impl UpstreamSockets {
+    /// Up to `per_family` sockets for each address family, each replaced after sending
+    /// `max_queries` queries. Sockets are opened as they are first needed.
+    pub fn new(per_family: usize, max_queries: u32) -> UpstreamSockets {
+        return UpstreamSockets {
+            slots: Vec::new(),
+            per_family: per_family.max(1),
+            max_queries: max_queries.max(1),
+        };
+    }
+
+    /// A socket to send a query to `upstream` from, picked at random, and the slot it is in.
+    /// The query counts as waiting for an answer until `answered` is called for the slot.
+    pub fn pick(&mut self, upstream: SocketAddr) -> io::Result<(usize, &UdpSocket)> {
+        let v6 = upstream.is_ipv6();
+        let family = self.slots.iter().filter(|s| s.v6 == v6).count();
+        if family < self.per_family {
+            self.slots.push(Slot {
+                socket: bind(v6)?,
+                v6,
+                queries: 0,
+                inflight: 0,
+            });
+        }
+        let fresh: Vec<usize> = (0..self.slots.len())
+            .filter(|i| self.slots[*i].v6 == v6 && self.slots[*i].queries < self.max_queries)
+            .collect();
+        // with every socket of the family worn out and still busy, keep using them for now.
+        let candidates: Vec<usize> = if fresh.is_empty() {
+            (0..self.slots.len())
+                .filter(|i| self.slots[*i].v6 == v6)
+                .collect()
+        } else {
+            fresh
+        };
+        let i = candidates[rand::random::<usize>() % candidates.len()];
+        let slot = &mut self.slots[i];
+        slot.queries = slot.queries.saturating_add(1);
+        slot.inflight += 1;
+        return Ok((i, &self.slots[i].socket));
+    }
+
+    /// Records that a query sent from `slot` got its answer, or no longer waits for one. A worn
+    /// out socket with nothing left to wait for is replaced.
+    pub fn answered(&mut self, slot: usize) {
+        let Some(s) = self.slots.get_mut(slot) else {
+            return;
+        };
+        s.inflight = s.inflight.saturating_sub(1);
+        if s.inflight == 0 && s.queries >= self.max_queries {
+            // if no new socket can be had, the old one does no harm.
+            if let Ok(socket) = bind(s.v6) {
+                s.socket = socket;
+                s.queries = 0;
+            }
+        }
+    }
+
+    /// Every socket, with its slot.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &UdpSocket)> {
+        return self.slots.iter().map(|s| &s.socket).enumerate();
+    }
+}
+
+/// A non-blocking socket on a port picked by the kernel, which randomizes it.
+fn bind(v6: bool) -> io::Result<UdpSocket> {
+    let socket = if v6 {
+        UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?
+    } else {
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?
+    };
+    socket.set_nonblocking(true)?;
+    return Ok(socket);
+}
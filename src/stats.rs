@@ -0,0 +1,219 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    thread,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    /// queries received from clients
+    Queries,
+    /// responses sent back to clients
+    Responses,
+    /// queries sent to the upstream resolver
+    UpstreamQueries,
+    /// responses received from the upstream resolver
+    UpstreamResponses,
+    /// packets relayed by the fast forwarding path
+    FastForwarded,
+    /// packets dropped because they couldn't be parsed
+    ParseErrors,
+    /// queries refused because of in-flight limits
+    Overloaded,
+    /// queries that ran out of time waiting for the upstream resolver
+    TimedOut,
+    /// upstream queries sent again over TCP because the UDP answer was truncated
+    TcpRetries,
+    /// queries answered from the cache
+    CacheHits,
+    /// upstream queries refreshing cached answers about to expire
+    Prefetches,
+    /// queries that waited on an identical query already sent upstream instead of sending
+    /// their own
+    Coalesced,
+    /// upstream answers dropped for not matching the query they claim to answer: from another
+    /// address, or to another question
+    Mismatched,
+    /// queries refused: from clients not allowed recursion, or not asking for it with no answer
+    /// in the cache
+    Refused,
+}
+
+impl Counter {
+    /// Every counter, in the order declared above, which is also the order of their slots.
+    pub const ALL: &'static [Counter] = &[
+        Counter::Queries,
+        Counter::Responses,
+        Counter::UpstreamQueries,
+        Counter::UpstreamResponses,
+        Counter::FastForwarded,
+        Counter::ParseErrors,
+        Counter::Overloaded,
+        Counter::TimedOut,
+        Counter::TcpRetries,
+        Counter::CacheHits,
+        Counter::Prefetches,
+        Counter::Coalesced,
+        Counter::Mismatched,
+        Counter::Refused,
+    ];
+}
+
+const COUNTERS: usize = Counter::ALL.len();
+
+/// One set of counters, padded to its own cache line so shards used by different threads don't
+/// contend.
+#[repr(align(64))]
+#[derive(Default)]
+struct Shard {
+    counts: [AtomicU64; COUNTERS],
+}
+
+/// Packet path statistics. Each thread increments its own shard with relaxed atomics; the shards
+/// are only summed when somebody reads the numbers.
+pub struct Stats {
+    shards: Box<[Shard]>,
+}
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Stats {
+    /// One shard per available core.
+    pub fn new() -> Self {
+        let shards = thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_shards(shards)
+    }
+
+    pub fn with_shards(shards: usize) -> Self {
+        Stats {
+            shards: (0..shards.max(1)).map(|_| Shard::default()).collect(),
+        }
+    }
+
+    pub fn incr(&self, counter: Counter) {
+        self.add(counter, 1);
+    }
+
+    pub fn add(&self, counter: Counter, n: u64) {
+        let shard = SHARD.with(|s| *s) % self.shards.len();
+        self.shards[shard].counts[counter as usize].fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, counter: Counter) -> u64 {
+        return self
+            .shards
+            .iter()
+            .map(|s| s.counts[counter as usize].load(Ordering::Relaxed))
+            .sum();
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        return StatsSnapshot {
+            queries: self.get(Counter::Queries),
+            responses: self.get(Counter::Responses),
+            upstream_queries: self.get(Counter::UpstreamQueries),
+            upstream_responses: self.get(Counter::UpstreamResponses),
+            fast_forwarded: self.get(Counter::FastForwarded),
+            parse_errors: self.get(Counter::ParseErrors),
+            overloaded: self.get(Counter::Overloaded),
+            timed_out: self.get(Counter::TimedOut),
+            tcp_retries: self.get(Counter::TcpRetries),
+            cache_hits: self.get(Counter::CacheHits),
+            prefetches: self.get(Counter::Prefetches),
+            coalesced: self.get(Counter::Coalesced),
+            mismatched: self.get(Counter::Mismatched),
+            refused: self.get(Counter::Refused),
+        };
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counter totals at one point in time. Shards are read one after another, so under load the
+/// totals are not an atomic cut across counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub queries: u64,
+    pub responses: u64,
+    pub upstream_queries: u64,
+    pub upstream_responses: u64,
+    pub fast_forwarded: u64,
+    pub parse_errors: u64,
+    pub overloaded: u64,
+    pub timed_out: u64,
+    pub tcp_retries: u64,
+    pub cache_hits: u64,
+    pub prefetches: u64,
+    pub coalesced: u64,
+    pub mismatched: u64,
+    pub refused: u64,
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "queries={} responses={} upstream_queries={} upstream_responses={} fast_forwarded={} parse_errors={} overloaded={} timed_out={} tcp_retries={} cache_hits={} prefetches={} coalesced={} mismatched={} refused={}",
+            self.queries,
+            self.responses,
+            self.upstream_queries,
+            self.upstream_responses,
+            self.fast_forwarded,
+            self.parse_errors,
+            self.overloaded,
+            self.timed_out,
+            self.tcp_retries,
+            self.cache_hits,
+            self.prefetches,
+            self.coalesced,
+            self.mismatched,
+            self.refused
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn every_counter_has_its_own_slot() {
+        for (i, counter) in Counter::ALL.iter().enumerate() {
+            assert_eq!(*counter as usize, i);
+        }
+    }
+
+    #[test]
+    fn counts_from_all_threads_add_up() {
+        let stats = Arc::new(Stats::with_shards(2));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let stats = Arc::clone(&stats);
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        stats.incr(Counter::Queries);
+                    }
+                    stats.add(Counter::Refused, 3);
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(stats.get(Counter::Queries), 4000);
+        assert_eq!(stats.get(Counter::Responses), 0);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.queries, 4000);
+        assert_eq!(snapshot.refused, 12);
+        assert!(snapshot.to_string().contains("queries=4000 "));
+    }
+}
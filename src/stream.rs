@@ -0,0 +1,142 @@
+//! Messages over a byte stream, as on TCP and TLS connections (RFC 1035 section 4.2.2): each one
+//! is preceded by its length as a 2 byte big endian integer. Reads off a stream split and merge
+//! these frames arbitrarily, so `FrameDecoder` buffers whatever arrives and hands out frames as
+//! they complete.
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::message::{Message, MessageError};
+
+/// Length of the prefix in front of every message.
+pub const PREFIX_LEN: usize = 2;
+
+/// A message too long for the 2 byte length prefix.
+#[derive(Debug, thiserror::Error)]
+#[error("message of {0} bytes is too long to frame")]
+pub struct FrameTooLong(pub usize);
+
+/// Collects stream data and splits it into messages.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: BytesMut,
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        return FrameDecoder::default();
+    }
+
+    /// Appends data read from the stream, which may hold any number of frames or parts of them.
+    pub fn push(&mut self, bites: &[u8]) {
+        self.buf.extend_from_slice(bites);
+    }
+
+    /// Takes the next complete frame off the front, without its length prefix. None until all of
+    /// it has arrived.
+    pub fn next_frame(&mut self) -> Option<Bytes> {
+        if self.buf.len() < PREFIX_LEN {
+            return None;
+        }
+        let len = u16::from_be_bytes([self.buf[0], self.buf[1]]) as usize;
+        if self.buf.len() < PREFIX_LEN + len {
+            return None;
+        }
+        self.buf.advance(PREFIX_LEN);
+        return Some(self.buf.split_to(len).freeze());
+    }
+
+    /// Takes the next complete frame and decodes it. A malformed message only costs its own
+    /// frame; the one after it can still be read.
+    pub fn next_message(&mut self) -> Option<Result<Message, MessageError>> {
+        return self.next_frame().map(|frame| Message::parse(&frame));
+    }
+
+    /// Bytes received that aren't part of a complete frame yet.
+    pub fn buffered(&self) -> usize {
+        return self.buf.len();
+    }
+
+    /// Whether a frame has been started but not finished, e.g. when the stream ends.
+    pub fn is_partial(&self) -> bool {
+        return !self.buf.is_empty();
+    }
+}
+
+/// Appends `message` to `buf` behind its length prefix. Messages over 65535 bytes can't be
+/// framed, and leave `buf` as it was; truncate them first.
+pub fn write_frame(message: &Message, buf: &mut BytesMut) -> Result<(), FrameTooLong> {
+    let at = buf.len();
+    buf.put_u16(0);
+    message.write(buf);
+    let written = buf.len() - at - PREFIX_LEN;
+    let Ok(len) = u16::try_from(written) else {
+        buf.truncate(at);
+        return Err(FrameTooLong(written));
+    };
+    buf[at..at + PREFIX_LEN].copy_from_slice(&len.to_be_bytes());
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Header;
+    use alloc::vec;
+
+    fn framed(messages: &[&Message]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        for m in messages {
+            write_frame(m, &mut buf).unwrap();
+        }
+        return buf;
+    }
+
+    #[test]
+    fn length_prefix_split_across_reads() {
+        let m = Message::new(Header::new(7));
+        let bites = framed(&[&m]);
+        let mut frames = FrameDecoder::new();
+        frames.push(&bites[..1]);
+        assert!(frames.next_frame().is_none());
+        frames.push(&bites[1..5]);
+        assert!(frames.next_frame().is_none());
+        assert!(frames.is_partial());
+        frames.push(&bites[5..]);
+        assert_eq!(frames.next_message().unwrap().unwrap().header.id, 7);
+        assert!(!frames.is_partial());
+    }
+
+    #[test]
+    fn several_frames_in_one_read() {
+        let (one, two) = (Message::new(Header::new(1)), Message::new(Header::new(2)));
+        let mut bites = framed(&[&one, &two]);
+        // and the start of a third.
+        bites.extend_from_slice(&[0, 12, 0]);
+        let mut frames = FrameDecoder::new();
+        frames.push(&bites);
+        assert_eq!(frames.next_message().unwrap().unwrap().header.id, 1);
+        assert_eq!(frames.next_message().unwrap().unwrap().header.id, 2);
+        assert!(frames.next_frame().is_none());
+        assert_eq!(frames.buffered(), 3);
+    }
+
+    #[test]
+    fn zero_length_frame() {
+        let mut frames = FrameDecoder::new();
+        frames.push(&[0, 0, 0, 12]);
+        assert_eq!(frames.next_frame().unwrap().len(), 0);
+        // the frame after it is still found.
+        assert!(frames.next_frame().is_none());
+        assert_eq!(frames.buffered(), 2);
+    }
+
+    #[test]
+    fn messages_over_65535_bytes_are_not_framed() {
+        let mut m = Message::new(Header::new(1));
+        let record = alloc::format!("big.example. 60 IN TXT \"{}\"", "x".repeat(255));
+        m.answers = vec![record.parse().unwrap(); 300];
+        let mut buf = BytesMut::from(&b"kept"[..]);
+        let err = write_frame(&m, &mut buf).unwrap_err();
+        assert!(err.0 > u16::MAX as usize);
+        assert_eq!(&buf[..], b"kept");
+    }
+}
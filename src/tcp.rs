@@ -0,0 +1,456 @@
+//! DNS over TCP (RFC 7766). Every message on a connection is preceded by its length as a 2 byte
+//! big endian integer, see `stream`. Clients may pipeline queries, and responses go back in
+//! whatever order they become ready.
+//!
+//! Everything here is non-blocking and driven from the main loop: `wait` sleeps until something
+//! is readable, `read_queries` accepts connections and collects complete queries, `send` queues
+//! a response and `expire` closes connections that have gone quiet.
+use bytes::Bytes;
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::stream::{FrameDecoder, PREFIX_LEN};
+
+/// Identifies one accepted connection for the lifetime of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnId(u64);
+
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    /// Connections kept open at once. At the limit, a new connection evicts the connection that
+    /// has been idle longest, or is refused if none is idle.
+    pub max_connections: usize,
+    /// How long a connection with nothing in flight may stay silent before it is closed.
+    pub idle_timeout: Duration,
+    /// How long a query may take to arrive once its first byte has.
+    pub query_timeout: Duration,
+    /// Queries a connection may have waiting for answers. Past that, it isn't read from until
+    /// some are answered, and a connection that sends more than that many queries of the
+    /// largest size in one go is closed.
+    pub max_pipelined: usize,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig {
+            max_connections: 128,
+            idle_timeout: Duration::from_secs(10),
+            query_timeout: Duration::from_secs(2),
+            max_pipelined: 16,
+        }
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    peer: SocketAddr,
+    frames: FrameDecoder,
+    write_buf: Vec<u8>,
+    last_activity: Instant,
+    /// when the first byte of a still incomplete query arrived
+    partial_since: Option<Instant>,
+    /// queries handed out by `read_queries` and not answered yet
+    inflight: usize,
+    /// the client has shut down its side; close once everything is answered
+    read_closed: bool,
+}
+
+impl Connection {
+    fn is_idle(&self) -> bool {
+        self.inflight == 0 && !self.frames.is_partial() && self.write_buf.is_empty()
+    }
+
+    /// Reads whatever is available. Returns false if the connection is broken, or has more
+    /// than `max_buffered` bytes of queries waiting.
+    fn fill(&mut self, max_buffered: usize, now: Instant) -> bool {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.read_closed = true;
+                    return true;
+                }
+                Ok(n) => {
+                    self.frames.push(&chunk[..n]);
+                    self.last_activity = now;
+                    if self.frames.buffered() > max_buffered {
+                        return false;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Takes up to `max` complete frames off the front of what has been read.
+    fn take_frames(&mut self, max: usize, now: Instant) -> Vec<Bytes> {
+        let mut frames = vec![];
+        while frames.len() < max {
+            match self.frames.next_frame() {
+                Some(frame) => frames.push(frame),
+                None => break,
+            }
+        }
+        if !self.frames.is_partial() {
+            self.partial_since = None;
+        } else if !frames.is_empty() || self.partial_since.is_none() {
+            self.partial_since = Some(now);
+        }
+        frames
+    }
+
+    /// Writes as much of the write buffer as the socket takes. Returns false if the connection
+    /// is broken.
+    fn flush(&mut self, now: Instant) -> bool {
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                    self.last_activity = now;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+pub struct TcpServer {
+    listener: TcpListener,
+    config: TcpConfig,
+    conns: HashMap<ConnId, Connection>,
+    next_id: u64,
+}
+
+impl TcpServer {
+    pub fn bind(addr: SocketAddr, config: TcpConfig) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(TcpServer {
+            listener,
+            config,
+            conns: HashMap::new(),
+            next_id: 0,
+        })
+    }
+
+    /// The address the listener is bound to, e.g. to learn the port picked for port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// Blocks until `udp`, one of the `upstream` sockets, the listener or a connection has
+    /// something to do, or `timeout` passes. Returns whether `udp` is readable.
+    pub fn wait(&self, udp: &UdpSocket, upstream: &[&UdpSocket], timeout: Duration) -> bool {
+        sys::wait(self, udp, upstream, timeout)
+    }
+
+    /// Same as `wait`, for whatever stands in for the UDP socket, e.g. an io_uring that polls
+    /// readable when receives have completed.
+    #[cfg(unix)]
+    pub fn wait_on(
+        &self,
+        udp: &impl std::os::fd::AsRawFd,
+        upstream: &[&UdpSocket],
+        timeout: Duration,
+    ) -> bool {
+        sys::wait(self, udp, upstream, timeout)
+    }
+
+    /// Accepts pending connections and returns every complete query read from any connection,
+    /// with the connection and peer it came from.
+    pub fn read_queries(&mut self, now: Instant) -> Vec<(ConnId, SocketAddr, Bytes)> {
+        self.accept(now);
+        let mut queries = vec![];
+        let mut broken = vec![];
+        // room for a full pipeline of the largest queries, which is all a client waiting for
+        // its answers can have sent.
+        let max_buffered = (PREFIX_LEN + u16::MAX as usize) * self.config.max_pipelined;
+        for (id, conn) in self.conns.iter_mut() {
+            let room = self.config.max_pipelined.saturating_sub(conn.inflight);
+            // a connection with a full pipeline is left alone until some of it is answered.
+            if !conn.read_closed && room > 0 && !conn.fill(max_buffered, now) {
+                broken.push(*id);
+                continue;
+            }
+            for frame in conn.take_frames(room, now) {
+                conn.inflight += 1;
+                queries.push((*id, conn.peer, frame));
+            }
+        }
+        for id in broken {
+            self.conns.remove(&id);
+        }
+        queries
+    }
+
+    /// Queues `response` for the connection and writes what it can right away. Each call
+    /// answers one query returned by `read_queries`.
+    pub fn send(&mut self, id: ConnId, response: &[u8], now: Instant) {
+        let conn = match self.conns.get_mut(&id) {
+            Some(c) => c,
+            // the client went away while its query was being resolved.
+            None => return,
+        };
+        conn.inflight = conn.inflight.saturating_sub(1);
+        conn.write_buf
+            .extend_from_slice(&(response.len() as u16).to_be_bytes());
+        conn.write_buf.extend_from_slice(response);
+        if !conn.flush(now) {
+            self.conns.remove(&id);
+        }
+    }
+
+    /// Closes a connection, e.g. after it sent something that isn't DNS.
+    pub fn close(&mut self, id: ConnId) {
+        self.conns.remove(&id);
+    }
+
+    /// Retries pending writes, then closes connections that are idle past the idle timeout,
+    /// stuck halfway through a query past the query timeout, or finished.
+    pub fn expire(&mut self, now: Instant) {
+        let config = &self.config;
+        self.conns.retain(|_, conn| {
+            if !conn.flush(now) {
+                return false;
+            }
+            if conn.read_closed && conn.inflight == 0 && conn.write_buf.is_empty() {
+                return false;
+            }
+            if let Some(since) = conn.partial_since {
+                if now.duration_since(since) >= config.query_timeout {
+                    return false;
+                }
+            }
+            // a connection waiting on the resolver isn't idle, but one whose client stopped
+            // reading its answers is.
+            let waiting = conn.inflight > 0 && conn.write_buf.is_empty();
+            waiting || now.duration_since(conn.last_activity) < config.idle_timeout
+        });
+    }
+
+    fn accept(&mut self, now: Instant) {
+        loop {
+            let (stream, peer) = match self.listener.accept() {
+                Ok(s) => s,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            };
+            if self.conns.len() >= self.config.max_connections && !self.evict_idle() {
+                // dropping the stream refuses the connection.
+                continue;
+            }
+            if stream.set_nonblocking(true).is_err() {
+                continue;
+            }
+            let _ = stream.set_nodelay(true);
+            let id = ConnId(self.next_id);
+            self.next_id += 1;
+            self.conns.insert(
+                id,
+                Connection {
+                    stream,
+                    peer,
+                    frames: FrameDecoder::new(),
+                    write_buf: vec![],
+                    last_activity: now,
+                    partial_since: None,
+                    inflight: 0,
+                    read_closed: false,
+                },
+            );
+        }
+    }
+
+    /// Closes the connection that has been idle longest. Returns false if none is idle.
+    fn evict_idle(&mut self) -> bool {
+        let oldest = self
+            .conns
+            .iter()
+            .filter(|(_, c)| c.is_idle())
+            .min_by_key(|(_, c)| c.last_activity)
+            .map(|(id, _)| *id);
+        match oldest {
+            Some(id) => {
+                self.conns.remove(&id);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Sends `query` to `addr` over a new connection and waits for the response. Blocking, with
+/// `timeout` applying to the connect and to each read and write; meant for a worker thread.
+pub fn exchange(addr: SocketAddr, query: &[u8], timeout: Duration) -> io::Result<Bytes> {
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut framed = Vec::with_capacity(PREFIX_LEN + query.len());
+    framed.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    framed.extend_from_slice(query);
+    stream.write_all(&framed)?;
+    let mut frames = FrameDecoder::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(frame) = frames.next_frame() {
+            return Ok(frame);
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => frames.push(&chunk[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::{net::UdpSocket, os::fd::AsRawFd, time::Duration};
+
+    use super::TcpServer;
+
+    pub fn wait(
+        tcp: &TcpServer,
+        udp: &impl AsRawFd,
+        upstream: &[&UdpSocket],
+        timeout: Duration,
+    ) -> bool {
+        let mut fds = vec![
+            libc::pollfd {
+                fd: udp.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: tcp.listener.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        for conn in tcp.conns.values() {
+            let mut events = 0;
+            if !conn.read_closed && conn.inflight < tcp.config.max_pipelined {
+                events |= libc::POLLIN;
+            }
+            if !conn.write_buf.is_empty() {
+                events |= libc::POLLOUT;
+            }
+            fds.push(libc::pollfd {
+                fd: conn.stream.as_raw_fd(),
+                events,
+                revents: 0,
+            });
+        }
+        for socket in upstream {
+            fds.push(libc::pollfd {
+                fd: socket.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            });
+        }
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as libc::c_int;
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        // on error, let the caller try the socket anyway.
+        ready < 0 || fds[0].revents != 0
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::{net::UdpSocket, thread, time::Duration};
+
+    use super::TcpServer;
+
+    pub fn wait(_: &TcpServer, _: &UdpSocket, _: &[&UdpSocket], timeout: Duration) -> bool {
+        thread::sleep(timeout.min(Duration::from_millis(1)));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn framed(len: usize, count: usize) -> Vec<u8> {
+        let mut bites = vec![];
+        for _ in 0..count {
+            bites.extend_from_slice(&(len as u16).to_be_bytes());
+            bites.resize(bites.len() + len, 0);
+        }
+        return bites;
+    }
+
+    /// Calls `read_queries` until `done` holds of the queries read so far, or gives up.
+    fn read_until(
+        server: &mut TcpServer,
+        done: impl Fn(&TcpServer, &[(ConnId, SocketAddr, Bytes)]) -> bool,
+    ) -> Vec<(ConnId, SocketAddr, Bytes)> {
+        let mut queries = vec![];
+        for _ in 0..200 {
+            queries.extend(server.read_queries(Instant::now()));
+            if done(server, &queries) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        return queries;
+    }
+
+    #[test]
+    fn full_pipeline_is_not_read_until_answered() {
+        let config = TcpConfig {
+            max_pipelined: 2,
+            ..TcpConfig::default()
+        };
+        let mut server = TcpServer::bind("127.0.0.1:0".parse().unwrap(), config).unwrap();
+        let mut client = TcpStream::connect(server.listener.local_addr().unwrap()).unwrap();
+        client.write_all(&framed(12, 5)).unwrap();
+        let queries = read_until(&mut server, |_, q| q.len() >= 2);
+        assert_eq!(queries.len(), 2);
+        let buffered = |server: &TcpServer| server.conns.values().next().unwrap().frames.buffered();
+        let before = buffered(&server);
+        // whatever comes next waits in the socket, not in our buffers.
+        client.write_all(&framed(12, 5)).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(server.read_queries(Instant::now()).is_empty());
+        assert_eq!(buffered(&server), before);
+
+        server.send(queries[0].0, &[0; 12], Instant::now());
+        let more = read_until(&mut server, |_, q| !q.is_empty());
+        assert_eq!(more.len(), 1);
+    }
+
+    #[test]
+    fn connection_sending_past_the_pipeline_is_closed() {
+        let config = TcpConfig {
+            max_pipelined: 2,
+            ..TcpConfig::default()
+        };
+        let mut server = TcpServer::bind("127.0.0.1:0".parse().unwrap(), config).unwrap();
+        let mut client = TcpStream::connect(server.listener.local_addr().unwrap()).unwrap();
+        // more than two of the largest queries, in one go.
+        let writer = thread::spawn(move || {
+            let _ = client.write_all(&framed(u16::MAX as usize, 4));
+            client
+        });
+        read_until(&mut server, |server, _| server.connection_count() == 0);
+        assert_eq!(server.connection_count(), 0);
+        drop(writer.join());
+    }
+}
@@ -0,0 +1,335 @@
+//! Helpers for the presentation format of records (RFC 1035 section 5), as used in zone files
+//! and dig output.
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+use crate::name::{Name, NameError};
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE32HEX: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+/// Writes `bites` as uppercase hex.
+pub(crate) fn write_hex(f: &mut fmt::Formatter<'_>, bites: &[u8]) -> fmt::Result {
+    for b in bites {
+        write!(f, "{:02X}", b)?;
+    }
+    return Ok(());
+}
+
+/// Writes `bites` as padded base64 (RFC 4648 section 4).
+pub(crate) fn write_base64(f: &mut fmt::Formatter<'_>, bites: &[u8]) -> fmt::Result {
+    for chunk in bites.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let sextet = (n >> (18 - 6 * i)) & 0x3f;
+                write!(f, "{}", BASE64[sextet as usize] as char)?;
+            } else {
+                f.write_str("=")?;
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// Writes `bites` as unpadded base32 with the extended hex alphabet (RFC 4648 section 7), as
+/// NSEC3 hashes are.
+pub(crate) fn write_base32hex(f: &mut fmt::Formatter<'_>, bites: &[u8]) -> fmt::Result {
+    for chunk in bites.chunks(5) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u64, |n, (i, &b)| n | (b as u64) << (32 - 8 * i));
+        // every started quintet of bits gets a digit.
+        let digits = (chunk.len() * 8).div_ceil(5);
+        for i in 0..digits {
+            let quintet = (n >> (35 - 5 * i)) & 0x1f;
+            write!(f, "{}", BASE32HEX[quintet as usize] as char)?;
+        }
+    }
+    return Ok(());
+}
+
+/// Writes a character-string in double quotes, escaping quotes and backslashes with `\` and
+/// bytes outside printable ASCII as `\DDD`.
+pub(crate) fn write_quoted(f: &mut fmt::Formatter<'_>, s: &[u8]) -> fmt::Result {
+    f.write_str("\"")?;
+    for &b in s {
+        match b {
+            b'"' | b'\\' => write!(f, "\\{}", b as char)?,
+            0x20..=0x7e => write!(f, "{}", b as char)?,
+            _ => write!(f, "\\{:03}", b)?,
+        }
+    }
+    return f.write_str("\"");
+}
+
+/// Writes seconds since the epoch as YYYYMMDDHHmmSS in UTC, the RRSIG time format
+/// (RFC 4034 section 3.2).
+pub(crate) fn write_timestamp(f: &mut fmt::Formatter<'_>, secs: u32) -> fmt::Result {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // days to civil date, after Howard Hinnant's algorithm.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    return write!(
+        f,
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    );
+}
+
+/// Why a record in presentation form couldn't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextError {
+    /// the text ended before every field was read
+    MissingField,
+    /// more fields than the record type has
+    TrailingField,
+    /// the named field isn't valid, e.g. a TTL that isn't a number
+    BadField(&'static str),
+    /// a domain name isn't valid
+    Name(NameError),
+    /// a quote or parenthesis is never closed
+    Unbalanced,
+}
+
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TextError::MissingField => f.write_str("missing field"),
+            TextError::TrailingField => f.write_str("unexpected trailing field"),
+            TextError::BadField(field) => write!(f, "bad {}", field),
+            TextError::Name(e) => write!(f, "bad name: {}", e),
+            TextError::Unbalanced => f.write_str("unbalanced quote or parenthesis"),
+        }
+    }
+}
+
+impl From<NameError> for TextError {
+    fn from(e: NameError) -> TextError {
+        return TextError::Name(e);
+    }
+}
+
+/// Splits a line into whitespace separated fields. Quoted strings are kept whole, quotes
+/// included, and escapes are left for the field's own parser. Parentheses only group lines in
+/// zone files, so they are skipped, and a `;` starts a comment.
+pub(crate) fn tokenize(s: &str) -> Result<Vec<&str>, TextError> {
+    let mut tokens = Vec::new();
+    let bites = s.as_bytes();
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < bites.len() {
+        match bites[i] {
+            b' ' | b'\t' | b'\r' | b'\n' => i += 1,
+            b'(' => {
+                depth += 1;
+                i += 1;
+            }
+            b')' => {
+                depth = depth.checked_sub(1).ok_or(TextError::Unbalanced)?;
+                i += 1;
+            }
+            b';' => break,
+            _ => {
+                let start = i;
+                let mut quoted = false;
+                while i < bites.len() {
+                    match bites[i] {
+                        b'\\' => i += 1,
+                        b'"' => quoted = !quoted,
+                        b' ' | b'\t' | b'\r' | b'\n' | b'(' | b')' | b';' if !quoted => break,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                if quoted {
+                    return Err(TextError::Unbalanced);
+                }
+                tokens.push(&s[start..i.min(bites.len())]);
+            }
+        }
+    }
+    if depth != 0 {
+        return Err(TextError::Unbalanced);
+    }
+    return Ok(tokens);
+}
+
+/// The bytes of a character-string field: quotes dropped, `\X` and `\DDD` escapes resolved.
+pub(crate) fn unquote(token: &str) -> Result<Vec<u8>, TextError> {
+    let mut out = Vec::with_capacity(token.len());
+    let mut bites = token.bytes();
+    while let Some(b) = bites.next() {
+        match b {
+            b'"' => {}
+            b'\\' => {
+                let first = bites.next().ok_or(TextError::BadField("escape"))?;
+                if !first.is_ascii_digit() {
+                    out.push(first);
+                    continue;
+                }
+                let mut value = (first - b'0') as u16;
+                for _ in 0..2 {
+                    match bites.next() {
+                        Some(d) if d.is_ascii_digit() => value = value * 10 + (d - b'0') as u16,
+                        _ => return Err(TextError::BadField("escape")),
+                    }
+                }
+                out.push(u8::try_from(value).map_err(|_| TextError::BadField("escape"))?);
+            }
+            _ => out.push(b),
+        }
+    }
+    return Ok(out);
+}
+
+pub(crate) fn parse_hex(s: &str) -> Result<Vec<u8>, TextError> {
+    let bad = TextError::BadField("hex");
+    if !s.len().is_multiple_of(2) {
+        return Err(bad);
+    }
+    return (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or(bad)
+        })
+        .collect();
+}
+
+/// Decodes padded base64. Padding may be left out.
+pub(crate) fn parse_base64(s: &str) -> Result<Vec<u8>, TextError> {
+    let s = s.trim_end_matches('=');
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let mut n: u32 = 0;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let sextet = BASE64
+            .iter()
+            .position(|&d| d == c)
+            .ok_or(TextError::BadField("base64"))?;
+        n = n << 6 | sextet as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    return Ok(out);
+}
+
+/// Decodes unpadded base32 with the extended hex alphabet, in either case.
+pub(crate) fn parse_base32hex(s: &str) -> Result<Vec<u8>, TextError> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut n: u64 = 0;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let quintet = BASE32HEX
+            .iter()
+            .position(|&d| d == c.to_ascii_uppercase())
+            .ok_or(TextError::BadField("base32"))?;
+        n = n << 5 | quintet as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    return Ok(out);
+}
+
+/// Reads an RRSIG time: YYYYMMDDHHmmSS in UTC, or plain seconds since the epoch.
+pub(crate) fn parse_timestamp(s: &str) -> Result<u32, TextError> {
+    let bad = TextError::BadField("timestamp");
+    if s.len() != 14 {
+        return s.parse().map_err(|_| bad);
+    }
+    let field = |range: core::ops::Range<usize>| -> Result<i64, TextError> {
+        return s.get(range).and_then(|d| d.parse().ok()).ok_or(bad);
+    };
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hour, minute, second) = (field(8..10)?, field(10..12)?, field(12..14)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return Err(bad);
+    }
+    // civil date to days, the inverse of the conversion in write_timestamp.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    // RRSIG times are serial numbers, so later dates wrap around.
+    return Ok(secs.rem_euclid(1 << 32) as u32);
+}
+
+/// The fields of one record, read front to back.
+pub(crate) struct Fields<'a> {
+    tokens: &'a [&'a str],
+}
+
+impl<'a> Fields<'a> {
+    pub(crate) fn new(tokens: &'a [&'a str]) -> Fields<'a> {
+        return Fields { tokens };
+    }
+
+    pub(crate) fn peek(&self) -> Option<&'a str> {
+        return self.tokens.first().copied();
+    }
+
+    pub(crate) fn next(&mut self) -> Result<&'a str, TextError> {
+        let (first, rest) = self.tokens.split_first().ok_or(TextError::MissingField)?;
+        self.tokens = rest;
+        return Ok(first);
+    }
+
+    pub(crate) fn number<T: core::str::FromStr>(
+        &mut self,
+        what: &'static str,
+    ) -> Result<T, TextError> {
+        return self.next()?.parse().map_err(|_| TextError::BadField(what));
+    }
+
+    pub(crate) fn name(&mut self) -> Result<Name, TextError> {
+        return Ok(self.next()?.parse()?);
+    }
+
+    /// Every field left, joined, for base64 and hex data that zone files may split up.
+    pub(crate) fn joined_rest(&mut self) -> String {
+        let rest = self.tokens.concat();
+        self.tokens = &[];
+        return rest;
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        return self.tokens.is_empty();
+    }
+
+    /// Fails if any field is left over.
+    pub(crate) fn finish(&self) -> Result<(), TextError> {
+        if !self.tokens.is_empty() {
+            return Err(TextError::TrailingField);
+        }
+        return Ok(());
+    }
+}
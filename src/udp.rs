@@ -0,0 +1,501 @@
+//! Batched UDP I/O. On Linux, responses to the same destination can be handed to the kernel in a
+//! single `sendmsg` using UDP segmentation offload (GSO), and receive offload (GRO) lets one
+//! `recvmsg` return several coalesced datagrams. Datagrams from and to any number of peers can be
+//! moved in one `recvmmsg` or `sendmmsg`. Elsewhere, or when the kernel refuses, everything falls
+//! back to one syscall per datagram.
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+/// Largest number of segments the kernel accepts in one GSO send.
+pub const MAX_GSO_SEGMENTS: usize = 64;
+/// Largest payload a single UDP send can carry over IPv4.
+pub const MAX_UDP_PAYLOAD: usize = 65507;
+/// Most datagrams `recv_many` and `send_many` move in one syscall.
+pub const MAX_BATCH: usize = 64;
+
+/// Sends every packet in `packets` to `dest`. Runs of equally sized packets (the last of a run
+/// may be shorter) go out as one GSO send where supported.
+pub fn send_batch(socket: &UdpSocket, dest: SocketAddr, packets: &[&[u8]]) -> io::Result<()> {
+    let mut start = 0;
+    while start < packets.len() {
+        let size = packets[start].len();
+        let fits = |start: usize, end: usize| {
+            end - start < MAX_GSO_SEGMENTS && (end - start + 1) * size <= MAX_UDP_PAYLOAD
+        };
+        let mut end = start + 1;
+        while end < packets.len() && fits(start, end) && packets[end].len() == size {
+            end += 1;
+        }
+        // one shorter packet may close the run.
+        if end < packets.len() && fits(start, end) && packets[end].len() < size {
+            end += 1;
+        }
+        let run = &packets[start..end];
+        if run.len() == 1 || sys::send_gso(socket, dest, run, size).is_err() {
+            for p in run {
+                socket.send_to(p, dest)?;
+            }
+        }
+        start = end;
+    }
+    return Ok(());
+}
+
+/// Asks the kernel to coalesce incoming datagrams. Returns false where GRO isn't available, in
+/// which case `recv_segments` still works but always returns one datagram.
+pub fn enable_gro(socket: &UdpSocket) -> bool {
+    return sys::enable_gro(socket).is_ok();
+}
+
+/// Receives into `buf` and returns the total length, the sender, and the size of each coalesced
+/// datagram. Split `buf[..len]` into `segment_size` chunks (the last may be shorter) to get the
+/// individual datagrams back.
+pub fn recv_segments(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, usize)> {
+    return sys::recv_gro(socket, buf);
+}
+
+/// Receives up to one datagram into each of `bufs`, at most `MAX_BATCH`, and returns how many
+/// came in. `received` is filled with the length and sender of each, in order. Waits, as the
+/// socket is set to, for the first datagram only. Without `recvmmsg`, receives just one.
+pub fn recv_many(
+    socket: &UdpSocket,
+    bufs: &mut [Vec<u8>],
+    received: &mut Vec<(usize, SocketAddr)>,
+) -> io::Result<usize> {
+    received.clear();
+    sys::recv_many(socket, bufs, received)?;
+    return Ok(received.len());
+}
+
+/// Sends each packet to the address paired with it, `MAX_BATCH` at a time. Returns the index
+/// and error of every packet that couldn't be sent; the others still are.
+pub fn send_many<P: AsRef<[u8]>>(
+    socket: &UdpSocket,
+    packets: &[(P, SocketAddr)],
+) -> Vec<(usize, io::Error)> {
+    return sys::send_many(socket, packets);
+}
+
+/// Binds a socket to `addr` with SO_REUSEPORT set, so that several sockets, e.g. one per worker
+/// thread, can share the port and the kernel spreads incoming datagrams across them. Linux only.
+pub fn bind_reuseport(addr: SocketAddr) -> io::Result<UdpSocket> {
+    return sys::bind_reuseport(addr);
+}
+
+/// Waits until `socket` or any of `others` is readable, or `timeout` passes. Returns whether
+/// `socket` is readable. Where sockets can't be polled, returns right away that it is.
+#[cfg(unix)]
+pub fn wait(socket: &UdpSocket, others: &[&UdpSocket], timeout: Duration) -> bool {
+    return wait_on(socket, others, timeout);
+}
+
+/// Same as `wait`, for whatever stands in for the socket, e.g. an io_uring that polls readable
+/// when receives have completed.
+#[cfg(unix)]
+pub fn wait_on(
+    socket: &impl std::os::fd::AsRawFd,
+    others: &[&UdpSocket],
+    timeout: Duration,
+) -> bool {
+    let mut fds = vec![socket.as_raw_fd()];
+    fds.extend(others.iter().map(|s| std::os::fd::AsRawFd::as_raw_fd(*s)));
+    let ready = poll_readable(&fds, timeout);
+    // on error, let the caller try the socket anyway.
+    return ready.first().copied().unwrap_or(true);
+}
+
+#[cfg(not(unix))]
+pub fn wait(_: &UdpSocket, _: &[&UdpSocket], _: Duration) -> bool {
+    return true;
+}
+
+/// Which of `sockets` are readable right now.
+#[cfg(unix)]
+pub fn readable(sockets: &[&UdpSocket]) -> Vec<bool> {
+    let fds: Vec<_> = sockets
+        .iter()
+        .map(|s| std::os::fd::AsRawFd::as_raw_fd(*s))
+        .collect();
+    let ready = poll_readable(&fds, Duration::ZERO);
+    if ready.is_empty() {
+        return vec![true; sockets.len()];
+    }
+    return ready;
+}
+
+#[cfg(not(unix))]
+pub fn readable(sockets: &[&UdpSocket]) -> Vec<bool> {
+    return vec![true; sockets.len()];
+}
+
+/// Polls `fds` for reading, for up to `timeout`. Returns which are readable, or nothing if the
+/// poll failed.
+#[cfg(unix)]
+fn poll_readable(fds: &[std::os::fd::RawFd], timeout: Duration) -> Vec<bool> {
+    let mut polled: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|fd| libc::pollfd {
+            fd: *fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let timeout = timeout.as_millis().min(i32::MAX as u128) as libc::c_int;
+    let ready = unsafe { libc::poll(polled.as_mut_ptr(), polled.len() as libc::nfds_t, timeout) };
+    if ready < 0 {
+        return vec![];
+    }
+    return polled.iter().map(|p| p.revents != 0).collect();
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub(crate) use sys::{socket_addr_from_raw, socket_addr_to_raw};
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{
+        io, mem,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+        ptr,
+    };
+
+    use super::MAX_BATCH;
+
+    pub fn socket_addr_to_raw(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(a) => {
+                let sin = libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: a.port().to_be(),
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from_ne_bytes(a.ip().octets()),
+                    },
+                    sin_zero: [0; 8],
+                };
+                unsafe { ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, sin) };
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(a) => {
+                let sin6 = libc::sockaddr_in6 {
+                    sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                    sin6_port: a.port().to_be(),
+                    sin6_flowinfo: a.flowinfo(),
+                    sin6_addr: libc::in6_addr {
+                        s6_addr: a.ip().octets(),
+                    },
+                    sin6_scope_id: a.scope_id(),
+                };
+                unsafe { ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, sin6) };
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        return (storage, len as libc::socklen_t);
+    }
+
+    pub fn socket_addr_from_raw(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+                Ok(SocketAddr::V4(SocketAddrV4::new(
+                    ip,
+                    u16::from_be(sin.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    ip,
+                    u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo,
+                    sin6.sin6_scope_id,
+                )))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unsupported address family",
+            )),
+        }
+    }
+
+    pub fn send_gso(
+        socket: &UdpSocket,
+        dest: SocketAddr,
+        segments: &[&[u8]],
+        segment_size: usize,
+    ) -> io::Result<()> {
+        let mut payload: Vec<u8> = Vec::with_capacity(segment_size * segments.len());
+        for s in segments {
+            payload.extend_from_slice(s);
+        }
+        let (mut addr, addr_len) = socket_addr_to_raw(dest);
+        let mut iov = libc::iovec {
+            iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+            iov_len: payload.len(),
+        };
+        let space = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as usize;
+        let mut control = vec![0u8; space];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = addr_len;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size as u16);
+        }
+        let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(());
+    }
+
+    pub fn enable_gro(socket: &UdpSocket) -> io::Result<()> {
+        let on: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_GRO,
+                &on as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(());
+    }
+
+    pub fn bind_reuseport(addr: SocketAddr) -> io::Result<UdpSocket> {
+        let family = if addr.is_ipv4() {
+            libc::AF_INET
+        } else {
+            libc::AF_INET6
+        };
+        let fd = unsafe { libc::socket(family, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // owned right away, so the descriptor is closed on every error path below.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let on: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_REUSEPORT,
+                &on as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (storage, len) = socket_addr_to_raw(addr);
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &storage as *const _ as *const libc::sockaddr,
+                len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(UdpSocket::from(fd));
+    }
+
+    pub fn recv_many(
+        socket: &UdpSocket,
+        bufs: &mut [Vec<u8>],
+        received: &mut Vec<(usize, SocketAddr)>,
+    ) -> io::Result<()> {
+        // on the stack, so a receive allocates nothing.
+        let mut addrs: [libc::sockaddr_storage; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut iovs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+        let count = bufs.len().min(MAX_BATCH);
+        for (((buf, addr), iov), msg) in bufs
+            .iter_mut()
+            .zip(&mut addrs)
+            .zip(&mut iovs)
+            .zip(&mut msgs)
+        {
+            *iov = libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            };
+            msg.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+        }
+        // blocks for the first datagram, then takes only what is already there.
+        let n = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                count as libc::c_uint,
+                libc::MSG_WAITFORONE,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for (msg, addr) in msgs.iter().zip(&addrs).take(n as usize) {
+            received.push((msg.msg_len as usize, socket_addr_from_raw(addr)?));
+        }
+        return Ok(());
+    }
+
+    pub fn send_many<P: AsRef<[u8]>>(
+        socket: &UdpSocket,
+        packets: &[(P, SocketAddr)],
+    ) -> Vec<(usize, io::Error)> {
+        let mut failed = Vec::new();
+        let mut start = 0;
+        while start < packets.len() {
+            let mut addrs: [libc::sockaddr_storage; MAX_BATCH] = unsafe { mem::zeroed() };
+            let mut iovs: [libc::iovec; MAX_BATCH] = unsafe { mem::zeroed() };
+            let mut msgs: [libc::mmsghdr; MAX_BATCH] = unsafe { mem::zeroed() };
+            let batch = &packets[start..packets.len().min(start + MAX_BATCH)];
+            for ((((packet, dest), addr), iov), msg) in batch
+                .iter()
+                .map(|(p, dest)| (p.as_ref(), dest))
+                .zip(&mut addrs)
+                .zip(&mut iovs)
+                .zip(&mut msgs)
+            {
+                let (raw, len) = socket_addr_to_raw(*dest);
+                *addr = raw;
+                // sendmmsg only reads the payload, whatever the pointer type says.
+                *iov = libc::iovec {
+                    iov_base: packet.as_ptr() as *mut libc::c_void,
+                    iov_len: packet.len(),
+                };
+                msg.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+                msg.msg_hdr.msg_namelen = len;
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+            }
+            let sent = unsafe {
+                libc::sendmmsg(
+                    socket.as_raw_fd(),
+                    msgs.as_mut_ptr(),
+                    batch.len() as libc::c_uint,
+                    0,
+                )
+            };
+            if sent > 0 {
+                start += sent as usize;
+                continue;
+            }
+            // the first packet of the batch failed; skip it and go on with the rest.
+            let e = if sent < 0 {
+                io::Error::last_os_error()
+            } else {
+                io::ErrorKind::WriteZero.into()
+            };
+            failed.push((start, e));
+            start += 1;
+        }
+        return failed;
+    }
+
+    pub fn recv_gro(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, usize)> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let space = unsafe { libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as u32) } as usize;
+        let mut control = vec![0u8; space];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = len as usize;
+        let mut segment_size = len;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                    let size = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                    segment_size = size as usize;
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        return Ok((len, socket_addr_from_raw(&addr)?, segment_size));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::{
+        io,
+        net::{SocketAddr, UdpSocket},
+    };
+
+    pub fn send_gso(_: &UdpSocket, _: SocketAddr, _: &[&[u8]], _: usize) -> io::Result<()> {
+        return Err(io::ErrorKind::Unsupported.into());
+    }
+
+    pub fn enable_gro(_: &UdpSocket) -> io::Result<()> {
+        return Err(io::ErrorKind::Unsupported.into());
+    }
+
+    pub fn bind_reuseport(_: SocketAddr) -> io::Result<UdpSocket> {
+        return Err(io::ErrorKind::Unsupported.into());
+    }
+
+    pub fn recv_gro(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, usize)> {
+        let (len, source) = socket.recv_from(buf)?;
+        return Ok((len, source, len));
+    }
+
+    pub fn recv_many(
+        socket: &UdpSocket,
+        bufs: &mut [Vec<u8>],
+        received: &mut Vec<(usize, SocketAddr)>,
+    ) -> io::Result<()> {
+        if let Some(buf) = bufs.first_mut() {
+            received.push(socket.recv_from(buf)?);
+        }
+        return Ok(());
+    }
+
+    pub fn send_many<P: AsRef<[u8]>>(
+        socket: &UdpSocket,
+        packets: &[(P, SocketAddr)],
+    ) -> Vec<(usize, io::Error)> {
+        let mut failed = Vec::new();
+        for (i, (packet, dest)) in packets.iter().enumerate() {
+            if let Err(e) = socket.send_to(packet.as_ref(), dest) {
+                failed.push((i, e));
+            }
+        }
+        return failed;
+    }
+}
@@ -0,0 +1,254 @@
+//! The upstream resolvers queries are forwarded to, and how healthy each one looks. An upstream
+//! that lets too many queries in a row time out is marked down: queries go to the others until
+//! a probe, sent to it every so often, gets an answer again. Among those that are up, a
+//! `Selection` strategy picks where each query goes. With all of them down, queries go to the
+//! first one given, whatever the strategy, rather than nowhere.
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Round trip time an unanswered query counts as, at most.
+const UNANSWERED_RTT: Duration = Duration::from_secs(2);
+
+/// How the upstream for a query is picked among those that are up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Selection {
+    /// Always the first one, in the order given. The others are only fallbacks.
+    #[default]
+    Priority,
+    /// Each in turn.
+    RoundRobin,
+    /// Any of them, at random.
+    Random,
+    /// The one that has been answering fastest, by smoothed round trip time. Upstreams without
+    /// a measurement yet go first, so every one gets measured.
+    LowestLatency,
+}
+
+#[derive(Debug, Clone)]
+struct Upstream {
+    addr: SocketAddr,
+    /// queries in a row that went unanswered
+    failures: u32,
+    /// smoothed round trip time, None until the first answer
+    srtt: Option<Duration>,
+    /// when a down upstream gets its next probe; None while it is up
+    next_probe: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Upstreams {
+    upstreams: Vec<Upstream>,
+    /// where round robin selection goes next
+    next: usize,
+}
+
+impl Upstreams {
+    /// The upstreams at `addrs`, all assumed up.
+    pub fn new(addrs: Vec<SocketAddr>) -> Upstreams {
+        let upstreams = addrs
+            .into_iter()
+            .map(|addr| Upstream {
+                addr,
+                failures: 0,
+                srtt: None,
+                next_probe: None,
+            })
+            .collect();
+        return Upstreams { upstreams, next: 0 };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.upstreams.is_empty();
+    }
+
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        return self.upstreams.iter().any(|u| u.addr == addr);
+    }
+
+    /// The upstream the next query goes to, picked by `selection` among those that are up. With
+    /// every upstream down, the first one anyway, since a query that might be answered beats
+    /// none. None without upstreams.
+    pub fn select(&mut self, selection: Selection) -> Option<SocketAddr> {
+        let up: Vec<&Upstream> = self
+            .upstreams
+            .iter()
+            .filter(|u| u.next_probe.is_none())
+            .collect();
+        if up.is_empty() {
+            return self.upstreams.first().map(|u| u.addr);
+        }
+        let chosen = match selection {
+            Selection::Priority => up[0],
+            Selection::RoundRobin => {
+                let chosen = up[self.next % up.len()];
+                self.next = self.next.wrapping_add(1);
+                chosen
+            }
+            Selection::Random => up[rand::random::<usize>() % up.len()],
+            Selection::LowestLatency => up
+                .iter()
+                .min_by_key(|u| u.srtt.unwrap_or(Duration::ZERO))
+                .unwrap(),
+        };
+        return Some(chosen.addr);
+    }
+
+    /// The first upstream that is up, in the order given, as `Selection::Priority` picks it.
+    pub fn first_up(&self) -> Option<SocketAddr> {
+        let up = self.upstreams.iter().find(|u| u.next_probe.is_none());
+        return up.or(self.upstreams.first()).map(|u| u.addr);
+    }
+
+    /// Records an answer from `addr`, which brings it back up if it was down. `rtt` is how long
+    /// it took, if known.
+    pub fn success(&mut self, addr: SocketAddr, rtt: Option<Duration>) {
+        if let Some(u) = self.upstreams.iter_mut().find(|u| u.addr == addr) {
+            u.failures = 0;
+            u.next_probe = None;
+            if let Some(rtt) = rtt {
+                // the usual 7/8 smoothing, as for TCP (RFC 6298).
+                u.srtt = Some(match u.srtt {
+                    Some(srtt) => (srtt * 7 + rtt) / 8,
+                    None => rtt,
+                });
+            }
+        }
+    }
+
+    /// Records a query to `addr` that went unanswered. After `max_failures` in a row it is
+    /// marked down, with its first probe due `probe_interval` from `now`. Returns whether it
+    /// just went down.
+    pub fn failure(
+        &mut self,
+        addr: SocketAddr,
+        max_failures: u32,
+        probe_interval: Duration,
+        now: Instant,
+    ) -> bool {
+        let Some(u) = self.upstreams.iter_mut().find(|u| u.addr == addr) else {
+            return false;
+        };
+        u.failures = u.failures.saturating_add(1);
+        // an unanswered query counts as a slow answer, so with latency based selection an
+        // upstream that struggles loses its turn well before it is marked down.
+        u.srtt = Some(match u.srtt {
+            Some(srtt) => (srtt * 2).min(UNANSWERED_RTT).max(srtt),
+            None => UNANSWERED_RTT,
+        });
+        if u.next_probe.is_some() || u.failures < max_failures.max(1) {
+            return false;
+        }
+        u.next_probe = Some(now + probe_interval);
+        return true;
+    }
+
+    /// The upstreams that are down and due for a probe at `now`. Their next probe is scheduled
+    /// `probe_interval` later, in case this one goes unanswered too.
+    pub fn due_probes(&mut self, probe_interval: Duration, now: Instant) -> Vec<SocketAddr> {
+        let mut due = vec![];
+        for u in self.upstreams.iter_mut() {
+            if let Some(at) = u.next_probe {
+                if at <= now {
+                    u.next_probe = Some(now + probe_interval);
+                    due.push(u.addr);
+                }
+            }
+        }
+        return due;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+    fn upstreams(n: u16) -> (Upstreams, Vec<SocketAddr>) {
+        let addrs: Vec<SocketAddr> = (1..=n)
+            .map(|i| SocketAddr::from(([192, 0, 2, i as u8], 53)))
+            .collect();
+        return (Upstreams::new(addrs.clone()), addrs);
+    }
+
+    /// Lets `max_failures` queries to `addr` go unanswered, taking it down.
+    fn take_down(upstreams: &mut Upstreams, addr: SocketAddr, now: Instant) {
+        assert!(!upstreams.failure(addr, 2, PROBE_INTERVAL, now));
+        assert!(upstreams.failure(addr, 2, PROBE_INTERVAL, now));
+    }
+
+    #[test]
+    fn round_robin_takes_turns_among_those_up() {
+        let (mut upstreams, addrs) = upstreams(3);
+        let picked: Vec<_> = (0..6)
+            .map(|_| upstreams.select(Selection::RoundRobin).unwrap())
+            .collect();
+        assert_eq!(
+            picked,
+            [addrs[0], addrs[1], addrs[2], addrs[0], addrs[1], addrs[2]]
+        );
+
+        take_down(&mut upstreams, addrs[1], Instant::now());
+        for _ in 0..4 {
+            assert_ne!(upstreams.select(Selection::RoundRobin), Some(addrs[1]));
+        }
+    }
+
+    #[test]
+    fn down_upstreams_are_skipped_until_they_answer_a_probe() {
+        let (mut upstreams, addrs) = upstreams(2);
+        let now = Instant::now();
+        assert_eq!(upstreams.select(Selection::Priority), Some(addrs[0]));
+        take_down(&mut upstreams, addrs[0], now);
+        assert_eq!(upstreams.select(Selection::Priority), Some(addrs[1]));
+        assert_eq!(upstreams.first_up(), Some(addrs[1]));
+
+        // probes come due one interval after going down, then every interval.
+        assert!(upstreams.due_probes(PROBE_INTERVAL, now).is_empty());
+        let later = now + PROBE_INTERVAL;
+        assert_eq!(upstreams.due_probes(PROBE_INTERVAL, later), [addrs[0]]);
+        assert!(upstreams.due_probes(PROBE_INTERVAL, later).is_empty());
+
+        upstreams.success(addrs[0], None);
+        assert_eq!(upstreams.select(Selection::Priority), Some(addrs[0]));
+        assert!(upstreams
+            .due_probes(PROBE_INTERVAL, later + PROBE_INTERVAL)
+            .is_empty());
+    }
+
+    #[test]
+    fn with_all_down_the_first_is_used() {
+        let (mut upstreams, addrs) = upstreams(3);
+        let now = Instant::now();
+        for addr in &addrs {
+            take_down(&mut upstreams, *addr, now);
+        }
+        for selection in [
+            Selection::Priority,
+            Selection::RoundRobin,
+            Selection::Random,
+            Selection::LowestLatency,
+        ] {
+            assert_eq!(upstreams.select(selection), Some(addrs[0]));
+        }
+        assert_eq!(upstreams.first_up(), Some(addrs[0]));
+        assert_eq!(Upstreams::new(vec![]).select(Selection::Priority), None);
+    }
+
+    #[test]
+    fn lowest_latency_prefers_the_fastest_once_all_are_measured() {
+        let (mut upstreams, addrs) = upstreams(2);
+        upstreams.success(addrs[0], Some(Duration::from_millis(50)));
+        // not measured yet, so it goes first.
+        assert_eq!(upstreams.select(Selection::LowestLatency), Some(addrs[1]));
+        upstreams.success(addrs[1], Some(Duration::from_millis(10)));
+        assert_eq!(upstreams.select(Selection::LowestLatency), Some(addrs[1]));
+        // unanswered queries make it look slow long before it is marked down.
+        for _ in 0..3 {
+            assert!(!upstreams.failure(addrs[1], 10, PROBE_INTERVAL, Instant::now()));
+        }
+        assert_eq!(upstreams.select(Selection::LowestLatency), Some(addrs[0]));
+    }
+}
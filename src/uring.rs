@@ -0,0 +1,259 @@
+//! io_uring path for the UDP socket. A fixed set of `recvmsg` operations is kept posted on the
+//! ring, each with its own buffer, so the kernel fills them as datagrams arrive and a single
+//! `io_uring_enter` reaps however many have completed. Batches of responses go out as `sendmsg`
+//! operations on the same ring.
+//!
+//! Only the client UDP socket goes through the ring. TCP accepts, reads and writes, and the
+//! upstream sockets, stay on `poll` and plain syscalls.
+use std::{
+    io, mem,
+    net::{SocketAddr, UdpSocket},
+    os::fd::{AsRawFd, RawFd},
+    time::Duration,
+};
+
+use io_uring::{opcode, types, IoUring};
+
+use crate::udp;
+
+/// Set in the user data of sends, to tell their completions from those of receives, which
+/// carry the index of their slot.
+const SEND: u64 = 1 << 63;
+
+/// One posted receive. Lives in a boxed slice that is never resized, so the pointers the kernel
+/// holds into it stay valid.
+struct Slot {
+    buf: Box<[u8]>,
+    addr: libc::sockaddr_storage,
+    iov: libc::iovec,
+    msg: libc::msghdr,
+}
+
+pub struct UringUdp {
+    ring: IoUring,
+    fd: RawFd,
+    slots: Box<[Slot]>,
+    /// completions of receives reaped while waiting for sends, for `for_each_received`
+    received: Vec<(usize, i32)>,
+    /// set when `send_many` gave up on sends still in flight; nothing is submitted after
+    broken: bool,
+}
+
+impl UringUdp {
+    /// Sets up a ring with `depth` receives of `buf_size` bytes each posted on `socket`.
+    pub fn new(socket: &UdpSocket, depth: u32, buf_size: usize) -> io::Result<Self> {
+        let ring = IoUring::new(depth.next_power_of_two())?;
+        let slots = (0..depth)
+            .map(|_| Slot {
+                buf: vec![0; buf_size].into_boxed_slice(),
+                addr: unsafe { mem::zeroed() },
+                iov: unsafe { mem::zeroed() },
+                msg: unsafe { mem::zeroed() },
+            })
+            .collect();
+        let mut uring = UringUdp {
+            ring,
+            fd: socket.as_raw_fd(),
+            slots,
+            received: Vec::new(),
+            broken: false,
+        };
+        for i in 0..uring.slots.len() {
+            uring.post(i)?;
+        }
+        uring.ring.submit()?;
+        return Ok(uring);
+    }
+
+    /// Blocks until at least one datagram has arrived or `timeout` passes.
+    pub fn wait(&mut self, timeout: Duration) -> io::Result<()> {
+        self.check()?;
+        if self.has_received() {
+            return Ok(());
+        }
+        let ts = types::Timespec::from(timeout);
+        let args = types::SubmitArgs::new().timespec(&ts);
+        match self.ring.submitter().submit_with_args(1, &args) {
+            Ok(_) => return Ok(()),
+            Err(e) if e.raw_os_error() == Some(libc::ETIME) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+
+    /// Whether datagrams that arrived during `send_many` are waiting for `for_each_received`.
+    /// The ring doesn't poll readable for those, so don't wait on it while there are any.
+    pub fn has_received(&self) -> bool {
+        return !self.received.is_empty();
+    }
+
+    /// Calls `f` with every datagram received since the last call and its sender, then posts
+    /// the receives again. Never blocks.
+    pub fn for_each_received(
+        &mut self,
+        mut f: impl FnMut(&mut [u8], SocketAddr),
+    ) -> io::Result<()> {
+        self.check()?;
+        let mut done = mem::take(&mut self.received);
+        done.extend(
+            self.ring
+                .completion()
+                .map(|cqe| (cqe.user_data() as usize, cqe.result())),
+        );
+        for (i, result) in done {
+            let slot = &mut self.slots[i];
+            // a datagram cut short to fit the buffer is skipped like a failed receive too.
+            if result >= 0 && slot.msg.msg_flags & libc::MSG_TRUNC == 0 {
+                // a sender of an unsupported address family is skipped like a failed receive.
+                if let Ok(source) = udp::socket_addr_from_raw(&slot.addr) {
+                    f(&mut slot.buf[..result as usize], source);
+                }
+            }
+            self.post(i)?;
+        }
+        self.ring.submit()?;
+        return Ok(());
+    }
+
+    /// Sends each packet to the address paired with it, `MAX_BATCH` at a time, and waits for
+    /// the sends to complete. Returns the index and error of every packet that couldn't be
+    /// sent; the others still are.
+    ///
+    /// Fails if the ring can't be entered to wait for the sends. The ring is unusable after
+    /// that, as sends may still be in flight, and every later call fails too.
+    pub fn send_many<P: AsRef<[u8]>>(
+        &mut self,
+        packets: &[(P, SocketAddr)],
+    ) -> io::Result<Vec<(usize, io::Error)>> {
+        self.check()?;
+        let mut failed = Vec::new();
+        for (n, batch) in packets.chunks(udp::MAX_BATCH).enumerate() {
+            let start = n * udp::MAX_BATCH;
+            let mut addrs: [libc::sockaddr_storage; udp::MAX_BATCH] = unsafe { mem::zeroed() };
+            let mut iovs: [libc::iovec; udp::MAX_BATCH] = unsafe { mem::zeroed() };
+            let mut msgs: [libc::msghdr; udp::MAX_BATCH] = unsafe { mem::zeroed() };
+            let mut posted = 0;
+            for (i, ((((packet, dest), addr), iov), msg)) in batch
+                .iter()
+                .map(|(p, dest)| (p.as_ref(), dest))
+                .zip(&mut addrs)
+                .zip(&mut iovs)
+                .zip(&mut msgs)
+                .enumerate()
+            {
+                let (raw, len) = udp::socket_addr_to_raw(*dest);
+                *addr = raw;
+                // sendmsg only reads the payload, whatever the pointer type says.
+                *iov = libc::iovec {
+                    iov_base: packet.as_ptr() as *mut libc::c_void,
+                    iov_len: packet.len(),
+                };
+                msg.msg_name = addr as *mut _ as *mut libc::c_void;
+                msg.msg_namelen = len;
+                msg.msg_iov = iov;
+                msg.msg_iovlen = 1;
+                let entry = opcode::SendMsg::new(types::Fd(self.fd), msg)
+                    .build()
+                    .user_data(SEND | i as u64);
+                match unsafe { self.ring.submission().push(&entry) } {
+                    Ok(()) => posted += 1,
+                    Err(_) => failed.push((
+                        start + i,
+                        io::Error::other("io_uring submission queue full"),
+                    )),
+                }
+            }
+            // the kernel reads the packets and the headers above until each send completes,
+            // so there's no returning before every one has.
+            while posted > 0 {
+                match self.ring.submit_and_wait(1) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    // the completion queue is full; reaping below makes room.
+                    Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {}
+                    Err(e) => {
+                        self.broken = true;
+                        return Err(e);
+                    }
+                }
+                for cqe in self.ring.completion() {
+                    if cqe.user_data() & SEND == 0 {
+                        self.received.push((cqe.user_data() as usize, cqe.result()));
+                        continue;
+                    }
+                    posted -= 1;
+                    if cqe.result() < 0 {
+                        let i = (cqe.user_data() & !SEND) as usize;
+                        failed.push((start + i, io::Error::from_raw_os_error(-cqe.result())));
+                    }
+                }
+            }
+        }
+        return Ok(failed);
+    }
+
+    /// Fails once `send_many` has given up on the ring.
+    fn check(&self) -> io::Result<()> {
+        if self.broken {
+            return Err(io::Error::other("io_uring abandoned after a failed send"));
+        }
+        return Ok(());
+    }
+
+    /// Queues receive `i` on the submission queue.
+    fn post(&mut self, i: usize) -> io::Result<()> {
+        let slot = &mut self.slots[i];
+        slot.iov = libc::iovec {
+            iov_base: slot.buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: slot.buf.len(),
+        };
+        slot.msg = unsafe { mem::zeroed() };
+        slot.msg.msg_name = &mut slot.addr as *mut _ as *mut libc::c_void;
+        slot.msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        slot.msg.msg_iov = &mut slot.iov;
+        slot.msg.msg_iovlen = 1;
+        let entry = opcode::RecvMsg::new(types::Fd(self.fd), &mut slot.msg)
+            .build()
+            .user_data(i as u64);
+        // the queue has room for every slot, and each slot is posted at most once at a time.
+        unsafe { self.ring.submission().push(&entry) }
+            .map_err(|_| io::Error::other("io_uring submission queue full"))?;
+        return Ok(());
+    }
+}
+
+impl AsRawFd for UringUdp {
+    /// The ring itself, which polls readable while completions are waiting.
+    fn as_raw_fd(&self) -> RawFd {
+        self.ring.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_and_receives_through_the_ring() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut uring = UringUdp::new(&socket, 4, 512).unwrap();
+        peer.send_to(b"query", socket.local_addr().unwrap())
+            .unwrap();
+        uring.wait(Duration::from_secs(1)).unwrap();
+        let mut got = Vec::new();
+        uring
+            .for_each_received(|packet, source| got.push((packet.to_vec(), source)))
+            .unwrap();
+        assert_eq!(got, [(b"query".to_vec(), peer.local_addr().unwrap())]);
+
+        let dest = peer.local_addr().unwrap();
+        let failed = uring.send_many(&[(b"one", dest), (b"two", dest)]).unwrap();
+        assert!(failed.is_empty());
+        let mut buf = [0; 16];
+        let (len, _) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"one");
+        let (len, _) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"two");
+    }
+}
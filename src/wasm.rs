@@ -0,0 +1,79 @@
+use alloc::{format, string::String, vec::Vec};
+use wasm_bindgen::prelude::*;
+
+use crate::doh::{self, Method};
+use crate::message::{Edns, Header, Message, QType, Question, ResourceClass};
+use crate::name::Name;
+
+/// Parses a raw DNS packet and returns a human readable dump of it.
+#[wasm_bindgen(js_name = parsePacket)]
+pub fn parse_packet(bites: &[u8]) -> Result<String, JsError> {
+    let m = parse(bites)?;
+    return Ok(format!("{:#?}", m));
+}
+
+/// Parses a raw DNS packet and returns it in presentation form, laid out the way dig prints it.
+#[wasm_bindgen(js_name = formatPacket)]
+pub fn format_packet(bites: &[u8]) -> Result<String, JsError> {
+    let m = parse(bites)?;
+    return Ok(format!("{}", m));
+}
+
+/// Builds a recursive query for `name` and `qtype` (a mnemonic such as `AAAA`, or `TYPE65`),
+/// with an EDNS record advertising 1232 bytes.
+#[wasm_bindgen(js_name = encodeQuery)]
+pub fn encode_query(id: u16, name: &str, qtype: &str) -> Result<Vec<u8>, JsError> {
+    let name: Name = name
+        .parse()
+        .map_err(|e| JsError::new(&format!("invalid name: {}", e)))?;
+    let tipe: QType = qtype
+        .parse()
+        .map_err(|e| JsError::new(&format!("invalid type: {}", e)))?;
+    let mut header = Header::new(id);
+    header.rd = true;
+    let mut m = Message::new(header);
+    m.questions.push(Question {
+        tipe,
+        class: ResourceClass::IN,
+        name,
+        unicast_response: false,
+    });
+    m.edns = Some(Edns::new(1232));
+    return Ok(m.to_bytes().to_vec());
+}
+
+/// Parses a raw DNS packet and serializes it again, the way the server would put it on the wire.
+#[wasm_bindgen(js_name = reencodePacket)]
+pub fn reencode_packet(bites: &[u8]) -> Result<Vec<u8>, JsError> {
+    let m = parse(bites)?;
+    return Ok(m.to_bytes().to_vec());
+}
+
+/// The path and query string of a DNS over HTTPS GET request (RFC 8484) for the query in
+/// `bites`, to be fetched from the server's origin with `Accept: application/dns-message`.
+/// `path` is the endpoint, e.g. `/dns-query`.
+#[wasm_bindgen(js_name = dohGetPath)]
+pub fn doh_get_path(bites: &[u8], path: &str) -> Result<String, JsError> {
+    let query = parse(bites)?;
+    return Ok(doh::Request::new(&query, path, Method::Get).path);
+}
+
+/// Takes apart a fetched DNS over HTTPS response and returns its message in presentation form.
+/// Fails on a status other than 2xx or a Content-Type other than application/dns-message.
+#[wasm_bindgen(js_name = dohParseResponse)]
+pub fn doh_parse_response(
+    status: u16,
+    content_type: Option<String>,
+    body: &[u8],
+) -> Result<String, JsError> {
+    let m = doh::parse_response(status, content_type.as_deref(), body)
+        .map_err(|e| JsError::new(&format!("{}", e)))?;
+    return Ok(format!("{}", m));
+}
+
+fn parse(bites: &[u8]) -> Result<Message, JsError> {
+    match Message::parse(bites) {
+        Ok(m) => Ok(m),
+        Err(e) => Err(JsError::new(&format!("invalid DNS packet: {}", e))),
+    }
+}
@@ -0,0 +1,307 @@
+//! Zones this server answers for with authority. A zone file holds one record per line in
+//! master file form, as `Record` parses it, with blank lines and `;` comments skipped. There is
+//! no `$ORIGIN`, `$TTL` or `$INCLUDE`, owner names aren't carried over from the line before, and
+//! records can't span lines. Wildcards aren't expanded.
+use std::collections::BTreeMap;
+
+use crate::message::{rcode, QType, Question, Record, ResourceClass};
+use crate::name::Name;
+use crate::rdata::RData;
+use crate::text::TextError;
+
+/// Most CNAMEs followed within the zone for one answer.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Why a zone file couldn't be read.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ZoneError {
+    #[error("line {line}: {error}")]
+    Record { line: usize, error: TextError },
+    #[error("no SOA record")]
+    NoSoa,
+    #[error("line {line}: {name} is outside the zone")]
+    OutOfZone { line: usize, name: Name },
+}
+
+/// What a zone has to say about a question.
+#[derive(Debug, Clone)]
+pub struct Answer {
+    pub rcode: u8,
+    /// false for referrals to a delegated child zone
+    pub authoritative: bool,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Zone {
+    apex: Name,
+    /// owner name -> its records
+    records: BTreeMap<Name, Vec<Record>>,
+}
+
+impl Zone {
+    /// Reads a zone file. The owner of its SOA record is the apex, and every record must be at
+    /// or below it.
+    pub fn parse(text: &str) -> Result<Zone, ZoneError> {
+        let mut records = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let record: Record = line
+                .parse()
+                .map_err(|error| ZoneError::Record { line: i + 1, error })?;
+            records.push((i + 1, record));
+        }
+        let apex = records
+            .iter()
+            .find(|(_, r)| r.tipe == QType::SOA)
+            .map(|(_, r)| r.name.clone())
+            .ok_or(ZoneError::NoSoa)?;
+        let mut zone = Zone {
+            apex,
+            records: BTreeMap::new(),
+        };
+        for (line, record) in records {
+            if !record.name.is_subdomain_of(&zone.apex) {
+                let name = record.name;
+                return Err(ZoneError::OutOfZone { line, name });
+            }
+            zone.records
+                .entry(record.name.clone())
+                .or_default()
+                .push(record);
+        }
+        return Ok(zone);
+    }
+
+    pub fn apex(&self) -> &Name {
+        return &self.apex;
+    }
+
+    /// Whether `name` is at or below the apex. Names below a delegation count too; they get a
+    /// referral.
+    pub fn contains(&self, name: &Name) -> bool {
+        return name.is_subdomain_of(&self.apex);
+    }
+
+    /// The answer to `q`, whose name must be in the zone: its records, CNAMEs within the zone
+    /// followed; a referral if it is at or below a delegation; or else NODATA or NXDOMAIN, after
+    /// any CNAMEs, with the SOA record.
+    pub fn answer(&self, q: &Question) -> Answer {
+        let mut answer = Answer {
+            rcode: rcode::NOERROR,
+            authoritative: true,
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        };
+        if let Some(cut) = self.delegation(&q.name, q.tipe == QType::DS) {
+            answer.authoritative = false;
+            answer.authorities = self.rrset(cut, &QType::NS, &q.class);
+            for ns in answer.authorities.iter() {
+                if let RData::NS(target) = &ns.rdata {
+                    answer
+                        .additionals
+                        .extend(self.rrset(target, &QType::A, &q.class));
+                    answer
+                        .additionals
+                        .extend(self.rrset(target, &QType::AAAA, &q.class));
+                }
+            }
+            return answer;
+        }
+        let mut name = q.name.clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let found = self.rrset(&name, &q.tipe, &q.class);
+            if !found.is_empty() {
+                answer.answers.extend(found);
+                return answer;
+            }
+            let cname = self.rrset(&name, &QType::CNAME, &q.class);
+            let target = match cname.first().map(|r| &r.rdata) {
+                Some(RData::CNAME(target)) => target.clone(),
+                _ => break,
+            };
+            answer.answers.extend(cname);
+            // a target elsewhere, or delegated, is for the client to chase.
+            if !self.contains(&target) || self.delegation(&target, false).is_some() {
+                return answer;
+            }
+            name = target;
+        }
+        // the rcode is about the last name in the chain (RFC 6604).
+        if !self.exists(&name) {
+            answer.rcode = rcode::NXDOMAIN;
+        }
+        // RFC 2308 section 3: negative answers are cached for the lesser of the two.
+        let soa = self.rrset(&self.apex, &QType::SOA, &ResourceClass::IN);
+        answer.authorities = soa
+            .into_iter()
+            .map(|mut r| {
+                if let RData::SOA(soa) = &r.rdata {
+                    r.ttl = r.ttl.min(soa.minimum);
+                }
+                r
+            })
+            .collect();
+        return answer;
+    }
+
+    /// The owner of the NS records delegating `name` away, if any: the nearest name above it,
+    /// below the apex, that has some. `name` itself counts too, unless `parent_side` asks for
+    /// records the parent holds at the cut, like DS.
+    fn delegation(&self, name: &Name, parent_side: bool) -> Option<&Name> {
+        let mut above = Vec::new();
+        let mut n = name.clone();
+        if parent_side {
+            n = n.parent()?;
+        }
+        while n.label_count() > self.apex.label_count() {
+            above.push(n.clone());
+            n = n.parent()?;
+        }
+        // the topmost cut wins; anything below it belongs to the child.
+        return above.iter().rev().find_map(|n| {
+            let (owner, records) = self.records.get_key_value(n)?;
+            records.iter().any(|r| r.tipe == QType::NS).then_some(owner)
+        });
+    }
+
+    /// The records at `name` of type `tipe`, or of every type for ANY.
+    fn rrset(&self, name: &Name, tipe: &QType, class: &ResourceClass) -> Vec<Record> {
+        let Some(records) = self.records.get(name) else {
+            return Vec::new();
+        };
+        return records
+            .iter()
+            .filter(|r| (*tipe == QType::ANY || r.tipe == *tipe) && r.class == *class)
+            .cloned()
+            .collect();
+    }
+
+    /// Whether `name` has records, or names below it do (an empty non-terminal).
+    fn exists(&self, name: &Name) -> bool {
+        // in canonical order a name comes right before the names below it.
+        return self
+            .records
+            .range(name.clone()..)
+            .next()
+            .is_some_and(|(owner, _)| owner.is_subdomain_of(name));
+    }
+}
+
+/// The zones a server answers for.
+#[derive(Debug, Clone, Default)]
+pub struct Zones {
+    zones: Vec<Zone>,
+}
+
+impl Zones {
+    pub fn new(zones: Vec<Zone>) -> Zones {
+        return Zones { zones };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.zones.is_empty();
+    }
+
+    /// The zone `name` is in: the one with the closest enclosing apex.
+    pub fn find(&self, name: &Name) -> Option<&Zone> {
+        return self
+            .zones
+            .iter()
+            .filter(|z| z.contains(name))
+            .max_by_key(|z| z.apex.label_count());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = "
+        ; the apex
+        example.org. 3600 IN SOA ns1.example.org. admin.example.org. 1 7200 3600 1209600 300
+        example.org. 3600 IN NS ns1.example.org.
+        ns1.example.org. 3600 IN A 192.0.2.1
+        www.example.org. 3600 IN A 192.0.2.10
+        alias.example.org. 3600 IN CNAME again.example.org.
+        again.example.org. 3600 IN CNAME www.example.org.
+        dangling.example.org. 3600 IN CNAME nope.example.org.
+        a.b.example.org. 3600 IN TXT \"deep\"
+        sub.example.org. 3600 IN NS ns.sub.example.org.
+        ns.sub.example.org. 3600 IN A 192.0.2.53
+    ";
+
+    fn ask(name: &str, tipe: QType) -> Answer {
+        let zone = Zone::parse(ZONE).unwrap();
+        return zone.answer(&Question {
+            name: name.parse().unwrap(),
+            tipe,
+            class: ResourceClass::IN,
+            unicast_response: false,
+        });
+    }
+
+    fn types(records: &[Record]) -> Vec<QType> {
+        return records.iter().map(|r| r.tipe.clone()).collect();
+    }
+
+    #[test]
+    fn names_below_a_delegation_get_a_referral() {
+        let answer = ask("www.sub.example.org", QType::A);
+        assert!(!answer.authoritative);
+        assert_eq!(answer.rcode, rcode::NOERROR);
+        assert!(answer.answers.is_empty());
+        assert_eq!(types(&answer.authorities), [QType::NS]);
+        assert_eq!(answer.authorities[0].name.to_string(), "sub.example.org.");
+        // glue for the name server.
+        assert_eq!(types(&answer.additionals), [QType::A]);
+    }
+
+    #[test]
+    fn cname_chains_are_followed_within_the_zone() {
+        let answer = ask("alias.example.org", QType::A);
+        assert!(answer.authoritative);
+        assert_eq!(
+            types(&answer.answers),
+            [QType::CNAME, QType::CNAME, QType::A]
+        );
+        assert!(answer.authorities.is_empty());
+    }
+
+    #[test]
+    fn dangling_cname_ends_in_nxdomain() {
+        let answer = ask("dangling.example.org", QType::A);
+        assert_eq!(answer.rcode, rcode::NXDOMAIN);
+        assert_eq!(types(&answer.answers), [QType::CNAME]);
+        assert_eq!(types(&answer.authorities), [QType::SOA]);
+    }
+
+    #[test]
+    fn negative_answers_carry_the_soa() {
+        let nxdomain = ask("nope.example.org", QType::A);
+        assert_eq!(nxdomain.rcode, rcode::NXDOMAIN);
+        assert!(nxdomain.answers.is_empty());
+        assert_eq!(types(&nxdomain.authorities), [QType::SOA]);
+        // negative TTL: the lesser of the SOA's TTL and its minimum.
+        assert_eq!(nxdomain.authorities[0].ttl, 300);
+
+        let nodata = ask("www.example.org", QType::AAAA);
+        assert_eq!(nodata.rcode, rcode::NOERROR);
+        assert!(nodata.answers.is_empty());
+        assert_eq!(types(&nodata.authorities), [QType::SOA]);
+    }
+
+    #[test]
+    fn empty_non_terminals_exist() {
+        let answer = ask("b.example.org", QType::TXT);
+        assert_eq!(answer.rcode, rcode::NOERROR);
+        assert!(answer.answers.is_empty());
+        assert_eq!(types(&answer.authorities), [QType::SOA]);
+    }
+}
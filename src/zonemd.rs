@@ -0,0 +1,165 @@
+//! Zone digests (RFC 8976): a hash over every record of a zone, published in a ZONEMD record at
+//! the apex so that a copy of the zone can be checked however it was transferred.
+use alloc::vec::Vec;
+use bytes::{Bytes, BytesMut};
+use sha2::{Digest, Sha384, Sha512};
+
+use crate::message::{QType, Record};
+use crate::name::Name;
+use crate::rdata::RData;
+
+/// The SIMPLE scheme: one digest over the whole zone. The only one defined.
+pub const SCHEME_SIMPLE: u8 = 1;
+pub const HASH_SHA384: u8 = 1;
+pub const HASH_SHA512: u8 = 2;
+
+/// Why a zone failed verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ZonemdError {
+    #[error("no SOA record at the zone apex")]
+    NoSoa,
+    #[error("no ZONEMD record at the zone apex")]
+    NoZonemd,
+    #[error("no ZONEMD record at the zone apex has a supported scheme and hash algorithm")]
+    Unsupported,
+    #[error("ZONEMD serial {zonemd} doesn't match SOA serial {soa}")]
+    SerialMismatch { soa: u32, zonemd: u32 },
+    #[error("zone digest doesn't match")]
+    Mismatch,
+}
+
+/// The SIMPLE digest of the zone at `apex`, given all of its records in any order, glue
+/// included. Records outside the zone, the apex ZONEMD records and the RRSIGs over them are left
+/// out, as are duplicates. None if `hash_algorithm` isn't SHA-384 or SHA-512.
+pub fn digest(apex: &Name, records: &[Record], hash_algorithm: u8) -> Option<Vec<u8>> {
+    if hash_algorithm != HASH_SHA384 && hash_algorithm != HASH_SHA512 {
+        return None;
+    }
+    // each record with what it sorts by: canonical owner, type, and canonical rdata.
+    let mut keyed: Vec<(Name, u16, Bytes, Record)> = records
+        .iter()
+        .filter(|r| r.name.is_subdomain_of(apex) && !is_apex_zonemd(apex, r))
+        .map(|r| {
+            let r = r.to_canonical();
+            (r.name.clone(), r.tipe.value(), r.rdata.to_bytes(), r)
+        })
+        .collect();
+    keyed.sort_by(|a, b| (&a.0, a.1, &a.2).cmp(&(&b.0, b.1, &b.2)));
+    keyed.dedup_by(|a, b| a.0 == b.0 && a.1 == b.1 && a.2 == b.2);
+    let mut buf = BytesMut::new();
+    for (_, _, _, record) in &keyed {
+        record.write_canonical(&mut buf);
+    }
+    let digest = match hash_algorithm {
+        HASH_SHA384 => Sha384::digest(&buf).to_vec(),
+        _ => Sha512::digest(&buf).to_vec(),
+    };
+    return Some(digest);
+}
+
+/// Checks the zone at `apex` against its ZONEMD records. It passes if any of them with a
+/// supported scheme and hash algorithm matches; the others are ignored.
+pub fn verify(apex: &Name, records: &[Record]) -> Result<(), ZonemdError> {
+    let soa_serial = records
+        .iter()
+        .find_map(|r| match &r.rdata {
+            RData::SOA(soa) if r.name == *apex => Some(soa.serial),
+            _ => None,
+        })
+        .ok_or(ZonemdError::NoSoa)?;
+    let mut result = Err(ZonemdError::NoZonemd);
+    for record in records.iter().filter(|r| r.name == *apex) {
+        let RData::ZONEMD {
+            serial,
+            scheme,
+            hash_algorithm,
+            digest: expected,
+        } = &record.rdata
+        else {
+            continue;
+        };
+        let actual = match *scheme {
+            SCHEME_SIMPLE => digest(apex, records, *hash_algorithm),
+            _ => None,
+        };
+        let Some(actual) = actual else {
+            // a record we can't check only counts if there is nothing more telling.
+            if result == Err(ZonemdError::NoZonemd) {
+                result = Err(ZonemdError::Unsupported);
+            }
+            continue;
+        };
+        if *serial != soa_serial {
+            result = Err(ZonemdError::SerialMismatch {
+                soa: soa_serial,
+                zonemd: *serial,
+            });
+        } else if actual == *expected {
+            return Ok(());
+        } else {
+            result = Err(ZonemdError::Mismatch);
+        }
+    }
+    return result;
+}
+
+/// Whether `record` is a ZONEMD record at the apex or an RRSIG over one. These can't be part of
+/// the digest they carry.
+fn is_apex_zonemd(apex: &Name, record: &Record) -> bool {
+    if record.name != *apex {
+        return false;
+    }
+    return match &record.rdata {
+        RData::ZONEMD { .. } => true,
+        RData::RRSIG(rrsig) => rrsig.type_covered == QType::ZONEMD.value(),
+        _ => false,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The simple EXAMPLE zone of RFC 8976 appendix A.1, with its SHA-384 digest.
+    fn example_zone() -> Vec<Record> {
+        return [
+            "example. 86400 IN SOA ns1.example. admin.example. 2018031900 1800 900 604800 86400",
+            "example. 86400 IN NS ns1.example.",
+            "example. 86400 IN NS ns2.example.",
+            "example. 86400 IN ZONEMD 2018031900 1 1 c68090d90a7aed716bc459f9340e3d7c1370d4d24b7e2fc3a1ddc0b9a87153b9a9713b3c9ae5cc27777f98b8e730044c",
+            "ns1.example. 3600 IN A 203.0.113.63",
+            "ns2.example. 3600 IN AAAA 2001:db8::63",
+        ]
+        .iter()
+        .map(|r| r.parse().unwrap())
+        .collect();
+    }
+
+    #[test]
+    fn rfc_8976_simple_example_zone_verifies() {
+        let apex: Name = "example.".parse().unwrap();
+        let mut records = example_zone();
+        assert_eq!(verify(&apex, &records), Ok(()));
+        // order doesn't matter, and neither do duplicates.
+        records.reverse();
+        records.push(records[0].clone());
+        assert_eq!(verify(&apex, &records), Ok(()));
+
+        let digest = digest(&apex, &records, HASH_SHA384).unwrap();
+        assert_eq!(digest.len(), 48);
+        assert_eq!(digest[..4], [0xc6, 0x80, 0x90, 0xd9]);
+    }
+
+    #[test]
+    fn changed_zone_fails_to_verify() {
+        let apex: Name = "example.".parse().unwrap();
+        let mut records = example_zone();
+        records.push("ns3.example. 3600 IN A 203.0.113.64".parse().unwrap());
+        assert_eq!(verify(&apex, &records), Err(ZonemdError::Mismatch));
+
+        let mut records = example_zone();
+        records.retain(|r| r.tipe != QType::ZONEMD);
+        assert_eq!(verify(&apex, &records), Err(ZonemdError::NoZonemd));
+        assert_eq!(digest(&apex, &records, 3), None);
+    }
+}